@@ -0,0 +1,318 @@
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use xyncer_share::audio::{AudioDecoder, AudioError, AvSync, JitterBuffer, CHANNELS, SAMPLE_RATE};
+use xyncer_share::frames::Frame;
+
+// Plays the server's audio, as SAMPLE_RATE stereo
+pub trait AudioOutput: Send {
+    fn play(&mut self, samples: &[i16]) -> Result<(), AudioError>;
+
+    // How long until samples handed to play now are heard
+    fn latency(&self) -> Duration;
+
+    // Called once nothing more will be played
+    fn finish(&mut self) -> Result<(), AudioError> {
+        Ok(())
+    }
+}
+
+// Plays nothing, for receiving audio without a device to hear it on
+#[derive(Default)]
+pub struct NullOutput {
+    pub samples: u64,
+}
+
+impl AudioOutput for NullOutput {
+    fn play(&mut self, samples: &[i16]) -> Result<(), AudioError> {
+        self.samples += samples.len() as u64;
+
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// Size of a WAV header, which the samples follow
+const WAV_HEADER_SIZE: u32 = 44;
+
+// Writes what would be played to a WAV file
+pub struct WavOutput {
+    file: std::io::BufWriter<std::fs::File>,
+    size: u32, // Bytes of samples written so far
+}
+
+impl WavOutput {
+    pub fn create(path: &Path) -> Result<Self, AudioError> {
+        let mut output = WavOutput {
+            file: std::io::BufWriter::new(std::fs::File::create(path)?),
+            size: 0,
+        };
+
+        // The sizes in it are filled in once we know them
+        output.write_header()?;
+
+        Ok(output)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let block_align = (CHANNELS * 2) as u16;
+
+        self.file.write_all(b"RIFF")?;
+        self.file
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.size).to_le_bytes())?;
+        self.file.write_all(b"WAVEfmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?; // Size of the fmt chunk
+        self.file.write_all(&1u16.to_le_bytes())?; // PCM
+        self.file.write_all(&(CHANNELS as u16).to_le_bytes())?;
+        self.file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        self.file
+            .write_all(&(SAMPLE_RATE * u32::from(block_align)).to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        self.file.write_all(b"data")?;
+        self.file.write_all(&self.size.to_le_bytes())
+    }
+}
+
+impl AudioOutput for WavOutput {
+    fn play(&mut self, samples: &[i16]) -> Result<(), AudioError> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+
+        self.size += samples.len() as u32 * 2;
+
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn finish(&mut self) -> Result<(), AudioError> {
+        self.file.rewind()?;
+        self.write_header()?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
+
+// Plays through the default output device
+#[cfg(feature = "system-audio")]
+pub struct SpeakerOutput {
+    queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<i16>>>,
+    _stop: std::sync::mpsc::Sender<()>, // The device is let go of once this is dropped
+}
+
+// Most audio to queue for the device. Past this it has fallen behind, and the oldest is skipped.
+#[cfg(feature = "system-audio")]
+const SPEAKER_MAX_QUEUE: Duration = Duration::from_millis(200);
+
+#[cfg(feature = "system-audio")]
+impl SpeakerOutput {
+    pub fn open() -> Result<Self, AudioError> {
+        let queue = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
+        let (stop_sender, stop) = std::sync::mpsc::channel::<()>();
+        let (opened_sender, opened) = std::sync::mpsc::channel();
+
+        let device_queue = queue.clone();
+
+        // cpal streams can't move between threads, so it lives on one of its own
+        std::thread::spawn(move || match speaker::open(device_queue) {
+            Ok(stream) => {
+                let _ = opened_sender.send(Ok(()));
+
+                // Returns once the output is dropped
+                let _ = stop.recv();
+
+                drop(stream);
+            }
+            Err(e) => {
+                let _ = opened_sender.send(Err(e));
+            }
+        });
+
+        opened.recv()??;
+
+        Ok(SpeakerOutput {
+            queue,
+            _stop: stop_sender,
+        })
+    }
+}
+
+#[cfg(feature = "system-audio")]
+impl AudioOutput for SpeakerOutput {
+    fn play(&mut self, samples: &[i16]) -> Result<(), AudioError> {
+        let max_queue =
+            SPEAKER_MAX_QUEUE.as_millis() as usize * SAMPLE_RATE as usize / 1000 * CHANNELS;
+        let mut queue = self.queue.lock().unwrap();
+
+        queue.extend(samples);
+
+        if queue.len() > max_queue {
+            let skipped = queue.len() - max_queue;
+
+            queue.drain(..skipped);
+        }
+
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        let queued = self.queue.lock().unwrap().len() / CHANNELS;
+
+        Duration::from_secs_f64(queued as f64 / f64::from(SAMPLE_RATE))
+    }
+}
+
+#[cfg(feature = "system-audio")]
+mod speaker {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use xyncer_share::audio::{AudioError, CHANNELS, SAMPLE_RATE};
+
+    type Queue = std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<i16>>>;
+
+    // Starts playing the queue on the default output device, converting it to whatever the device runs at
+    pub fn open(queue: Queue) -> Result<cpal::Stream, AudioError> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("There is no output device to play audio on")?;
+
+        let config = device.default_output_config()?;
+        let channels = usize::from(config.channels()).max(1);
+
+        // Device samples per channel for each of ours, played nearest first
+        let step = f64::from(SAMPLE_RATE) / f64::from(config.sample_rate().0);
+        let mut position = 0.0;
+        let mut current = [0i16; CHANNELS];
+
+        let mut next_frame = move || {
+            let mut queue = queue.lock().unwrap();
+
+            position += step;
+
+            while position >= 1.0 {
+                position -= 1.0;
+
+                // Silence while waiting for more
+                for sample in current.iter_mut() {
+                    *sample = queue.pop_front().unwrap_or(0);
+                }
+            }
+
+            current
+        };
+
+        let on_error = |e| log::warn!("Audio playback failed: {}", e);
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_output_stream(
+                &config.config(),
+                move |data: &mut [f32], _: &_| {
+                    for frame in data.chunks_exact_mut(channels) {
+                        let samples = next_frame();
+
+                        // Mono gets the left side, and channels past stereo are left silent
+                        for (channel, sample) in frame.iter_mut().enumerate() {
+                            *sample = samples
+                                .get(channel)
+                                .map_or(0.0, |sample| f32::from(*sample) / f32::from(i16::MAX));
+                        }
+                    }
+                },
+                on_error,
+                None,
+            )?,
+            cpal::SampleFormat::I16 => device.build_output_stream(
+                &config.config(),
+                move |data: &mut [i16], _: &_| {
+                    for frame in data.chunks_exact_mut(channels) {
+                        let samples = next_frame();
+
+                        for (channel, sample) in frame.iter_mut().enumerate() {
+                            *sample = samples.get(channel).copied().unwrap_or(0);
+                        }
+                    }
+                },
+                on_error,
+                None,
+            )?,
+            format => return Err(format!("Can't play {} audio", format).into()),
+        };
+
+        stream.play()?;
+
+        Ok(stream)
+    }
+}
+
+// The audio being received from the server, waiting its turn to be played, and the frames waiting for it to be
+// heard. Kept apart from the session, so playing a packet doesn't hold up everything else that uses it.
+#[derive(Default)]
+pub struct Playback {
+    pub stream: Option<Stream>, // None unless the server is streaming audio to us
+    pub av_sync: AvSync<(u32, Frame)>,
+}
+
+pub struct Stream {
+    pub jitter: JitterBuffer,
+    pub decoder: Box<dyn AudioDecoder>,
+}
+
+impl Playback {
+    pub fn stop(&mut self) {
+        self.stream = None;
+        self.av_sync.audio_stopped();
+    }
+
+    pub fn clear(&mut self) {
+        self.stream = None;
+        self.av_sync.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_output_counts_what_it_is_given() {
+        let mut output = NullOutput::default();
+
+        output.play(&[0; 8]).unwrap();
+        output.play(&[1; 4]).unwrap();
+        output.finish().unwrap();
+
+        assert_eq!(output.samples, 12);
+        assert_eq!(output.latency(), Duration::ZERO);
+    }
+
+    #[test]
+    fn wav_output_fills_in_its_sizes() {
+        let path = std::env::temp_dir().join(format!("xyncer-test-{}.wav", std::process::id()));
+
+        let mut output = WavOutput::create(&path).unwrap();
+
+        output.play(&[1, -1, 2, -2]).unwrap();
+        output.finish().unwrap();
+        drop(output);
+
+        let wav = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let size = |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 8);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(size(4), WAV_HEADER_SIZE - 8 + 8);
+        assert_eq!(size(40), 8);
+        assert_eq!(&wav[44..46], &1i16.to_le_bytes());
+    }
+}
@@ -0,0 +1,203 @@
+use std::path::PathBuf;
+
+// Command line flags
+#[derive(clap::Parser, Debug)]
+#[command(group(clap::ArgGroup::new("launch_target").args(["launch_app", "launch_executable"])))]
+#[command(
+    version,
+    about = "Shows windows shared by xyncer servers on this device"
+)]
+pub struct Cli {
+    /// Connect with a saved server, launch one of its apps and show only that app's windows, as desktop entries do
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["PROFILE", "APP"],
+        conflicts_with = "headless"
+    )]
+    pub launch: Option<Vec<String>>,
+
+    /// Run without a window, for scripting and testing against servers
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Saved server to connect to
+    #[arg(long, value_name = "NAME", requires = "headless")]
+    pub profile: Option<String>,
+
+    /// Server to connect to, as wss://host:port or ws://host:port
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        requires = "headless",
+        conflicts_with = "profile"
+    )]
+    pub address: Option<String>,
+
+    /// Passphrase shown by the server, asked for on standard input if not given or saved in the profile
+    #[arg(long, requires = "headless")]
+    pub passphrase: Option<String>,
+
+    /// Print the remote windows, one per line as id, process, size and title separated by tabs [default if nothing else is asked for]
+    #[arg(long, requires = "headless")]
+    pub list: bool,
+
+    /// Print the server's app catalog, one app per line as id, category, name and executable separated by tabs
+    #[arg(long, requires = "headless")]
+    pub list_apps: bool,
+
+    /// Add the server's apps to this device's applications menu, each launching through the profile
+    #[arg(long, requires = "profile")]
+    pub install_launchers: bool,
+
+    /// Put this text on the server's clipboard
+    #[arg(long, value_name = "TEXT", requires = "headless")]
+    pub copy: Option<String>,
+
+    /// Wait for the server's clipboard to change and print the text on it
+    #[arg(long, requires = "headless")]
+    pub paste: bool,
+
+    /// Send a file to the server, can be given more than once
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    pub upload: Vec<PathBuf>,
+
+    /// Download one of the server's shared files by its path in the shared directory, can be given more than once
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    pub download: Vec<String>,
+
+    /// Where to save downloaded files [default: the current directory]
+    #[arg(long, value_name = "DIRECTORY", requires = "download")]
+    pub download_directory: Option<PathBuf>,
+
+    /// Save frames of the remote windows as PNG files in this directory
+    #[arg(long, value_name = "DIRECTORY", requires = "headless")]
+    pub dump_frames: Option<PathBuf>,
+
+    /// Window to save frames of, can be given more than once [default: the launched app's windows, or every window]
+    #[arg(long = "window", value_name = "ID", requires = "dump_frames")]
+    pub windows: Vec<u32>,
+
+    /// Frames to save per window. Frames are only sent when a window changes, so fewer may be saved
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 1,
+        requires = "dump_frames"
+    )]
+    pub frames: u32,
+
+    /// Play the server's audio for this many seconds, then print how many packets were received, played, made up for
+    /// and dropped, and how often playback ran dry, separated by tabs
+    #[arg(long, value_name = "SECONDS", requires = "headless")]
+    pub audio: Option<u64>,
+
+    /// Save the audio played to this WAV file
+    #[arg(long, value_name = "PATH", requires = "audio")]
+    pub audio_wav: Option<PathBuf>,
+
+    /// App in the server's catalog to launch
+    #[arg(long, value_name = "ID", requires = "headless")]
+    pub launch_app: Option<String>,
+
+    /// Executable on the server host to launch
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    pub launch_executable: Option<String>,
+
+    /// Argument for the launched app, can be given more than once
+    #[arg(
+        long = "launch-arg",
+        value_name = "ARGUMENT",
+        allow_hyphen_values = true,
+        requires = "launch_target"
+    )]
+    pub launch_arguments: Vec<String>,
+
+    /// Working directory for the launched app
+    #[arg(long, value_name = "DIRECTORY", requires = "launch_target")]
+    pub launch_directory: Option<String>,
+
+    /// Environment variable for the launched app, can be given more than once
+    #[arg(
+        long = "launch-env",
+        value_name = "NAME=VALUE",
+        value_parser = parse_environment_variable,
+        requires = "launch_target"
+    )]
+    pub launch_environment: Vec<(String, String)>,
+
+    /// How long to wait for the server at each step, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub timeout: u64,
+
+    /// One of off, error, warn, info, debug or trace [default: info, or warn when headless]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<log::LevelFilter>,
+}
+
+fn parse_environment_variable(variable: &str) -> Result<(String, String), String> {
+    variable
+        .split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("\"{}\" is not of the form NAME=VALUE", variable))
+}
+
+impl Cli {
+    pub fn log_level(&self) -> log::LevelFilter {
+        match self.log_level {
+            Some(log_level) => log_level,
+            // Output is for scripts when headless, so keep the log quiet
+            None if self.headless => log::LevelFilter::Warn,
+            None => log::LevelFilter::Info,
+        }
+    }
+
+    // Whether the clipboard is used when headless
+    pub fn uses_clipboard(&self) -> bool {
+        self.copy.is_some() || self.paste
+    }
+
+    // Whether files are sent or received when headless
+    pub fn transfers_files(&self) -> bool {
+        !self.upload.is_empty() || !self.download.is_empty()
+    }
+
+    // Whether to print the remote windows, which is done when nothing else is asked for
+    pub fn prints_windows(&self) -> bool {
+        self.list
+            || (self.dump_frames.is_none()
+                && self.launch_data().is_none()
+                && !self.list_apps
+                && !self.install_launchers
+                && !self.uses_clipboard()
+                && !self.transfers_files()
+                && self.audio.is_none())
+    }
+
+    // The profile and app to launch from a desktop entry, if any
+    pub fn launch_from_profile(&self) -> Option<(String, String)> {
+        match self.launch.as_deref() {
+            Some([profile, app]) => Some((profile.clone(), app.clone())),
+            _ => None,
+        }
+    }
+
+    // The launch asked for when headless, if any
+    pub fn launch_data(&self) -> Option<xyncer_share::payloads::LaunchAppData> {
+        let target = match (&self.launch_app, &self.launch_executable) {
+            (Some(id), _) => xyncer_share::payloads::LaunchTarget::App(id.clone()),
+            (None, Some(executable)) => {
+                xyncer_share::payloads::LaunchTarget::Executable(executable.clone())
+            }
+            (None, None) => return None,
+        };
+
+        Some(xyncer_share::payloads::LaunchAppData {
+            target,
+            arguments: self.launch_arguments.clone(),
+            working_directory: self.launch_directory.clone(),
+            environment: self.launch_environment.iter().cloned().collect(),
+        })
+    }
+}
@@ -1,16 +1,13 @@
 use std::future::Future;
 
-use bytes;
-use fastwebsockets;
-use http_body_util;
-use hyper;
-use hyper_util;
 use std::sync::Arc;
-use tokio;
 use tokio::sync::RwLock;
 
-use crate::session;
-use xyncer_share::{self, Websocket};
+use crate::{audio, events, session, tls};
+use xyncer_share::Websocket;
+
+// How often to check for clipboard changes
+const CLIPBOARD_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(500);
 
 // Tie Hyper's executor to Tokio's runtime
 struct SpawnExecutor;
@@ -26,19 +23,82 @@ where
     }
 }
 
-// Connects to the specified WebSocket server
+// Aborts a task when dropped, so it can't outlive the connection that started it
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// The tasks that run alongside a connection, which end with it however it ends
+#[derive(Default)]
+pub struct Tasks {
+    heartbeat: Option<AbortOnDrop>, // Started once the server says hello
+    audio: Option<AbortOnDrop>,     // Started once the server says it streams audio
+}
+
+impl Tasks {
+    pub fn start_heartbeats(&mut self, payload_sender: flume::Sender<xyncer_share::Payload>, interval: u8) {
+        self.heartbeat = Some(AbortOnDrop(tokio::spawn(async move {
+            loop {
+                if let Err(e) = payload_sender.send(heartbeat()) {
+                    log::error!("Error sending heartbeat (stopping): {}", e);
+
+                    break;
+                }
+
+                tokio::time::sleep(tokio::time::Duration::from_secs(interval.into())).await;
+            }
+        })));
+    }
+
+    pub fn start_audio(
+        &mut self,
+        playback: Arc<std::sync::Mutex<audio::Playback>>,
+        output: Arc<std::sync::Mutex<Box<dyn audio::AudioOutput>>>,
+    ) {
+        self.audio = Some(AbortOnDrop(tokio::spawn(play_audio(playback, output))));
+    }
+}
+
+fn heartbeat() -> xyncer_share::Payload {
+    xyncer_share::Payload {
+        op_code: xyncer_share::OP::Heartbeat,
+        event_name: xyncer_share::Event::None,
+        data: xyncer_share::payloads::PayloadData::Heartbeat,
+        sequence: None,
+        nonce: None,
+    }
+}
+
+// Connects to the specified WebSocket server, over TLS unless the address starts with ws://.
+// Returns the fingerprint of the server's certificate along with the connection.
 async fn connect(
-    server_url: &str,
-) -> Result<
-    fastwebsockets::FragmentCollector<hyper_util::rt::TokioIo<hyper::upgrade::Upgraded>>,
-    Box<dyn std::error::Error + Send + Sync>,
-> {
+    server_address: &str,
+    pinned_fingerprint: Option<&str>,
+) -> Result<(xyncer_share::Connection, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let (secure, address) = match server_address.split_once("://") {
+        Some(("wss", address)) => (true, address),
+        Some(("ws", address)) => (false, address),
+        Some((scheme, _)) => return Err(format!("Unsupported scheme {}://, use wss:// or ws://", scheme).into()),
+        None => (true, server_address),
+    };
+
+    let address = address.trim_end_matches('/');
+
+    // A pinned certificate means nothing over a connection without one
+    if !secure && pinned_fingerprint.is_some() {
+        return Err(format!("The profile pins a certificate for {}, so it has to be connected to with wss://", address).into());
+    }
+
     // Connect to the WebSocket server
-    let stream = tokio::net::TcpStream::connect(server_url).await?;
+    let stream = tokio::net::TcpStream::connect(address).await?;
 
     // Create a WebSocket handshake request
     let request = hyper::Request::builder()
-        .uri(format!("ws://{}/", server_url))
+        .uri(format!("{}://{}/", if secure { "wss" } else { "ws" }, address))
         .header("Connection", "Upgrade")
         .header("Upgrade", "websocket")
         .header("Sec-WebSocket-Version", "13")
@@ -48,37 +108,717 @@ async fn connect(
         )
         .body(http_body_util::Empty::<bytes::Bytes>::new())?;
 
+    if !secure {
+        log::warn!("Connecting to {} without TLS, passphrases and windows will be sent in cleartext", address);
+
+        // Perform the WebSocket handshake
+        let (ws, _) = fastwebsockets::handshake::client(&SpawnExecutor, request, stream).await?;
+
+        // Create a Connection to handle the WebSocket messages
+        return Ok((xyncer_share::Connection::new(ws), None));
+    }
+
+    // Names aren't checked (the certificate is pinned instead), but are still sent for SNI
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let server_name = rustls::pki_types::ServerName::try_from(host.trim_matches(['[', ']']).to_string())?;
+
+    let stream = tokio_rustls::TlsConnector::from(tls::config()?)
+        .connect(server_name, stream)
+        .await?;
+
+    let certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .ok_or("The server did not present a certificate")?;
+
+    let fingerprint = xyncer_share::certificate_fingerprint(certificate);
+
+    match pinned_fingerprint {
+        // The profile pins a certificate, so nothing else will do
+        Some(pinned_fingerprint) if pinned_fingerprint != fingerprint => {
+            return Err(format!(
+                "The certificate of {} does not match the one pinned in the profile (expected {}, got {})",
+                address, pinned_fingerprint, fingerprint
+            )
+            .into());
+        }
+        Some(_) => {}
+        None => tls::verify_fingerprint(address, &fingerprint)?,
+    }
+
     // Perform the WebSocket handshake
     let (ws, _) = fastwebsockets::handshake::client(&SpawnExecutor, request, stream).await?;
 
-    // Create a FragmentCollector to handle the WebSocket messages
-    Ok(fastwebsockets::FragmentCollector::new(ws))
+    // Create a Connection to handle the WebSocket messages
+    Ok((xyncer_share::Connection::new(ws), Some(fingerprint)))
+}
+
+// First delay between reconnection attempts, doubled after every failed attempt
+const RECONNECT_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Longest delay between reconnection attempts
+const RECONNECT_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+// How long to wait before the given reconnection attempt, with jitter so clients don't all reconnect at once
+fn reconnect_delay(attempt: u32) -> std::time::Duration {
+    let delay = RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RECONNECT_MAX_DELAY);
+
+    delay.mul_f64(rand::Rng::gen_range(&mut rand::thread_rng(), 0.5..=1.0))
+}
+
+// Builds an Identify payload for the given passphrase, offering the preferred codec (if any) first
+pub fn identify(
+    passphrase: String,
+    preferred_codec: Option<&str>,
+    capabilities: Vec<xyncer_share::payloads::Capability>,
+) -> xyncer_share::Payload {
+    let mut codecs = xyncer_share::codecs::names();
+
+    // The server picks the first codec it supports
+    if let Some(index) = codecs.iter().position(|codec| Some(codec.as_str()) == preferred_codec) {
+        let codec = codecs.remove(index);
+
+        codecs.insert(0, codec);
+    }
+
+    xyncer_share::Payload {
+        op_code: xyncer_share::OP::Identify,
+        event_name: xyncer_share::Event::None,
+        data: xyncer_share::payloads::PayloadData::Identify(xyncer_share::payloads::IdentifyData {
+            passphrase,
+            codecs,
+            audio_codecs: xyncer_share::audio::names(),
+            protocol_version: xyncer_share::PROTOCOL_VERSION,
+            capabilities,
+        }),
+        sequence: None,
+        nonce: None,
+    }
+}
+
+// What the server says became of a launch
+pub type LaunchResult =
+    Result<xyncer_share::payloads::AppLaunchedData, xyncer_share::payloads::LaunchFailedData>;
+
+// Asks the server to launch an app, returning how it went. The server answers once the app opens its windows, or
+// once it gives up waiting for them.
+pub async fn launch_app(
+    session_data_guard: &RwLock<session::Session>,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    data: xyncer_share::payloads::LaunchAppData,
+    timeout: std::time::Duration,
+) -> Result<LaunchResult, xyncer_share::RequestError> {
+    let payload = xyncer_share::Payload {
+        op_code: xyncer_share::OP::LaunchApp,
+        event_name: xyncer_share::Event::None,
+        data: xyncer_share::payloads::PayloadData::LaunchApp(data),
+        sequence: None,
+        nonce: None,
+    };
+
+    // Obtain a read lock on the session data
+    let (payload, answer) = session_data_guard.read().await.requests.start(payload);
+
+    // Nothing sends it once the client task is gone
+    if payload_sender.send(payload).is_err() {
+        return Err(xyncer_share::RequestError::Closed(fastwebsockets::WebSocketError::ConnectionClosed));
+    }
+
+    let payload = answer.wait(timeout).await?;
+
+    if let Some(launched) = <events::AppLaunched as events::Kind>::data(&payload) {
+        return Ok(Ok(launched.clone()));
+    }
+
+    <events::LaunchFailed as events::Kind>::data(&payload)
+        .map(|failed| Err(failed.clone()))
+        .ok_or_else(|| xyncer_share::RequestError::Failed(xyncer_share::payloads::ErrorCode::DecodeError.populate()))
+}
+
+// Builds a Clipboard payload, putting the data on the server's clipboard
+pub fn set_clipboard(data: xyncer_share::payloads::ClipboardData) -> xyncer_share::Payload {
+    xyncer_share::Payload {
+        op_code: xyncer_share::OP::Clipboard,
+        event_name: xyncer_share::Event::None,
+        data: xyncer_share::payloads::PayloadData::Clipboard(data),
+        sequence: None,
+        nonce: None,
+    }
+}
+
+// Id for a transfer we start, unique enough that the server won't have another by it
+fn transfer_id() -> String {
+    rand::distributions::DistString::sample_string(
+        &rand::distributions::Alphanumeric,
+        &mut rand::thread_rng(),
+        16,
+    )
+}
+
+// Offers a file on this device to the server, reading it first for its checksum
+pub async fn send_file(
+    session_data_guard: &RwLock<session::Session>,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    path: std::path::PathBuf,
+) {
+    let transfer_id = transfer_id();
+    let name = path.display().to_string();
+
+    // Reading the whole file takes a while, so keep it off the async workers
+    let opened = match tokio::task::spawn_blocking({
+        let transfer_id = transfer_id.clone();
+
+        move || xyncer_share::transfer::Outgoing::open(transfer_id, &path)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => Err(e.into()),
+    };
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    let payload = match opened {
+        Ok(outgoing) => session_data.transfers.offer(outgoing),
+        Err(e) => {
+            return session_data.transfers.failed_to_start(
+                transfer_id,
+                name,
+                xyncer_share::transfer::Direction::Sending,
+                e.to_string(),
+            )
+        }
+    };
+
+    // Drop the write lock on the session data
+    drop(session_data);
+
+    if let Err(e) = payload_sender.send(payload) {
+        log::error!("Error sending file offer: {}", e);
+    }
+}
+
+// Asks the server for one of its shared files, which is saved in the download directory
+pub fn request_file(
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    session_data: &mut session::Session,
+    path: String,
+) {
+    let payload = session_data.transfers.request(transfer_id(), path);
+
+    if let Err(e) = payload_sender.send(payload) {
+        log::error!("Error sending file request: {}", e);
+    }
+}
+
+// Starts or stops streaming a remote window
+pub fn set_streaming(
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    session_data: &mut session::Session,
+    window_id: u32,
+    streaming: bool,
+) {
+    let data = xyncer_share::payloads::SubscribeData { window_id };
+
+    let payload = if streaming {
+        session_data
+            .subscriptions
+            .insert(window_id, xyncer_share::frames::FrameDecoder::new(session_data.codec.clone()));
+
+        xyncer_share::Payload {
+            op_code: xyncer_share::OP::Subscribe,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::Subscribe(data),
+            sequence: None,
+            nonce: Some(session_data.requests.nonce()), // Failures arrive through the RequestFailed handlers
+        }
+    } else {
+        session_data.subscriptions.remove(&window_id);
+        session_data.frames.remove(&window_id);
+
+        xyncer_share::Payload {
+            op_code: xyncer_share::OP::Unsubscribe,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::Unsubscribe(data),
+            sequence: None,
+            nonce: None,
+        }
+    };
+
+    if let Err(e) = payload_sender.send(payload) {
+        log::error!("Error sending subscription payload: {}", e);
+    }
+}
+
+// Starts or stops playing the server's audio, which has to have been negotiated
+pub fn set_audio(
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    session_data: &mut session::Session,
+    playing: bool,
+) {
+    let payload = if playing {
+        let decoder = match session_data.audio_codec.decoder() {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                log::error!("Could not play the {} audio codec: {}", session_data.audio_codec.name(), e);
+
+                return;
+            }
+        };
+
+        // Packet numbers start again every time the server starts streaming
+        session_data.playback.lock().unwrap().stream = Some(audio::Stream {
+            jitter: xyncer_share::audio::JitterBuffer::default(),
+            decoder,
+        });
+
+        xyncer_share::Payload {
+            op_code: xyncer_share::OP::StartAudio,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::StartAudio,
+            sequence: None,
+            nonce: Some(session_data.requests.nonce()),
+        }
+    } else {
+        session_data.playback.lock().unwrap().stop();
+
+        xyncer_share::Payload {
+            op_code: xyncer_share::OP::StopAudio,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::StopAudio,
+            sequence: None,
+            nonce: None,
+        }
+    };
+
+    if let Err(e) = payload_sender.send(payload) {
+        log::error!("Error sending audio payload: {}", e);
+    }
+}
+
+// Plays a packet of the server's audio every packet's worth of time, for as long as the connection lasts
+async fn play_audio(
+    playback: Arc<std::sync::Mutex<audio::Playback>>,
+    output: Arc<std::sync::Mutex<Box<dyn audio::AudioOutput>>>,
+) {
+    // Ticks we were too busy for are played straight after, so playback keeps pace with the server
+    let mut interval = tokio::time::interval(xyncer_share::audio::PACKET_DURATION);
+
+    loop {
+        interval.tick().await;
+
+        let mut playback = playback.lock().unwrap();
+        let playback = &mut *playback;
+
+        let Some((timestamp, samples)) = playback.stream.as_mut().and_then(|stream| {
+            let playout = stream.jitter.pop()?;
+
+            Some(match playout {
+                xyncer_share::audio::Playout::Packet(data) => (data.timestamp, stream.decoder.decode(Some(&data.data))),
+                xyncer_share::audio::Playout::Lost { timestamp } => (timestamp, stream.decoder.decode(None)),
+            })
+        }) else {
+            continue;
+        };
+
+        let mut output = output.lock().unwrap();
+
+        // Frames are held back until their audio is heard, or for too long
+        match samples.and_then(|samples| output.play(&samples)) {
+            Ok(()) => playback.av_sync.audio_played(timestamp, output.latency()),
+            Err(e) => log::warn!("Could not play audio: {}", e),
+        }
+    }
+}
+
+// Offers and asks for unfinished files again after (re)connecting, if the server still takes files
+fn resync_transfers(session_data: &mut session::Session) -> Vec<xyncer_share::Payload> {
+    if !session_data
+        .capabilities
+        .contains(&xyncer_share::payloads::Capability::Files)
+    {
+        return Vec::new();
+    }
+
+    session_data.transfers.resync()
+}
+
+fn send_transfer_payloads(
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    payloads: Vec<xyncer_share::Payload>,
+) {
+    for payload in payloads {
+        if let Err(e) = payload_sender.send(payload) {
+            log::error!("Error sending file transfer payload: {}", e);
+        }
+    }
+}
+
+// Surfaces an invalid session to the user
+async fn set_session_error(
+    session_data_guard: &Arc<RwLock<session::Session>>,
+    data: xyncer_share::payloads::InvalidSessionData,
+) {
+    log::error!("Session invalidated: {}", data);
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    // The server has forgotten the session, so there is nothing to resume
+    session_data.error = Some(data.to_string());
+    session_data.reset();
+
+    // An expired session can be replaced by identifying again, but anything else needs the user
+    if !matches!(data.code, xyncer_share::payloads::ErrorCode::ResumeFailed) {
+        session_data.auto_reconnect = false;
+    }
+}
+
+// Hands a part of a file transfer to the session's transfers, sending whatever they answer with
+fn handle_transfer(
+    context: &mut events::Context<'_>,
+    data: &xyncer_share::payloads::PayloadData,
+) -> Result<(), xyncer_share::payloads::InvalidSessionData> {
+    let directory = context.session.download_directory.clone();
+    let transfers = context.session.transfers.handle(&directory, data);
+
+    send_transfer_payloads(context.payload_sender, transfers);
+
+    Ok(())
+}
+
+// The handlers every client needs, which more can be added to
+pub fn handlers() -> events::Handlers {
+    let mut handlers = events::Handlers::default();
+
+    handlers
+        // Start sending heartbeats, then carry on with the session we had, if there was one
+        .on::<events::Hello>(|context, data| {
+            if !xyncer_share::is_compatible(data.protocol_version) {
+                return Err(xyncer_share::payloads::ErrorCode::IncompatibleVersion.populate());
+            }
+
+            context.start_heartbeats(data.heartbeat_interval);
+
+            let session_data = &*context.session;
+
+            // Pick up where we left off if the previous connection dropped
+            if let Some(resume) = session_data.resume.clone() {
+                log::info!("Resuming session {} from event {}", resume.session_id, resume.sequence);
+
+                if let Err(e) = context.payload_sender.send(xyncer_share::Payload {
+                    op_code: xyncer_share::OP::Resume,
+                    event_name: xyncer_share::Event::None,
+                    data: xyncer_share::payloads::PayloadData::Resume(resume),
+                    sequence: None,
+                    nonce: None,
+                }) {
+                    log::error!("Error sending resume payload: {}", e);
+                }
+            // The session is gone, but the passphrase that got us in last time may still work
+            } else if session_data.auto_reconnect && !session_data.password.is_empty() {
+                log::info!("Identifying again with the previous passphrase");
+
+                let payload = identify(
+                    session_data.password.clone(),
+                    session_data.preferred_codec.as_deref(),
+                    session_data.offered_capabilities(),
+                );
+
+                if let Err(e) = context.payload_sender.send(payload) {
+                    log::error!("Error sending identify payload: {}", e);
+                }
+            }
+
+            Ok(())
+        })
+        // Server requesting a heartbeat
+        .on::<events::Heartbeat>(|context, _| {
+            if let Err(e) = context.payload_sender.send(heartbeat()) {
+                log::error!("Error sending heartbeat in response to heartbeat request: {}", e);
+            }
+
+            Ok(())
+        })
+        // The passphrase we sent was wrong, the user has to enter it again
+        .on::<events::ReIdentify>(|context, _| {
+            let session_data = &mut *context.session;
+
+            session_data.password.clear();
+            session_data.error = Some("Invalid passphrase, try again".to_string());
+
+            // The user has to step in, so stop reconnecting on their behalf
+            session_data.auto_reconnect = false;
+            session_data.reconnect = None;
+
+            Ok(())
+        })
+        // The server has closed the connection, and only has to hear why
+        .on::<events::InvalidSession>(|_, data| Err(data.clone()))
+        .on::<events::Ready>(|context, data| {
+            let codec = xyncer_share::codecs::get(&data.codec)
+                .ok_or_else(|| xyncer_share::payloads::ErrorCode::DecodeError.populate())?;
+
+            let session_data = &mut *context.session;
+
+            session_data.authenticated = true;
+            session_data.auto_reconnect = true;
+            session_data.reconnect = None;
+            session_data.error = None;
+            session_data.codec = codec;
+            session_data.capabilities = data.capabilities.clone();
+
+            // Servers that don't say what their limit is use the default
+            session_data.clipboard_max_size = data.clipboard_max_size.unwrap_or(xyncer_share::clipboard::DEFAULT_MAX_SIZE);
+
+            // Audio is only worth missing out on, not disconnecting over
+            match xyncer_share::audio::get(&data.audio_codec) {
+                Some(audio_codec) => session_data.audio_codec = audio_codec,
+                None => {
+                    log::warn!("The server picked the {} audio codec, which we don't support, so its audio won't be played", data.audio_codec);
+
+                    session_data.capabilities.retain(|capability| *capability != xyncer_share::payloads::Capability::Audio);
+                }
+            }
+
+            session_data.resume = Some(xyncer_share::payloads::ResumeData {
+                session_id: data.session_id.clone(),
+                resume_token: data.resume_token.clone(),
+                sequence: 0,
+            });
+
+            let list_windows = session_data.capabilities.contains(&xyncer_share::payloads::Capability::Windows);
+            let list_apps = session_data.capabilities.contains(&xyncer_share::payloads::Capability::Launch);
+            let stream_audio = session_data.capabilities.contains(&xyncer_share::payloads::Capability::Audio);
+
+            // Carry on with the transfers the previous session didn't finish
+            send_transfer_payloads(context.payload_sender, resync_transfers(session_data));
+
+            if stream_audio {
+                set_audio(context.payload_sender, session_data, true);
+
+                context.start_audio();
+            }
+
+            if list_windows {
+                if let Err(e) = context.payload_sender.send(xyncer_share::Payload {
+                    op_code: xyncer_share::OP::ListWindows,
+                    event_name: xyncer_share::Event::None,
+                    data: xyncer_share::payloads::PayloadData::ListWindows,
+                    sequence: None,
+                    nonce: None,
+                }) {
+                    log::error!("Error requesting window list: {}", e);
+                }
+            }
+
+            if list_apps {
+                if let Err(e) = context.payload_sender.send(xyncer_share::Payload {
+                    op_code: xyncer_share::OP::ListApps,
+                    event_name: xyncer_share::Event::None,
+                    data: xyncer_share::payloads::PayloadData::ListApps,
+                    sequence: None,
+                    nonce: None,
+                }) {
+                    log::error!("Error requesting app catalog: {}", e);
+                }
+            }
+
+            Ok(())
+        })
+        // The server will send keyframes for our subscriptions
+        .on::<events::Resumed>(|context, _| {
+            let session_data = &mut *context.session;
+
+            session_data.authenticated = true;
+            session_data.reconnect = None;
+            session_data.error = None;
+
+            // Chunks in flight when the connection dropped are gone, so pick up from what arrived
+            send_transfer_payloads(context.payload_sender, resync_transfers(session_data));
+
+            // The server stops streaming audio when the connection drops, so start again if we were playing it
+            if session_data.playing_audio() {
+                set_audio(context.payload_sender, session_data, true);
+            }
+
+            if session_data.capabilities.contains(&xyncer_share::payloads::Capability::Audio) {
+                context.start_audio();
+            }
+
+            Ok(())
+        })
+        .on::<events::WindowList>(|context, data| {
+            context.session.windows = data
+                .windows
+                .iter()
+                .map(|window| (window.id, window.clone()))
+                .collect();
+            context.session.windows_listed = true;
+
+            Ok(())
+        })
+        .on::<events::WindowCreated>(|context, window| {
+            context.session.windows.insert(window.id, window.clone());
+
+            Ok(())
+        })
+        .on::<events::WindowDestroyed>(|context, data| {
+            context.session.windows.remove(&data.id);
+            context.session.subscriptions.remove(&data.id);
+            context.session.frames.remove(&data.id);
+
+            Ok(())
+        })
+        .on::<events::WindowMoved>(|context, data| {
+            if let Some(window) = context.session.windows.get_mut(&data.id) {
+                window.geometry = data.geometry;
+            }
+
+            Ok(())
+        })
+        .on::<events::WindowTitleChanged>(|context, data| {
+            if let Some(window) = context.session.windows.get_mut(&data.id) {
+                window.title = data.title.clone();
+            }
+
+            Ok(())
+        })
+        .on::<events::AppCatalog>(|context, data| {
+            context.session.apps = Some(data.apps.clone());
+
+            Ok(())
+        })
+        .on::<events::Clipboard>(|context, data| {
+            if data.size() > context.session.clipboard_max_size {
+                log::warn!(
+                    "Ignoring {} bytes of clipboard from the server, over the limit of {}",
+                    data.size(),
+                    context.session.clipboard_max_size
+                );
+
+                return Ok(());
+            }
+
+            match context.session.clipboard.clone() {
+                Some(clipboard) => {
+                    if let Err(e) = context.clipboard_watcher.apply(clipboard.as_ref(), data) {
+                        log::warn!("Could not write the clipboard: {}", e);
+                    }
+                }
+                None => context.session.remote_clipboard = Some(data.clone()),
+            }
+
+            Ok(())
+        })
+        .on::<events::Frame>(|context, data| {
+            let session_data = &mut *context.session;
+
+            // Frames for windows we have since unsubscribed from may still be in flight
+            let Some(decoder) = session_data.subscriptions.get_mut(&data.window_id) else {
+                return Ok(());
+            };
+
+            match decoder.decode(data) {
+                Ok(frame) => {
+                    let frame = frame.clone();
+
+                    // Shown once the audio captured alongside it is heard
+                    session_data.playback.lock().unwrap().av_sync.push(data.timestamp, (data.window_id, frame));
+                    session_data.release_frames();
+                }
+                // We lost track of the frame, so start again from a full one
+                Err(e) => {
+                    log::warn!("Could not decode frame of window {}, requesting a keyframe: {}", data.window_id, e);
+
+                    if let Err(e) = context.payload_sender.send(xyncer_share::Payload {
+                        op_code: xyncer_share::OP::RequestKeyframe,
+                        event_name: xyncer_share::Event::None,
+                        data: xyncer_share::payloads::PayloadData::RequestKeyframe(
+                            xyncer_share::payloads::SubscribeData { window_id: data.window_id },
+                        ),
+                        sequence: None,
+                        nonce: None,
+                    }) {
+                        log::error!("Error requesting keyframe: {}", e);
+                    }
+                }
+            }
+
+            Ok(())
+        })
+        .on::<events::Audio>(|context, data| {
+            // Packets sent before the server heard we stopped may still be in flight
+            if let Some(stream) = context.session.playback.lock().unwrap().stream.as_mut() {
+                stream.jitter.push(data.clone());
+            }
+
+            Ok(())
+        })
+        .on::<events::FileOffer>(handle_transfer)
+        .on::<events::FileAccept>(handle_transfer)
+        .on::<events::FileChunk>(handle_transfer)
+        .on::<events::FileAck>(handle_transfer)
+        .on::<events::FileEnd>(handle_transfer)
+        .on::<events::RequestFailed>(|_, data| {
+            log::warn!("The server could not handle a request: {}", data);
+
+            Ok(())
+        });
+
+    handlers
 }
 
 pub async fn start_client(
     session_data_guard: Arc<RwLock<session::Session>>,
     payload_receiver: flume::Receiver<xyncer_share::Payload>,
     payload_sender: flume::Sender<xyncer_share::Payload>,
+    handlers: Arc<events::Handlers>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Obtain a read lock on the session data
     let session_data = session_data_guard.read().await;
 
     // Connect to the WebSocket server
-    let mut websocket = connect(&session_data.server_address).await?;
+    let (mut websocket, fingerprint) = connect(
+        &session_data.server_address,
+        session_data.pinned_fingerprint.as_deref(),
+    )
+    .await?;
 
     // Drop the read lock on the session data
     drop(session_data);
 
+    // Anything queued while we were disconnected was meant for the previous connection,
+    // but anything queued once we say we are connected is meant for this one
+    payload_receiver.drain();
+
     // Obtain a write lock on the session data
     let mut session_data = session_data_guard.write().await;
 
     // Update the session data
     session_data.connected = true;
     session_data.error = None;
+    session_data.fingerprint = fingerprint;
+    session_data.requests = websocket.requests();
 
     // Drop the write lock on the session data
     drop(session_data);
 
+    // Whoever waits on a request gets the answer, which the connection fails once it is gone
+    let requests = websocket.requests();
+
+    // Sends heartbeats and plays the server's audio for this connection, once the handlers start them
+    let mut tasks = Tasks::default();
+
+    // Looks for clipboard changes on this device, ignoring the ones the server made
+    let mut clipboard_poll_interval = tokio::time::interval(CLIPBOARD_POLL_INTERVAL);
+    let mut clipboard_watcher = xyncer_share::clipboard::ClipboardWatcher::default();
+
     loop {
         tokio::select! {
                 // Handle incoming WebSocket messages
@@ -88,73 +828,146 @@ pub async fn start_client(
                     match msg.opcode {
                         fastwebsockets::OpCode::Binary => {
                             let bytes = msg.payload.to_owned();
-                            let payload: xyncer_share::Payload = rmp_serde::from_slice(&bytes).unwrap();
 
-                            log::info!("Received payload: {:?}", payload);
+                            let payload: xyncer_share::Payload = match rmp_serde::from_slice(&bytes) {
+                                Ok(payload) => payload,
+                                Err(e) => {
+                                    log::error!("Could not decode payload: {}", e);
 
-                            match payload.op_code {
-                                // Start the heartbeat sender
-                                xyncer_share::OP::Hello => {
-                                    let heartbeat_interval = match payload.data {
-                                        xyncer_share::payloads::PayloadData::Hello(data) => {
-                                            data.heartbeat_interval
-                                        }
-                                        _ => {
-                                            unimplemented!();
-                                        }
-                                    };
+                                    set_session_error(&session_data_guard, xyncer_share::payloads::ErrorCode::DecodeError.populate()).await;
+                                    websocket.close().await?;
 
-                                    let payload_sender_cloned = payload_sender.clone();
-                                    let session_data_guard_cloned = session_data_guard.clone();
+                                    break;
+                                }
+                            };
 
-                                    tokio::spawn(async move {
-                                        loop {
-                                            // Obtain a read lock on the session data
-                                            let session_data = session_data_guard_cloned.read().await;
+                            if payload.op_code.is_streamed() {
+                                log::debug!("Received payload: {:?}", payload);
+                            } else {
+                                log::info!("Received payload: {:?}", payload);
+                            }
 
-                                            if !session_data.connected {
-                                                log::info!("Stopping heartbeat (not connected)");
+                            // Remember how far we got, so resuming only replays the events we missed
+                            if let Some(sequence) = payload.sequence {
+                                // Obtain a write lock on the session data
+                                let mut session_data = session_data_guard.write().await;
 
-                                                break;
-                                            }
+                                if let Some(resume) = session_data.resume.as_mut() {
+                                    resume.sequence = sequence;
+                                }
+                            }
+
+                            // A failed request that someone waits on is theirs to deal with, but the events answering
+                            // one still update the session like any other
+                            if requests.answer(&payload) && payload.op_code == xyncer_share::OP::Error {
+                                continue;
+                            }
+
+                            match payload.op_code {
+                                xyncer_share::OP::HeartbeatAck => {},
+                                // Handed to whoever subscribed to them
+                                xyncer_share::OP::Hello
+                                | xyncer_share::OP::Heartbeat
+                                | xyncer_share::OP::ReIdentify
+                                | xyncer_share::OP::InvalidSession
+                                | xyncer_share::OP::Dispatch
+                                | xyncer_share::OP::Clipboard
+                                | xyncer_share::OP::Error
+                                | xyncer_share::OP::Frame
+                                | xyncer_share::OP::Audio
+                                | xyncer_share::OP::FileOffer
+                                | xyncer_share::OP::FileAccept
+                                | xyncer_share::OP::FileChunk
+                                | xyncer_share::OP::FileAck
+                                | xyncer_share::OP::FileEnd => {
+                                    // Obtain a write lock on the session data
+                                    let mut session_data = session_data_guard.write().await;
+
+                                    let result = handlers.dispatch(
+                                        &mut events::Context {
+                                            session: &mut session_data,
+                                            payload_sender: &payload_sender,
+                                            clipboard_watcher: &mut clipboard_watcher,
+                                            tasks: &mut tasks,
+                                        },
+                                        &payload,
+                                    );
 
-                                            // Drop the read lock on the session data, ASAP to avoid deadlocks
-                                            drop(session_data);
+                                    // Drop the write lock on the session data
+                                    drop(session_data);
 
-                                            if let Err(e) = payload_sender_cloned.send(xyncer_share::Payload {
-                                                op_code: xyncer_share::OP::Heartbeat,
-                                                event_name: xyncer_share::Event::None,
-                                                data: xyncer_share::payloads::PayloadData::Heartbeat,
-                                            }) {
-                                                log::error!("Error sending heartbeat (stopping): {}", e);
+                                    match result {
+                                        Ok(true) => {}
+                                        // Events can go unhandled, but anything else has the wrong data for its OP code
+                                        Ok(false) if payload.op_code == xyncer_share::OP::Dispatch => {
+                                            log::debug!("Nothing handles {:?}, ignoring", payload.event_name);
+                                        }
+                                        Ok(false) => {
+                                            set_session_error(&session_data_guard, xyncer_share::payloads::ErrorCode::DecodeError.populate()).await;
+                                            websocket.close().await?;
+
+                                            break;
+                                        }
+                                        // The server may have closed the connection already, like after an InvalidSession
+                                        Err(data) => {
+                                            set_session_error(&session_data_guard, data).await;
 
-                                                break;
+                                            if let Err(e) = websocket.close().await {
+                                                log::debug!("Could not close the connection: {}", e);
                                             }
 
-                                            tokio::time::sleep(tokio::time::Duration::from_secs(heartbeat_interval.into())).await;
+                                            break;
                                         }
-                                    });
-                                },
-                                // Server requesting a heartbeat
-                                xyncer_share::OP::Heartbeat => {
-                                    if let Err(e) = payload_sender.send(xyncer_share::Payload {
-                                        op_code: xyncer_share::OP::Heartbeat,
-                                        event_name: xyncer_share::Event::None,
-                                        data: xyncer_share::payloads::PayloadData::Heartbeat,
-                                    }) {
-                                        log::error!("Error sending heartbeat in response to heartbeat request: {}", e);
                                     }
                                 },
-                                xyncer_share::OP::HeartbeatAck => {},
+                                // Only the client sends these
                                 _ => {
-                                    unimplemented!();
-                                }
+                                    log::error!("Received unexpected OP code: {:?}", payload.op_code);
+
+                                    set_session_error(&session_data_guard, xyncer_share::payloads::ErrorCode::UnknownOP.populate()).await;
+                                    websocket.close().await?;
+
+                                    break;
+                                },
                             }
                         }
+                        // Payloads are always MessagePack encoded, so text frames are invalid
+                        fastwebsockets::OpCode::Text => {
+                            set_session_error(&session_data_guard, xyncer_share::payloads::ErrorCode::DecodeError.populate()).await;
+                            websocket.close().await?;
+
+                            break;
+                        }
                         fastwebsockets::OpCode::Close => {
                             break;
                         }
-                        _ => unimplemented!(),
+                        _ => {}
+                    }
+                },
+                // Send clipboard changes to the server
+                _ = clipboard_poll_interval.tick() => {
+                    // Obtain a read lock on the session data
+                    let session_data = session_data_guard.read().await;
+
+                    let clipboard = session_data.clipboard.clone().filter(|_| {
+                        session_data.authenticated
+                            && session_data.capabilities.contains(&xyncer_share::payloads::Capability::Clipboard)
+                    });
+                    let max_size = session_data.clipboard_max_size;
+
+                    // Drop the read lock on the session data
+                    drop(session_data);
+
+                    let Some(data) = clipboard.and_then(|clipboard| clipboard_watcher.poll(clipboard.as_ref())) else {
+                        continue;
+                    };
+
+                    let data = data.limit(max_size);
+
+                    if !data.items.is_empty() {
+                        if let Err(e) = payload_sender.send(set_clipboard(data)) {
+                            log::error!("Error sending clipboard payload: {}", e);
+                        }
                     }
                 },
                 // Handle outgoing WebSocket messages
@@ -173,5 +986,87 @@ pub async fn start_client(
         }
     }
 
+    // Stop sending heartbeats and playing audio for this connection
+    drop(tasks);
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    // The connection is gone, but the session may still be resumed
+    session_data.disconnect();
+
+    // Drop the write lock on the session data
+    drop(session_data);
+
     Ok(())
 }
+
+// Runs the client, reconnecting with exponential backoff while the session should stay connected
+pub async fn run_client(
+    session_data_guard: Arc<RwLock<session::Session>>,
+    payload_receiver: flume::Receiver<xyncer_share::Payload>,
+    payload_sender: flume::Sender<xyncer_share::Payload>,
+    handlers: Arc<events::Handlers>,
+    cancel_reconnect: Arc<tokio::sync::Notify>,
+) {
+    loop {
+        let result = start_client(
+            session_data_guard.clone(),
+            payload_receiver.clone(),
+            payload_sender.clone(),
+            handlers.clone(),
+        )
+        .await;
+
+        // Obtain a write lock on the session data
+        let mut session_data = session_data_guard.write().await;
+
+        if let Err(e) = result {
+            log::error!("Error running client: {}", e);
+
+            // Set the error message, and reset the connection state (keeping the session to resume)
+            session_data.error = e.to_string().into();
+            session_data.disconnect();
+        }
+
+        if !session_data.auto_reconnect {
+            session_data.reconnect = None;
+
+            break;
+        }
+
+        // Attempts only count up while none of them get us authenticated again
+        let attempt = session_data.reconnect.map_or(0, |reconnect| reconnect.attempt) + 1;
+        let delay = reconnect_delay(attempt);
+
+        session_data.reconnect = Some(session::Reconnect {
+            attempt,
+            at: Some(std::time::Instant::now() + delay),
+        });
+
+        // Drop the write lock on the session data
+        drop(session_data);
+
+        log::info!("Reconnecting in {:?} (attempt {})", delay, attempt);
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel_reconnect.notified() => {
+                log::info!("Stopped reconnecting");
+
+                // Obtain a write lock on the session data
+                let mut session_data = session_data_guard.write().await;
+
+                session_data.auto_reconnect = false;
+                session_data.reconnect = None;
+
+                break;
+            }
+        }
+
+        // Obtain a write lock on the session data
+        let mut session_data = session_data_guard.write().await;
+
+        session_data.reconnect = Some(session::Reconnect { attempt, at: None });
+    }
+}
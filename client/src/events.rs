@@ -0,0 +1,415 @@
+use crate::{client, session};
+use xyncer_share::payloads::{
+    AppCatalogData, AppLaunchedData, AudioData, ClipboardData, FrameData, HelloData,
+    InvalidSessionData, LaunchFailedData, PayloadData, ReadyData, WindowData, WindowDestroyedData,
+    WindowListData, WindowMovedData, WindowTitleChangedData,
+};
+
+// A kind of payload that handlers can subscribe to, and the data they are handed
+pub trait Kind: 'static {
+    type Data;
+
+    // What the server sends payloads of this kind with
+    const OP: xyncer_share::OP;
+    const EVENT: xyncer_share::Event;
+
+    // The data in the PayloadData variant of this kind
+    fn variant(data: &PayloadData) -> Option<&Self::Data>;
+
+    // The payload's data, if it is one of these. The right data sent with another OP code or event is malformed.
+    fn data(payload: &xyncer_share::Payload) -> Option<&Self::Data> {
+        if payload.op_code != Self::OP || payload.event_name != Self::EVENT {
+            return None;
+        }
+
+        Self::variant(&payload.data)
+    }
+}
+
+// Declares kinds for the payloads whose data is in the given PayloadData variant, sent with the given OP code and
+// event (or none, if left out)
+macro_rules! kinds {
+    ($($kind:ident => $op:ident $(/ $event:ident)? : $variant:ident($data:ty),)*) => {
+        $(
+            pub struct $kind;
+
+            impl Kind for $kind {
+                type Data = $data;
+
+                const OP: xyncer_share::OP = xyncer_share::OP::$op;
+                const EVENT: xyncer_share::Event = event!($($event)?);
+
+                fn variant(data: &PayloadData) -> Option<&$data> {
+                    match data {
+                        PayloadData::$variant(data) => Some(data),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+// The event of a kind, None unless it is dispatched
+macro_rules! event {
+    () => {
+        xyncer_share::Event::None
+    };
+    ($event:ident) => {
+        xyncer_share::Event::$event
+    };
+}
+
+kinds! {
+    Hello => Hello: Hello(HelloData),
+    InvalidSession => InvalidSession: InvalidSession(InvalidSessionData), // The server has forgotten the session
+    Ready => Dispatch / Ready: Ready(ReadyData),
+    WindowList => Dispatch / WindowList: WindowList(WindowListData),
+    WindowCreated => Dispatch / WindowCreated: WindowCreated(WindowData),
+    WindowDestroyed => Dispatch / WindowDestroyed: WindowDestroyed(WindowDestroyedData),
+    WindowMoved => Dispatch / WindowMoved: WindowMoved(WindowMovedData),
+    WindowTitleChanged => Dispatch / WindowTitleChanged: WindowTitleChanged(WindowTitleChangedData),
+    AppLaunched => Dispatch / AppLaunched: AppLaunched(AppLaunchedData),
+    LaunchFailed => Dispatch / LaunchFailed: LaunchFailed(LaunchFailedData),
+    AppCatalog => Dispatch / AppCatalog: AppCatalog(AppCatalogData),
+    Clipboard => Clipboard: Clipboard(ClipboardData), // The server's clipboard changed
+    RequestFailed => Error: Error(InvalidSessionData), // A request of ours failed, but the session carries on
+    Frame => Frame: Frame(FrameData),
+    Audio => Audio: Audio(AudioData),
+}
+
+// Declares kinds for the payloads that carry nothing besides their PayloadData variant
+macro_rules! signals {
+    ($($kind:ident => $op:ident $(/ $event:ident)? : $variant:ident,)*) => {
+        $(
+            pub struct $kind;
+
+            impl Kind for $kind {
+                type Data = ();
+
+                const OP: xyncer_share::OP = xyncer_share::OP::$op;
+                const EVENT: xyncer_share::Event = event!($($event)?);
+
+                fn variant(data: &PayloadData) -> Option<&()> {
+                    matches!(data, PayloadData::$variant).then_some(&())
+                }
+            }
+        )*
+    };
+}
+
+signals! {
+    Heartbeat => Heartbeat: Heartbeat,       // The server wants to hear from us before it gives up on the connection
+    ReIdentify => ReIdentify: ReIdentify,    // The passphrase we sent was wrong
+    Resumed => Dispatch / Resumed: Resumed, // The server has replayed everything we missed
+}
+
+// Declares kinds for the parts of a file transfer, in either direction, handed over whole as Transfers takes them
+macro_rules! transfers {
+    ($($kind:ident,)*) => {
+        $(
+            pub struct $kind;
+
+            impl Kind for $kind {
+                type Data = PayloadData;
+
+                const OP: xyncer_share::OP = xyncer_share::OP::$kind;
+                const EVENT: xyncer_share::Event = xyncer_share::Event::None;
+
+                fn variant(data: &PayloadData) -> Option<&PayloadData> {
+                    matches!(data, PayloadData::$kind(_)).then_some(data)
+                }
+            }
+        )*
+    };
+}
+
+transfers! {
+    FileOffer,
+    FileAccept,
+    FileChunk,
+    FileAck,
+    FileEnd,
+}
+
+// What handlers get to work with, while the client holds a write lock on the session data
+pub struct Context<'a> {
+    pub session: &'a mut session::Session,
+    pub payload_sender: &'a flume::Sender<xyncer_share::Payload>,
+    pub clipboard_watcher: &'a mut xyncer_share::clipboard::ClipboardWatcher,
+    pub tasks: &'a mut client::Tasks,
+}
+
+impl Context<'_> {
+    // Sends a heartbeat every interval (in seconds), for as long as the connection lasts
+    pub fn start_heartbeats(&mut self, interval: u8) {
+        self.tasks
+            .start_heartbeats(self.payload_sender.clone(), interval);
+    }
+
+    // Plays the server's audio as it arrives, for as long as the connection lasts
+    pub fn start_audio(&mut self) {
+        // Without an output there is nothing to play the audio on
+        if let Some(output) = self.session.audio_output.clone() {
+            self.tasks
+                .start_audio(self.session.playback.clone(), output);
+        }
+    }
+}
+
+// Handlers return an error to invalidate the session, closing the connection
+type Handler = Box<
+    dyn Fn(&mut Context<'_>, &xyncer_share::Payload) -> Option<Result<(), InvalidSessionData>>
+        + Send
+        + Sync,
+>;
+
+// Handlers for the events the server sends, called in the order they were added
+#[derive(Default)]
+pub struct Handlers {
+    handlers: Vec<Handler>,
+}
+
+impl Handlers {
+    // Calls the handler with the data of every payload of the given kind
+    pub fn on<K: Kind>(
+        &mut self,
+        handler: impl Fn(&mut Context<'_>, &K::Data) -> Result<(), InvalidSessionData>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        self.handlers.push(Box::new(move |context, payload| {
+            K::data(payload).map(|data| handler(context, data))
+        }));
+
+        self
+    }
+
+    // Hands a payload to the handlers of its kind, returning whether there were any
+    pub fn dispatch(
+        &self,
+        context: &mut Context<'_>,
+        payload: &xyncer_share::Payload,
+    ) -> Result<bool, InvalidSessionData> {
+        let mut handled = false;
+
+        for handler in &self.handlers {
+            if let Some(result) = handler(context, payload) {
+                result?;
+
+                handled = true;
+            }
+        }
+
+        Ok(handled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn payload(op_code: xyncer_share::OP, data: PayloadData) -> xyncer_share::Payload {
+        xyncer_share::Payload {
+            op_code,
+            event_name: xyncer_share::Event::None,
+            data,
+            sequence: None,
+            nonce: None,
+        }
+    }
+
+    fn hello() -> xyncer_share::Payload {
+        payload(
+            xyncer_share::OP::Hello,
+            PayloadData::Hello(HelloData {
+                heartbeat_interval: 30,
+                protocol_version: xyncer_share::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+            }),
+        )
+    }
+
+    // What a Context borrows, owned for the length of a test
+    struct Client {
+        session: session::Session,
+        payload_sender: flume::Sender<xyncer_share::Payload>,
+        clipboard_watcher: xyncer_share::clipboard::ClipboardWatcher,
+        tasks: client::Tasks,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Client {
+                session: session::Session::default(),
+                payload_sender: flume::unbounded().0,
+                clipboard_watcher: xyncer_share::clipboard::ClipboardWatcher::default(),
+                tasks: client::Tasks::default(),
+            }
+        }
+
+        fn dispatch(
+            &mut self,
+            handlers: &Handlers,
+            payload: &xyncer_share::Payload,
+        ) -> Result<bool, InvalidSessionData> {
+            handlers.dispatch(
+                &mut Context {
+                    session: &mut self.session,
+                    payload_sender: &self.payload_sender,
+                    clipboard_watcher: &mut self.clipboard_watcher,
+                    tasks: &mut self.tasks,
+                },
+                payload,
+            )
+        }
+    }
+
+    // Handlers of Hello payloads that note down their name when called, and fail if told to
+    fn recording(names: &[(&'static str, bool)]) -> (Handlers, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = Handlers::default();
+
+        for (name, fails) in names.iter().copied() {
+            let calls = calls.clone();
+
+            handlers.on::<Hello>(move |_, _| {
+                calls.lock().unwrap().push(name);
+
+                match fails {
+                    true => Err(xyncer_share::payloads::ErrorCode::DecodeError.populate()),
+                    false => Ok(()),
+                }
+            });
+        }
+
+        (handlers, calls)
+    }
+
+    #[test]
+    fn handlers_run_in_the_order_they_were_added() {
+        let (handlers, calls) = recording(&[("first", false), ("second", false)]);
+
+        assert!(matches!(
+            Client::new().dispatch(&handlers, &hello()),
+            Ok(true)
+        ));
+        assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn payloads_nothing_handles_are_reported() {
+        let (handlers, calls) = recording(&[("first", false)]);
+        let heartbeat = payload(xyncer_share::OP::Heartbeat, PayloadData::Heartbeat);
+
+        assert!(matches!(
+            Client::new().dispatch(&handlers, &heartbeat),
+            Ok(false)
+        ));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn errors_stop_the_handlers_after_them() {
+        let (handlers, calls) = recording(&[("first", false), ("fails", true), ("last", false)]);
+
+        assert!(matches!(
+            Client::new().dispatch(&handlers, &hello()),
+            Err(InvalidSessionData {
+                code: xyncer_share::payloads::ErrorCode::DecodeError,
+                ..
+            })
+        ));
+        assert_eq!(*calls.lock().unwrap(), ["first", "fails"]);
+    }
+
+    #[test]
+    fn data_sent_with_another_op_or_event_is_not_handled() {
+        let (handlers, calls) = recording(&[("first", false)]);
+
+        // Hello data, dispatched as an event it isn't
+        let dispatched = xyncer_share::Payload {
+            op_code: xyncer_share::OP::Dispatch,
+            event_name: xyncer_share::Event::Ready,
+            ..hello()
+        };
+
+        assert!(matches!(
+            Client::new().dispatch(&handlers, &dispatched),
+            Ok(false)
+        ));
+        assert!(calls.lock().unwrap().is_empty());
+
+        let resumed = |event_name| xyncer_share::Payload {
+            event_name,
+            ..payload(xyncer_share::OP::Dispatch, PayloadData::Resumed)
+        };
+
+        assert!(Resumed::data(&resumed(xyncer_share::Event::Resumed)).is_some());
+        assert!(Resumed::data(&resumed(xyncer_share::Event::Ready)).is_none());
+        assert!(Resumed::data(&payload(xyncer_share::OP::Resume, PayloadData::Resumed)).is_none());
+    }
+
+    #[test]
+    fn signals_only_match_their_variant() {
+        let heartbeat = payload(xyncer_share::OP::Heartbeat, PayloadData::Heartbeat);
+        let ack = payload(xyncer_share::OP::Heartbeat, PayloadData::HeartbeatAck);
+
+        assert!(Heartbeat::data(&heartbeat).is_some());
+        assert!(Heartbeat::data(&ack).is_none());
+        assert!(ReIdentify::data(&heartbeat).is_none());
+    }
+
+    #[test]
+    fn file_transfers_are_handed_over_whole() {
+        let data = PayloadData::FileEnd(xyncer_share::payloads::FileEndData {
+            transfer_id: "transfer".to_string(),
+            error: None,
+        });
+
+        assert!(matches!(
+            FileEnd::data(&payload(xyncer_share::OP::FileEnd, data.clone())),
+            Some(PayloadData::FileEnd(_))
+        ));
+        assert!(FileEnd::data(&payload(xyncer_share::OP::FileOffer, data.clone())).is_none());
+        assert!(FileOffer::data(&payload(xyncer_share::OP::FileOffer, data)).is_none());
+    }
+
+    #[test]
+    fn clipboards_over_the_servers_limit_are_not_applied() {
+        let handlers = client::handlers();
+        let mut client = Client::new();
+
+        client.session.clipboard_max_size = 4;
+
+        let clipboard = |text: &str| {
+            payload(
+                xyncer_share::OP::Clipboard,
+                PayloadData::Clipboard(ClipboardData {
+                    items: vec![xyncer_share::payloads::ClipboardItem::new(
+                        xyncer_share::clipboard::TEXT,
+                        text.as_bytes().to_vec(),
+                    )],
+                }),
+            )
+        };
+
+        assert!(matches!(
+            client.dispatch(&handlers, &clipboard("too long")),
+            Ok(true)
+        ));
+        assert!(client.session.remote_clipboard.is_none());
+
+        assert!(matches!(
+            client.dispatch(&handlers, &clipboard("fits")),
+            Ok(true)
+        ));
+        assert_eq!(
+            client.session.remote_clipboard.unwrap().text(),
+            Some("fits")
+        );
+    }
+}
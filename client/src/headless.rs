@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::{audio, cli, client, launchers, profiles, session};
+use xyncer_share::audio::JitterStats;
+use xyncer_share::payloads::{
+    AppData, AppLaunchedData, Capability, ClipboardData, ClipboardItem, LaunchAppData, WindowData,
+};
+use xyncer_share::transfer::State;
+
+// How often to look at the session data while waiting for the server
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type ClientTask = tokio::task::JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+// Connects without a window, does what the command line asked for, and disconnects
+pub async fn run(cli: cli::Cli) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut session_data = session::Session::default();
+
+    match (&cli.profile, &cli.address) {
+        (Some(name), _) => {
+            let profiles = profiles::Profiles::load();
+
+            profiles
+                .get(name)
+                .ok_or_else(|| format!("There is no profile named {}", name))?
+                .apply(&mut session_data);
+        }
+        (None, Some(address)) => session_data.server_address = address.clone(),
+        (None, None) => return Err("Either --profile or --address is needed".into()),
+    }
+
+    // A passphrase on the command line wins over a saved one
+    if let Some(passphrase) = &cli.passphrase {
+        session_data.password = passphrase.clone();
+    }
+
+    // Downloads go where we are run from, unless told otherwise
+    session_data.download_directory = cli.download_directory.clone().unwrap_or_default();
+
+    // There is no clipboard of our own here, so only offer to sync when asked to use the server's
+    session_data.sync_clipboard = cli.uses_clipboard();
+
+    // There are no speakers here, so audio is only asked for when told to play it
+    if cli.audio.is_some() {
+        let output: Box<dyn audio::AudioOutput> = match &cli.audio_wav {
+            Some(path) => Box::new(audio::WavOutput::create(path)?),
+            None => Box::new(audio::NullOutput::default()),
+        };
+
+        session_data.audio_output = Some(Arc::new(std::sync::Mutex::new(output)));
+    }
+
+    // With a passphrase, the client identifies as soon as the server says hello
+    session_data.auto_reconnect = !session_data.password.is_empty();
+
+    let session_data_guard = Arc::new(RwLock::new(session_data));
+    let (payload_sender, payload_receiver) = flume::unbounded();
+
+    // No reconnecting here, a dropped connection fails the run
+    let mut client_task = tokio::spawn(client::start_client(
+        session_data_guard.clone(),
+        payload_receiver,
+        payload_sender.clone(),
+        Arc::new(client::handlers()),
+    ));
+
+    let result = run_steps(&cli, &session_data_guard, &mut client_task, &payload_sender).await;
+
+    // Closes the connection
+    client_task.abort();
+
+    result
+}
+
+async fn run_steps(
+    cli: &cli::Cli,
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let timeout = Duration::from_secs(cli.timeout);
+
+    wait_for(
+        session_data_guard,
+        client_task,
+        timeout,
+        "the connection",
+        |session_data| session_data.connected.then_some(()),
+    )
+    .await?;
+
+    // Obtain a read lock on the session data
+    let session_data = session_data_guard.read().await;
+
+    let address = session_data.server_address.clone();
+    let preferred_codec = session_data.preferred_codec.clone();
+    let capabilities = session_data.offered_capabilities();
+    let identified = !session_data.password.is_empty();
+
+    // Drop the read lock on the session data
+    drop(session_data);
+
+    // Random passphrases are only shown once the connection is made, so ask for one now
+    if !identified {
+        let passphrase = tokio::task::spawn_blocking(move || read_passphrase(&address)).await??;
+
+        if passphrase.is_empty() {
+            return Err("No passphrase given".into());
+        }
+
+        // Obtain a write lock on the session data
+        session_data_guard.write().await.password = passphrase.clone();
+
+        payload_sender.send(client::identify(
+            passphrase,
+            preferred_codec.as_deref(),
+            capabilities,
+        ))?;
+    }
+
+    let capabilities = wait_for(
+        session_data_guard,
+        client_task,
+        timeout,
+        "authentication",
+        |session_data| {
+            session_data
+                .authenticated
+                .then(|| session_data.capabilities.clone())
+        },
+    )
+    .await?;
+
+    if !capabilities.contains(&Capability::Windows) {
+        return Err("The server does not share windows".into());
+    }
+
+    let windows = wait_for(
+        session_data_guard,
+        client_task,
+        timeout,
+        "the window list",
+        |session_data| {
+            session_data
+                .windows_listed
+                .then(|| session_data.windows.values().cloned().collect::<Vec<_>>())
+        },
+    )
+    .await?;
+
+    if cli.prints_windows() {
+        print_windows(&windows);
+    }
+
+    if cli.list_apps || cli.install_launchers {
+        if !capabilities.contains(&Capability::Launch) {
+            return Err("The server does not launch apps".into());
+        }
+
+        let apps = wait_for(
+            session_data_guard,
+            client_task,
+            timeout,
+            "the app catalog",
+            |session_data| session_data.apps.clone(),
+        )
+        .await?;
+
+        if cli.list_apps {
+            print_apps(&apps);
+        }
+
+        // Only allowed with a profile, which the launchers connect through
+        if let (true, Some(profile)) = (cli.install_launchers, &cli.profile) {
+            for entry in launchers::install(profile, &apps)? {
+                println!("{}", entry.display());
+            }
+        }
+    }
+
+    if cli.uses_clipboard() && !capabilities.contains(&Capability::Clipboard) {
+        return Err("The server does not sync its clipboard".into());
+    }
+
+    if let Some(text) = &cli.copy {
+        payload_sender.send(client::set_clipboard(ClipboardData {
+            items: vec![ClipboardItem::new(
+                xyncer_share::clipboard::TEXT,
+                text.clone().into_bytes(),
+            )],
+        }))?;
+
+        // Nothing answers it, so just make sure it went out before we disconnect
+        wait_for(
+            session_data_guard,
+            client_task,
+            timeout,
+            "the clipboard to be sent",
+            |_| payload_sender.is_empty().then_some(()),
+        )
+        .await?;
+    }
+
+    if cli.paste {
+        let data = wait_for(
+            session_data_guard,
+            client_task,
+            timeout,
+            "the clipboard to change",
+            |session_data| session_data.remote_clipboard.take(),
+        )
+        .await?;
+
+        println!(
+            "{}",
+            data.text()
+                .ok_or("There is no text on the server's clipboard")?
+        );
+    }
+
+    if cli.transfers_files() {
+        if !capabilities.contains(&Capability::Files) {
+            return Err("The server does not take files".into());
+        }
+
+        transfer_files(
+            cli,
+            session_data_guard,
+            client_task,
+            payload_sender,
+            timeout,
+        )
+        .await?;
+    }
+
+    // Frames are dumped from the launched app's windows, unless told otherwise
+    let mut window_ids: Vec<u32> = windows.iter().map(|window| window.id).collect();
+
+    if let Some(data) = cli.launch_data() {
+        if !capabilities.contains(&Capability::Launch) {
+            return Err("The server does not launch apps".into());
+        }
+
+        let launched = launch_app(
+            session_data_guard,
+            client_task,
+            payload_sender,
+            timeout,
+            data,
+        )
+        .await?;
+
+        println!("{}", launched.pid);
+        print_windows(&launched.windows);
+
+        window_ids = launched.windows.iter().map(|window| window.id).collect();
+    }
+
+    if !cli.windows.is_empty() {
+        window_ids = cli.windows.clone();
+    }
+
+    if let Some(directory) = &cli.dump_frames {
+        if !capabilities.contains(&Capability::Frames) {
+            return Err("The server does not stream windows".into());
+        }
+
+        dump_frames(
+            cli,
+            session_data_guard,
+            client_task,
+            payload_sender,
+            directory,
+            &window_ids,
+        )
+        .await?;
+    }
+
+    if let Some(seconds) = cli.audio {
+        if !capabilities.contains(&Capability::Audio) {
+            return Err("The server does not stream audio".into());
+        }
+
+        let stats = play_audio(
+            session_data_guard,
+            client_task,
+            payload_sender,
+            Duration::from_secs(seconds),
+        )
+        .await?;
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            stats.received, stats.played, stats.concealed, stats.dropped, stats.underruns
+        );
+
+        if stats.played == 0 {
+            return Err("No audio arrived in time".into());
+        }
+    }
+
+    Ok(())
+}
+
+// Prints windows one per line, as id, process, size and title separated by tabs
+fn print_windows(windows: &[WindowData]) {
+    for window in windows {
+        println!(
+            "{}\t{}\t{}x{}\t{}",
+            window.id,
+            window.process_name,
+            window.geometry.width,
+            window.geometry.height,
+            window.title
+        );
+    }
+}
+
+// Prints apps one per line, as id, category, name and executable separated by tabs
+fn print_apps(apps: &[AppData]) {
+    for app in apps {
+        println!(
+            "{}\t{}\t{}\t{}",
+            app.id,
+            app.category.as_deref().unwrap_or_default(),
+            app.name,
+            app.executable
+        );
+    }
+}
+
+// Launches an app, returning its process id and the windows it opened
+async fn launch_app(
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    timeout: Duration,
+    data: LaunchAppData,
+) -> Result<AppLaunchedData, Box<dyn std::error::Error + Send + Sync>> {
+    match client::launch_app(session_data_guard, payload_sender, data, timeout).await {
+        Ok(Ok(launched)) => Ok(launched),
+        Ok(Err(failed)) => {
+            Err(format!("Could not launch {}: {}", failed.target, failed.error).into())
+        }
+        // Why the connection ended says more than that it did
+        Err(xyncer_share::RequestError::Closed(_)) => {
+            // Obtain a read lock on the session data
+            let error = session_data_guard.read().await.error.clone();
+
+            match error {
+                Some(error) => Err(error.into()),
+                None => Err(stopped(client_task).await),
+            }
+        }
+        Err(xyncer_share::RequestError::Timeout) => {
+            Err("Timed out waiting for the app to launch".into())
+        }
+        Err(e) => Err(format!("Could not launch the app: {}", e).into()),
+    }
+}
+
+// Uploads and downloads the files asked for, printing where each went once they are all done
+async fn transfer_files(
+    cli: &cli::Cli,
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    for path in &cli.upload {
+        client::send_file(session_data_guard, payload_sender, path.clone()).await;
+    }
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    for path in &cli.download {
+        client::request_file(payload_sender, &mut session_data, path.clone());
+    }
+
+    // Drop the write lock on the session data
+    drop(session_data);
+
+    // Files can take much longer than the timeout to transfer, so it only counts while nothing arrives
+    let mut transferred = 0;
+
+    loop {
+        let (done, total) = wait_for(
+            session_data_guard,
+            client_task,
+            timeout,
+            "the files to transfer",
+            |session_data| {
+                let done = !session_data.transfers.is_active();
+                let total: u64 = session_data
+                    .transfers
+                    .progress()
+                    .iter()
+                    .map(|progress| progress.transferred)
+                    .sum();
+
+                (done || total != transferred).then_some((done, total))
+            },
+        )
+        .await?;
+
+        if done {
+            break;
+        }
+
+        transferred = total;
+    }
+
+    // Obtain a read lock on the session data
+    let progress = session_data_guard.read().await.transfers.progress();
+
+    let mut errors = Vec::new();
+
+    for progress in progress {
+        match progress.state {
+            State::Complete(Some(path)) => println!("{}", path.display()),
+            State::Complete(None) => println!("{}", progress.name),
+            State::Failed(error) => {
+                errors.push(format!("Could not transfer {}: {}", progress.name, error))
+            }
+            _ => {}
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    Ok(())
+}
+
+// Streams the chosen windows, saving their frames as PNG files until each has enough or we time out
+async fn dump_frames(
+    cli: &cli::Cli,
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    directory: &Path,
+    window_ids: &[u32],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if window_ids.is_empty() {
+        return Err("There are no windows to save frames of".into());
+    }
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    if let Some(window_id) = window_ids
+        .iter()
+        .find(|window_id| !session_data.windows.contains_key(window_id))
+    {
+        return Err(format!("There is no window {}", window_id).into());
+    }
+
+    std::fs::create_dir_all(directory)?;
+
+    for window_id in window_ids {
+        client::set_streaming(payload_sender, &mut session_data, *window_id, true);
+    }
+
+    // Drop the write lock on the session data
+    drop(session_data);
+
+    let mut saved: HashMap<u32, u32> = window_ids.iter().map(|window_id| (*window_id, 0)).collect();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(cli.timeout);
+
+    // Only the latest frame of each window is kept, so frames arriving faster than we look are skipped
+    while saved.values().any(|count| *count < cli.frames) && tokio::time::Instant::now() < deadline
+    {
+        // Obtain a write lock on the session data
+        let mut session_data = session_data_guard.write().await;
+
+        session_data.release_frames();
+
+        let frames: Vec<_> = session_data.frames.drain().collect();
+
+        // Drop the write lock on the session data
+        drop(session_data);
+
+        for (window_id, frame) in frames {
+            let Some(count) = saved
+                .get_mut(&window_id)
+                .filter(|count| **count < cli.frames)
+            else {
+                continue;
+            };
+
+            *count += 1;
+
+            let path = directory.join(format!("{}-{:04}.png", window_id, count));
+
+            image::save_buffer(
+                &path,
+                &frame.rgba,
+                frame.width,
+                frame.height,
+                image::ExtendedColorType::Rgba8,
+            )?;
+
+            println!("{}", path.display());
+        }
+
+        if client_task.is_finished() {
+            return Err(stopped(client_task).await);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    for window_id in window_ids {
+        match saved[window_id] {
+            0 => return Err(format!("No frames of window {} arrived in time", window_id).into()),
+            count if count < cli.frames => {
+                log::warn!(
+                    "Only {} of {} frames of window {} arrived in time",
+                    count,
+                    cli.frames,
+                    window_id
+                )
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Plays the server's audio for a while, which started once we were authenticated, returning how playback went
+async fn play_audio(
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    payload_sender: &flume::Sender<xyncer_share::Payload>,
+    duration: Duration,
+) -> Result<JitterStats, Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = tokio::time::Instant::now() + duration;
+
+    while tokio::time::Instant::now() < deadline {
+        if client_task.is_finished() {
+            return Err(stopped(client_task).await);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    // Obtain a write lock on the session data
+    let mut session_data = session_data_guard.write().await;
+
+    let stats = session_data
+        .playback
+        .lock()
+        .unwrap()
+        .stream
+        .as_ref()
+        .map(|stream| stream.jitter.stats)
+        .unwrap_or_default();
+
+    client::set_audio(payload_sender, &mut session_data, false);
+
+    // Nothing more is played, so the WAV file can be finished
+    if let Some(output) = &session_data.audio_output {
+        output.lock().unwrap().finish()?;
+    }
+
+    Ok(stats)
+}
+
+// Waits until `ready` returns something, failing if the session errors, the connection ends, or we time out
+async fn wait_for<T>(
+    session_data_guard: &RwLock<session::Session>,
+    client_task: &mut ClientTask,
+    timeout: Duration,
+    what: &str,
+    mut ready: impl FnMut(&mut session::Session) -> Option<T>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        // Obtain a write lock on the session data
+        let mut session_data = session_data_guard.write().await;
+
+        if let Some(error) = &session_data.error {
+            return Err(error.clone().into());
+        }
+
+        if let Some(value) = ready(&mut session_data) {
+            return Ok(value);
+        }
+
+        // Drop the write lock on the session data
+        drop(session_data);
+
+        if client_task.is_finished() {
+            return Err(stopped(client_task).await);
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(format!("Timed out waiting for {}", what).into());
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Why the client task stopped before we were done
+async fn stopped(client_task: &mut ClientTask) -> Box<dyn std::error::Error + Send + Sync> {
+    match client_task.await {
+        Ok(Ok(())) => "The server closed the connection".into(),
+        Ok(Err(e)) => e,
+        Err(e) => e.into(),
+    }
+}
+
+// Asks for the passphrase the server shows for this connection
+fn read_passphrase(address: &str) -> std::io::Result<String> {
+    eprint!("Passphrase for {}: ", address);
+
+    std::io::Write::flush(&mut std::io::stderr())?;
+
+    let mut passphrase = String::new();
+
+    std::io::stdin().read_line(&mut passphrase)?;
+
+    Ok(passphrase.trim().to_string())
+}
@@ -0,0 +1,187 @@
+use eframe::egui;
+
+use xyncer_share::payloads::{InputEvent, Modifiers, MouseButton, WindowGeometry};
+
+// Scroll distance that counts as one wheel notch, in points
+const POINTS_PER_NOTCH: f32 = 50.0;
+
+// Maps a key to its PS/2 set 1 scancode (US layout), see `payloads::InputEvent`
+fn scancode(key: egui::Key) -> Option<u32> {
+    use egui::Key;
+
+    let scancode = match key {
+        Key::Escape => 0x01,
+        Key::Num1 => 0x02,
+        Key::Num2 => 0x03,
+        Key::Num3 => 0x04,
+        Key::Num4 => 0x05,
+        Key::Num5 => 0x06,
+        Key::Num6 => 0x07,
+        Key::Num7 => 0x08,
+        Key::Num8 => 0x09,
+        Key::Num9 => 0x0a,
+        Key::Num0 => 0x0b,
+        Key::Minus => 0x0c,
+        Key::Equals | Key::Plus => 0x0d,
+        Key::Backspace => 0x0e,
+        Key::Tab => 0x0f,
+        Key::Q => 0x10,
+        Key::W => 0x11,
+        Key::E => 0x12,
+        Key::R => 0x13,
+        Key::T => 0x14,
+        Key::Y => 0x15,
+        Key::U => 0x16,
+        Key::I => 0x17,
+        Key::O => 0x18,
+        Key::P => 0x19,
+        Key::OpenBracket => 0x1a,
+        Key::CloseBracket => 0x1b,
+        Key::Enter => 0x1c,
+        Key::A => 0x1e,
+        Key::S => 0x1f,
+        Key::D => 0x20,
+        Key::F => 0x21,
+        Key::G => 0x22,
+        Key::H => 0x23,
+        Key::J => 0x24,
+        Key::K => 0x25,
+        Key::L => 0x26,
+        Key::Semicolon | Key::Colon => 0x27,
+        Key::Backtick => 0x29,
+        Key::Backslash | Key::Pipe => 0x2b,
+        Key::Z => 0x2c,
+        Key::X => 0x2d,
+        Key::C => 0x2e,
+        Key::V => 0x2f,
+        Key::B => 0x30,
+        Key::N => 0x31,
+        Key::M => 0x32,
+        Key::Comma => 0x33,
+        Key::Period => 0x34,
+        Key::Slash | Key::Questionmark => 0x35,
+        Key::Space => 0x39,
+        Key::F1 => 0x3b,
+        Key::F2 => 0x3c,
+        Key::F3 => 0x3d,
+        Key::F4 => 0x3e,
+        Key::F5 => 0x3f,
+        Key::F6 => 0x40,
+        Key::F7 => 0x41,
+        Key::F8 => 0x42,
+        Key::F9 => 0x43,
+        Key::F10 => 0x44,
+        Key::F11 => 0x57,
+        Key::F12 => 0x58,
+        Key::Home => 0xe047,
+        Key::ArrowUp => 0xe048,
+        Key::PageUp => 0xe049,
+        Key::ArrowLeft => 0xe04b,
+        Key::ArrowRight => 0xe04d,
+        Key::End => 0xe04f,
+        Key::ArrowDown => 0xe050,
+        Key::PageDown => 0xe051,
+        Key::Insert => 0xe052,
+        Key::Delete => 0xe053,
+        _ => return None,
+    };
+
+    Some(scancode)
+}
+
+fn modifiers(modifiers: egui::Modifiers) -> Modifiers {
+    Modifiers {
+        shift: modifiers.shift,
+        ctrl: modifiers.ctrl,
+        alt: modifiers.alt,
+        // `command` is ctrl on Windows / Linux, so only use `mac_cmd` for the meta key
+        meta: modifiers.mac_cmd,
+    }
+}
+
+// Translates an egui event in a viewport into input for the remote window shown in `rect`
+pub fn translate(
+    event: &egui::Event,
+    rect: egui::Rect,
+    geometry: &WindowGeometry,
+) -> Option<InputEvent> {
+    // Converts a position in the viewport to pixels in the remote window
+    let to_remote = |pos: egui::Pos2| {
+        let relative = (pos - rect.min) / rect.size();
+
+        (
+            (relative.x * geometry.width as f32) as i32,
+            (relative.y * geometry.height as f32) as i32,
+        )
+    };
+
+    match event {
+        egui::Event::Key {
+            key,
+            physical_key,
+            pressed,
+            modifiers: key_modifiers,
+            ..
+        } => {
+            // Prefer the physical key, the remote applies its own keyboard layout
+            let scancode = scancode(physical_key.unwrap_or(*key))?;
+            let modifiers = modifiers(*key_modifiers);
+
+            Some(if *pressed {
+                InputEvent::KeyDown {
+                    scancode,
+                    modifiers,
+                }
+            } else {
+                InputEvent::KeyUp {
+                    scancode,
+                    modifiers,
+                }
+            })
+        }
+        // Typed characters are already sent as keys, so only forward text from input methods
+        egui::Event::CompositionEnd(text) => Some(InputEvent::Text { text: text.clone() }),
+        egui::Event::PointerMoved(pos) if rect.contains(*pos) => {
+            let (x, y) = to_remote(*pos);
+
+            Some(InputEvent::MouseMove { x, y })
+        }
+        egui::Event::PointerButton {
+            pos,
+            button,
+            pressed,
+            ..
+        } if rect.contains(*pos) => {
+            let (x, y) = to_remote(*pos);
+
+            let button = match button {
+                egui::PointerButton::Primary => MouseButton::Left,
+                egui::PointerButton::Secondary => MouseButton::Right,
+                egui::PointerButton::Middle => MouseButton::Middle,
+                egui::PointerButton::Extra1 => MouseButton::Back,
+                egui::PointerButton::Extra2 => MouseButton::Forward,
+            };
+
+            Some(InputEvent::MouseButton {
+                button,
+                pressed: *pressed,
+                x,
+                y,
+            })
+        }
+        egui::Event::MouseWheel { unit, delta, .. } => {
+            let notches = match unit {
+                egui::MouseWheelUnit::Point => *delta / POINTS_PER_NOTCH,
+                egui::MouseWheelUnit::Line => *delta,
+                egui::MouseWheelUnit::Page => *delta * 3.0,
+            };
+
+            // egui scrolls content right for positive x, Windows scrolls the view right
+            Some(InputEvent::MouseWheel {
+                delta_x: -notches.x,
+                delta_y: notches.y,
+            })
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use xyncer_share::payloads::AppData;
+
+// Marks the desktop entries we wrote, and which profile they launch through
+const PROFILE_KEY: &str = "X-Xyncer-Profile=";
+
+// Where desktop environments look for the user's own apps
+fn applications_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|data_dir| data_dir.join("applications"))
+}
+
+fn icons_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|data_dir| data_dir.join("xyncer").join("icons"))
+}
+
+// Writes a desktop entry (and icon) for each of a server's apps, each starting this client to launch it.
+// Returns the entries written, which replace those written for the profile before.
+pub fn install(
+    profile: &str,
+    apps: &[AppData],
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
+    if !cfg!(target_os = "linux") {
+        return Err("Launchers can only be installed on Linux".into());
+    }
+
+    let applications_dir = applications_dir().ok_or("Could not find the applications directory")?;
+    let icons_dir = icons_dir().ok_or("Could not find a directory for icons")?;
+
+    std::fs::create_dir_all(&applications_dir)?;
+    std::fs::create_dir_all(&icons_dir)?;
+
+    // Apps the server no longer has would be left behind otherwise
+    remove(&applications_dir, &icons_dir, profile)?;
+
+    let executable = std::env::current_exe()?;
+    let mut entries = Vec::new();
+
+    for app in apps {
+        let name = format!("xyncer-{}-{}", slug(profile), slug(&app.id));
+
+        let mut entry = format!(
+            "[Desktop Entry]\nType=Application\nName={}\nComment={} on {}\nExec={} --launch {} {}\nTerminal=false\n",
+            escape(&app.name),
+            escape(&app.name),
+            escape(profile),
+            quote(&executable.to_string_lossy()),
+            quote(profile),
+            quote(&app.id),
+        );
+
+        if let Some(icon) = &app.icon {
+            let path = icons_dir.join(format!("{}.png", name));
+
+            image::save_buffer(
+                &path,
+                &icon.rgba,
+                icon.width,
+                icon.height,
+                image::ExtendedColorType::Rgba8,
+            )?;
+
+            entry.push_str(&format!("Icon={}\n", escape(&path.to_string_lossy())));
+        }
+
+        entry.push_str(&format!("{}{}\n", PROFILE_KEY, escape(profile)));
+
+        let path = applications_dir.join(format!("{}.desktop", name));
+
+        std::fs::write(&path, entry)?;
+
+        entries.push(path);
+    }
+
+    Ok(entries)
+}
+
+// Removes the desktop entries (and icons) written for a profile
+fn remove(
+    applications_dir: &Path,
+    icons_dir: &Path,
+    profile: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let profile_line = format!("{}{}", PROFILE_KEY, escape(profile));
+
+    for entry in std::fs::read_dir(applications_dir)? {
+        let path = entry?.path();
+
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("xyncer-"))
+            .and_then(|name| name.strip_suffix(".desktop"))
+        else {
+            continue;
+        };
+
+        // Profiles with similar names share a prefix, so go by what the entry says
+        let ours = std::fs::read_to_string(&path)
+            .is_ok_and(|contents| contents.lines().any(|line| line == profile_line));
+
+        if !ours {
+            continue;
+        }
+
+        std::fs::remove_file(&path)?;
+
+        let icon = icons_dir.join(format!("xyncer-{}.png", name));
+
+        if icon.exists() {
+            std::fs::remove_file(icon)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Makes a name safe for a file name
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() {
+                character.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+// Escapes a desktop entry value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+}
+
+// Quotes an argument of the Exec key, which is unescaped as a value before its quotes are parsed
+fn quote(argument: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for character in argument.chars() {
+        match character {
+            '"' | '`' | '$' | '\\' => {
+                quoted.push('\\');
+                quoted.push(character);
+            }
+            // Would be taken for a field code otherwise
+            '%' => quoted.push_str("%%"),
+            character => quoted.push(character),
+        }
+    }
+
+    quoted.push('"');
+
+    escape(&quoted)
+}
@@ -1,25 +1,47 @@
 use eframe::egui;
-use simple_logger;
 
+mod audio;
+mod cli;
 mod client;
+mod events;
+mod headless;
+mod input;
+mod launchers;
+mod profiles;
 mod session;
+mod tls;
 mod ui;
+mod windows;
 
 #[tokio::main]
 async fn main() -> Result<(), eframe::Error> {
+    let cli = <cli::Cli as clap::Parser>::parse();
+
     simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
+        .with_level(cli.log_level())
         .init()
         .unwrap();
 
+    if cli.headless {
+        if let Err(e) = headless::run(cli).await {
+            eprintln!("{}", e);
+
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([300.0, 400.0]),
         ..Default::default()
     };
 
+    let launch = cli.launch_from_profile();
+
     eframe::run_native(
         "Xyncer",
         options,
-        Box::new(|_cc| Box::<ui::Xyncer>::default()),
+        Box::new(|_cc| Box::new(ui::Xyncer::new(launch))),
     )
 }
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::session;
+
+// A saved server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    pub address: String,
+    pub fingerprint: Option<String>, // Pinned certificate fingerprint, filled in on first connection if left empty
+    pub codec: Option<String>,       // Preferred frame codec, offered to the server first
+    pub passphrase: Option<String>,  // Last passphrase that got us in
+    pub auto_connect: bool,          // Connect to this server when the client starts
+    pub clipboard: bool,             // Sync the clipboard with the server's
+    pub audio: bool,                 // Play the server's audio
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: String::new(),
+            address: String::new(),
+            fingerprint: None,
+            codec: None,
+            passphrase: None,
+            auto_connect: false,
+            clipboard: true,
+            audio: true,
+        }
+    }
+}
+
+impl Profile {
+    // Sets the session up to connect to this server
+    pub fn apply(&self, session_data: &mut session::Session) {
+        session_data.server_address = self.address.clone();
+        session_data.password = self.passphrase.clone().unwrap_or_default();
+        session_data.pinned_fingerprint = self.fingerprint.clone();
+        session_data.preferred_codec = self.codec.clone();
+        session_data.sync_clipboard = self.clipboard;
+        session_data.play_audio = self.audio;
+
+        // With a saved passphrase, we can get in (and back in) without the user
+        session_data.auto_reconnect = self.passphrase.is_some();
+    }
+
+    // Remembers the passphrase and fingerprint of an authenticated session, returning whether anything changed
+    pub fn remember(&mut self, session_data: &session::Session) -> bool {
+        let mut changed = false;
+
+        if !session_data.password.is_empty()
+            && self.passphrase.as_deref() != Some(session_data.password.as_str())
+        {
+            self.passphrase = Some(session_data.password.clone());
+            changed = true;
+        }
+
+        if self.fingerprint.is_none() && session_data.fingerprint.is_some() {
+            self.fingerprint = session_data.fingerprint.clone();
+            changed = true;
+        }
+
+        changed
+    }
+}
+
+// Saved servers, kept in the user's config directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Profiles {
+    #[serde(default, rename = "profile")]
+    pub profiles: Vec<Profile>,
+}
+
+impl Profiles {
+    fn path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("xyncer")
+            .join("profiles.toml")
+    }
+
+    // Loads the saved profiles, starting from scratch if there are none or they can't be read
+    pub fn load() -> Self {
+        let path = Self::path();
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Profiles::default(),
+            Err(e) => {
+                log::error!("Could not read profiles from {}: {}", path.display(), e);
+
+                return Profiles::default();
+            }
+        };
+
+        toml::from_str(&contents).unwrap_or_else(|e| {
+            log::error!("Could not parse profiles from {}: {}", path.display(), e);
+
+            Profiles::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = Self::path();
+
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+
+        // Profiles can hold passphrases, so only we should be able to read them
+        let mut file = std::fs::OpenOptions::new();
+
+        file.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+
+        std::io::Write::write_all(
+            &mut file.open(path)?,
+            toml::to_string_pretty(self)?.as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    // Why a profile can't be saved at the given index (None for a new one), if it can't
+    pub fn validate(&self, index: Option<usize>, profile: &Profile) -> Option<String> {
+        if profile.name.trim().is_empty() {
+            return Some("The profile needs a name".to_string());
+        }
+
+        if profile.address.trim().is_empty() {
+            return Some("The profile needs a server address".to_string());
+        }
+
+        let duplicate = self
+            .profiles
+            .iter()
+            .enumerate()
+            .any(|(other_index, other)| Some(other_index) != index && other.name == profile.name);
+
+        if duplicate {
+            return Some(format!("There is already a profile named {}", profile.name));
+        }
+
+        None
+    }
+}
@@ -1,4 +1,22 @@
-#[derive(Clone)]
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::audio::{AudioOutput, Playback};
+use xyncer_share::audio::AudioCodec;
+use xyncer_share::clipboard::Clipboard;
+use xyncer_share::codecs::Codec;
+use xyncer_share::frames::{Frame, FrameDecoder};
+use xyncer_share::payloads::{AppData, Capability, ClipboardData, ResumeData, WindowData};
+use xyncer_share::transfer::Transfers;
+
+// Progress of reconnecting after the connection dropped
+#[derive(Clone, Copy, Debug)]
+pub struct Reconnect {
+    pub attempt: u32,
+    pub at: Option<std::time::Instant>, // When the next attempt starts, None while it is in progress
+}
+
 pub struct Session {
     pub authenticated: bool,
     pub connected: bool,
@@ -7,4 +25,144 @@ pub struct Session {
     pub password: String,
 
     pub server_address: String,
+
+    // Fingerprint of the server's certificate, None if connected without TLS
+    pub fingerprint: Option<String>,
+
+    // Set from the profile we are connecting with, if any
+    pub pinned_fingerprint: Option<String>,
+    pub preferred_codec: Option<String>,
+
+    // Remote windows, keyed by window id
+    pub windows: BTreeMap<u32, WindowData>,
+    pub windows_listed: bool, // Set once the server has sent the full window list
+
+    // Remote windows we are streaming, with the decoder for each, and their latest frames
+    pub subscriptions: BTreeMap<u32, FrameDecoder>,
+    pub frames: HashMap<u32, Frame>,
+
+    pub apps: Option<Vec<AppData>>, // The server's app catalog, once it has sent it
+
+    // This device's clipboard, kept in sync with the server's if sync_clipboard is set
+    pub clipboard: Option<Arc<dyn Clipboard>>,
+    pub sync_clipboard: bool,
+    pub remote_clipboard: Option<ClipboardData>, // Latest from the server, when there is no clipboard to put it on
+    pub clipboard_max_size: usize, // Largest clipboard to send or accept, as the server told us
+
+    // Where the server's audio is played, if play_audio is set, and the audio and decoded frames waiting to be played
+    pub audio_output: Option<Arc<Mutex<Box<dyn AudioOutput>>>>,
+    pub play_audio: bool,
+    pub playback: Arc<Mutex<Playback>>,
+
+    // Files being sent to and from the server, carried on after reconnecting, and where received ones go
+    pub transfers: Transfers,
+    pub download_directory: PathBuf,
+
+    // Frame codec picked by the server
+    pub codec: Arc<dyn Codec>,
+
+    // Audio codec picked by the server
+    pub audio_codec: Arc<dyn AudioCodec>,
+
+    // Capabilities supported by both us and the server
+    pub capabilities: Vec<Capability>,
+
+    // Set once the server sends Ready, so the session can be resumed if the connection drops
+    pub resume: Option<ResumeData>,
+
+    // Requests sent over the current connection, which its answers are handed to
+    pub requests: xyncer_share::Requests,
+
+    // Whether to reconnect (and resume, or identify again) if the connection drops, set once we are authenticated
+    pub auto_reconnect: bool,
+    pub reconnect: Option<Reconnect>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            authenticated: false,
+            connected: false,
+            error: None,
+            password: String::new(),
+            server_address: String::new(),
+            fingerprint: None,
+            pinned_fingerprint: None,
+            preferred_codec: None,
+            windows: BTreeMap::new(),
+            windows_listed: false,
+            subscriptions: BTreeMap::new(),
+            frames: HashMap::new(),
+            apps: None,
+            clipboard: None,
+            sync_clipboard: true,
+            remote_clipboard: None,
+            clipboard_max_size: xyncer_share::clipboard::DEFAULT_MAX_SIZE,
+            audio_output: None,
+            play_audio: true,
+            playback: Arc::default(),
+            transfers: Transfers::default(),
+            // The working directory if there is no Downloads folder
+            download_directory: dirs::download_dir().unwrap_or_default(),
+            codec: Arc::new(xyncer_share::codecs::RawCodec),
+            audio_codec: Arc::new(xyncer_share::audio::PcmCodec),
+            capabilities: Vec::new(),
+            resume: None,
+            requests: xyncer_share::Requests::default(),
+            auto_reconnect: false,
+            reconnect: None,
+        }
+    }
+}
+
+impl Session {
+    // Marks the connection as gone, keeping the session so it can be resumed
+    pub fn disconnect(&mut self) {
+        self.connected = false;
+        self.authenticated = false;
+    }
+
+    // Forgets the session entirely
+    pub fn reset(&mut self) {
+        self.disconnect();
+
+        self.capabilities.clear();
+        self.resume = None;
+
+        self.windows.clear();
+        self.windows_listed = false;
+        self.subscriptions.clear();
+        self.frames.clear();
+        self.playback.lock().unwrap().clear();
+        self.apps = None;
+        self.remote_clipboard = None;
+    }
+
+    // What we offer the server, leaving out what the profile turned off
+    pub fn offered_capabilities(&self) -> Vec<Capability> {
+        xyncer_share::capabilities()
+            .into_iter()
+            .filter(|capability| match capability {
+                Capability::Clipboard => self.sync_clipboard,
+                Capability::Audio => self.play_audio && self.audio_output.is_some(),
+                _ => true,
+            })
+            .collect()
+    }
+
+    pub fn playing_audio(&self) -> bool {
+        self.playback.lock().unwrap().stream.is_some()
+    }
+
+    // Shows the frames the audio has caught up with
+    pub fn release_frames(&mut self) {
+        let due = self.playback.lock().unwrap().av_sync.due();
+
+        for (window_id, frame) in due {
+            // The window may have been unsubscribed from while its frame was held back
+            if self.subscriptions.contains_key(&window_id) {
+                self.frames.insert(window_id, frame);
+            }
+        }
+    }
 }
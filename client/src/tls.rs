@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Servers usually have self-signed certificates, so instead of checking them against a CA,
+// we pin the certificate a server presents the first time we connect to it (trust on first use)
+#[derive(Debug)]
+struct PinningVerifier {
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinningVerifier {
+    // The certificate is checked against the pinned one once the handshake is done
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    // The server still has to prove it holds the certificate's key
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// TLS config for connecting to servers
+pub fn config() -> Result<Arc<rustls::ClientConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier { provider }))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+// File the pinned certificate fingerprints are kept in, one `address fingerprint` pair per line
+fn known_servers_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("xyncer")
+        .join("known_servers")
+}
+
+// The fingerprint pinned for a server, if we have connected to it before
+fn known_fingerprint(address: &str) -> Option<String> {
+    let known_servers = std::fs::read_to_string(known_servers_path()).ok()?;
+
+    known_servers.lines().find_map(|line| {
+        let (known_address, fingerprint) = line.split_once(' ')?;
+
+        (known_address == address).then(|| fingerprint.trim().to_string())
+    })
+}
+
+// Pins the fingerprint of a server we haven't connected to before
+fn remember_fingerprint(address: &str, fingerprint: &str) -> std::io::Result<()> {
+    let path = known_servers_path();
+
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    std::io::Write::write_all(
+        &mut file,
+        format!("{} {}\n", address, fingerprint).as_bytes(),
+    )
+}
+
+// Checks the fingerprint of a server's certificate against the pinned one, pinning it on first use
+pub fn verify_fingerprint(
+    address: &str,
+    fingerprint: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match known_fingerprint(address) {
+        Some(known_fingerprint) if known_fingerprint == fingerprint => Ok(()),
+        Some(known_fingerprint) => Err(format!(
+            "The certificate of {} has changed (expected {}, got {}). If you trust the new certificate, remove {} from {}.",
+            address,
+            known_fingerprint,
+            fingerprint,
+            address,
+            known_servers_path().display()
+        )
+        .into()),
+        None => {
+            log::warn!(
+                "Trusting {} on first use, certificate fingerprint: {}",
+                address,
+                fingerprint
+            );
+
+            remember_fingerprint(address, fingerprint)?;
+
+            Ok(())
+        }
+    }
+}
@@ -1,32 +1,688 @@
 use eframe::egui;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-use crate::{client::start_client, session};
+use crate::{client, events, launchers, profiles, session, windows};
+
+// How long to wait for the server to say how a launch went, which it does once the app opens its windows
+const LAUNCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// An app the server launched, or why it couldn't
+type Launched = Result<xyncer_share::payloads::AppLaunchedData, String>;
 
 pub struct Xyncer {
     pub payload_sender: flume::Sender<xyncer_share::Payload>,
     pub payload_receiver: flume::Receiver<xyncer_share::Payload>,
 
     pub session_data_guard: Arc<RwLock<session::Session>>,
+
+    remote_windows: windows::RemoteWindows,
+
+    // Wakes the client task up to stop it reconnecting
+    cancel_reconnect: Arc<tokio::sync::Notify>,
+
+    // Saved servers, and the one we are connecting with
+    profiles: profiles::Profiles,
+    selected_profile: Option<usize>,
+
+    profile_editor: Option<ProfileEditor>,
+
+    // Form for launching apps on the server, and why the last launch failed
+    launch_executable: String,
+    launch_arguments: String,
+    launch_error: Option<String>,
+
+    // Icons of the server's apps, keyed by app id
+    app_icons: HashMap<String, egui::TextureHandle>,
+
+    // How adding the server's apps to the applications menu went
+    launchers_message: Option<String>,
+
+    // Path of a file on the server to download
+    download_path: String,
+
+    launch_mode: Option<LaunchMode>,
+
+    // How the launches we asked for went, sent from the tasks waiting on them
+    launches: (flume::Sender<Launched>, flume::Receiver<Launched>),
+
+    // Requests the server couldn't handle, sent from the client task, and the latest of them
+    request_errors: (flume::Sender<String>, flume::Receiver<String>),
+    request_error: Option<String>,
+}
+
+// Started from a desktop entry, to launch one app and show only its windows
+struct LaunchMode {
+    app: String,
+    sent: bool,
+    windows_opened: bool,
+}
+
+// A profile being added or edited
+struct ProfileEditor {
+    index: Option<usize>, // None for a new profile
+    profile: profiles::Profile,
+    passphrase: String,
+    error: Option<String>,
 }
 
 impl Default for Xyncer {
     fn default() -> Self {
         let (payload_sender, payload_receiver) = flume::unbounded();
 
-        let session_data = session::Session {
-            authenticated: false,
-            connected: false,
-            error: None,
-            password: String::new(),
-            server_address: String::new(),
-        };
+        let session_data = session::Session::default();
 
         Xyncer {
-            payload_sender: payload_sender,
-            payload_receiver: payload_receiver,
+            payload_sender,
+            payload_receiver,
             session_data_guard: Arc::new(RwLock::new(session_data)),
+            remote_windows: windows::RemoteWindows::default(),
+            cancel_reconnect: Arc::new(tokio::sync::Notify::new()),
+            profiles: profiles::Profiles::default(),
+            selected_profile: None,
+            profile_editor: None,
+            launch_executable: String::new(),
+            launch_arguments: String::new(),
+            launch_error: None,
+            app_icons: HashMap::new(),
+            launchers_message: None,
+            download_path: String::new(),
+            launch_mode: None,
+            launches: flume::unbounded(),
+            request_errors: flume::unbounded(),
+            request_error: None,
+        }
+    }
+}
+
+impl Xyncer {
+    // Loads the saved profiles, connecting to the one marked to auto-connect, or to launch an app through the given one
+    pub fn new(launch: Option<(String, String)>) -> Self {
+        let mut xyncer = Xyncer {
+            profiles: profiles::Profiles::load(),
+            ..Default::default()
+        };
+
+        let session_data_guard = xyncer.session_data_guard.clone();
+
+        // Without a clipboard (like on a display server we can't talk to), there is nothing to sync
+        match xyncer_share::clipboard::SystemClipboard::new() {
+            Ok(clipboard) => {
+                write_session_data(&session_data_guard).clipboard = Some(Arc::new(clipboard))
+            }
+            Err(e) => log::warn!("Could not open the clipboard, it won't be synced: {}", e),
+        }
+
+        // Without an output device there is nothing to play the server's audio on, so it isn't asked for
+        #[cfg(feature = "system-audio")]
+        match crate::audio::SpeakerOutput::open() {
+            Ok(output) => {
+                write_session_data(&session_data_guard).audio_output =
+                    Some(Arc::new(std::sync::Mutex::new(Box::new(output))))
+            }
+            Err(e) => log::warn!(
+                "Could not open an audio output, audio won't be played: {}",
+                e
+            ),
+        }
+
+        let index = match &launch {
+            Some((profile, _)) => xyncer
+                .profiles
+                .profiles
+                .iter()
+                .position(|saved| saved.name == *profile),
+            None => xyncer
+                .profiles
+                .profiles
+                .iter()
+                .position(|profile| profile.auto_connect),
+        };
+
+        match (index, launch) {
+            (Some(index), launch) => {
+                log::info!("Connecting to {}", xyncer.profiles.profiles[index].name);
+
+                xyncer.launch_mode = launch.map(|(_, app)| LaunchMode {
+                    app,
+                    sent: false,
+                    windows_opened: false,
+                });
+
+                xyncer.select_profile(&mut write_session_data(&session_data_guard), index);
+                xyncer.connect();
+            }
+            (None, Some((profile, _))) => {
+                write_session_data(&session_data_guard).error =
+                    Some(format!("There is no profile named {}", profile));
+            }
+            (None, None) => {}
+        }
+
+        xyncer
+    }
+
+    // Starts the client task
+    fn connect(&self) {
+        // Clone the session data guard and payload sender/receiver
+        let session_data_guard_clone = self.session_data_guard.clone();
+        let payload_sender_clone = self.payload_sender.clone();
+        let payload_receiver_clone = self.payload_receiver.clone();
+        let cancel_reconnect_clone = self.cancel_reconnect.clone();
+        let request_error_sender = self.request_errors.0.clone();
+
+        let mut handlers = client::handlers();
+
+        // Shown until the next one, as the user may not have been looking when it arrived
+        handlers.on::<events::RequestFailed>(move |_, data| {
+            // The window may have closed in the meantime
+            let _ = request_error_sender.send(data.to_string());
+
+            Ok(())
+        });
+
+        // Start the client
+        tokio::spawn(client::run_client(
+            session_data_guard_clone,
+            payload_receiver_clone,
+            payload_sender_clone,
+            Arc::new(handlers),
+            cancel_reconnect_clone,
+        ));
+    }
+
+    fn select_profile(&mut self, session_data: &mut session::Session, index: usize) {
+        self.selected_profile = Some(index);
+        self.profiles.profiles[index].apply(session_data);
+    }
+
+    fn save_profiles(&self) {
+        if let Err(e) = self.profiles.save() {
+            log::error!("Error saving profiles: {}", e);
+        }
+    }
+
+    // Lists the saved servers, with buttons to add, edit and delete them
+    fn profiles_ui(&mut self, ui: &mut egui::Ui, session_data: &mut session::Session) {
+        ui.heading("Servers");
+
+        if self.profiles.profiles.is_empty() {
+            ui.label("No saved servers yet.");
+        }
+
+        let mut selected = None;
+        let mut edited = None;
+        let mut deleted = None;
+
+        for (index, profile) in self.profiles.profiles.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui
+                    .selectable_label(self.selected_profile == Some(index), &profile.name)
+                    .clicked()
+                {
+                    selected = Some(index);
+                }
+
+                ui.label(egui::RichText::new(&profile.address).weak());
+
+                if ui.small_button("Edit").clicked() {
+                    edited = Some(index);
+                }
+
+                if ui.small_button("Delete").clicked() {
+                    deleted = Some(index);
+                }
+            });
+        }
+
+        if let Some(index) = selected {
+            self.select_profile(session_data, index);
+        }
+
+        if let Some(index) = edited {
+            let profile = self.profiles.profiles[index].clone();
+
+            self.profile_editor = Some(ProfileEditor {
+                index: Some(index),
+                passphrase: profile.passphrase.clone().unwrap_or_default(),
+                profile,
+                error: None,
+            });
+        }
+
+        if let Some(index) = deleted {
+            self.profiles.profiles.remove(index);
+
+            self.selected_profile = match self.selected_profile {
+                Some(selected) if selected == index => None,
+                Some(selected) if selected > index => Some(selected - 1),
+                selected => selected,
+            };
+
+            // The editor's index may no longer point at the same profile
+            self.profile_editor = None;
+
+            self.save_profiles();
+        }
+
+        if self.profile_editor.is_none() && ui.button("Add server").clicked() {
+            self.profile_editor = Some(ProfileEditor {
+                index: None,
+                profile: profiles::Profile {
+                    address: session_data.server_address.clone(),
+                    ..Default::default()
+                },
+                passphrase: String::new(),
+                error: None,
+            });
+        }
+
+        let Some(editor) = self.profile_editor.as_mut() else {
+            return;
+        };
+
+        let mut save = false;
+        let mut cancel = false;
+
+        ui.add_space(6.0);
+
+        egui::Grid::new("profile-editor")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Name:");
+                ui.text_edit_singleline(&mut editor.profile.name);
+                ui.end_row();
+
+                ui.label("Address:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut editor.profile.address)
+                        .hint_text("wss://host:port"),
+                );
+                ui.end_row();
+
+                ui.label("Fingerprint:");
+
+                let mut fingerprint = editor.profile.fingerprint.clone().unwrap_or_default();
+
+                if ui
+                    .add(
+                        egui::TextEdit::singleline(&mut fingerprint)
+                            .hint_text("Pinned on first connection"),
+                    )
+                    .changed()
+                {
+                    editor.profile.fingerprint = Some(fingerprint.trim().to_string())
+                        .filter(|fingerprint| !fingerprint.is_empty());
+                }
+
+                ui.end_row();
+
+                ui.label("Codec:");
+
+                egui::ComboBox::from_id_source("profile-codec")
+                    .selected_text(editor.profile.codec.as_deref().unwrap_or("Automatic"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut editor.profile.codec, None, "Automatic");
+
+                        for name in xyncer_share::codecs::names() {
+                            ui.selectable_value(
+                                &mut editor.profile.codec,
+                                Some(name.clone()),
+                                name,
+                            );
+                        }
+                    });
+
+                ui.end_row();
+
+                ui.label("Passphrase:");
+                ui.add(egui::TextEdit::singleline(&mut editor.passphrase).password(true));
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(&mut editor.profile.auto_connect, "Connect on startup");
+                ui.checkbox(&mut editor.profile.clipboard, "Sync the clipboard");
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(&mut editor.profile.audio, "Play the server's audio");
+                ui.end_row();
+            });
+
+        if let Some(error) = &editor.error {
+            ui.label(egui::RichText::new(error).color(egui::Color32::from_rgb(255, 105, 97)));
+        }
+
+        ui.horizontal(|ui| {
+            save = ui.button("Save").clicked();
+            cancel = ui.button("Cancel").clicked();
+        });
+
+        if cancel {
+            self.profile_editor = None;
+
+            return;
+        }
+
+        if !save {
+            return;
+        }
+
+        let mut profile = editor.profile.clone();
+
+        profile.name = profile.name.trim().to_string();
+        profile.address = profile.address.trim().to_string();
+        profile.passphrase =
+            Some(editor.passphrase.clone()).filter(|passphrase| !passphrase.is_empty());
+
+        if let Some(error) = self.profiles.validate(editor.index, &profile) {
+            editor.error = Some(error);
+
+            return;
+        }
+
+        // Only one profile can connect on startup
+        if profile.auto_connect {
+            for other in &mut self.profiles.profiles {
+                other.auto_connect = false;
+            }
+        }
+
+        let index = match editor.index {
+            Some(index) => {
+                self.profiles.profiles[index] = profile;
+
+                index
+            }
+            None => {
+                self.profiles.profiles.push(profile);
+
+                self.profiles.profiles.len() - 1
+            }
+        };
+
+        self.profile_editor = None;
+        self.save_profiles();
+        self.select_profile(session_data, index);
+    }
+
+    // Lists the server's apps by category, returning the id of the one clicked
+    fn apps_ui(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        apps: &[xyncer_share::payloads::AppData],
+    ) -> Option<String> {
+        let mut clicked = None;
+
+        // Apps without a category come first
+        let mut categories: BTreeMap<Option<&str>, Vec<&xyncer_share::payloads::AppData>> =
+            BTreeMap::new();
+
+        for app in apps {
+            categories
+                .entry(app.category.as_deref())
+                .or_default()
+                .push(app);
+        }
+
+        for (category, apps) in categories {
+            egui::CollapsingHeader::new(category.unwrap_or("Apps"))
+                .id_source(("app-category", category))
+                .default_open(category.is_none())
+                .show(ui, |ui| {
+                    for app in apps {
+                        let icon = app.icon.as_ref().map(|icon| {
+                            self.app_icons
+                                .entry(app.id.clone())
+                                .or_insert_with(|| {
+                                    ctx.load_texture(
+                                        format!("app-icon-{}", app.id),
+                                        egui::ColorImage::from_rgba_unmultiplied(
+                                            [icon.width as usize, icon.height as usize],
+                                            &icon.rgba,
+                                        ),
+                                        egui::TextureOptions::LINEAR,
+                                    )
+                                })
+                                .id()
+                        });
+
+                        let button = match icon {
+                            Some(texture_id) => egui::Button::image_and_text(
+                                egui::load::SizedTexture::new(texture_id, egui::vec2(16.0, 16.0)),
+                                &app.name,
+                            ),
+                            None => egui::Button::new(&app.name),
+                        };
+
+                        if ui.add(button).on_hover_text(&app.executable).clicked() {
+                            clicked = Some(app.id.clone());
+                        }
+                    }
+                });
+        }
+
+        clicked
+    }
+
+    // Lists the file transfers, with forms to send and download files
+    fn files_ui(&mut self, ui: &mut egui::Ui, session_data: &mut session::Session) {
+        ui.heading("Files");
+
+        ui.label("Drop files on this window or a remote one to send them to the server.");
+
+        if ui.button("Send files...").clicked() {
+            // Created here, as some platforms need dialogs to come from the thread running the UI
+            let dialog = rfd::AsyncFileDialog::new()
+                .set_title("Send files to the server")
+                .pick_files();
+
+            let session_data_guard = self.session_data_guard.clone();
+            let payload_sender = self.payload_sender.clone();
+
+            tokio::spawn(async move {
+                if let Some(files) = dialog.await {
+                    let paths = files.iter().map(|file| file.path().to_path_buf()).collect();
+
+                    send_files(session_data_guard, payload_sender, paths).await;
+                }
+            });
+        }
+
+        ui.add_space(6.0);
+
+        ui.horizontal(|ui| {
+            ui.label("Download:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.download_path)
+                    .hint_text("Path in the server's shared directory"),
+            );
+
+            if ui.button("Download").clicked() && !self.download_path.trim().is_empty() {
+                client::request_file(
+                    &self.payload_sender,
+                    session_data,
+                    self.download_path.trim().to_string(),
+                );
+
+                self.download_path.clear();
+            }
+        });
+
+        ui.label(
+            egui::RichText::new(format!(
+                "Downloads are saved to {}",
+                session_data.download_directory.display()
+            ))
+            .weak(),
+        );
+
+        let progress = session_data.transfers.progress();
+
+        if progress.is_empty() {
+            return;
+        }
+
+        ui.add_space(6.0);
+
+        let mut cancelled = Vec::new();
+
+        egui::Grid::new("transfers").num_columns(3).show(ui, |ui| {
+            for transfer in &progress {
+                ui.label(match transfer.direction {
+                    xyncer_share::transfer::Direction::Sending => format!("⬆ {}", transfer.name),
+                    xyncer_share::transfer::Direction::Receiving => format!("⬇ {}", transfer.name),
+                });
+
+                let finished = match &transfer.state {
+                    xyncer_share::transfer::State::Requested => {
+                        ui.label(egui::RichText::new("Waiting for the server").weak());
+
+                        false
+                    }
+                    xyncer_share::transfer::State::Offered => {
+                        ui.label(egui::RichText::new("Waiting for the server to accept").weak());
+
+                        false
+                    }
+                    xyncer_share::transfer::State::Transferring => {
+                        let fraction = match transfer.size {
+                            0 => 1.0,
+                            size => transfer.transferred as f32 / size as f32,
+                        };
+
+                        ui.add(egui::ProgressBar::new(fraction).desired_width(200.0).text(
+                            format!(
+                                "{} of {}",
+                                format_size(transfer.transferred),
+                                format_size(transfer.size)
+                            ),
+                        ));
+
+                        false
+                    }
+                    xyncer_share::transfer::State::Complete(Some(path)) => {
+                        ui.label(
+                            egui::RichText::new(format!("Saved to {}", path.display())).weak(),
+                        );
+
+                        true
+                    }
+                    xyncer_share::transfer::State::Complete(None) => {
+                        ui.label(egui::RichText::new("Sent").weak());
+
+                        true
+                    }
+                    xyncer_share::transfer::State::Failed(error) => {
+                        ui.label(
+                            egui::RichText::new(error).color(egui::Color32::from_rgb(255, 105, 97)),
+                        );
+
+                        true
+                    }
+                };
+
+                if finished {
+                    ui.label("");
+                } else if ui.small_button("Cancel").clicked() {
+                    cancelled.push(transfer.transfer_id.clone());
+                }
+
+                ui.end_row();
+            }
+        });
+
+        for transfer_id in cancelled {
+            let payload = session_data.transfers.cancel(&transfer_id);
+
+            if let Err(e) = self.payload_sender.send(payload) {
+                log::error!("Error sending file end payload: {}", e);
+            }
+        }
+
+        if progress.iter().any(|transfer| {
+            matches!(
+                transfer.state,
+                xyncer_share::transfer::State::Complete(_)
+                    | xyncer_share::transfer::State::Failed(_)
+            )
+        }) && ui.button("Clear finished").clicked()
+        {
+            session_data.transfers.clear_finished();
+        }
+    }
+}
+
+// Sends files to the server one after another, as each is read whole for its checksum first
+async fn send_files(
+    session_data_guard: Arc<RwLock<session::Session>>,
+    payload_sender: flume::Sender<xyncer_share::Payload>,
+    paths: Vec<PathBuf>,
+) {
+    for path in paths {
+        client::send_file(&session_data_guard, &payload_sender, path).await;
+    }
+}
+
+// Asks the server to launch an app, sending back how it went for the UI to show
+async fn launch_app(
+    session_data_guard: Arc<RwLock<session::Session>>,
+    payload_sender: flume::Sender<xyncer_share::Payload>,
+    launches: flume::Sender<Launched>,
+    data: xyncer_share::payloads::LaunchAppData,
+) {
+    let target = data.target.clone();
+
+    let result = match client::launch_app(
+        &session_data_guard,
+        &payload_sender,
+        data,
+        LAUNCH_TIMEOUT,
+    )
+    .await
+    {
+        Ok(Ok(launched)) => Ok(launched),
+        Ok(Err(failed)) => Err(format!(
+            "Could not launch {}: {}",
+            failed.target, failed.error
+        )),
+        Err(e) => Err(format!("Could not launch {}: {}", target, e)),
+    };
+
+    // The UI may have closed in the meantime
+    let _ = launches.send(result);
+}
+
+// Formats a number of bytes for showing to the user
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// Loops until we obtain a write lock on the session data
+fn write_session_data(
+    session_data_guard: &RwLock<session::Session>,
+) -> tokio::sync::RwLockWriteGuard<'_, session::Session> {
+    loop {
+        // Try to obtain a write lock on the session data
+        if let Ok(session_data) = session_data_guard.try_write() {
+            return session_data;
         }
     }
 }
@@ -35,30 +691,274 @@ impl eframe::App for Xyncer {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         use egui::special_emojis::{GITHUB, OS_APPLE, OS_LINUX, OS_WINDOWS};
 
-        // Obtain a read lock on the session data
-        let session_data = self.session_data_guard.try_read().unwrap();
+        // The client task updates the session data in the background, so keep repainting to pick it up
+        ctx.request_repaint_after(std::time::Duration::from_millis(250));
+
+        // Held separately from self, so the panels below can borrow self mutably while holding a lock
+        let session_data_guard = self.session_data_guard.clone();
+
+        // Obtain a read lock on the session data, or try again next frame if the client task holds it
+        let Ok(session_data) = session_data_guard.try_read() else {
+            // The streamed windows would close if they went a frame without being shown
+            self.show_remote_windows(ctx);
+
+            return;
+        };
 
         egui::CentralPanel::default().show(ctx, |ui| {
             if session_data.authenticated {
-            } else {
                 // Drop the read lock on the session data
                 drop(session_data);
 
-                let mut session_data: tokio::sync::RwLockWriteGuard<session::Session>;
+                let mut session_data = write_session_data(&session_data_guard);
 
-                // Loop until we obtain a write lock on the session data
-                loop {
-                    // Try to obtain a write lock on the session data
-                    let try_session_data = self.session_data_guard.try_write();
+                // Remember what got us in, for next time
+                if let Some(profile) = self
+                    .selected_profile
+                    .and_then(|index| self.profiles.profiles.get_mut(index))
+                {
+                    if profile.remember(&session_data) {
+                        self.save_profiles();
+                    }
+                }
+
+                let can_stream = session_data
+                    .capabilities
+                    .contains(&xyncer_share::payloads::Capability::Frames);
 
-                    if try_session_data.is_ok() {
-                        // Set the session data
-                        session_data = try_session_data.unwrap();
+                if let Some(error) = self.request_errors.1.try_iter().last() {
+                    self.request_error = Some(error);
+                }
+
+                if let Some(error) = &self.request_error {
+                    ui.label(egui::RichText::new(error).color(egui::Color32::from_rgb(255, 105, 97)));
+                }
+
+                ui.heading("Windows");
+
+                if can_stream {
+                    ui.label("Tick a window to open it on this device.");
+                } else {
+                    ui.label("The server does not support streaming windows.");
+                }
+
+                if session_data.windows.is_empty() {
+                    ui.label("The server has no windows open.");
+                }
+
+                // Windows whose streaming checkbox was toggled this frame
+                let mut toggled = Vec::new();
+
+                for window in session_data.windows.values() {
+                    let mut streaming = session_data.subscriptions.contains_key(&window.id);
+
+                    ui.horizontal(|ui| {
+                        if !can_stream {
+                            ui.label(&window.title);
+                        } else if ui.checkbox(&mut streaming, &window.title).changed() {
+                            toggled.push((window.id, streaming));
+                        }
 
-                        break;
+                        ui.label(
+                            egui::RichText::new(format!(
+                                "{} ({}x{})",
+                                window.process_name, window.geometry.width, window.geometry.height
+                            ))
+                            .weak(),
+                        );
+                    });
+                }
+
+                for (window_id, streaming) in toggled {
+                    client::set_streaming(&self.payload_sender, &mut session_data, window_id, streaming);
+                }
+
+                if session_data
+                    .capabilities
+                    .contains(&xyncer_share::payloads::Capability::Audio)
+                {
+                    let mut playing = session_data.playing_audio();
+
+                    ui.add_space(6.0);
+
+                    if ui.checkbox(&mut playing, "Play the server's audio").changed() {
+                        client::set_audio(&self.payload_sender, &mut session_data, playing);
+                    }
+                }
+
+                // Open the windows of launched apps straight away
+                let launches: Vec<_> = self.launches.1.try_iter().collect();
+
+                for result in launches {
+                    match result {
+                        Ok(launched) => {
+                            self.launch_error = None;
+
+                            // Out of the way of the app's windows, which are all there is to see
+                            if let Some(launch_mode) = &mut self.launch_mode {
+                                if can_stream && !launched.windows.is_empty() {
+                                    launch_mode.windows_opened = true;
+
+                                    ctx.send_viewport_cmd(egui::ViewportCommand::Minimized(true));
+                                }
+                            }
+
+                            for window in launched.windows.iter().filter(|_| can_stream) {
+                                if !session_data.subscriptions.contains_key(&window.id) {
+                                    client::set_streaming(&self.payload_sender, &mut session_data, window.id, true);
+                                }
+                            }
+                        }
+                        Err(error) => {
+                            self.launch_error = Some(error);
+                        }
                     }
                 }
 
+                let can_launch = session_data
+                    .capabilities
+                    .contains(&xyncer_share::payloads::Capability::Launch);
+
+                if let Some(launch_mode) = &mut self.launch_mode {
+                    if !launch_mode.sent {
+                        launch_mode.sent = true;
+
+                        if can_launch {
+                            let data = xyncer_share::payloads::LaunchAppData {
+                                target: xyncer_share::payloads::LaunchTarget::App(launch_mode.app.clone()),
+                                arguments: Vec::new(),
+                                working_directory: None,
+                                environment: Default::default(),
+                            };
+
+                            tokio::spawn(launch_app(
+                                self.session_data_guard.clone(),
+                                self.payload_sender.clone(),
+                                self.launches.0.clone(),
+                                data,
+                            ));
+                        } else {
+                            self.launch_error = Some("The server does not launch apps".to_string());
+                        }
+                    }
+
+                    // The user is done with the app once they have closed all its windows
+                    if launch_mode.windows_opened && session_data.subscriptions.is_empty() {
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    }
+                }
+
+                if can_launch {
+                    ui.add_space(12.0);
+
+                    ui.heading("Launch");
+
+                    match &session_data.apps {
+                        None => {
+                            // The icons were of another server's apps
+                            self.app_icons.clear();
+
+                            ui.label("Loading the server's apps...");
+                        }
+                        Some(apps) if apps.is_empty() => {
+                            ui.label("The server has no apps to pick from.");
+                        }
+                        Some(apps) => {
+                            if let Some(id) = self.apps_ui(ctx, ui, apps) {
+                                let data = xyncer_share::payloads::LaunchAppData {
+                                    target: xyncer_share::payloads::LaunchTarget::App(id),
+                                    arguments: Vec::new(),
+                                    working_directory: None,
+                                    environment: Default::default(),
+                                };
+
+                                tokio::spawn(launch_app(
+                                    self.session_data_guard.clone(),
+                                    self.payload_sender.clone(),
+                                    self.launches.0.clone(),
+                                    data,
+                                ));
+                            }
+                        }
+                    }
+
+                    // Desktop entries launch through a profile
+                    let profile = self.selected_profile.map(|index| self.profiles.profiles[index].name.clone());
+
+                    if let (true, Some(profile), Some(apps)) = (cfg!(target_os = "linux"), profile, &session_data.apps) {
+                        if !apps.is_empty() && ui.button("Add to applications menu").clicked() {
+                            self.launchers_message = Some(match launchers::install(&profile, apps) {
+                                Ok(entries) => format!("Added {} apps to the applications menu", entries.len()),
+                                Err(e) => format!("Could not add the apps to the applications menu: {}", e),
+                            });
+                        }
+
+                        if let Some(message) = &self.launchers_message {
+                            ui.label(egui::RichText::new(message).weak());
+                        }
+                    }
+
+                    ui.add_space(6.0);
+
+                    ui.label("Or run an executable, if the server allows it:");
+
+                    egui::Grid::new("launch").num_columns(2).show(ui, |ui| {
+                        ui.label("Executable:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.launch_executable)
+                                .hint_text("C:\\Windows\\notepad.exe"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Arguments:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.launch_arguments)
+                                .hint_text("Separated by spaces"),
+                        );
+                        ui.end_row();
+                    });
+
+                    if ui.button("Launch").clicked() && !self.launch_executable.trim().is_empty() {
+                        let data = xyncer_share::payloads::LaunchAppData {
+                            target: xyncer_share::payloads::LaunchTarget::Executable(
+                                self.launch_executable.trim().to_string(),
+                            ),
+                            arguments: self.launch_arguments.split_whitespace().map(String::from).collect(),
+                            working_directory: None,
+                            environment: Default::default(),
+                        };
+
+                        tokio::spawn(launch_app(
+                            self.session_data_guard.clone(),
+                            self.payload_sender.clone(),
+                            self.launches.0.clone(),
+                            data,
+                        ));
+                    }
+
+                    if let Some(error) = &self.launch_error {
+                        ui.label(egui::RichText::new(error).color(egui::Color32::from_rgb(255, 105, 97)));
+                    }
+                }
+
+                if session_data
+                    .capabilities
+                    .contains(&xyncer_share::payloads::Capability::Files)
+                {
+                    ui.add_space(12.0);
+
+                    self.files_ui(ui, &mut session_data);
+                }
+
+                self.remote_windows.update(ctx, &mut session_data);
+            } else {
+                // Drop the read lock on the session data
+                drop(session_data);
+
+                let mut session_data = write_session_data(&session_data_guard);
+
+                self.remote_windows.update(ctx, &mut session_data);
+
                 ui.heading("xyncer");
 
                 ui.label(format!(
@@ -68,14 +968,50 @@ impl eframe::App for Xyncer {
 
                 ui.add_space(12.0);
 
+                // Servers can only be picked before connecting
+                if !session_data.connected && session_data.reconnect.is_none() {
+                    self.profiles_ui(ui, &mut session_data);
+
+                    ui.add_space(12.0);
+                }
+
                 ui.heading("Authentication");
 
                 ui.horizontal(|ui| {
                     ui.label("Server Address:");
-                    ui.text_edit_singleline(&mut session_data.server_address);
+
+                    // Typing an address connects without a profile
+                    if ui.text_edit_singleline(&mut session_data.server_address).changed() {
+                        self.selected_profile = None;
+
+                        session_data.pinned_fingerprint = None;
+                        session_data.preferred_codec = None;
+                        session_data.sync_clipboard = true;
+                        session_data.play_audio = true;
+                    }
                 });
 
                 if session_data.connected {
+                    // Shown so the user can check it against the one the server logs
+                    match &session_data.fingerprint {
+                        Some(fingerprint) => ui.label(
+                            egui::RichText::new(format!("Certificate fingerprint: {}", fingerprint)).weak(),
+                        ),
+                        None => ui.label(
+                            egui::RichText::new("Not using TLS, the connection is not encrypted.")
+                                .color(egui::Color32::from_rgb(255, 179, 71)),
+                        ),
+                    };
+                }
+
+                // We still have a session from before the connection dropped, so it will be resumed
+                let resuming = session_data.resume.is_some();
+
+                if resuming {
+                    if session_data.reconnect.is_none() {
+                        ui.label("The connection dropped, connect again to resume the session.");
+                    }
+                } else if session_data.connected {
                     ui.horizontal(|ui| {
                         ui.label("Password:");
                         ui.text_edit_singleline(&mut session_data.password);
@@ -92,39 +1028,42 @@ impl eframe::App for Xyncer {
                     });
                 }
 
+                let connected = session_data.connected;
+                let password = session_data.password.clone();
+                let preferred_codec = session_data.preferred_codec.clone();
+                let capabilities = session_data.offered_capabilities();
+                let reconnect = session_data.reconnect;
+
                 // Drop the write lock on the session data
                 drop(session_data);
 
-                if ui.button("Connect").clicked() {
-                    // Clone the session data guard and payload sender/receiver
-                    let session_data_guard_clone = self.session_data_guard.clone();
-                    let payload_sender_clone = self.payload_sender.clone();
-                    let payload_receiver_clone = self.payload_receiver.clone();
-
-                    // Start the client
-                    tokio::spawn(async move {
-                        let session_data_guard_clone_clone = session_data_guard_clone.clone();
-
-                        if let Err(e) = start_client(
-                            session_data_guard_clone,
-                            payload_receiver_clone,
-                            payload_sender_clone,
-                        )
-                        .await
-                        {
-                            log::error!("Error running client: {}", e);
-
-                            // Obtain a write lock on the session data
-                            let mut session_data =
-                                session_data_guard_clone_clone.try_write().unwrap();
-
-                            // Set the error message
-                            session_data.error = e.to_string().into();
-
-                            // Drop the write lock on the session data
-                            drop(session_data);
-                        }
+                if let Some(reconnect) = reconnect {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+
+                        match reconnect.at {
+                            Some(at) => ui.label(format!(
+                                "Connection lost, reconnecting in {}s (attempt {})",
+                                at.saturating_duration_since(std::time::Instant::now()).as_secs() + 1,
+                                reconnect.attempt
+                            )),
+                            None => ui.label(format!("Reconnecting (attempt {})", reconnect.attempt)),
+                        };
                     });
+
+                    if reconnect.at.is_some() && ui.button("Stop reconnecting").clicked() {
+                        self.cancel_reconnect.notify_waiters();
+                    }
+                } else if connected {
+                    if resuming {
+                        ui.spinner();
+                    } else if ui.button("Authenticate").clicked() {
+                        if let Err(e) = self.payload_sender.send(client::identify(password, preferred_codec.as_deref(), capabilities)) {
+                            log::error!("Error sending identify payload: {}", e);
+                        }
+                    }
+                } else if ui.button("Connect").clicked() {
+                    self.connect();
                 }
 
                 ui.add_space(12.0);
@@ -149,5 +1088,64 @@ impl eframe::App for Xyncer {
                 });
             }
         });
+
+        self.show_remote_windows(ctx);
+    }
+}
+
+impl Xyncer {
+    // Shows each streamed window in its own native window, as of the last time the session could be read
+    fn show_remote_windows(&mut self, ctx: &egui::Context) {
+        let mut viewport_output = self.remote_windows.show(ctx);
+
+        // Files dropped on this window are sent too
+        if self.remote_windows.accept_files {
+            windows::show_drop_target(ctx);
+
+            ctx.input(|input| {
+                viewport_output.dropped.extend(
+                    input
+                        .raw
+                        .dropped_files
+                        .iter()
+                        .filter_map(|file| file.path.clone()),
+                );
+            });
+        }
+
+        if !viewport_output.dropped.is_empty() {
+            tokio::spawn(send_files(
+                self.session_data_guard.clone(),
+                self.payload_sender.clone(),
+                viewport_output.dropped,
+            ));
+        }
+
+        if self.remote_windows.forward_input {
+            for data in viewport_output.input {
+                if let Err(e) = self.payload_sender.send(xyncer_share::Payload {
+                    op_code: xyncer_share::OP::Input,
+                    event_name: xyncer_share::Event::None,
+                    data: xyncer_share::payloads::PayloadData::Input(data),
+                    sequence: None,
+                    nonce: None,
+                }) {
+                    log::error!("Error sending input payload: {}", e);
+                }
+            }
+        }
+
+        if !viewport_output.closed.is_empty() {
+            let mut session_data = write_session_data(&self.session_data_guard);
+
+            for window_id in viewport_output.closed {
+                client::set_streaming(&self.payload_sender, &mut session_data, window_id, false);
+            }
+        }
+
+        // Keep up with the frame stream while windows are open
+        if !self.remote_windows.is_empty() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(1000 / 30));
+        }
     }
 }
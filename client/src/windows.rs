@@ -0,0 +1,166 @@
+use eframe::egui;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{input, session};
+use xyncer_share::payloads::{InputData, WindowData};
+
+// What happened in the remote windows' viewports this frame
+#[derive(Default)]
+pub struct ViewportOutput {
+    pub closed: Vec<u32>,
+    pub input: Vec<InputData>,
+    pub dropped: Vec<PathBuf>, // Files dropped on a window, to send to the server
+}
+
+// Native windows mirroring the streamed remote windows. Viewports close in any frame they aren't shown in, so what
+// to show is kept from the last time the session could be read, for the frames it can't be.
+#[derive(Default)]
+pub struct RemoteWindows {
+    textures: HashMap<u32, egui::TextureHandle>,
+    windows: Vec<WindowData>, // Streamed windows, only while authenticated
+    pub forward_input: bool,  // Whether to send their input to the server
+    pub accept_files: bool,   // Whether files dropped on them can be sent
+}
+
+impl RemoteWindows {
+    // Takes a new snapshot of the streamed windows, uploading new frames as textures and forgetting windows that are
+    // no longer streamed
+    pub fn update(&mut self, ctx: &egui::Context, session_data: &mut session::Session) {
+        session_data.release_frames();
+
+        for (window_id, frame) in session_data.frames.drain() {
+            let image = egui::ColorImage::from_rgba_unmultiplied(
+                [frame.width as usize, frame.height as usize],
+                &frame.rgba,
+            );
+
+            match self.textures.get_mut(&window_id) {
+                Some(texture) => texture.set(image, egui::TextureOptions::LINEAR),
+                None => {
+                    let texture = ctx.load_texture(
+                        format!("remote-window-{}", window_id),
+                        image,
+                        egui::TextureOptions::LINEAR,
+                    );
+
+                    self.textures.insert(window_id, texture);
+                }
+            }
+        }
+
+        self.textures
+            .retain(|window_id, _| session_data.subscriptions.contains_key(window_id));
+
+        let has = |capability| {
+            session_data.authenticated && session_data.capabilities.contains(&capability)
+        };
+
+        self.forward_input = has(xyncer_share::payloads::Capability::Input);
+        self.accept_files = has(xyncer_share::payloads::Capability::Files);
+
+        // Nothing is streamed until the session is authenticated again
+        self.windows = match session_data.authenticated {
+            true => session_data
+                .subscriptions
+                .keys()
+                .filter_map(|window_id| session_data.windows.get(window_id).cloned())
+                .collect(),
+            false => Vec::new(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    // Shows every streamed window in its own viewport, collecting the input for each, and the files dropped on
+    // them if they can be sent
+    pub fn show(&self, ctx: &egui::Context) -> ViewportOutput {
+        let mut output = ViewportOutput::default();
+        let accept_files = self.accept_files;
+
+        for window in &self.windows {
+            let texture = self.textures.get(&window.id);
+
+            // Remote geometry is in pixels, egui works in points
+            let size = egui::vec2(window.geometry.width as f32, window.geometry.height as f32)
+                / ctx.pixels_per_point();
+
+            let close_requested = ctx.show_viewport_immediate(
+                egui::ViewportId::from_hash_of(("remote-window", window.id)),
+                egui::ViewportBuilder::default()
+                    .with_title(&window.title)
+                    .with_inner_size(size),
+                |ctx, _class| {
+                    egui::CentralPanel::default()
+                        .frame(egui::Frame::none())
+                        .show(ctx, |ui| match texture {
+                            Some(texture) => {
+                                let rect = ui
+                                    .add(
+                                        egui::Image::new(egui::load::SizedTexture::from_handle(texture))
+                                            .fit_to_exact_size(ui.available_size()),
+                                    )
+                                    .rect;
+
+                                ctx.input(|input| {
+                                    output.input.extend(input.events.iter().filter_map(|event| {
+                                        Some(InputData {
+                                            window_id: window.id,
+                                            event: input::translate(event, rect, &window.geometry)?,
+                                        })
+                                    }));
+                                });
+                            }
+                            // No frame has arrived yet
+                            None => {
+                                ui.centered_and_justified(|ui| ui.spinner());
+                            }
+                        });
+
+                    if accept_files {
+                        show_drop_target(ctx);
+
+                        ctx.input(|input| {
+                            output.dropped.extend(
+                                input.raw.dropped_files.iter().filter_map(|file| file.path.clone()),
+                            );
+                        });
+                    }
+
+                    ctx.input(|input| input.viewport().close_requested())
+                },
+            );
+
+            if close_requested {
+                output.closed.push(window.id);
+            }
+        }
+
+        output
+    }
+}
+
+// Shades a window while files are dragged over it, to show they will be sent if dropped
+pub fn show_drop_target(ctx: &egui::Context) {
+    if ctx.input(|input| input.raw.hovered_files.is_empty()) {
+        return;
+    }
+
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Foreground,
+        egui::Id::new("file-drop-target"),
+    ));
+
+    let rect = ctx.screen_rect();
+
+    painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(160));
+    painter.text(
+        rect.center(),
+        egui::Align2::CENTER_CENTER,
+        "Drop to send to the server",
+        egui::FontId::proportional(20.0),
+        egui::Color32::WHITE,
+    );
+}
@@ -0,0 +1,505 @@
+use std::f32::consts::TAU;
+
+use xyncer_share::audio::{
+    AudioEncoder, AudioError, CHANNELS, PACKET_DURATION, PACKET_FRAMES, SAMPLE_RATE,
+};
+use xyncer_share::payloads::{AudioData, ErrorCode};
+
+use crate::{capture, router};
+
+// A packet of interleaved samples, with the timestamp of its first one
+pub type Packet = (u64, Vec<i16>);
+
+// Captures the audio playing on the server host
+pub trait AudioSource: Send + Sync {
+    // Starts capturing for one session
+    fn stream(&self) -> Result<Box<dyn AudioStream>, AudioError>;
+}
+
+// One session's stream of captured audio, as SAMPLE_RATE stereo
+pub trait AudioStream: Send {
+    // The whole packets captured since the last read
+    fn read(&mut self) -> Vec<Packet>;
+}
+
+// Most packets a stream hands over at once. A session that falls further behind than this
+// (like while its connection was gone) skips ahead instead of sending old audio.
+const MAX_BACKLOG: usize = 10;
+
+// Plays a tone, for testing on hosts without audio capture
+pub struct SineAudioSource {
+    pub frequency: f32,
+}
+
+// Frequency of the test tone, in Hz
+const SINE_FREQUENCY: f32 = 440.0;
+
+// Volume of the test tone, from 0 to 1
+const SINE_VOLUME: f32 = 0.2;
+
+struct SineStream {
+    frequency: f32,
+    started: std::time::Instant,
+    started_at: u64, // Timestamp of the first sample
+    frames: u64,     // Samples per channel made so far
+}
+
+impl AudioSource for SineAudioSource {
+    fn stream(&self) -> Result<Box<dyn AudioStream>, AudioError> {
+        Ok(Box::new(SineStream {
+            frequency: self.frequency,
+            started: std::time::Instant::now(),
+            started_at: capture::timestamp(),
+            frames: 0,
+        }))
+    }
+}
+
+impl AudioStream for SineStream {
+    fn read(&mut self) -> Vec<Packet> {
+        // Made as fast as real audio would be captured
+        let due = (self.started.elapsed().as_secs_f64() * f64::from(SAMPLE_RATE)) as u64;
+
+        let behind = due.saturating_sub(self.frames) / PACKET_FRAMES as u64;
+
+        if behind > MAX_BACKLOG as u64 {
+            self.frames += (behind - MAX_BACKLOG as u64) * PACKET_FRAMES as u64;
+        }
+
+        let mut packets = Vec::new();
+
+        while self.frames + PACKET_FRAMES as u64 <= due {
+            let timestamp = self.started_at + self.frames * 1000 / u64::from(SAMPLE_RATE);
+
+            let samples = (self.frames..self.frames + PACKET_FRAMES as u64)
+                .flat_map(|frame| {
+                    let time = (frame as f64 / f64::from(SAMPLE_RATE)) as f32;
+                    let sample = ((TAU * self.frequency * time).sin()
+                        * SINE_VOLUME
+                        * f32::from(i16::MAX)) as i16;
+
+                    [sample; CHANNELS]
+                })
+                .collect();
+
+            packets.push((timestamp, samples));
+
+            self.frames += PACKET_FRAMES as u64;
+        }
+
+        packets
+    }
+}
+
+// Captures whatever the default output device plays, through WASAPI loopback
+#[cfg(windows)]
+pub struct LoopbackAudioSource;
+
+#[cfg(windows)]
+impl AudioSource for LoopbackAudioSource {
+    fn stream(&self) -> Result<Box<dyn AudioStream>, AudioError> {
+        let (packet_sender, packets) = std::sync::mpsc::channel();
+        let (opened_sender, opened) = std::sync::mpsc::channel();
+
+        // cpal streams can't move between threads, so each lives on one of its own until the session lets go of it
+        std::thread::spawn(move || {
+            let stopped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+            let stream = match loopback::open(packet_sender, stopped.clone()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = opened_sender.send(Err(e));
+
+                    return;
+                }
+            };
+
+            let _ = opened_sender.send(Ok(()));
+
+            while !stopped.load(std::sync::atomic::Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+
+            drop(stream);
+        });
+
+        opened.recv()??;
+
+        Ok(Box::new(LoopbackStream { packets }))
+    }
+}
+
+#[cfg(windows)]
+struct LoopbackStream {
+    packets: std::sync::mpsc::Receiver<Packet>,
+}
+
+#[cfg(windows)]
+impl AudioStream for LoopbackStream {
+    fn read(&mut self) -> Vec<Packet> {
+        let mut packets: Vec<Packet> = self.packets.try_iter().collect();
+
+        // Only the latest audio is worth sending
+        if packets.len() > MAX_BACKLOG {
+            packets.drain(..packets.len() - MAX_BACKLOG);
+        }
+
+        packets
+    }
+}
+
+#[cfg(windows)]
+mod loopback {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use super::Packet;
+    use xyncer_share::audio::{AudioError, CHANNELS, PACKET_SAMPLES, SAMPLE_RATE};
+
+    // Starts capturing the default output device, sending packets until stopped is set (which it sets
+    // itself once nobody is receiving them)
+    pub fn open(
+        packet_sender: std::sync::mpsc::Sender<Packet>,
+        stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    ) -> Result<cpal::Stream, AudioError> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("There is no output device to capture")?;
+
+        let config = device.default_output_config()?;
+        let mut packetizer = Packetizer::new(config.channels().into(), config.sample_rate().0);
+
+        let mut on_samples = move |samples: &[f32]| {
+            for packet in packetizer.push(samples) {
+                if packet_sender.send(packet).is_err() {
+                    stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        };
+
+        let on_error = |e| log::warn!("Audio capture failed: {}", e);
+
+        // Building an input stream on an output device captures what it plays
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_input_stream(
+                &config.config(),
+                move |samples: &[f32], _: &_| on_samples(samples),
+                on_error,
+                None,
+            )?,
+            cpal::SampleFormat::I16 => device.build_input_stream(
+                &config.config(),
+                move |samples: &[i16], _: &_| {
+                    let samples: Vec<f32> = samples
+                        .iter()
+                        .map(|sample| f32::from(*sample) / f32::from(i16::MAX))
+                        .collect();
+
+                    on_samples(&samples)
+                },
+                on_error,
+                None,
+            )?,
+            format => return Err(format!("Can't capture {} audio", format).into()),
+        };
+
+        stream.play()?;
+
+        Ok(stream)
+    }
+
+    // Turns the device's samples into packets of SAMPLE_RATE stereo, whatever its rate and channels
+    struct Packetizer {
+        channels: usize,
+        step: f64,     // Device samples per channel for each of ours
+        position: f64, // Where our next sample falls between the device's previous and current ones
+        previous: [f32; CHANNELS],
+        samples: Vec<i16>,
+    }
+
+    impl Packetizer {
+        fn new(channels: usize, sample_rate: u32) -> Self {
+            Packetizer {
+                channels: channels.max(1),
+                step: f64::from(sample_rate) / f64::from(SAMPLE_RATE),
+                position: 0.0,
+                previous: [0.0; CHANNELS],
+                samples: Vec::with_capacity(PACKET_SAMPLES),
+            }
+        }
+
+        fn push(&mut self, samples: &[f32]) -> Vec<Packet> {
+            let mut packets = Vec::new();
+
+            for frame in samples.chunks_exact(self.channels) {
+                // Mono is played on both sides, and anything past stereo is left out
+                let current = [frame[0], frame[1.min(self.channels - 1)]];
+
+                // Linear interpolation is rough, but devices rarely run at another rate
+                while self.position < 1.0 {
+                    for (previous, current) in self.previous.iter().zip(current) {
+                        let sample = previous + (current - previous) * self.position as f32;
+
+                        self.samples
+                            .push((sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16);
+                    }
+
+                    self.position += self.step;
+
+                    if self.samples.len() == PACKET_SAMPLES {
+                        // Stamped when complete, less the time it covers
+                        let timestamp =
+                            crate::capture::timestamp().saturating_sub(
+                                xyncer_share::audio::PACKET_DURATION.as_millis() as u64,
+                            );
+
+                        packets.push((timestamp, std::mem::take(&mut self.samples)));
+                    }
+                }
+
+                self.position -= 1.0;
+                self.previous = current;
+            }
+
+            packets
+        }
+    }
+}
+
+// A session's audio, from capture to payloads
+pub struct Streaming {
+    stream: Box<dyn AudioStream>,
+    encoder: Box<dyn AudioEncoder>,
+    packet: u64, // Number of the next packet
+}
+
+impl Streaming {
+    pub fn start(
+        source: &dyn AudioSource,
+        codec: &dyn xyncer_share::audio::AudioCodec,
+    ) -> Result<Self, AudioError> {
+        Ok(Streaming {
+            stream: source.stream()?,
+            encoder: codec.encoder()?,
+            packet: 0,
+        })
+    }
+
+    // Encodes the audio captured since the last look
+    pub fn read(&mut self) -> Vec<AudioData> {
+        let mut packets = Vec::new();
+
+        for (timestamp, samples) in self.stream.read() {
+            match self.encoder.encode(&samples) {
+                Ok(data) => packets.push(AudioData {
+                    packet: self.packet,
+                    timestamp,
+                    data,
+                }),
+                Err(e) => log::error!("Could not encode audio: {}", e),
+            }
+
+            // Counted even if it failed, so the client conceals it rather than waiting for it
+            self.packet += 1;
+        }
+
+        packets
+    }
+}
+
+// How often to look for captured audio
+pub const POLL_INTERVAL: std::time::Duration = PACKET_DURATION;
+
+// Picks the audio source for this host
+pub fn source(synthetic: bool) -> std::sync::Arc<dyn AudioSource> {
+    #[cfg(windows)]
+    if !synthetic {
+        return std::sync::Arc::new(LoopbackAudioSource);
+    }
+
+    #[cfg(not(windows))]
+    if !synthetic {
+        log::warn!("Audio capture is only supported on Windows, using a test tone");
+    }
+
+    std::sync::Arc::new(SineAudioSource {
+        frequency: SINE_FREQUENCY,
+    })
+}
+
+// Starting and stopping the stream of the host's audio
+pub fn route(router: &mut router::Router) {
+    router
+        .on(xyncer_share::OP::StartAudio, |context, _| {
+            // Only negotiated when there is an audio source
+            let Some(audio_source) = context.options.audio_source.as_ref() else {
+                context.replies.reject(ErrorCode::Unavailable);
+
+                return Ok(());
+            };
+
+            let session_data = &mut *context.session;
+
+            if session_data.audio.is_some() {
+                return Ok(());
+            }
+
+            match Streaming::start(audio_source.as_ref(), session_data.audio_codec.as_ref()) {
+                Ok(streaming) => {
+                    log::info!(
+                        "Streaming audio to client {} with the {} codec",
+                        session_data.address,
+                        session_data.audio_codec.name()
+                    );
+
+                    session_data.audio = Some(streaming);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Could not start streaming audio to client {}: {}",
+                        session_data.address,
+                        e
+                    );
+
+                    context.replies.reject(ErrorCode::Unavailable);
+                }
+            }
+
+            Ok(())
+        })
+        .on(xyncer_share::OP::StopAudio, |context, _| {
+            context.session.audio = None;
+
+            Ok(())
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::testing::{request, Harness};
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    use xyncer_share::audio::{AudioCodec, AudioDecoder, PcmCodec, PACKET_SAMPLES};
+
+    // Fails to encode every other packet
+    struct FlakyCodec;
+
+    struct FlakyEncoder {
+        packets: u64,
+    }
+
+    impl AudioCodec for FlakyCodec {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn encoder(&self) -> Result<Box<dyn AudioEncoder>, AudioError> {
+            Ok(Box::new(FlakyEncoder { packets: 0 }))
+        }
+
+        fn decoder(&self) -> Result<Box<dyn AudioDecoder>, AudioError> {
+            Err("Nothing to decode".into())
+        }
+    }
+
+    impl AudioEncoder for FlakyEncoder {
+        fn encode(&mut self, _: &[i16]) -> Result<Vec<u8>, AudioError> {
+            self.packets += 1;
+
+            if self.packets.is_multiple_of(2) {
+                return Err("Flaked".into());
+            }
+
+            Ok(vec![0])
+        }
+    }
+
+    fn sine() -> SineAudioSource {
+        SineAudioSource {
+            frequency: SINE_FREQUENCY,
+        }
+    }
+
+    #[test]
+    fn sine_packets_follow_each_other() {
+        let mut stream = sine().stream().unwrap();
+
+        std::thread::sleep(PACKET_DURATION * 5);
+
+        let packets = stream.read();
+        let duration = PACKET_DURATION.as_millis() as u64;
+
+        assert!(packets.len() >= 5);
+        assert!(packets
+            .iter()
+            .all(|(_, samples)| samples.len() == PACKET_SAMPLES));
+        assert!(packets
+            .windows(2)
+            .all(|pair| pair[1].0 == pair[0].0 + duration));
+
+        // Nothing new has been captured yet
+        assert!(stream.read().len() <= 1);
+    }
+
+    #[test]
+    fn sine_streams_skip_ahead_when_behind() {
+        let mut stream = sine().stream().unwrap();
+
+        std::thread::sleep(PACKET_DURATION * (MAX_BACKLOG as u32 * 2));
+
+        assert_eq!(stream.read().len(), MAX_BACKLOG);
+    }
+
+    #[test]
+    fn streaming_numbers_packets_in_order() {
+        let mut streaming = Streaming::start(&sine(), &PcmCodec).unwrap();
+        let mut decoder = PcmCodec.decoder().unwrap();
+
+        std::thread::sleep(PACKET_DURATION * 3);
+
+        let packets = streaming.read();
+
+        assert!(packets.len() >= 3);
+
+        for (number, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.packet, number as u64);
+            assert_eq!(
+                decoder.decode(Some(&packet.data)).unwrap().len(),
+                PACKET_SAMPLES
+            );
+        }
+    }
+
+    #[test]
+    fn packets_that_fail_to_encode_are_still_counted() {
+        let mut streaming = Streaming::start(&sine(), &FlakyCodec).unwrap();
+
+        std::thread::sleep(PACKET_DURATION * 4);
+
+        let numbers: Vec<u64> = streaming
+            .read()
+            .iter()
+            .map(|packet| packet.packet)
+            .collect();
+
+        assert!(numbers.len() >= 2);
+        assert!(numbers.iter().all(|number| number.is_multiple_of(2)));
+    }
+
+    #[test]
+    fn audio_starts_and_stops() {
+        let mut harness = Harness::authenticated();
+
+        let (result, _) =
+            harness.dispatch(request(OP::StartAudio, PayloadData::StartAudio, Some(1)));
+
+        assert_eq!(result, Ok(()));
+        assert!(harness.session.audio.is_some());
+
+        let (result, _) = harness.dispatch(request(OP::StopAudio, PayloadData::StopAudio, None));
+
+        assert_eq!(result, Ok(()));
+        assert!(harness.session.audio.is_none());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::input::RecordingInputSink;
+use crate::router;
+use crate::windows::WindowProvider;
+use xyncer_share::frames::{Frame, FrameEncoder};
+use xyncer_share::payloads::{ErrorCode, FrameData};
+
+pub type CaptureError = Box<dyn std::error::Error + Send + Sync>;
+
+// Captures the contents of windows on the server host
+pub trait FrameSource: Send + Sync {
+    fn capture(&self, window_id: u32) -> Result<Frame, CaptureError>;
+}
+
+// Captures windows using XCap
+#[cfg(windows)]
+pub struct XCapFrameSource;
+
+#[cfg(windows)]
+impl FrameSource for XCapFrameSource {
+    fn capture(&self, window_id: u32) -> Result<Frame, CaptureError> {
+        let window = xcap::Window::all()?
+            .into_iter()
+            .find(|window| window.id() == window_id)
+            .ok_or_else(|| format!("Window {} does not exist", window_id))?;
+
+        let image = window.capture_image()?;
+
+        Ok(Frame {
+            width: image.width(),
+            height: image.height(),
+            rgba: image.into_raw(),
+        })
+    }
+}
+
+// Draws a test pattern for the synthetic windows, with a square that moves every frame, and a
+// marker where the recorded input last put the pointer
+pub struct SyntheticFrameSource {
+    window_provider: Arc<dyn WindowProvider>,
+    input_recorder: Arc<RecordingInputSink>,
+    frame_counts: Mutex<HashMap<u32, u32>>,
+}
+
+impl SyntheticFrameSource {
+    pub fn new(
+        window_provider: Arc<dyn WindowProvider>,
+        input_recorder: Arc<RecordingInputSink>,
+    ) -> Self {
+        SyntheticFrameSource {
+            window_provider,
+            input_recorder,
+            frame_counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Where the pointer was last seen over a window
+    fn pointer_position(&self, window_id: u32) -> Option<(i32, i32)> {
+        self.input_recorder
+            .events()
+            .into_iter()
+            .rev()
+            .filter(|(id, _)| *id == window_id)
+            .find_map(|(_, event)| match event {
+                xyncer_share::payloads::InputEvent::MouseMove { x, y }
+                | xyncer_share::payloads::InputEvent::MouseButton { x, y, .. } => Some((x, y)),
+                _ => None,
+            })
+    }
+}
+
+// Size of the moving square, in pixels
+const SYNTHETIC_SQUARE_SIZE: u32 = 32;
+
+// Size of the pointer marker, in pixels
+const SYNTHETIC_POINTER_SIZE: i32 = 4;
+
+impl FrameSource for SyntheticFrameSource {
+    fn capture(&self, window_id: u32) -> Result<Frame, CaptureError> {
+        let geometry = self
+            .window_provider
+            .windows()
+            .into_iter()
+            .find(|window| window.id == window_id)
+            .ok_or_else(|| format!("Window {} does not exist", window_id))?
+            .geometry;
+
+        let frame_count = {
+            let mut frame_counts = self.frame_counts.lock().unwrap();
+            let frame_count = frame_counts.entry(window_id).or_insert(0);
+
+            *frame_count = frame_count.wrapping_add(1);
+            *frame_count
+        };
+
+        let (width, height) = (geometry.width, geometry.height);
+
+        // Bounce the square back and forth along the middle of the window
+        let travel = width.saturating_sub(SYNTHETIC_SQUARE_SIZE).max(1);
+        let position = (frame_count * 8) % (travel * 2);
+        let square_x = if position < travel { position } else { travel * 2 - position };
+        let square_y = height.saturating_sub(SYNTHETIC_SQUARE_SIZE) / 2;
+
+        let pointer = self.pointer_position(window_id);
+
+        let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let in_square = x >= square_x
+                    && x < square_x + SYNTHETIC_SQUARE_SIZE
+                    && y >= square_y
+                    && y < square_y + SYNTHETIC_SQUARE_SIZE;
+
+                let on_pointer = pointer.is_some_and(|(pointer_x, pointer_y)| {
+                    (x as i32 - pointer_x).abs() <= SYNTHETIC_POINTER_SIZE
+                        && (y as i32 - pointer_y).abs() <= SYNTHETIC_POINTER_SIZE
+                });
+
+                if on_pointer {
+                    rgba.extend_from_slice(&[0xff, 0x00, 0x00, 0xff]);
+                } else if in_square {
+                    rgba.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+                } else {
+                    rgba.extend_from_slice(&[
+                        (x * 255 / width.max(1)) as u8,
+                        (y * 255 / height.max(1)) as u8,
+                        (window_id * 64) as u8,
+                        0xff,
+                    ]);
+                }
+            }
+        }
+
+        Ok(Frame {
+            width,
+            height,
+            rgba,
+        })
+    }
+}
+
+// Milliseconds since the UNIX epoch, used to timestamp frames
+pub fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Picks the frame source for this host
+pub fn source(
+    synthetic: bool,
+    window_provider: Arc<dyn WindowProvider>,
+    input_recorder: Arc<RecordingInputSink>,
+) -> Arc<dyn FrameSource> {
+    #[cfg(windows)]
+    if !synthetic {
+        return Arc::new(XCapFrameSource);
+    }
+
+    #[cfg(not(windows))]
+    if !synthetic {
+        log::warn!("Frame capture is only supported on Windows, using a test pattern");
+    }
+
+    Arc::new(SyntheticFrameSource::new(window_provider, input_recorder))
+}
+
+// The encoder of a subscribed window, shared with the capture task while it encodes a frame
+pub type Encoder = Arc<Mutex<FrameEncoder>>;
+
+// The subscribed windows to capture on one tick
+pub type Job = Vec<(u32, Encoder)>;
+
+// A window's changed frame, or why it couldn't be captured, with the encoder of the subscription
+// it was captured for
+pub type Captured = (u32, Encoder, Result<FrameData, CaptureError>);
+
+// Captures and encodes the frames of each job, so a slow capture doesn't hold up the session
+pub async fn capture_frames(
+    frame_source: Arc<dyn FrameSource>,
+    mut jobs: tokio::sync::mpsc::Receiver<Job>,
+    frames: tokio::sync::mpsc::Sender<Captured>,
+) {
+    while let Some(job) = jobs.recv().await {
+        let frame_source = frame_source.clone();
+        let frames = frames.clone();
+
+        // Capturing and encoding can be slow, so keep them off the async workers
+        let result = tokio::task::spawn_blocking(move || {
+            for (window_id, encoder) in job {
+                let frame = match frame_source.capture(window_id) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        if frames.blocking_send((window_id, encoder, Err(e))).is_err() {
+                            return;
+                        }
+
+                        continue;
+                    }
+                };
+
+                let encoded = encoder
+                    .lock()
+                    .unwrap()
+                    .encode(window_id, timestamp(), frame);
+
+                let data = match encoded {
+                    Ok(Some(data)) => data,
+                    // Nothing changed, so there is nothing to send
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Could not encode frame of window {}: {}", window_id, e);
+
+                        continue;
+                    }
+                };
+
+                // The session is gone, so nobody wants the rest
+                if frames
+                    .blocking_send((window_id, encoder, Ok(data)))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        })
+        .await;
+
+        if let Err(e) = result {
+            log::error!("Frame capture task failed: {}", e);
+        }
+    }
+}
+
+// Subscribing to the frames of windows
+pub fn route(router: &mut router::Router) {
+    router
+        .on(xyncer_share::OP::Subscribe, |context, payload| {
+            let xyncer_share::payloads::PayloadData::Subscribe(data) = payload.data else {
+                return Err(router::malformed(context, payload.op_code));
+            };
+
+            if !context.session.windows.contains_key(&data.window_id) {
+                log::warn!(
+                    "Client {} subscribed to unknown window {}, ignoring",
+                    context.session.address,
+                    data.window_id
+                );
+
+                context.replies.reject(ErrorCode::UnknownWindow);
+
+                return Ok(());
+            }
+
+            let codec = context.session.codec.clone();
+
+            context
+                .session
+                .subscriptions
+                .entry(data.window_id)
+                .or_insert_with(|| Arc::new(Mutex::new(FrameEncoder::new(codec))));
+
+            Ok(())
+        })
+        .on(xyncer_share::OP::Unsubscribe, |context, payload| {
+            let xyncer_share::payloads::PayloadData::Unsubscribe(data) = payload.data else {
+                return Err(router::malformed(context, payload.op_code));
+            };
+
+            context.session.subscriptions.remove(&data.window_id);
+
+            Ok(())
+        })
+        .on(xyncer_share::OP::RequestKeyframe, |context, payload| {
+            let xyncer_share::payloads::PayloadData::RequestKeyframe(data) = payload.data else {
+                return Err(router::malformed(context, payload.op_code));
+            };
+
+            if let Some(encoder) = context.session.subscriptions.get(&data.window_id) {
+                encoder.lock().unwrap().request_keyframe();
+            }
+
+            Ok(())
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router::testing::{request, Harness};
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    #[test]
+    fn subscriptions_come_and_go() {
+        let mut harness = Harness::authenticated();
+
+        harness.subscribe(1);
+
+        let data =
+            PayloadData::RequestKeyframe(xyncer_share::payloads::SubscribeData { window_id: 1 });
+        let (result, _) = harness.dispatch(request(OP::RequestKeyframe, data, None));
+
+        assert_eq!(result, Ok(()));
+
+        let data = PayloadData::Unsubscribe(xyncer_share::payloads::SubscribeData { window_id: 1 });
+        let (result, _) = harness.dispatch(request(OP::Unsubscribe, data, None));
+
+        assert_eq!(result, Ok(()));
+        assert!(harness.session.subscriptions.is_empty());
+    }
+}
@@ -0,0 +1,568 @@
+use std::path::{Path, PathBuf};
+
+use crate::{config, launch, router};
+use xyncer_share::payloads::{AppData, WindowIcon};
+
+// App icons are scaled down to fit in a square this many pixels wide
+const ICON_SIZE: u32 = 48;
+
+// How many folders deep to look for apps
+const MAX_DEPTH: usize = 4;
+
+// An app in the catalog, with what launching it runs
+#[derive(Clone, Debug)]
+pub struct CatalogApp {
+    pub app: AppData,
+    pub command: launch::LaunchCommand,
+}
+
+// Apps clients can launch by their id, in the order they were found
+#[derive(Default)]
+pub struct Catalog {
+    apps: Vec<CatalogApp>,
+}
+
+impl Catalog {
+    pub fn get(&self, id: &str) -> Option<&CatalogApp> {
+        self.apps.iter().find(|app| app.app.id == id)
+    }
+
+    // What clients are shown
+    pub fn apps(&self) -> Vec<AppData> {
+        self.apps.iter().map(|app| app.app.clone()).collect()
+    }
+
+    // Adds an app, unless one with the same id was found first
+    fn add(&mut self, app: CatalogApp) {
+        if self.get(&app.app.id).is_some() {
+            log::debug!(
+                "Skipping {}, there is already an app with the id {}",
+                app.app.executable,
+                app.app.id
+            );
+
+            return;
+        }
+
+        self.apps.push(app);
+    }
+}
+
+// Builds the catalog from the registered apps, the configured directories and (on Windows) the Start Menu
+pub fn discover(launch: &config::Launch) -> Catalog {
+    let mut catalog = Catalog::default();
+
+    for (id, app) in &launch.apps {
+        let executable = expand_environment(&app.executable);
+
+        let icon = match &app.icon {
+            Some(icon) => load_icon(icon),
+            None => executable_icon(&executable, 0),
+        };
+
+        catalog.add(CatalogApp {
+            app: AppData {
+                id: id.clone(),
+                name: app.name.clone().unwrap_or_else(|| id.clone()),
+                executable: executable.clone(),
+                category: app.category.clone(),
+                icon,
+            },
+            command: launch::LaunchCommand {
+                executable,
+                arguments: app.arguments.clone(),
+                working_directory: app.working_directory.clone(),
+                environment: app.environment.clone(),
+            },
+        });
+    }
+
+    for directory in &launch.directories {
+        scan(&mut catalog, directory);
+    }
+
+    if launch.start_menu {
+        for directory in start_menu_directories() {
+            scan(&mut catalog, &directory);
+        }
+    }
+
+    log::info!("Found {} apps for clients to launch", catalog.apps.len());
+
+    catalog
+}
+
+// The Start Menu's program folders, for every user and then for the current one
+#[cfg(windows)]
+fn start_menu_directories() -> Vec<PathBuf> {
+    [std::env::var_os("ProgramData"), std::env::var_os("APPDATA")]
+        .into_iter()
+        .flatten()
+        .map(|root| {
+            PathBuf::from(root)
+                .join("Microsoft")
+                .join("Windows")
+                .join("Start Menu")
+                .join("Programs")
+        })
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn start_menu_directories() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+// Adds the shortcuts and executables in a directory (and the folders in it) to the catalog
+fn scan(catalog: &mut Catalog, root: &Path) {
+    let mut directories = vec![(root.to_path_buf(), 0)];
+
+    while let Some((directory, depth)) = directories.pop() {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!(
+                    "Could not read app directory {}: {}",
+                    directory.display(),
+                    e
+                );
+
+                continue;
+            }
+        };
+
+        // Sorted, so the same app wins an id clash every time
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+
+        paths.sort();
+
+        for path in paths {
+            if path.is_dir() {
+                if depth < MAX_DEPTH {
+                    directories.push((path, depth + 1));
+                }
+
+                continue;
+            }
+
+            if let Some(app) = app_from_file(root, &path) {
+                catalog.add(app);
+            }
+        }
+    }
+}
+
+// Turns a shortcut or executable into an app, named after the file and categorised by the folder it is in
+fn app_from_file(root: &Path, path: &Path) -> Option<CatalogApp> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    let (command, icon_location) = match extension.as_deref() {
+        Some("lnk") => shortcut(path)?,
+        Some("exe") => (command_for(path.to_string_lossy().into_owned()), None),
+        _ if is_executable(path) => (command_for(path.to_string_lossy().into_owned()), None),
+        _ => return None,
+    };
+
+    let name = path.file_stem()?.to_string_lossy().into_owned();
+
+    // Start Menu folders are full of these, and nobody wants to launch them remotely
+    if name.to_lowercase().contains("uninstall")
+        || Path::new(&command.executable)
+            .file_stem()
+            .is_some_and(|stem| stem.to_string_lossy().to_lowercase().starts_with("unins"))
+    {
+        return None;
+    }
+
+    let relative = path.strip_prefix(root).ok()?.with_extension("");
+
+    // Paths are joined with forward slashes on every OS, so ids and categories look the same everywhere
+    let join = |path: &Path| {
+        path.components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+
+    let category = relative
+        .parent()
+        .map(join)
+        .filter(|category| !category.is_empty());
+
+    // A PNG next to the app wins over the icon in its executable
+    let icon_path = path.with_extension("png");
+
+    let icon = if icon_path.is_file() {
+        load_icon(&icon_path)
+    } else {
+        let (icon_file, icon_index) =
+            icon_location.unwrap_or_else(|| (command.executable.clone(), 0));
+
+        executable_icon(&icon_file, icon_index)
+    };
+
+    Some(CatalogApp {
+        app: AppData {
+            id: join(&relative),
+            name,
+            executable: command.executable.clone(),
+            category,
+            icon,
+        },
+        command,
+    })
+}
+
+fn command_for(executable: String) -> launch::LaunchCommand {
+    launch::LaunchCommand {
+        executable,
+        arguments: Vec::new(),
+        working_directory: None,
+        environment: Default::default(),
+    }
+}
+
+// Reads what a shortcut runs, and where its icon is
+fn shortcut(path: &Path) -> Option<(launch::LaunchCommand, Option<(String, i32)>)> {
+    let link = match lnk::ShellLink::open(path) {
+        Ok(link) => link,
+        Err(e) => {
+            log::debug!("Could not read shortcut {}: {:?}", path.display(), e);
+
+            return None;
+        }
+    };
+
+    // Shortcuts to documents, websites and installer-managed apps have no executable to run
+    let executable = link.link_info().as_ref().and_then(|link_info| {
+        link_info
+            .local_base_path_unicode()
+            .clone()
+            .or_else(|| link_info.local_base_path().clone())
+    })?;
+
+    let executable = expand_environment(&executable);
+
+    if !executable.to_lowercase().ends_with(".exe") {
+        return None;
+    }
+
+    let icon_location = link.icon_location().as_ref().map(|icon_location| {
+        (
+            expand_environment(icon_location),
+            link.header().icon_index(),
+        )
+    });
+
+    let command = launch::LaunchCommand {
+        executable,
+        arguments: link
+            .arguments()
+            .as_deref()
+            .map(split_arguments)
+            .unwrap_or_default(),
+        working_directory: link
+            .working_dir()
+            .as_deref()
+            .map(expand_environment)
+            .filter(|working_directory| !working_directory.is_empty()),
+        environment: Default::default(),
+    };
+
+    Some((command, icon_location))
+}
+
+// Splits a command line into arguments, keeping quoted ones together
+fn split_arguments(command_line: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut argument = String::new();
+    let mut quoted = false;
+    let mut started = false;
+
+    for character in command_line.chars() {
+        match character {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            character if character.is_whitespace() && !quoted => {
+                if started {
+                    arguments.push(std::mem::take(&mut argument));
+                    started = false;
+                }
+            }
+            character => {
+                argument.push(character);
+                started = true;
+            }
+        }
+    }
+
+    if started {
+        arguments.push(argument);
+    }
+
+    arguments
+}
+
+// Expands %VARIABLE%s, which shortcuts use for paths like %SystemRoot%, leaving unknown ones as they are
+fn expand_environment(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find('%') {
+        let Some(length) = rest[start + 1..].find('%') else {
+            break;
+        };
+
+        let name = &rest[start + 1..start + 1 + length];
+
+        expanded.push_str(&rest[..start]);
+
+        match std::env::var(name) {
+            Ok(variable) if !name.is_empty() => expanded.push_str(&variable),
+            _ => expanded.push_str(&rest[start..start + length + 2]),
+        }
+
+        rest = &rest[start + length + 2..];
+    }
+
+    expanded.push_str(rest);
+
+    expanded
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Executables are found by their extension everywhere else
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+// Loads an icon from an image file, scaling it down to size
+fn load_icon(path: &Path) -> Option<WindowIcon> {
+    let image = match image::open(path) {
+        Ok(image) => image,
+        Err(e) => {
+            log::warn!("Could not load icon {}: {}", path.display(), e);
+
+            return None;
+        }
+    };
+
+    let image = if image.width() > ICON_SIZE || image.height() > ICON_SIZE {
+        image.resize(ICON_SIZE, ICON_SIZE, image::imageops::FilterType::Triangle)
+    } else {
+        image
+    }
+    .into_rgba8();
+
+    Some(WindowIcon {
+        width: image.width(),
+        height: image.height(),
+        rgba: image.into_raw(),
+    })
+}
+
+// Extracts the large icon embedded in an executable (or DLL)
+#[cfg(windows)]
+fn executable_icon(path: &str, index: i32) -> Option<WindowIcon> {
+    use windows_sys::Win32::UI::Shell::ExtractIconExW;
+    use windows_sys::Win32::UI::WindowsAndMessaging::DestroyIcon;
+
+    let path: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut icon = 0;
+
+    unsafe {
+        if ExtractIconExW(path.as_ptr(), index, &mut icon, std::ptr::null_mut(), 1) == 0
+            || icon == 0
+        {
+            return None;
+        }
+
+        let window_icon = icon_pixels(icon);
+
+        DestroyIcon(icon);
+
+        window_icon
+    }
+}
+
+// Reads the pixels of an icon, which stays the caller's to destroy
+#[cfg(windows)]
+pub fn icon_pixels(icon: windows_sys::Win32::UI::WindowsAndMessaging::HICON) -> Option<WindowIcon> {
+    use windows_sys::Win32::Graphics::Gdi::DeleteObject;
+    use windows_sys::Win32::UI::WindowsAndMessaging::{GetIconInfo, ICONINFO};
+
+    if icon == 0 {
+        return None;
+    }
+
+    unsafe {
+        let mut icon_info: ICONINFO = std::mem::zeroed();
+
+        if GetIconInfo(icon, &mut icon_info) == 0 {
+            return None;
+        }
+
+        let window_icon = bitmap_icon(icon_info.hbmColor);
+
+        // GetIconInfo makes copies of the bitmaps, which are ours to free
+        DeleteObject(icon_info.hbmColor);
+        DeleteObject(icon_info.hbmMask);
+
+        window_icon
+    }
+}
+
+// Reads the pixels of an icon's colour bitmap
+#[cfg(windows)]
+unsafe fn bitmap_icon(bitmap: windows_sys::Win32::Graphics::Gdi::HBITMAP) -> Option<WindowIcon> {
+    use windows_sys::Win32::Graphics::Gdi::*;
+
+    // Monochrome icons only have a mask
+    if bitmap == 0 {
+        return None;
+    }
+
+    let mut header: BITMAP = std::mem::zeroed();
+
+    if GetObjectW(
+        bitmap,
+        std::mem::size_of::<BITMAP>() as i32,
+        &mut header as *mut BITMAP as *mut std::ffi::c_void,
+    ) == 0
+    {
+        return None;
+    }
+
+    let width = header.bmWidth as u32;
+    let height = header.bmHeight as u32;
+
+    let mut bitmap_info: BITMAPINFO = std::mem::zeroed();
+
+    bitmap_info.bmiHeader.biSize = std::mem::size_of::<BITMAPINFOHEADER>() as u32;
+    bitmap_info.bmiHeader.biWidth = width as i32;
+    bitmap_info.bmiHeader.biHeight = -(height as i32); // Top-down
+    bitmap_info.bmiHeader.biPlanes = 1;
+    bitmap_info.bmiHeader.biBitCount = 32;
+    bitmap_info.bmiHeader.biCompression = BI_RGB;
+
+    let mut bgra = vec![0u8; (width * height * 4) as usize];
+
+    let dc = CreateCompatibleDC(0);
+
+    let lines = GetDIBits(
+        dc,
+        bitmap,
+        0,
+        height,
+        bgra.as_mut_ptr() as *mut std::ffi::c_void,
+        &mut bitmap_info,
+        DIB_RGB_COLORS,
+    );
+
+    DeleteDC(dc);
+
+    if lines == 0 {
+        return None;
+    }
+
+    // Old icons have no alpha channel, and would come out invisible
+    let opaque = bgra.chunks_exact(4).all(|pixel| pixel[3] == 0);
+
+    let rgba = bgra
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            [
+                pixel[2],
+                pixel[1],
+                pixel[0],
+                if opaque { 0xff } else { pixel[3] },
+            ]
+        })
+        .collect();
+
+    Some(WindowIcon {
+        width,
+        height,
+        rgba,
+    })
+}
+
+// Icons can only be extracted from executables on Windows
+#[cfg(not(windows))]
+fn executable_icon(_path: &str, _index: i32) -> Option<WindowIcon> {
+    None
+}
+
+// Listing the apps clients can launch
+pub fn route(router: &mut router::Router) {
+    router.on(xyncer_share::OP::ListApps, |context, _| {
+        let event = context.session.sequence(xyncer_share::Payload {
+            op_code: xyncer_share::OP::Dispatch,
+            event_name: xyncer_share::Event::AppCatalog,
+            data: xyncer_share::payloads::PayloadData::AppCatalog(
+                xyncer_share::payloads::AppCatalogData {
+                    apps: context.options.catalog.apps(),
+                },
+            ),
+            sequence: None,
+            nonce: None,
+        });
+
+        context.replies.answer(event);
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::router::testing::{request, Harness};
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    // A catalog of one app, as configured rather than found in the start menu
+    fn hello() -> Catalog {
+        let launch: config::Launch = toml::from_str(
+            r#"
+            start_menu = false
+
+            [apps.hello]
+            executable = "/usr/bin/hello"
+            "#,
+        )
+        .unwrap();
+
+        discover(&launch)
+    }
+
+    #[test]
+    fn apps_are_listed() {
+        let mut harness = Harness::authenticated();
+
+        harness.options.catalog = Arc::new(hello());
+
+        let (result, replies) =
+            harness.dispatch(request(OP::ListApps, PayloadData::ListApps, Some(4)));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies[0].nonce, Some(4));
+        assert!(matches!(&replies[0].data, PayloadData::AppCatalog(data) if data.apps.len() == 1));
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{router, server};
+use xyncer_share::clipboard::{Clipboard, ClipboardError, ClipboardWatcher};
+use xyncer_share::payloads::{ClipboardData, ErrorCode};
+
+// Holds what clients copy, for hosts without a clipboard to sync. Shared by every session, like a real one.
+#[derive(Default)]
+pub struct SyntheticClipboard {
+    data: Mutex<ClipboardData>,
+}
+
+impl Clipboard for SyntheticClipboard {
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        Ok(self.data.lock().unwrap().clone())
+    }
+
+    fn write(&self, data: &ClipboardData) -> Result<(), ClipboardError> {
+        log::info!("Synthetic clipboard now holds {:?}", data);
+
+        *self.data.lock().unwrap() = data.clone();
+
+        Ok(())
+    }
+}
+
+// Picks the clipboard for this host
+pub fn clipboard(synthetic: bool) -> Arc<dyn Clipboard> {
+    #[cfg(windows)]
+    if !synthetic {
+        match xyncer_share::clipboard::SystemClipboard::new() {
+            Ok(clipboard) => return Arc::new(clipboard),
+            Err(e) => log::error!("Could not open the clipboard, using a synthetic one: {}", e),
+        }
+    }
+
+    #[cfg(not(windows))]
+    if !synthetic {
+        log::warn!("Clipboard sync is only supported on Windows, using a synthetic clipboard");
+    }
+
+    Arc::new(SyntheticClipboard::default())
+}
+
+// This host's clipboard if it changed since the watcher last looked, cut down to the size limit
+pub fn poll(options: &server::Options, watcher: &mut ClipboardWatcher) -> Option<ClipboardData> {
+    let clipboard = options.clipboard.as_ref()?;
+    let data = watcher
+        .poll(clipboard.as_ref())?
+        .limit(options.clipboard_max_size);
+
+    // Nothing fits, so there is nothing to send
+    (!data.items.is_empty()).then_some(data)
+}
+
+// Putting what clients copy on this host's clipboard
+pub fn route(router: &mut router::Router) {
+    router.on(xyncer_share::OP::Clipboard, |context, payload| {
+        let xyncer_share::payloads::PayloadData::Clipboard(data) = payload.data else {
+            return Err(router::malformed(context, payload.op_code));
+        };
+
+        // Only negotiated when there is a clipboard
+        let Some(clipboard) = context.options.clipboard.as_ref() else {
+            context.replies.reject(ErrorCode::Unavailable);
+
+            return Ok(());
+        };
+
+        if data.size() > context.options.clipboard_max_size {
+            log::warn!(
+                "Client {} sent {} bytes of clipboard, over the limit of {}, ignoring",
+                context.session.address,
+                data.size(),
+                context.options.clipboard_max_size
+            );
+
+            context.replies.reject(ErrorCode::TooLarge);
+
+            return Ok(());
+        }
+
+        log::info!("Client {} changed the clipboard", context.session.address);
+
+        if let Err(e) = context
+            .connection
+            .clipboard_watcher
+            .apply(clipboard.as_ref(), &data)
+        {
+            log::warn!(
+                "Could not write the clipboard of client {}: {}",
+                context.session.address,
+                e
+            );
+        }
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::testing::{rejection, request, Harness};
+    use xyncer_share::payloads::{ClipboardItem, PayloadData};
+    use xyncer_share::OP;
+
+    fn text(size: usize) -> ClipboardData {
+        ClipboardData {
+            items: vec![ClipboardItem::new(
+                xyncer_share::clipboard::TEXT,
+                vec![b'a'; size],
+            )],
+        }
+    }
+
+    fn clipboard(size: usize) -> PayloadData {
+        PayloadData::Clipboard(text(size))
+    }
+
+    #[test]
+    fn clipboards_within_the_limit_are_applied() {
+        let mut harness = Harness::authenticated();
+
+        let (result, replies) = harness.dispatch(request(OP::Clipboard, clipboard(16), Some(1)));
+
+        assert_eq!(result, Ok(()));
+        assert!(replies.is_empty());
+
+        let clipboard_data = harness.options.clipboard.as_ref().unwrap().read().unwrap();
+
+        assert_eq!(clipboard_data.items[0].data, vec![b'a'; 16]);
+
+        let size = harness.options.clipboard_max_size + 1;
+        let (result, replies) = harness.dispatch(request(OP::Clipboard, clipboard(size), Some(2)));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(rejection(&replies[0]), Some(ErrorCode::TooLarge));
+    }
+
+    #[test]
+    fn clients_changes_do_not_bounce_back() {
+        let mut harness = Harness::authenticated();
+
+        // The first look only remembers what is there
+        assert!(poll(&harness.options, &mut harness.connection.clipboard_watcher).is_none());
+
+        let (result, _) = harness.dispatch(request(OP::Clipboard, clipboard(16), None));
+
+        assert_eq!(result, Ok(()));
+        assert!(poll(&harness.options, &mut harness.connection.clipboard_watcher).is_none());
+
+        harness
+            .options
+            .clipboard
+            .as_ref()
+            .unwrap()
+            .write(&text(8))
+            .unwrap();
+
+        assert_eq!(
+            poll(&harness.options, &mut harness.connection.clipboard_watcher),
+            Some(text(8))
+        );
+    }
+
+    #[test]
+    fn changes_over_the_limit_are_not_sent() {
+        let mut harness = Harness::authenticated();
+        let clipboard = harness.options.clipboard.clone().unwrap();
+
+        assert!(poll(&harness.options, &mut harness.connection.clipboard_watcher).is_none());
+
+        clipboard
+            .write(&text(harness.options.clipboard_max_size + 1))
+            .unwrap();
+
+        assert!(poll(&harness.options, &mut harness.connection.clipboard_watcher).is_none());
+    }
+}
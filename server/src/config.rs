@@ -0,0 +1,611 @@
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Command line flags, which take precedence over the config file
+#[derive(clap::Parser, Debug)]
+#[command(version, about = "Shares this host's windows with xyncer clients")]
+pub struct Cli {
+    /// Config file to load [default: xyncer/server.toml in the user's config directory, if it exists]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, can be given more than once
+    #[arg(long = "bind", value_name = "ADDRESS")]
+    pub bind: Vec<String>,
+
+    /// Port to listen on
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// How often clients have to send a heartbeat, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub heartbeat_interval: Option<u8>,
+
+    /// How late a heartbeat can be before the client is asked for one, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub heartbeat_jitter: Option<u8>,
+
+    /// Use this passphrase for every connection, instead of a random one per connection
+    #[arg(long)]
+    pub passphrase: Option<String>,
+
+    /// Invalid passphrases a client can send before it is disconnected
+    #[arg(long)]
+    pub max_password_attempts: Option<u8>,
+
+    /// Most connections to accept at once
+    #[arg(long)]
+    pub max_sessions: Option<usize>,
+
+    /// TLS certificate (PEM), generated on first run if it doesn't exist
+    #[arg(long, value_name = "PATH")]
+    pub cert: Option<PathBuf>,
+
+    /// TLS private key (PEM), generated on first run if it doesn't exist
+    #[arg(long, value_name = "PATH")]
+    pub key: Option<PathBuf>,
+
+    /// Serve without TLS, sending passphrases and windows in cleartext
+    #[arg(long)]
+    pub no_tls: bool,
+
+    /// Don't sync this host's clipboard with clients
+    #[arg(long)]
+    pub no_clipboard: bool,
+
+    /// Don't stream this host's audio to clients
+    #[arg(long)]
+    pub no_audio: bool,
+
+    /// Don't let clients send files to this host or download files from it
+    #[arg(long)]
+    pub no_files: bool,
+
+    /// Where files from clients are saved, and the only place they can download files from [default: xyncer in the user's Downloads folder]
+    #[arg(long, value_name = "PATH")]
+    pub files_directory: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Serve fake windows instead of real ones, useful for development on hosts without capture support
+    #[arg(long)]
+    pub synthetic: bool,
+}
+
+// How connections get their passphrase
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "policy", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Passphrase {
+    // Generated per connection, and shown in the log
+    Random {
+        #[serde(default = "default_passphrase_length")]
+        length: usize,
+    },
+    Fixed {
+        passphrase: String,
+    },
+}
+
+fn default_passphrase_length() -> usize {
+    5
+}
+
+impl Passphrase {
+    // The passphrase for a new connection
+    pub fn generate(&self) -> String {
+        match self {
+            Passphrase::Random { length } => {
+                Alphanumeric.sample_string(&mut rand::thread_rng(), *length)
+            }
+            Passphrase::Fixed { passphrase } => passphrase.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    pub interval: u8, // Seconds
+    pub jitter: u8,   // Seconds a heartbeat can be late by
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: 60,
+            jitter: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Resume {
+    pub grace_period: u16, // Seconds the session of a dropped connection is kept for its client to resume
+    pub max_detached: usize, // Most of those sessions kept at once
+}
+
+impl Default for Resume {
+    fn default() -> Self {
+        Resume {
+            grace_period: 60,
+            max_detached: 16,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub enabled: bool,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Tls {
+            enabled: true,
+            cert: None,
+            key: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Clipboard {
+    pub enabled: bool,
+    pub max_size: usize, // Bytes, larger contents are neither sent nor accepted
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Clipboard {
+            enabled: true,
+            max_size: xyncer_share::clipboard::DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    pub enabled: bool,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Audio { enabled: true }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Files {
+    pub enabled: bool,
+    pub directory: Option<PathBuf>, // Falls back to xyncer in the user's Downloads folder
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Files {
+            enabled: true,
+            directory: None,
+        }
+    }
+}
+
+// An app clients can launch by its id
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct App {
+    pub name: Option<String>, // Shown to clients instead of the id
+    pub category: Option<String>,
+    pub icon: Option<PathBuf>, // PNG
+    pub executable: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default)]
+    pub working_directory: Option<String>,
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Launch {
+    pub allow_executables: bool, // Whether clients can start any executable, rather than only apps in the catalog
+    pub apps: BTreeMap<String, App>, // Keyed by id
+    pub directories: Vec<PathBuf>, // Searched for shortcuts and executables to add to the catalog
+    pub start_menu: bool, // Whether to add the Start Menu's shortcuts to the catalog, on Windows
+}
+
+impl Default for Launch {
+    fn default() -> Self {
+        Launch {
+            // Any authenticated client could run anything on the host, so that has to be asked for
+            allow_executables: false,
+            apps: BTreeMap::new(),
+            directories: Vec::new(),
+            start_menu: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub port: u16,
+    pub heartbeat: Heartbeat,
+    pub passphrase: Passphrase,
+    pub max_password_attempts: u8,
+    pub max_sessions: Option<usize>, // Unlimited if not set
+    pub resume: Resume,
+    pub tls: Tls,
+    pub launch: Launch,
+    pub clipboard: Clipboard,
+    pub audio: Audio,
+    pub files: Files,
+    pub log_level: String,
+    pub synthetic: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 8080,
+            heartbeat: Heartbeat::default(),
+            passphrase: Passphrase::Random {
+                length: default_passphrase_length(),
+            },
+            max_password_attempts: 3,
+            max_sessions: None,
+            resume: Resume::default(),
+            tls: Tls::default(),
+            launch: Launch::default(),
+            clipboard: Clipboard::default(),
+            audio: Audio::default(),
+            files: Files::default(),
+            log_level: "info".to_string(),
+            synthetic: false,
+        }
+    }
+}
+
+impl Config {
+    // Applies the command line flags on top of the config file
+    fn apply(&mut self, cli: Cli) {
+        if !cli.bind.is_empty() {
+            self.bind = cli.bind;
+        }
+
+        if let Some(port) = cli.port {
+            self.port = port;
+        }
+
+        if let Some(interval) = cli.heartbeat_interval {
+            self.heartbeat.interval = interval;
+        }
+
+        if let Some(jitter) = cli.heartbeat_jitter {
+            self.heartbeat.jitter = jitter;
+        }
+
+        if let Some(passphrase) = cli.passphrase {
+            self.passphrase = Passphrase::Fixed { passphrase };
+        }
+
+        if let Some(max_password_attempts) = cli.max_password_attempts {
+            self.max_password_attempts = max_password_attempts;
+        }
+
+        if cli.max_sessions.is_some() {
+            self.max_sessions = cli.max_sessions;
+        }
+
+        if cli.cert.is_some() {
+            self.tls.cert = cli.cert;
+        }
+
+        if cli.key.is_some() {
+            self.tls.key = cli.key;
+        }
+
+        if cli.no_tls {
+            self.tls.enabled = false;
+        }
+
+        if cli.no_clipboard {
+            self.clipboard.enabled = false;
+        }
+
+        if cli.no_audio {
+            self.audio.enabled = false;
+        }
+
+        if cli.no_files {
+            self.files.enabled = false;
+        }
+
+        if cli.files_directory.is_some() {
+            self.files.directory = cli.files_directory;
+        }
+
+        if let Some(log_level) = cli.log_level {
+            self.log_level = log_level;
+        }
+
+        if cli.synthetic {
+            self.synthetic = true;
+        }
+    }
+
+    // Every problem with the config, so they can all be fixed at once
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.bind.is_empty() {
+            errors.push("bind: at least one address is needed".to_string());
+        }
+
+        for address in &self.bind {
+            if address.parse::<std::net::IpAddr>().is_err() {
+                errors.push(format!("bind: \"{}\" is not an IP address", address));
+            }
+        }
+
+        if self.port == 0 {
+            errors.push("port: must be between 1 and 65535".to_string());
+        }
+
+        if self.heartbeat.interval == 0 {
+            errors.push("heartbeat.interval: must be at least 1 second".to_string());
+        }
+
+        if self.heartbeat.jitter == 0 {
+            errors.push("heartbeat.jitter: must be at least 1 second".to_string());
+        }
+
+        match &self.passphrase {
+            Passphrase::Random { length } if !(4..=64).contains(length) => {
+                errors.push("passphrase.length: must be between 4 and 64".to_string());
+            }
+            Passphrase::Fixed { passphrase } if passphrase.chars().count() < 8 => {
+                errors.push("passphrase.passphrase: must be at least 8 characters".to_string());
+            }
+            _ => {}
+        }
+
+        if self.max_password_attempts == 0 {
+            errors.push("max_password_attempts: must be at least 1".to_string());
+        }
+
+        if self.max_sessions == Some(0) {
+            errors.push("max_sessions: must be at least 1, or left out for no limit".to_string());
+        }
+
+        if self.resume.grace_period == 0 {
+            errors.push("resume.grace_period: must be at least 1 second".to_string());
+        }
+
+        if self.resume.max_detached == 0 {
+            errors.push("resume.max_detached: must be at least 1".to_string());
+        }
+
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            errors.push("tls: cert and key have to be set together".to_string());
+        }
+
+        for (id, app) in &self.launch.apps {
+            if app.executable.trim().is_empty() {
+                errors.push(format!("launch.apps.{}.executable: must not be empty", id));
+            }
+        }
+
+        if self.clipboard.max_size == 0 {
+            errors.push("clipboard.max_size: must be at least 1 byte".to_string());
+        }
+
+        if self.files.enabled && self.files_directory().is_none() {
+            errors.push(
+                "files.directory: there is no Downloads folder to fall back to, so it has to be set"
+                    .to_string(),
+            );
+        }
+
+        if self.log_level.parse::<log::LevelFilter>().is_err() {
+            errors.push(format!(
+                "log_level: \"{}\" is not one of off, error, warn, info, debug or trace",
+                self.log_level
+            ));
+        }
+
+        errors
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+        self.log_level.parse().unwrap_or(log::LevelFilter::Info)
+    }
+
+    // Where files from clients go and shared files come from, falling back to the Downloads folder if there is one
+    pub fn files_directory(&self) -> Option<PathBuf> {
+        self.files
+            .directory
+            .clone()
+            .or_else(|| dirs::download_dir().map(|download_dir| download_dir.join("xyncer")))
+    }
+
+    // The certificate and key to use, falling back to the default location
+    pub fn tls_paths(&self) -> (PathBuf, PathBuf) {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => crate::tls::default_paths(),
+        }
+    }
+}
+
+// Where the config file is looked for unless given one
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|directory| directory.join("xyncer").join("server.toml"))
+}
+
+// Loads the config file, applies the command line flags on top, and validates the result
+pub fn load() -> Result<Config, String> {
+    let cli = <Cli as clap::Parser>::parse();
+
+    // An explicitly given config file has to exist, the default one doesn't
+    let path = match &cli.config {
+        Some(path) => Some(path.clone()),
+        None => default_path().filter(|path| path.exists()),
+    };
+
+    let mut config = match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+
+            toml::from_str(&contents)
+                .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?
+        }
+        None => Config::default(),
+    };
+
+    config.apply(cli);
+
+    let errors = config.validate();
+
+    if !errors.is_empty() {
+        return Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")));
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        <Cli as clap::Parser>::try_parse_from(
+            std::iter::once("xyncer-server").chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let config = parse(
+            r#"
+            bind = ["localhost"]
+            port = 0
+            max_password_attempts = 0
+            log_level = "loud"
+
+            [resume]
+            grace_period = 0
+
+            [passphrase]
+            policy = "fixed"
+            passphrase = "short"
+
+            [files]
+            directory = "/tmp"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            [
+                "bind: \"localhost\" is not an IP address",
+                "port: must be between 1 and 65535",
+                "passphrase.passphrase: must be at least 8 characters",
+                "max_password_attempts: must be at least 1",
+                "resume.grace_period: must be at least 1 second",
+                "log_level: \"loud\" is not one of off, error, warn, info, debug or trace",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(parse("prot = 9000").is_err());
+        assert!(parse("[heartbeat]\ninterval = 30\njiter = 5").is_err());
+        assert!(parse("[passphrase]\npolicy = \"random\"\nlenght = 8").is_err());
+        assert!(parse("[launch.apps.editor]\nexecutable = \"editor\"\nargs = []").is_err());
+
+        assert!(parse("port = 9000\n[heartbeat]\ninterval = 30").is_ok());
+    }
+
+    #[test]
+    fn certs_and_keys_come_together() {
+        let cert = parse("[tls]\ncert = \"cert.pem\"\n[files]\ndirectory = \"/tmp\"").unwrap();
+        let key = parse("[tls]\nkey = \"key.pem\"\n[files]\ndirectory = \"/tmp\"").unwrap();
+        let both =
+            parse("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\n[files]\ndirectory = \"/tmp\"")
+                .unwrap();
+
+        assert_eq!(
+            cert.validate(),
+            ["tls: cert and key have to be set together"]
+        );
+        assert_eq!(
+            key.validate(),
+            ["tls: cert and key have to be set together"]
+        );
+        assert!(both.validate().is_empty());
+        assert_eq!(
+            both.tls_paths(),
+            (PathBuf::from("cert.pem"), PathBuf::from("key.pem"))
+        );
+    }
+
+    #[test]
+    fn command_line_flags_win_over_the_file() {
+        let mut config = parse(
+            r#"
+            bind = ["0.0.0.0"]
+            port = 9000
+            max_sessions = 4
+
+            [passphrase]
+            policy = "random"
+            length = 8
+
+            [clipboard]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+
+        config.apply(cli(&[
+            "--port",
+            "9001",
+            "--passphrase",
+            "password1",
+            "--no-clipboard",
+        ]));
+
+        assert_eq!(config.port, 9001);
+        assert!(matches!(
+            &config.passphrase,
+            Passphrase::Fixed { passphrase } if passphrase == "password1"
+        ));
+        assert!(!config.clipboard.enabled);
+
+        // What the flags leave alone stays as the file had it
+        assert_eq!(config.bind, ["0.0.0.0"]);
+        assert_eq!(config.max_sessions, Some(4));
+    }
+}
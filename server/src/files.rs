@@ -0,0 +1,142 @@
+use std::path::{Component, Path};
+
+use crate::router;
+use xyncer_share::payloads::ErrorCode;
+use xyncer_share::transfer::Outgoing;
+
+// Opens one of the shared files for sending, refusing paths that lead out of the shared directory.
+// The whole file is read for its checksum, so keep this off the async workers.
+pub fn open(
+    directory: &Path,
+    transfer_id: String,
+    path: &str,
+) -> Result<Outgoing, Box<dyn std::error::Error + Send + Sync>> {
+    let relative = Path::new(path);
+
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!("{} is not a path in the shared directory", path).into());
+    }
+
+    // Links in the directory could still lead out of it
+    let directory = directory.canonicalize()?;
+    let resolved = directory.join(relative).canonicalize()?;
+
+    if !resolved.starts_with(&directory) {
+        return Err(format!("{} is not a path in the shared directory", path).into());
+    }
+
+    Outgoing::open(transfer_id, &resolved)
+}
+
+// Transferring files, and offering the shared ones clients ask for
+pub fn route(router: &mut router::Router) {
+    for op_code in [
+        xyncer_share::OP::FileOffer,
+        xyncer_share::OP::FileAccept,
+        xyncer_share::OP::FileChunk,
+        xyncer_share::OP::FileAck,
+        xyncer_share::OP::FileEnd,
+    ] {
+        router.on(op_code, |context, payload| {
+            // Only negotiated when there is a directory for files
+            let Some(directory) = context.options.files.as_ref() else {
+                context.replies.reject(ErrorCode::Unavailable);
+
+                return Ok(());
+            };
+
+            for payload in context.session.transfers.handle(directory, &payload.data) {
+                context.replies.send(payload);
+            }
+
+            Ok(())
+        });
+    }
+
+    router.on(xyncer_share::OP::RequestFile, |context, payload| {
+        let xyncer_share::payloads::PayloadData::RequestFile(data) = payload.data else {
+            return Err(router::malformed(context, payload.op_code));
+        };
+
+        // Only negotiated when there is a directory for files
+        let Some(directory) = context.options.files.clone() else {
+            context.replies.reject(ErrorCode::Unavailable);
+
+            return Ok(());
+        };
+
+        log::info!("Client {} asked for {}", context.session.address, data.path);
+
+        let file_sender = context.connection.file_sender.clone();
+        let nonce = context.replies.nonce();
+
+        // Checksumming reads the whole file, so keep serving the session meanwhile
+        tokio::task::spawn_blocking(move || {
+            let result = open(&directory, data.transfer_id.clone(), &data.path);
+
+            // Nobody is left to tell if the connection closed in the meantime
+            let _ = file_sender.send((nonce, data.transfer_id, result));
+        });
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router::testing::{request, Harness};
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    // A directory of its own for each test, as they run at the same time
+    fn directory(name: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("xyncer-files-{}-{}", std::process::id(), name));
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    #[tokio::test]
+    async fn files_are_offered_and_accepted() {
+        let mut harness = Harness::authenticated();
+        let directory = directory("files");
+
+        std::fs::write(directory.join("shared.txt"), "shared").unwrap();
+        harness.options.files = Some(directory.clone());
+
+        let data = PayloadData::RequestFile(xyncer_share::payloads::RequestFileData {
+            transfer_id: "request".to_string(),
+            path: "shared.txt".to_string(),
+        });
+        let (result, _) = harness.dispatch(request(OP::RequestFile, data, Some(6)));
+
+        assert_eq!(result, Ok(()));
+
+        let (nonce, transfer_id, opened) = harness.files.recv().await.unwrap();
+
+        assert_eq!(nonce, Some(6));
+        assert_eq!(transfer_id, "request");
+        assert!(opened.is_ok());
+
+        let data = PayloadData::FileOffer(xyncer_share::payloads::FileOfferData {
+            transfer_id: "offer".to_string(),
+            name: "offered.txt".to_string(),
+            size: 5,
+            checksum: "0".repeat(64),
+        });
+        let (result, replies) = harness.dispatch(request(OP::FileOffer, data, None));
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(result, Ok(()));
+        assert!(matches!(
+            &replies[0].data,
+            PayloadData::FileAccept(data) if data.transfer_id == "offer" && data.offset == 0
+        ));
+    }
+}
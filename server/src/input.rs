@@ -0,0 +1,434 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::router;
+use xyncer_share::payloads::{ErrorCode, InputEvent, WindowData};
+
+// Injects input into windows on the server host
+pub trait InputSink: Send + Sync {
+    fn inject(
+        &self,
+        window: &WindowData,
+        event: &InputEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+// Injects input using SendInput
+#[cfg(windows)]
+pub struct SendInputSink;
+
+#[cfg(windows)]
+impl SendInputSink {
+    fn keyboard_input(
+        scancode: u32,
+        flags: windows_sys::Win32::UI::Input::KeyboardAndMouse::KEYBD_EVENT_FLAGS,
+    ) -> windows_sys::Win32::UI::Input::KeyboardAndMouse::INPUT {
+        use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
+
+        // Extended keys are sent as their second byte, with a flag instead of the prefix
+        let extended = scancode & 0xff00 == 0xe000;
+
+        INPUT {
+            r#type: INPUT_KEYBOARD,
+            Anonymous: INPUT_0 {
+                ki: KEYBDINPUT {
+                    wVk: 0,
+                    wScan: (scancode & 0xff) as u16,
+                    dwFlags: flags | if extended { KEYEVENTF_EXTENDEDKEY } else { 0 },
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        }
+    }
+
+    fn mouse_input(
+        flags: windows_sys::Win32::UI::Input::KeyboardAndMouse::MOUSE_EVENT_FLAGS,
+        data: i32,
+    ) -> windows_sys::Win32::UI::Input::KeyboardAndMouse::INPUT {
+        use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
+
+        INPUT {
+            r#type: INPUT_MOUSE,
+            Anonymous: INPUT_0 {
+                mi: MOUSEINPUT {
+                    dx: 0,
+                    dy: 0,
+                    mouseData: data as u32,
+                    dwFlags: flags,
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        }
+    }
+
+    // Presses or releases the modifier keys that are held
+    fn modifier_inputs(
+        modifiers: &xyncer_share::payloads::Modifiers,
+        released: bool,
+    ) -> Vec<windows_sys::Win32::UI::Input::KeyboardAndMouse::INPUT> {
+        use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
+
+        let flags = KEYEVENTF_SCANCODE | if released { KEYEVENTF_KEYUP } else { 0 };
+
+        modifier_scancodes(modifiers)
+            .into_iter()
+            .map(|scancode| Self::keyboard_input(scancode, flags))
+            .collect()
+    }
+}
+
+// The scancodes of the modifier keys that are held, left ones standing in for either side
+#[cfg(any(windows, test))]
+fn modifier_scancodes(modifiers: &xyncer_share::payloads::Modifiers) -> Vec<u32> {
+    [
+        (modifiers.shift, 0x2a),
+        (modifiers.ctrl, 0x1d),
+        (modifiers.alt, 0x38),
+        (modifiers.meta, 0xe05b),
+    ]
+    .into_iter()
+    .filter(|(held, _)| *held)
+    .map(|(_, scancode)| scancode)
+    .collect()
+}
+
+// Where a position relative to a window is on the screen
+#[cfg(any(windows, test))]
+fn screen_position(window: &WindowData, x: i32, y: i32) -> (i32, i32) {
+    (window.geometry.x + x, window.geometry.y + y)
+}
+
+#[cfg(windows)]
+impl InputSink for SendInputSink {
+    fn inject(
+        &self,
+        window: &WindowData,
+        event: &InputEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
+        use windows_sys::Win32::UI::WindowsAndMessaging::{SetCursorPos, SetForegroundWindow};
+
+        // SendInput targets the foreground window, so bring the window forward first
+        unsafe { SetForegroundWindow(window.id as isize) };
+
+        // Mouse positions are relative to the window, the cursor is positioned on the screen
+        let move_cursor = |x: i32, y: i32| unsafe {
+            let (x, y) = screen_position(window, x, y);
+
+            SetCursorPos(x, y);
+        };
+
+        let inputs = match event {
+            InputEvent::KeyDown {
+                scancode,
+                modifiers,
+            } => {
+                let mut inputs = Self::modifier_inputs(modifiers, false);
+                inputs.push(Self::keyboard_input(*scancode, KEYEVENTF_SCANCODE));
+                inputs
+            }
+            InputEvent::KeyUp {
+                scancode,
+                modifiers,
+            } => {
+                let mut inputs = vec![Self::keyboard_input(
+                    *scancode,
+                    KEYEVENTF_SCANCODE | KEYEVENTF_KEYUP,
+                )];
+                inputs.extend(Self::modifier_inputs(modifiers, true));
+                inputs
+            }
+            InputEvent::Text { text } => text
+                .encode_utf16()
+                .flat_map(|unit| {
+                    let input = |flags| INPUT {
+                        r#type: INPUT_KEYBOARD,
+                        Anonymous: INPUT_0 {
+                            ki: KEYBDINPUT {
+                                wVk: 0,
+                                wScan: unit,
+                                dwFlags: flags,
+                                time: 0,
+                                dwExtraInfo: 0,
+                            },
+                        },
+                    };
+
+                    [input(KEYEVENTF_UNICODE), input(KEYEVENTF_UNICODE | KEYEVENTF_KEYUP)]
+                })
+                .collect(),
+            InputEvent::MouseMove { x, y } => {
+                move_cursor(*x, *y);
+
+                Vec::new()
+            }
+            InputEvent::MouseButton {
+                button,
+                pressed,
+                x,
+                y,
+            } => {
+                move_cursor(*x, *y);
+
+                // X button events carry XBUTTON1 (back) or XBUTTON2 (forward) as their data
+                let (flags, data) = match (button, pressed) {
+                    (xyncer_share::payloads::MouseButton::Left, true) => (MOUSEEVENTF_LEFTDOWN, 0),
+                    (xyncer_share::payloads::MouseButton::Left, false) => (MOUSEEVENTF_LEFTUP, 0),
+                    (xyncer_share::payloads::MouseButton::Right, true) => (MOUSEEVENTF_RIGHTDOWN, 0),
+                    (xyncer_share::payloads::MouseButton::Right, false) => (MOUSEEVENTF_RIGHTUP, 0),
+                    (xyncer_share::payloads::MouseButton::Middle, true) => (MOUSEEVENTF_MIDDLEDOWN, 0),
+                    (xyncer_share::payloads::MouseButton::Middle, false) => (MOUSEEVENTF_MIDDLEUP, 0),
+                    (xyncer_share::payloads::MouseButton::Back, true) => (MOUSEEVENTF_XDOWN, 1),
+                    (xyncer_share::payloads::MouseButton::Back, false) => (MOUSEEVENTF_XUP, 1),
+                    (xyncer_share::payloads::MouseButton::Forward, true) => (MOUSEEVENTF_XDOWN, 2),
+                    (xyncer_share::payloads::MouseButton::Forward, false) => (MOUSEEVENTF_XUP, 2),
+                };
+
+                vec![Self::mouse_input(flags, data)]
+            }
+            InputEvent::MouseWheel { delta_x, delta_y } => {
+                // One notch is WHEEL_DELTA (120)
+                let mut inputs = Vec::new();
+
+                if *delta_y != 0.0 {
+                    inputs.push(Self::mouse_input(MOUSEEVENTF_WHEEL, (delta_y * 120.0) as i32));
+                }
+
+                if *delta_x != 0.0 {
+                    inputs.push(Self::mouse_input(MOUSEEVENTF_HWHEEL, (delta_x * 120.0) as i32));
+                }
+
+                inputs
+            }
+        };
+
+        if inputs.is_empty() {
+            return Ok(());
+        }
+
+        let sent = unsafe {
+            SendInput(
+                inputs.len() as u32,
+                inputs.as_ptr(),
+                std::mem::size_of::<INPUT>() as i32,
+            )
+        };
+
+        if sent as usize != inputs.len() {
+            return Err("SendInput was blocked by another thread, or by UIPI".into());
+        }
+
+        Ok(())
+    }
+}
+
+// How many events the recording sink keeps
+const RECORDED_EVENTS: usize = 1024;
+
+// Records input instead of injecting it, for hosts without input injection support
+#[derive(Default)]
+pub struct RecordingInputSink {
+    events: Mutex<VecDeque<(u32, InputEvent)>>,
+}
+
+impl RecordingInputSink {
+    // The most recent events, oldest first, with the id of their window
+    pub fn events(&self) -> Vec<(u32, InputEvent)> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl InputSink for RecordingInputSink {
+    fn inject(
+        &self,
+        window: &WindowData,
+        event: &InputEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        log::debug!("Recorded input for window {}: {:?}", window.id, event);
+
+        let mut events = self.events.lock().unwrap();
+
+        if events.len() == RECORDED_EVENTS {
+            events.pop_front();
+        }
+
+        events.push_back((window.id, event.clone()));
+
+        Ok(())
+    }
+}
+
+// Picks the input sink for this host
+pub fn sink(synthetic: bool, recorder: Arc<RecordingInputSink>) -> Arc<dyn InputSink> {
+    #[cfg(windows)]
+    if !synthetic {
+        return Arc::new(SendInputSink);
+    }
+
+    #[cfg(not(windows))]
+    if !synthetic {
+        log::warn!("Input injection is only supported on Windows, recording input instead");
+    }
+
+    recorder
+}
+// Injecting input into windows
+pub fn route(router: &mut router::Router) {
+    router.on(xyncer_share::OP::Input, |context, payload| {
+        let xyncer_share::payloads::PayloadData::Input(data) = payload.data else {
+            return Err(router::malformed(context, payload.op_code));
+        };
+
+        // Clients can only control windows they can see
+        let window = context
+            .session
+            .windows
+            .get(&data.window_id)
+            .filter(|_| context.session.subscriptions.contains_key(&data.window_id));
+
+        let Some(window) = window else {
+            log::warn!(
+                "Client {} sent input for unsubscribed window {}, ignoring",
+                context.session.address,
+                data.window_id
+            );
+
+            context.replies.reject(ErrorCode::UnknownWindow);
+
+            return Ok(());
+        };
+
+        if let Err(e) = context.options.input_sink.inject(window, &data.event) {
+            log::warn!(
+                "Could not inject input into window {}: {}",
+                data.window_id,
+                e
+            );
+
+            context.replies.reject(ErrorCode::Unavailable);
+        }
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::testing::{rejection, request, Harness};
+    use crate::windows::{self, WindowProvider};
+    use xyncer_share::payloads::{InputData, Modifiers, MouseButton, PayloadData};
+
+    fn input(window_id: u32, event: InputEvent, nonce: Option<u64>) -> xyncer_share::Payload {
+        request(
+            xyncer_share::OP::Input,
+            PayloadData::Input(InputData { window_id, event }),
+            nonce,
+        )
+    }
+
+    #[test]
+    fn input_is_injected_into_subscribed_windows() {
+        let mut harness = Harness::authenticated();
+
+        harness.subscribe(2);
+
+        let events = [
+            InputEvent::MouseMove { x: 10, y: 20 },
+            InputEvent::MouseButton {
+                button: MouseButton::Right,
+                pressed: true,
+                x: 10,
+                y: 20,
+            },
+            InputEvent::KeyDown {
+                scancode: 0x1e,
+                modifiers: Modifiers {
+                    shift: true,
+                    ctrl: true,
+                    ..Default::default()
+                },
+            },
+            InputEvent::Text {
+                text: "hé".to_string(),
+            },
+        ];
+
+        for event in events.clone() {
+            let (result, replies) = harness.dispatch(input(2, event, Some(1)));
+
+            assert_eq!(result, Ok(()));
+            assert!(replies.is_empty());
+        }
+
+        // Positions stay relative to the window, and modifiers stay with their key
+        let recorded: Vec<_> = events.into_iter().map(|event| (2, event)).collect();
+
+        assert_eq!(harness.input.events(), recorded);
+    }
+
+    #[test]
+    fn input_for_unsubscribed_windows_is_rejected() {
+        let mut harness = Harness::authenticated();
+
+        // Known to the client, but not subscribed to
+        windows::list(
+            harness.options.window_provider.as_ref(),
+            &mut harness.session.windows,
+        );
+
+        let (result, replies) =
+            harness.dispatch(input(1, InputEvent::MouseMove { x: 1, y: 1 }, Some(7)));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].nonce, Some(7));
+        assert_eq!(rejection(&replies[0]), Some(ErrorCode::UnknownWindow));
+        assert!(harness.input.events().is_empty());
+    }
+
+    #[test]
+    fn positions_are_translated_to_the_screen() {
+        let harness = Harness::default();
+        let window = &harness.windows.windows()[1];
+
+        assert_eq!((window.geometry.x, window.geometry.y), (800, 200));
+        assert_eq!(screen_position(window, 10, 20), (810, 220));
+        assert_eq!(screen_position(window, -5, 0), (795, 200));
+    }
+
+    #[test]
+    fn held_modifiers_are_pressed() {
+        assert!(modifier_scancodes(&Modifiers::default()).is_empty());
+
+        let modifiers = Modifiers {
+            shift: true,
+            ctrl: false,
+            alt: true,
+            meta: true,
+        };
+
+        assert_eq!(modifier_scancodes(&modifiers), [0x2a, 0x38, 0xe05b]);
+    }
+
+    #[test]
+    fn recording_keeps_the_latest_events() {
+        let sink = RecordingInputSink::default();
+        let window = Harness::default().windows.windows()[0].clone();
+
+        for x in 0..RECORDED_EVENTS as i32 + 10 {
+            sink.inject(&window, &InputEvent::MouseMove { x, y: 0 })
+                .unwrap();
+        }
+
+        let events = sink.events();
+
+        assert_eq!(events.len(), RECORDED_EVENTS);
+        assert_eq!(
+            events[0],
+            (window.id, InputEvent::MouseMove { x: 10, y: 0 })
+        );
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::windows::{SyntheticWindowProvider, WindowProvider};
+use crate::{catalog, router};
+use xyncer_share::payloads::{LaunchAppData, LaunchTarget};
+
+// How long to wait for a launched process to open a window
+const WINDOW_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+// How often to look for the windows of a launched process
+const WINDOW_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(250);
+
+// A process to start, with catalog apps resolved to what they run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaunchCommand {
+    pub executable: String,
+    pub arguments: Vec<String>,
+    pub working_directory: Option<String>,
+    pub environment: BTreeMap<String, String>,
+}
+
+// Starts processes on the server host
+pub trait ProcessLauncher: Send + Sync {
+    // Returns the id of the new process
+    fn launch(
+        &self,
+        command: &LaunchCommand,
+    ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+// Starts real processes
+pub struct SystemLauncher;
+
+impl ProcessLauncher for SystemLauncher {
+    fn launch(
+        &self,
+        command: &LaunchCommand,
+    ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+        let mut process = std::process::Command::new(&command.executable);
+
+        // The app talks to the user through its windows, not through us
+        process
+            .args(&command.arguments)
+            .envs(&command.environment)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+
+        if let Some(working_directory) = &command.working_directory {
+            process.current_dir(working_directory);
+        }
+
+        let mut child = process.spawn()?;
+        let pid = child.id();
+
+        // Wait for the process in the background, so it doesn't linger once it exits
+        std::thread::spawn(move || match child.wait() {
+            Ok(status) => log::info!("Launched process {} exited with {}", pid, status),
+            Err(e) => log::warn!("Could not wait for launched process {}: {}", pid, e),
+        });
+
+        Ok(pid)
+    }
+}
+
+// First process id handed out by the synthetic launcher, well clear of the synthetic windows' 0
+const SYNTHETIC_FIRST_PID: u32 = 1000;
+
+// Pretends to start processes, opening a synthetic window for each, for hosts without real windows
+pub struct SyntheticLauncher {
+    window_provider: Arc<SyntheticWindowProvider>,
+    next_pid: AtomicU32,
+}
+
+impl SyntheticLauncher {
+    pub fn new(window_provider: Arc<SyntheticWindowProvider>) -> Self {
+        SyntheticLauncher {
+            window_provider,
+            next_pid: AtomicU32::new(SYNTHETIC_FIRST_PID),
+        }
+    }
+}
+
+impl ProcessLauncher for SyntheticLauncher {
+    fn launch(
+        &self,
+        command: &LaunchCommand,
+    ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+        let pid = self.next_pid.fetch_add(1, Ordering::SeqCst);
+
+        // Named after the executable, like a real window would be. Paths may come from any OS.
+        let process_name = command
+            .executable
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        log::info!("Pretending to launch {:?} as process {}", command, pid);
+
+        self.window_provider
+            .open(format!("Synthetic {}", process_name), process_name, pid);
+
+        Ok(pid)
+    }
+}
+
+// Picks the process launcher for this host
+pub fn launcher(
+    synthetic: bool,
+    synthetic_windows: Arc<SyntheticWindowProvider>,
+) -> Arc<dyn ProcessLauncher> {
+    if synthetic {
+        return Arc::new(SyntheticLauncher::new(synthetic_windows));
+    }
+
+    Arc::new(SystemLauncher)
+}
+
+// Works out what to run for a launch request, following the server's launch policy
+pub fn resolve(
+    allow_executables: bool,
+    catalog: &catalog::Catalog,
+    data: &LaunchAppData,
+) -> Result<LaunchCommand, String> {
+    match &data.target {
+        LaunchTarget::Executable(executable) => {
+            if !allow_executables {
+                return Err("This server only launches apps in its catalog".to_string());
+            }
+
+            Ok(LaunchCommand {
+                executable: executable.clone(),
+                arguments: data.arguments.clone(),
+                working_directory: data.working_directory.clone(),
+                environment: data.environment.clone(),
+            })
+        }
+        LaunchTarget::App(id) => {
+            let app = &catalog
+                .get(id)
+                .ok_or_else(|| format!("There is no app with the id {}", id))?
+                .command;
+
+            let mut environment = app.environment.clone();
+            environment.extend(data.environment.clone());
+
+            Ok(LaunchCommand {
+                executable: app.executable.clone(),
+                arguments: app
+                    .arguments
+                    .iter()
+                    .chain(&data.arguments)
+                    .cloned()
+                    .collect(),
+                working_directory: data
+                    .working_directory
+                    .clone()
+                    .or_else(|| app.working_directory.clone()),
+                environment,
+            })
+        }
+    }
+}
+
+// Launches an app and waits for it to open its first windows, returning the payload that reports how it went
+pub async fn launch(
+    launcher: Arc<dyn ProcessLauncher>,
+    window_provider: Arc<dyn WindowProvider>,
+    allow_executables: bool,
+    catalog: Arc<catalog::Catalog>,
+    data: LaunchAppData,
+) -> xyncer_share::Payload {
+    let failed = |target: LaunchTarget, error: String| {
+        log::warn!("Could not launch {}: {}", target, error);
+
+        xyncer_share::Payload {
+            op_code: xyncer_share::OP::Dispatch,
+            event_name: xyncer_share::Event::LaunchFailed,
+            data: xyncer_share::payloads::PayloadData::LaunchFailed(
+                xyncer_share::payloads::LaunchFailedData { target, error },
+            ),
+            sequence: None,
+            nonce: None,
+        }
+    };
+
+    let command = match resolve(allow_executables, &catalog, &data) {
+        Ok(command) => command,
+        Err(error) => return failed(data.target, error),
+    };
+
+    // Starting a process can block for a while
+    let pid = match tokio::task::spawn_blocking(move || launcher.launch(&command)).await {
+        Ok(Ok(pid)) => pid,
+        Ok(Err(e)) => return failed(data.target, e.to_string()),
+        Err(e) => return failed(data.target, e.to_string()),
+    };
+
+    log::info!("Launched {} as process {}", data.target, pid);
+
+    // Apps take a moment to open their windows, and some (like launchers handing off to another process) never do
+    let deadline = tokio::time::Instant::now() + WINDOW_TIMEOUT;
+    let mut windows = Vec::new();
+
+    while windows.is_empty() && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(WINDOW_POLL_INTERVAL).await;
+
+        windows = window_provider
+            .windows()
+            .into_iter()
+            .filter(|window| window.pid == pid)
+            .collect();
+    }
+
+    xyncer_share::Payload {
+        op_code: xyncer_share::OP::Dispatch,
+        event_name: xyncer_share::Event::AppLaunched,
+        data: xyncer_share::payloads::PayloadData::AppLaunched(
+            xyncer_share::payloads::AppLaunchedData {
+                target: data.target,
+                pid,
+                windows,
+            },
+        ),
+        sequence: None,
+        nonce: None,
+    }
+}
+
+// Launching apps
+pub fn route(router: &mut router::Router) {
+    router.on(xyncer_share::OP::LaunchApp, |context, payload| {
+        let xyncer_share::payloads::PayloadData::LaunchApp(data) = payload.data else {
+            return Err(router::malformed(context, payload.op_code));
+        };
+
+        log::info!(
+            "Client {} asked to launch {}",
+            context.session.address,
+            data.target
+        );
+
+        let options = context.options;
+        let launch_sender = context.connection.launch_sender.clone();
+        let nonce = context.replies.nonce();
+        let launch = launch(
+            options.process_launcher.clone(),
+            options.window_provider.clone(),
+            options.allow_executables,
+            options.catalog.clone(),
+            data,
+        );
+
+        // Waiting for the app's windows takes a while, so keep serving the session meanwhile
+        tokio::spawn(async move {
+            // Nobody is left to tell if the connection closed in the meantime
+            let _ = launch_sender.send((nonce, launch.await));
+        });
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config;
+    use crate::router::testing::Harness;
+    use xyncer_share::payloads::PayloadData;
+
+    // Records what it was asked to start, opening a window for it if given somewhere to
+    #[derive(Default)]
+    struct FakeLauncher {
+        windows: Option<Arc<SyntheticWindowProvider>>,
+        error: Option<&'static str>,
+        commands: Mutex<Vec<LaunchCommand>>,
+    }
+
+    const FAKE_PID: u32 = 4242;
+
+    impl ProcessLauncher for FakeLauncher {
+        fn launch(
+            &self,
+            command: &LaunchCommand,
+        ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+            self.commands.lock().unwrap().push(command.clone());
+
+            if let Some(error) = self.error {
+                return Err(error.into());
+            }
+
+            if let Some(windows) = &self.windows {
+                windows.open("Editor".to_string(), "editor".to_string(), FAKE_PID);
+            }
+
+            Ok(FAKE_PID)
+        }
+    }
+
+    fn catalog() -> catalog::Catalog {
+        let launch: config::Launch = toml::from_str(
+            r#"
+            start_menu = false
+
+            [apps.editor]
+            executable = "/usr/bin/editor"
+            arguments = ["--new-window"]
+            working_directory = "/srv"
+            environment = { LANG = "C", THEME = "dark" }
+            "#,
+        )
+        .unwrap();
+
+        catalog::discover(&launch)
+    }
+
+    fn request(target: LaunchTarget) -> LaunchAppData {
+        LaunchAppData {
+            target,
+            arguments: vec!["notes.txt".to_string()],
+            working_directory: None,
+            environment: BTreeMap::from([("THEME".to_string(), "light".to_string())]),
+        }
+    }
+
+    #[test]
+    fn executables_are_only_launched_when_allowed() {
+        let data = request(LaunchTarget::Executable("/bin/sh".to_string()));
+
+        assert!(resolve(false, &catalog(), &data).is_err());
+        assert_eq!(
+            resolve(true, &catalog(), &data),
+            Ok(LaunchCommand {
+                executable: "/bin/sh".to_string(),
+                arguments: vec!["notes.txt".to_string()],
+                working_directory: None,
+                environment: data.environment.clone(),
+            })
+        );
+    }
+
+    #[test]
+    fn catalog_apps_are_launched_either_way() {
+        let data = request(LaunchTarget::App("editor".to_string()));
+
+        assert!(resolve(false, &catalog(), &data).is_ok());
+        assert!(resolve(true, &catalog(), &data).is_ok());
+
+        let data = request(LaunchTarget::App("missing".to_string()));
+
+        assert!(resolve(true, &catalog(), &data).is_err());
+    }
+
+    #[test]
+    fn requests_add_to_catalog_apps() {
+        let mut data = request(LaunchTarget::App("editor".to_string()));
+
+        let command = resolve(false, &catalog(), &data).unwrap();
+
+        // The app's own arguments come first, and the request's environment wins
+        assert_eq!(command.executable, "/usr/bin/editor");
+        assert_eq!(command.arguments, ["--new-window", "notes.txt"]);
+        assert_eq!(command.working_directory.as_deref(), Some("/srv"));
+        assert_eq!(
+            command.environment,
+            BTreeMap::from([
+                ("LANG".to_string(), "C".to_string()),
+                ("THEME".to_string(), "light".to_string()),
+            ])
+        );
+
+        data.working_directory = Some("/home".to_string());
+
+        let command = resolve(false, &catalog(), &data).unwrap();
+
+        assert_eq!(command.working_directory.as_deref(), Some("/home"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn launches_report_the_windows_the_app_opened() {
+        let windows = Arc::new(SyntheticWindowProvider::new(Vec::new()));
+        let launcher = Arc::new(FakeLauncher {
+            windows: Some(windows.clone()),
+            ..Default::default()
+        });
+
+        let payload = launch(
+            launcher.clone(),
+            windows,
+            false,
+            Arc::new(catalog()),
+            request(LaunchTarget::App("editor".to_string())),
+        )
+        .await;
+
+        let PayloadData::AppLaunched(data) = payload.data else {
+            panic!("Expected AppLaunched, got {:?}", payload.data);
+        };
+
+        assert_eq!(data.pid, FAKE_PID);
+        assert_eq!(data.windows.len(), 1);
+        assert_eq!(data.windows[0].title, "Editor");
+        assert_eq!(launcher.commands.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn launches_stop_waiting_for_windows() {
+        let windows = Arc::new(SyntheticWindowProvider::new(Vec::new()));
+        let started = tokio::time::Instant::now();
+
+        let payload = launch(
+            Arc::new(FakeLauncher::default()),
+            windows,
+            false,
+            Arc::new(catalog()),
+            request(LaunchTarget::App("editor".to_string())),
+        )
+        .await;
+
+        let PayloadData::AppLaunched(data) = payload.data else {
+            panic!("Expected AppLaunched, got {:?}", payload.data);
+        };
+
+        assert!(data.windows.is_empty());
+        assert!(started.elapsed() >= WINDOW_TIMEOUT);
+        assert!(started.elapsed() < WINDOW_TIMEOUT + WINDOW_POLL_INTERVAL * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_launches_are_reported() {
+        let windows = Arc::new(SyntheticWindowProvider::new(Vec::new()));
+        let launcher = Arc::new(FakeLauncher {
+            error: Some("No such file"),
+            ..Default::default()
+        });
+
+        // Refused by the policy, without asking the launcher
+        let payload = launch(
+            launcher.clone(),
+            windows.clone(),
+            false,
+            Arc::new(catalog()),
+            request(LaunchTarget::Executable("/bin/sh".to_string())),
+        )
+        .await;
+
+        assert!(matches!(payload.data, PayloadData::LaunchFailed(_)));
+        assert!(launcher.commands.lock().unwrap().is_empty());
+
+        let payload = launch(
+            launcher.clone(),
+            windows,
+            true,
+            Arc::new(catalog()),
+            request(LaunchTarget::Executable("/bin/sh".to_string())),
+        )
+        .await;
+
+        let PayloadData::LaunchFailed(data) = payload.data else {
+            panic!("Expected LaunchFailed, got {:?}", payload.data);
+        };
+
+        assert_eq!(data.error, "No such file");
+        assert_eq!(launcher.commands.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn launch_requests_are_answered_once_the_app_opens_its_windows() {
+        let mut harness = Harness::authenticated();
+
+        harness.options.catalog = Arc::new(catalog());
+
+        let data = PayloadData::LaunchApp(request(LaunchTarget::App("editor".to_string())));
+        let (result, replies) = harness.dispatch(router::testing::request(
+            xyncer_share::OP::LaunchApp,
+            data,
+            Some(5),
+        ));
+
+        // Launching takes a while, so the answer comes later
+        assert_eq!(result, Ok(()));
+        assert!(replies.is_empty());
+
+        let (nonce, payload) = harness.launches.recv().await.unwrap();
+
+        assert_eq!(nonce, Some(5));
+        assert!(matches!(
+            &payload.data,
+            PayloadData::AppLaunched(data) if data.windows[0].process_name == "editor"
+        ));
+    }
+}
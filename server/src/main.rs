@@ -1,13 +1,104 @@
 use simple_logger::SimpleLogger;
 
+mod audio;
+mod capture;
+mod catalog;
+mod clipboard;
+mod config;
+mod files;
+mod input;
+mod launch;
+mod router;
 mod server;
 mod session;
+mod tls;
+mod windows;
 
 #[tokio::main]
 async fn main() {
-    SimpleLogger::new().init().unwrap();
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
 
-    if let Err(e) = server::start_server("127.0.0.1", 8080).await {
+            std::process::exit(1);
+        }
+    };
+
+    SimpleLogger::new()
+        .with_level(config.log_level())
+        .init()
+        .unwrap();
+
+    // Synthetic windows are shared with the synthetic launcher, which opens one per launched app
+    let synthetic_windows = std::sync::Arc::new(windows::SyntheticWindowProvider::default());
+    let window_provider = windows::provider(config.synthetic, synthetic_windows.clone());
+
+    // Input for synthetic windows is recorded, and drawn by their test pattern
+    let input_recorder = std::sync::Arc::new(input::RecordingInputSink::default());
+
+    let options = server::Options {
+        heartbeat_interval: config.heartbeat.interval,
+        heartbeat_jitter: config.heartbeat.jitter,
+        passphrase: config.passphrase.clone(),
+        max_password_attempts: config.max_password_attempts,
+        max_sessions: config.max_sessions,
+        active_sessions: std::sync::atomic::AtomicUsize::new(0),
+        frame_source: capture::source(
+            config.synthetic,
+            window_provider.clone(),
+            input_recorder.clone(),
+        ),
+        input_sink: input::sink(config.synthetic, input_recorder),
+        process_launcher: launch::launcher(config.synthetic, synthetic_windows),
+        allow_executables: config.launch.allow_executables,
+        catalog: std::sync::Arc::new(catalog::discover(&config.launch)),
+        clipboard: config
+            .clipboard
+            .enabled
+            .then(|| clipboard::clipboard(config.synthetic)),
+        clipboard_max_size: config.clipboard.max_size,
+        audio_source: config
+            .audio
+            .enabled
+            .then(|| audio::source(config.synthetic)),
+        files: config
+            .files
+            .enabled
+            .then(|| config.files_directory())
+            .flatten(),
+        window_provider,
+        detached_sessions: session::DetachedSessions::new(config.resume.max_detached),
+        resume_grace_period: std::time::Duration::from_secs(config.resume.grace_period.into()),
+        router: router::routes(),
+    };
+
+    // Serve over TLS unless told not to, generating a self-signed certificate if we weren't given one
+    let tls_config = if config.tls.enabled {
+        let (cert_path, key_path) = config.tls_paths();
+
+        match tls::load(&cert_path, &key_path) {
+            Ok((tls_config, fingerprint)) => {
+                // Clients are shown this when they first connect, so they can check it against this one
+                log::info!("Certificate fingerprint: {}", fingerprint);
+
+                Some(tls_config)
+            }
+            Err(e) => {
+                log::error!("Error loading TLS certificate: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+    } else {
+        log::warn!("TLS is disabled, passphrases and windows will be sent in cleartext");
+
+        None
+    };
+
+    if let Err(e) = server::start_server(&config.bind, config.port, options, tls_config).await {
         log::error!("Error starting server: {}", e);
+
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+
+use crate::{audio, capture, catalog, clipboard, files, input, launch, server, session, windows};
+use xyncer_share::payloads::ErrorCode;
+use xyncer_share::transfer::Outgoing;
+
+// Requested files, once they are checksummed and ready to offer (or not), with the nonce of the request
+pub type OpenedFile = (
+    Option<u64>,
+    String,
+    Result<Outgoing, Box<dyn std::error::Error + Send + Sync>>,
+);
+
+// What a connection keeps besides its session, which is gone once the connection closes
+pub struct Connection {
+    pub last_heartbeat: tokio::time::Instant,
+    pub requested_heartbeat_from_client: bool,
+
+    // Looks for clipboard changes on this host, ignoring the ones the client made
+    pub clipboard_watcher: xyncer_share::clipboard::ClipboardWatcher,
+
+    // Launches and requested files report back through these, as they take a while
+    pub launch_sender: tokio::sync::mpsc::UnboundedSender<(Option<u64>, xyncer_share::Payload)>,
+    pub file_sender: tokio::sync::mpsc::UnboundedSender<OpenedFile>,
+}
+
+// Payloads a handler sends back, once it is done
+pub struct Replies {
+    nonce: Option<u64>, // Of the request being handled
+    payloads: Vec<xyncer_share::Payload>,
+}
+
+impl Replies {
+    // Answers the request
+    pub fn answer(&mut self, payload: xyncer_share::Payload) {
+        self.payloads.push(payload.answering(self.nonce));
+    }
+
+    // Sends something other than the answer, like the events the request caused
+    pub fn send(&mut self, payload: xyncer_share::Payload) {
+        self.payloads.push(payload);
+    }
+
+    // Answers the request with an Error payload, if it asked for an answer
+    pub fn reject(&mut self, code: ErrorCode) {
+        if self.nonce.is_none() {
+            return;
+        }
+
+        self.answer(xyncer_share::Payload {
+            op_code: xyncer_share::OP::Error,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::Error(code.populate()),
+            sequence: None,
+            nonce: None,
+        });
+    }
+
+    // The nonce of the request, for answering it later
+    pub fn nonce(&self) -> Option<u64> {
+        self.nonce
+    }
+}
+
+// What handlers get to work with
+pub struct Context<'a> {
+    pub session: &'a mut session::Session,
+    pub options: &'a server::Options,
+    pub connection: &'a mut Connection,
+    pub replies: Replies,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        session: &'a mut session::Session,
+        options: &'a server::Options,
+        connection: &'a mut Connection,
+        nonce: Option<u64>,
+    ) -> Self {
+        Context {
+            session,
+            options,
+            connection,
+            replies: Replies {
+                nonce,
+                payloads: Vec::new(),
+            },
+        }
+    }
+
+    // The payloads to send back, in order
+    pub fn into_replies(self) -> Vec<xyncer_share::Payload> {
+        self.replies.payloads
+    }
+}
+
+// Handlers return an error code to invalidate the session, closing the connection
+type Handler =
+    Box<dyn Fn(&mut Context<'_>, xyncer_share::Payload) -> Result<(), ErrorCode> + Send + Sync>;
+
+// Handlers for what clients send, by OP code
+#[derive(Default)]
+pub struct Router {
+    handlers: HashMap<xyncer_share::OP, Handler>,
+}
+
+impl Router {
+    // Handles payloads with the OP code, in place of any handler added before
+    pub fn on(
+        &mut self,
+        op_code: xyncer_share::OP,
+        handler: impl Fn(&mut Context<'_>, xyncer_share::Payload) -> Result<(), ErrorCode>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        self.handlers.insert(op_code, Box::new(handler));
+
+        self
+    }
+
+    // Hands a payload to the handler of its OP code, once the client is allowed to send it
+    pub fn dispatch(
+        &self,
+        context: &mut Context<'_>,
+        payload: xyncer_share::Payload,
+    ) -> Result<(), ErrorCode> {
+        // Everything except heartbeats, identifying and resuming requires authentication
+        if !context.session.authenticated
+            && !matches!(
+                payload.op_code,
+                xyncer_share::OP::Heartbeat | xyncer_share::OP::Identify | xyncer_share::OP::Resume
+            )
+        {
+            log::warn!(
+                "Client {} sent {:?} before authenticating, closing connection",
+                context.session.address,
+                payload.op_code
+            );
+
+            return Err(ErrorCode::AuthenticationFailed);
+        }
+
+        // Features the client didn't negotiate are off limits
+        if let Some(capability) = payload.op_code.capability() {
+            if !context.session.capabilities.contains(&capability) {
+                log::warn!(
+                    "Client {} sent {:?} without negotiating {:?}, closing connection",
+                    context.session.address,
+                    payload.op_code,
+                    capability
+                );
+
+                return Err(ErrorCode::UnknownOP);
+            }
+        }
+
+        // Only the server sends the rest
+        let Some(handler) = self.handlers.get(&payload.op_code) else {
+            log::warn!(
+                "Client {} sent unexpected OP code {:?}, closing connection",
+                context.session.address,
+                payload.op_code
+            );
+
+            return Err(ErrorCode::UnknownOP);
+        };
+
+        handler(context, payload)
+    }
+}
+
+// Every handler the server has, added by the module of each feature
+pub fn routes() -> Router {
+    let mut router = Router::default();
+
+    session::route(&mut router);
+    windows::route(&mut router);
+    capture::route(&mut router);
+    input::route(&mut router);
+    launch::route(&mut router);
+    catalog::route(&mut router);
+    clipboard::route(&mut router);
+    files::route(&mut router);
+    audio::route(&mut router);
+
+    router
+}
+
+// Logs a payload without the data its OP code needs, which can't be trusted to have anything else right
+pub fn malformed(context: &Context<'_>, op_code: xyncer_share::OP) -> ErrorCode {
+    log::warn!(
+        "Client {} sent {:?} without its data, closing connection",
+        context.session.address,
+        op_code
+    );
+
+    ErrorCode::DecodeError
+}
+
+// A session over the synthetic providers, for testing handlers without a connection
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config;
+
+    pub struct Harness {
+        pub options: server::Options,
+        pub session: session::Session,
+        pub connection: Connection,
+        pub windows: Arc<windows::SyntheticWindowProvider>,
+        pub input: Arc<input::RecordingInputSink>,
+
+        // What launches and requested files report back, once they are done
+        pub launches: tokio::sync::mpsc::UnboundedReceiver<(Option<u64>, xyncer_share::Payload)>,
+        pub files: tokio::sync::mpsc::UnboundedReceiver<OpenedFile>,
+    }
+
+    // An unauthenticated session, on a server with every feature turned on
+    impl Default for Harness {
+        fn default() -> Self {
+            let windows = Arc::new(windows::SyntheticWindowProvider::default());
+            let input = Arc::new(input::RecordingInputSink::default());
+            let (launch_sender, launches) = tokio::sync::mpsc::unbounded_channel();
+            let (file_sender, files) = tokio::sync::mpsc::unbounded_channel();
+
+            Harness {
+                options: server::Options {
+                    heartbeat_interval: 30,
+                    heartbeat_jitter: 5,
+                    passphrase: config::Passphrase::Fixed {
+                        passphrase: "password1".to_string(),
+                    },
+                    max_password_attempts: 3,
+                    max_sessions: None,
+                    active_sessions: std::sync::atomic::AtomicUsize::new(0),
+                    window_provider: windows.clone(),
+                    frame_source: capture::source(true, windows.clone(), input.clone()),
+                    input_sink: input.clone(),
+                    process_launcher: launch::launcher(true, windows.clone()),
+                    allow_executables: false,
+                    catalog: Arc::new(catalog::Catalog::default()),
+                    clipboard: Some(clipboard::clipboard(true)),
+                    clipboard_max_size: 1024,
+                    audio_source: Some(audio::source(true)),
+                    files: Some(std::env::temp_dir()),
+                    detached_sessions: session::DetachedSessions::new(4),
+                    resume_grace_period: std::time::Duration::from_secs(60),
+                    router: routes(),
+                },
+                session: session::Session::new(
+                    "127.0.0.1:1234".to_string(),
+                    "password1".to_string(),
+                ),
+                connection: Connection {
+                    last_heartbeat: tokio::time::Instant::now(),
+                    requested_heartbeat_from_client: false,
+                    clipboard_watcher: xyncer_share::clipboard::ClipboardWatcher::default(),
+                    launch_sender,
+                    file_sender,
+                },
+                windows,
+                input,
+                launches,
+                files,
+            }
+        }
+    }
+
+    impl Harness {
+        // A session that has identified, with every capability the server offers
+        pub fn authenticated() -> Self {
+            let mut harness = Harness::default();
+
+            harness.session.authenticated = true;
+            harness.session.capabilities = harness.options.capabilities();
+
+            harness
+        }
+
+        // Routes a payload like the connection would, returning the result and the replies
+        pub fn dispatch(
+            &mut self,
+            payload: xyncer_share::Payload,
+        ) -> (Result<(), ErrorCode>, Vec<xyncer_share::Payload>) {
+            let mut context = Context::new(
+                &mut self.session,
+                &self.options,
+                &mut self.connection,
+                payload.nonce,
+            );
+
+            let result = self.options.router.dispatch(&mut context, payload);
+
+            (result, context.into_replies())
+        }
+
+        // Lists the windows and subscribes to one, as clients have to before sending it input
+        pub fn subscribe(&mut self, window_id: u32) {
+            let (result, _) = self.dispatch(request(
+                xyncer_share::OP::ListWindows,
+                xyncer_share::payloads::PayloadData::ListWindows,
+                None,
+            ));
+
+            assert_eq!(result, Ok(()));
+
+            let (result, _) = self.dispatch(request(
+                xyncer_share::OP::Subscribe,
+                xyncer_share::payloads::PayloadData::Subscribe(
+                    xyncer_share::payloads::SubscribeData { window_id },
+                ),
+                None,
+            ));
+
+            assert_eq!(result, Ok(()));
+        }
+    }
+
+    // A payload as a client would send it
+    pub fn request(
+        op_code: xyncer_share::OP,
+        data: xyncer_share::payloads::PayloadData,
+        nonce: Option<u64>,
+    ) -> xyncer_share::Payload {
+        xyncer_share::Payload {
+            op_code,
+            event_name: xyncer_share::Event::None,
+            data,
+            sequence: None,
+            nonce,
+        }
+    }
+
+    // The error code of an Error reply
+    pub fn rejection(payload: &xyncer_share::Payload) -> Option<ErrorCode> {
+        match &payload.data {
+            xyncer_share::payloads::PayloadData::Error(data) => Some(data.code),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{rejection, request, Harness};
+    use super::*;
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    #[test]
+    fn only_heartbeats_identify_and_resume_come_before_authenticating() {
+        let mut harness = Harness::default();
+
+        let (result, replies) =
+            harness.dispatch(request(OP::ListWindows, PayloadData::ListWindows, Some(1)));
+
+        assert_eq!(result, Err(ErrorCode::AuthenticationFailed));
+        assert!(replies.is_empty());
+
+        let (result, replies) =
+            harness.dispatch(request(OP::Heartbeat, PayloadData::Heartbeat, None));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies[0].op_code, OP::HeartbeatAck);
+        assert!(!harness.session.authenticated);
+    }
+
+    #[test]
+    fn features_that_were_not_negotiated_are_refused() {
+        let mut harness = Harness::authenticated();
+
+        harness
+            .session
+            .capabilities
+            .retain(|capability| *capability != xyncer_share::payloads::Capability::Clipboard);
+
+        let clipboard =
+            PayloadData::Clipboard(xyncer_share::payloads::ClipboardData { items: Vec::new() });
+        let (result, replies) = harness.dispatch(request(OP::Clipboard, clipboard, Some(1)));
+
+        assert_eq!(result, Err(ErrorCode::UnknownOP));
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn payloads_only_the_server_sends_are_refused() {
+        let mut harness = Harness::authenticated();
+
+        let (result, _) =
+            harness.dispatch(request(OP::HeartbeatAck, PayloadData::HeartbeatAck, None));
+
+        assert_eq!(result, Err(ErrorCode::UnknownOP));
+    }
+
+    #[test]
+    fn payloads_without_their_data_are_malformed() {
+        let mut harness = Harness::default();
+
+        let (result, _) = harness.dispatch(request(OP::Identify, PayloadData::Heartbeat, None));
+
+        assert_eq!(result, Err(ErrorCode::DecodeError));
+
+        let mut harness = Harness::authenticated();
+
+        for op_code in [
+            OP::Subscribe,
+            OP::Unsubscribe,
+            OP::RequestKeyframe,
+            OP::Input,
+            OP::LaunchApp,
+            OP::Clipboard,
+            OP::RequestFile,
+        ] {
+            let (result, _) = harness.dispatch(request(op_code, PayloadData::ListWindows, Some(1)));
+
+            assert_eq!(result, Err(ErrorCode::DecodeError), "{:?}", op_code);
+        }
+    }
+
+    #[test]
+    fn rejections_answer_the_request() {
+        let mut harness = Harness::authenticated();
+
+        let subscribe = |nonce| {
+            request(
+                OP::Subscribe,
+                PayloadData::Subscribe(xyncer_share::payloads::SubscribeData { window_id: 99 }),
+                nonce,
+            )
+        };
+
+        let (result, replies) = harness.dispatch(subscribe(Some(42)));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].nonce, Some(42));
+        assert_eq!(rejection(&replies[0]), Some(ErrorCode::UnknownWindow));
+
+        // Nobody asked to hear about it
+        let (result, replies) = harness.dispatch(subscribe(None));
+
+        assert_eq!(result, Ok(()));
+        assert!(replies.is_empty());
+    }
+}
@@ -1,96 +1,391 @@
-use axum;
-use fastwebsockets;
-use rand::distributions::{Alphanumeric, DistString};
 use std::net::SocketAddr;
-use tokio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::session;
-use xyncer_share::{self, Websocket};
+use crate::{audio, capture, catalog, clipboard, config, input, launch, router, session, windows};
+use xyncer_share::Websocket;
 
-// Run the WebSocket server
-pub async fn start_server(ip: &str, port: u16) -> Result<(), std::io::Error> {
-    let url = format!("{}:{}", ip, port);
+// How often to check for window changes
+const WINDOW_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(500);
 
+// How often to check for clipboard changes
+const CLIPBOARD_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+
+// How often to capture subscribed windows (15 FPS)
+const FRAME_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(1000 / 15);
+
+// Most captured frames to hold for the connection before the capture task waits for it
+const FRAME_BACKLOG: usize = 8;
+
+// Options shared by every connection
+pub struct Options {
+    pub heartbeat_interval: u8,
+    pub heartbeat_jitter: u8,
+    pub passphrase: config::Passphrase,
+    pub max_password_attempts: u8,
+    pub max_sessions: Option<usize>,
+    pub active_sessions: AtomicUsize,
+    pub window_provider: Arc<dyn windows::WindowProvider>,
+    pub frame_source: Arc<dyn capture::FrameSource>,
+    pub input_sink: Arc<dyn input::InputSink>,
+    pub process_launcher: Arc<dyn launch::ProcessLauncher>,
+    pub allow_executables: bool,
+    pub catalog: Arc<catalog::Catalog>,
+    pub clipboard: Option<Arc<dyn xyncer_share::clipboard::Clipboard>>, // None when clipboard sync is turned off
+    pub clipboard_max_size: usize,
+    pub audio_source: Option<Arc<dyn audio::AudioSource>>, // None when audio streaming is turned off
+    pub files: Option<std::path::PathBuf>, // Where files from clients go and shared files come from, None when turned off
+    pub detached_sessions: session::DetachedSessions,
+    pub resume_grace_period: tokio::time::Duration, // How long to keep the session of a dropped connection for its client to resume
+    pub router: router::Router, // Handles what clients send
+}
+
+impl Options {
+    // What this server offers, leaving out what the config turned off
+    pub fn capabilities(&self) -> Vec<xyncer_share::payloads::Capability> {
+        xyncer_share::capabilities()
+            .into_iter()
+            .filter(|capability| match capability {
+                xyncer_share::payloads::Capability::Clipboard => self.clipboard.is_some(),
+                xyncer_share::payloads::Capability::Audio => self.audio_source.is_some(),
+                xyncer_share::payloads::Capability::Files => self.files.is_some(),
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+// Run the WebSocket server on every given address, over TLS if given a config
+pub async fn start_server(
+    ips: &[String],
+    port: u16,
+    options: Options,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+) -> Result<(), std::io::Error> {
     // Create a new router
-    let app = axum::Router::new().route("/", axum::routing::get(upgrade_connection));
+    let app = axum::Router::new()
+        .route("/", axum::routing::get(upgrade_connection))
+        .with_state(Arc::new(options));
 
-    // Bind the server to the address and port
-    let listener = tokio::net::TcpListener::bind(&url).await.unwrap();
+    let mut servers = tokio::task::JoinSet::new();
 
-    log::info!("WebSocket server running on ws://{}", url);
+    for ip in ips {
+        let url = SocketAddr::new(ip.parse().map_err(std::io::Error::other)?, port);
 
-    // Start the server
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
+        // Bind the server to the address and port, before starting any so a bad address stops them all
+        let listener = tokio::net::TcpListener::bind(url).await?;
+
+        let app = app.clone();
+        let tls_config = tls_config.clone();
+
+        servers.spawn(async move {
+            match tls_config {
+                Some(tls_config) => {
+                    log::info!("WebSocket server running on wss://{}", url);
+
+                    // Start the server
+                    axum_server::from_tcp_rustls(
+                        listener.into_std()?,
+                        axum_server::tls_rustls::RustlsConfig::from_config(tls_config),
+                    )
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+                }
+                None => {
+                    log::info!("WebSocket server running on ws://{}", url);
+
+                    // Start the server
+                    axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }
+            }
+        });
+    }
+
+    // The servers only stop on errors, so give up on the first one
+    while let Some(result) = servers.join_next().await {
+        result.map_err(std::io::Error::other)??;
+    }
+
+    Ok(())
 }
 
 // Handles a WebSocket connection
 async fn handle_connection(
     future: fastwebsockets::upgrade::UpgradeFut,
-    session_data: session::Session,
+    mut session_data: session::Session,
+    options: Arc<Options>,
 ) -> Result<(), fastwebsockets::WebSocketError> {
     // Create a new WebSocket connection
-    let mut websocket = fastwebsockets::FragmentCollector::new(future.await?);
+    let mut websocket = xyncer_share::Connection::new(future.await?);
+
+    let result = run_session(&mut websocket, &mut session_data, &options).await;
+
+    // Sessions that were closed or invalidated are gone for good, but if the connection dropped the client may come back
+    if !matches!(result, Ok(Ending::Closed)) && session_data.authenticated {
+        // Nobody would hear the audio captured meanwhile, so the client starts it again once it resumes
+        session_data.audio = None;
+
+        log::info!(
+            "Connection with {} dropped, keeping session {} for {:?}",
+            session_data.address,
+            session_data.id,
+            options.resume_grace_period
+        );
+
+        if let Some(id) = options
+            .detached_sessions
+            .detach(session_data, options.resume_grace_period)
+        {
+            log::warn!("Too many sessions are waiting to be resumed, dropping session {}", id);
+        }
+
+        tokio::spawn(async move {
+            tokio::time::sleep(options.resume_grace_period).await;
+
+            for id in options.detached_sessions.expire() {
+                log::info!("Session {} was not resumed in time, dropping it", id);
+            }
+        });
+    }
 
+    result.map(|_| ())
+}
+
+// How a session's connection ended
+enum Ending {
+    // The client closed it, or the server invalidated the session
+    Closed,
+    // The client stopped answering heartbeats, like it would if its network went away
+    Dropped,
+}
+
+// Talks to the client until the connection is closed
+async fn run_session(
+    websocket: &mut xyncer_share::Connection,
+    session_data: &mut session::Session,
+    options: &Options,
+) -> Result<Ending, fastwebsockets::WebSocketError> {
     websocket
         .send_payload(xyncer_share::Payload {
             op_code: xyncer_share::OP::Hello,
             event_name: xyncer_share::Event::None,
             data: xyncer_share::payloads::PayloadData::Hello(xyncer_share::payloads::HelloData {
-                heartbeat_interval: 60,
+                heartbeat_interval: options.heartbeat_interval,
+                protocol_version: xyncer_share::PROTOCOL_VERSION,
+                capabilities: options.capabilities(),
             }),
+            sequence: None,
+            nonce: None,
         })
         .await?;
 
-    let mut last_heartbeat = tokio::time::Instant::now();
-    let mut requested_heartbeat_from_client = false;
+    let mut window_poll_interval = tokio::time::interval(WINDOW_POLL_INTERVAL);
+    let mut frame_interval = tokio::time::interval(FRAME_INTERVAL);
+
+    // Don't try to catch up on frames we were too slow to send
+    frame_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    // Audio is captured in the background, so this only collects and sends what's been captured since the last tick
+    let mut audio_poll_interval = tokio::time::interval(audio::POLL_INTERVAL);
+
+    audio_poll_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    let mut clipboard_poll_interval = tokio::time::interval(CLIPBOARD_POLL_INTERVAL);
+
+    // Launches report back through here once the app has opened its windows
+    let (launch_sender, mut launch_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    // Requested files report back through here once they are checksummed and ready to offer
+    let (file_sender, mut file_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    // Frames are captured in the background, one job of subscribed windows at a time, and come back through here
+    let (job_sender, job_receiver) = tokio::sync::mpsc::channel(1);
+    let (frame_sender, mut frame_receiver) = tokio::sync::mpsc::channel(FRAME_BACKLOG);
+
+    // Ends once the job sender is dropped with the connection
+    tokio::spawn(capture::capture_frames(options.frame_source.clone(), job_receiver, frame_sender));
+
+    let mut connection = router::Connection {
+        last_heartbeat: tokio::time::Instant::now(),
+        requested_heartbeat_from_client: false,
+        clipboard_watcher: xyncer_share::clipboard::ClipboardWatcher::default(),
+        launch_sender,
+        file_sender,
+    };
+
+    // How long to wait for a heartbeat, with some jitter for latency
+    let heartbeat_timeout = tokio::time::Duration::from_secs(
+        u64::from(options.heartbeat_interval) + u64::from(options.heartbeat_jitter),
+    );
 
     loop {
         let mut sleep_duration = tokio::time::Duration::from_secs(0);
 
-        // Make sure we have not gone past the waiting time (5 more seconds as a jitter)
-        if last_heartbeat.elapsed() <= tokio::time::Duration::from_secs(65) {
+        // Make sure we have not gone past the waiting time
+        if connection.last_heartbeat.elapsed() <= heartbeat_timeout {
             // If we haven't gone past the waiting time, calculate the remaining time
-            sleep_duration = tokio::time::Duration::from_secs(65) - last_heartbeat.elapsed();
+            sleep_duration = heartbeat_timeout - connection.last_heartbeat.elapsed();
         }
 
         tokio::select! {
             // Check if we have not received a heartbeat
             _ = tokio::time::sleep(sleep_duration) => {
-                if requested_heartbeat_from_client {
-                    // Close the connection because the client did not respond to the heartbeat request
-                    log::warn!("Client did not respond to heartbeat request, closing connection");
+                if connection.requested_heartbeat_from_client {
+                    // Give up on the connection, but not on the session, as the client may just have lost its network
+                    log::warn!("Client {} did not respond to heartbeat request, dropping connection", session_data.address);
+
+                    return Ok(Ending::Dropped);
+                } else {
+                    // Request a heartbeat from the client
+                    connection.requested_heartbeat_from_client = true;
+
+                    // Give the client a grace period to respond to the heartbeat request
+                    connection.last_heartbeat = tokio::time::Instant::now();
 
                     websocket
                         .send_payload(xyncer_share::Payload {
-                            op_code: xyncer_share::OP::InvalidSession,
+                            op_code: xyncer_share::OP::Heartbeat,
                             event_name: xyncer_share::Event::None,
-                            data: xyncer_share::payloads::PayloadData::InvalidSession(xyncer_share::payloads::ErrorCode::SessionTimeout.populate()),
+                            data: xyncer_share::payloads::PayloadData::Heartbeat,
+                            sequence: None,
+                            nonce: None,
                         })
                         .await?;
+                }
+            }
+            // Push window changes to authenticated clients
+            _ = window_poll_interval.tick() => {
+                if !session_data.authenticated
+                    || !session_data.capabilities.contains(&xyncer_share::payloads::Capability::Windows)
+                {
+                    continue;
+                }
 
-                    websocket.close().await?;
+                for payload in windows::poll(options.window_provider.as_ref(), &mut session_data.windows) {
+                    websocket.send_payload(session_data.sequence(payload)).await?;
+                }
 
-                    break;
-                } else {
-                    // Request a heartbeat from the client
-                    requested_heartbeat_from_client = true;
+                // Stop streaming windows that no longer exist
+                session_data.subscriptions.retain(|window_id, _| session_data.windows.contains_key(window_id));
+            }
+            // Push clipboard changes to authenticated clients
+            _ = clipboard_poll_interval.tick() => {
+                if !session_data.authenticated
+                    || !session_data.capabilities.contains(&xyncer_share::payloads::Capability::Clipboard)
+                {
+                    continue;
+                }
 
-                    // Give the client a grace period to respond to the heartbeat request
-                    last_heartbeat = tokio::time::Instant::now();
+                let Some(data) = clipboard::poll(options, &mut connection.clipboard_watcher) else {
+                    continue;
+                };
 
+                websocket
+                    .send_payload(xyncer_share::Payload {
+                        op_code: xyncer_share::OP::Clipboard,
+                        event_name: xyncer_share::Event::None,
+                        data: xyncer_share::payloads::PayloadData::Clipboard(data),
+                        sequence: None,
+                        nonce: None,
+                    })
+                    .await?;
+            }
+            // Stream the host's audio to clients that asked for it
+            _ = audio_poll_interval.tick() => {
+                let Some(streaming) = session_data.audio.as_mut() else {
+                    continue;
+                };
+
+                // Late or lost packets are made up for by the client, so these aren't sequenced or replayed
+                for data in streaming.read() {
                     websocket
                         .send_payload(xyncer_share::Payload {
-                            op_code: xyncer_share::OP::Heartbeat,
+                            op_code: xyncer_share::OP::Audio,
                             event_name: xyncer_share::Event::None,
-                            data: xyncer_share::payloads::PayloadData::Heartbeat,
+                            data: xyncer_share::payloads::PayloadData::Audio(data),
+                            sequence: None,
+                            nonce: None,
                         })
                         .await?;
                 }
             }
+            // Report finished launches
+            Some((nonce, payload)) = launch_receiver.recv() => {
+                // Announce the app's windows first, so the client can subscribe to them straight away
+                if session_data.capabilities.contains(&xyncer_share::payloads::Capability::Windows) {
+                    for payload in windows::poll(options.window_provider.as_ref(), &mut session_data.windows) {
+                        websocket.send_payload(session_data.sequence(payload)).await?;
+                    }
+                }
+
+                websocket.send_payload(session_data.sequence(payload).answering(nonce)).await?;
+            }
+            // Offer the files the client asked for
+            Some((nonce, transfer_id, result)) = file_receiver.recv() => {
+                let payload = match result {
+                    Ok(outgoing) => session_data.transfers.offer(outgoing),
+                    Err(e) => session_data.transfers.fail(&transfer_id, format!("Could not open the file: {}", e)),
+                };
+
+                websocket.send_payload(payload.answering(nonce)).await?;
+            }
+            // Hand the subscribed windows to the capture task
+            _ = frame_interval.tick() => {
+                if session_data.subscriptions.is_empty() {
+                    continue;
+                }
+
+                // The client hasn't kept up with the last frames, so these would only arrive late
+                if websocket.is_congested(xyncer_share::channels::Channel::Video) {
+                    continue;
+                }
+
+                let job = session_data
+                    .subscriptions
+                    .iter()
+                    .map(|(window_id, encoder)| (*window_id, encoder.clone()))
+                    .collect();
+
+                // The last windows are still being captured, so skip this tick like a missed one
+                let _ = job_sender.try_send(job);
+            }
+            // Stream frames of subscribed windows
+            Some((window_id, encoder, result)) = frame_receiver.recv() => {
+                // The window may have been unsubscribed from (and maybe subscribed to again) while we were capturing
+                let subscribed = session_data
+                    .subscriptions
+                    .get(&window_id)
+                    .is_some_and(|subscription| Arc::ptr_eq(subscription, &encoder));
+
+                if !subscribed {
+                    continue;
+                }
+
+                let data = match result {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log::warn!("Could not capture window {}, unsubscribing: {}", window_id, e);
+
+                        session_data.subscriptions.remove(&window_id);
+
+                        continue;
+                    }
+                };
+
+                websocket
+                    .send_payload(xyncer_share::Payload {
+                        op_code: xyncer_share::OP::Frame,
+                        event_name: xyncer_share::Event::None,
+                        data: xyncer_share::payloads::PayloadData::Frame(data),
+                        sequence: None,
+                        nonce: None,
+                    })
+                    .await?;
+            }
             // Check for an incoming message
             msg = websocket.read_frame() => {
                 let msg = msg?;
@@ -98,30 +393,50 @@ async fn handle_connection(
                 match msg.opcode {
                     fastwebsockets::OpCode::Binary => {
                         let bytes = msg.payload.to_owned();
-                        let payload: xyncer_share::Payload = rmp_serde::from_slice(&bytes).unwrap();
-
-                        log::info!("Received payload: {:?}", payload);
 
-                        match payload.op_code {
-                            xyncer_share::OP::Heartbeat => {
-                                last_heartbeat = tokio::time::Instant::now();
-                                requested_heartbeat_from_client = false;
+                        let payload: xyncer_share::Payload = match rmp_serde::from_slice(&bytes) {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                // Close the connection because we can't trust anything else the client sends
+                                log::warn!("Could not decode payload from {}, closing connection: {}", session_data.address, e);
 
                                 websocket
-                                    .send_payload(xyncer_share::Payload {
-                                        op_code: xyncer_share::OP::HeartbeatAck,
-                                        event_name: xyncer_share::Event::None,
-                                        data: xyncer_share::payloads::PayloadData::HeartbeatAck,
-                                    })
+                                    .invalidate_session(xyncer_share::payloads::ErrorCode::DecodeError)
                                     .await?;
+
+                                break;
                             }
-                            _ => {
-                                unimplemented!()
-                            }
+                        };
+
+                        if payload.op_code.is_streamed() {
+                            log::debug!("Received payload: {:?}", payload);
+                        } else {
+                            log::info!("Received payload: {:?}", payload);
+                        }
+
+                        let mut context = router::Context::new(session_data, options, &mut connection, payload.nonce);
+                        let result = options.router.dispatch(&mut context, payload);
+
+                        // Whatever the handler had to say goes out first, even if it then closes the connection
+                        for reply in context.into_replies() {
+                            websocket.send_payload(reply).await?;
+                        }
+
+                        if let Err(code) = result {
+                            websocket.invalidate_session(code).await?;
+
+                            break;
                         }
                     }
+                    // Payloads are always MessagePack encoded, so text frames are invalid
                     fastwebsockets::OpCode::Text => {
-                        unimplemented!();
+                        log::warn!("Client {} sent a text frame, closing connection", session_data.address);
+
+                        websocket
+                            .invalidate_session(xyncer_share::payloads::ErrorCode::DecodeError)
+                            .await?;
+
+                        break;
                     }
                     fastwebsockets::OpCode::Close => break,
                     _ => {}
@@ -130,33 +445,81 @@ async fn handle_connection(
         }
     }
 
-    Ok(())
+    Ok(Ending::Closed)
+}
+
+// One of the max_sessions a connection holds, given back when dropped however the connection ends
+struct SessionSlot(Arc<Options>);
+
+impl SessionSlot {
+    // None if every slot is taken
+    fn take(options: &Arc<Options>) -> Option<Self> {
+        options
+            .active_sessions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active_sessions| {
+                match options.max_sessions {
+                    Some(max_sessions) if active_sessions >= max_sessions => None,
+                    _ => Some(active_sessions + 1),
+                }
+            })
+            .ok()
+            .map(|_| SessionSlot(options.clone()))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.active_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 // Upgrades an HTTP connection to a WebSocket connection
 async fn upgrade_connection(
     ws: fastwebsockets::upgrade::IncomingUpgrade,
     axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<SocketAddr>,
-) -> impl axum::response::IntoResponse {
+    axum::extract::State(options): axum::extract::State<Arc<Options>>,
+) -> axum::response::Response {
+    // Turn connections over the limit away before upgrading them
+    let Some(slot) = SessionSlot::take(&options) else {
+        log::warn!("Turning away {}, the maximum number of sessions are active", addr);
+
+        return axum::response::IntoResponse::into_response((
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Too many sessions",
+        ));
+    };
+
     // Upgrade the connection to a WebSocket connection
-    let (response, future) = ws.upgrade().unwrap();
+    let (response, future) = match ws.upgrade() {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            log::warn!("Could not upgrade the connection with {}: {}", addr, e);
 
-    let session_data = session::Session {
-        authenticated: false,
-        address: addr.to_string(),
-        password: Alphanumeric.sample_string(&mut rand::thread_rng(), 5),
-        password_attempts: 0,
+            return axum::response::IntoResponse::into_response((
+                axum::http::StatusCode::BAD_REQUEST,
+                "Not a WebSocket upgrade",
+            ));
+        }
     };
 
+    let session_data = session::Session::new(addr.to_string(), options.passphrase.generate());
+
     log::info!("WebSocket connection established with: {}", addr);
 
+    // Random passphrases are only shown on the server, so the user has to read them from here
+    if let config::Passphrase::Random { .. } = options.passphrase {
+        log::info!("Passphrase for {}: {}", addr, session_data.password);
+    }
+
     // Spawn a new task to handle the WebSocket connection
     tokio::task::spawn(async move {
         // Handle the WebSocket connection, and log any errors
-        if let Err(e) = handle_connection(future, session_data).await {
+        if let Err(e) = handle_connection(future, session_data, options.clone()).await {
             log::error!("WebSocket connection with {} failed: {}", addr, e);
         }
+
+        drop(slot);
     });
 
-    response
+    axum::response::IntoResponse::into_response(response)
 }
@@ -1,6 +1,540 @@
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use subtle::ConstantTimeEq;
+
+use crate::audio::Streaming;
+use crate::{capture, router};
+use xyncer_share::audio::AudioCodec;
+use xyncer_share::codecs::Codec;
+use xyncer_share::payloads::{Capability, ErrorCode, WindowData};
+use xyncer_share::transfer::Transfers;
+
+// How many dispatched events to keep around for replaying to a resumed session
+const REPLAY_LIMIT: usize = 256;
+
 pub struct Session {
     pub authenticated: bool,
     pub address: String,
     pub password: String,
     pub password_attempts: u8,
+
+    // Windows the client knows about, keyed by window id
+    pub windows: HashMap<u32, WindowData>,
+
+    // Windows the client is streaming frames of, with the encoder for each
+    pub subscriptions: HashMap<u32, capture::Encoder>,
+
+    // Frame codec negotiated when the client identified
+    pub codec: Arc<dyn Codec>,
+
+    // Audio codec negotiated when the client identified
+    pub audio_codec: Arc<dyn AudioCodec>,
+
+    // The audio being streamed to the client, None until it asks for it
+    pub audio: Option<Streaming>,
+
+    // Capabilities negotiated when the client identified
+    pub capabilities: Vec<Capability>,
+
+    // Sent to the client on Ready, and needed to resume the session after the connection drops
+    pub id: String,
+    pub resume_token: String,
+
+    // Sequence number of the last dispatched event, and the latest events for replaying
+    pub sequence: u64,
+    pub replay: VecDeque<xyncer_share::Payload>,
+
+    // Files being sent to and from the client, kept with the session so they carry on when it is resumed
+    pub transfers: Transfers,
+}
+
+impl Session {
+    // The session of a new connection, which has to identify or resume before it can do anything
+    pub fn new(address: String, password: String) -> Self {
+        Session {
+            authenticated: false,
+            address,
+            password,
+            password_attempts: 0,
+            windows: HashMap::new(),
+            subscriptions: HashMap::new(),
+            codec: Arc::new(xyncer_share::codecs::RawCodec),
+            audio_codec: Arc::new(xyncer_share::audio::PcmCodec),
+            audio: None,
+            capabilities: Vec::new(),
+            id: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+            resume_token: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+            sequence: 0,
+            replay: VecDeque::new(),
+            transfers: Default::default(),
+        }
+    }
+
+    // Stamps a dispatched event with the next sequence number, and keeps it for replaying
+    pub fn sequence(&mut self, mut payload: xyncer_share::Payload) -> xyncer_share::Payload {
+        self.sequence += 1;
+        payload.sequence = Some(self.sequence);
+
+        if self.replay.len() >= REPLAY_LIMIT {
+            self.replay.pop_front();
+        }
+
+        self.replay.push_back(payload.clone());
+
+        payload
+    }
+
+    // The events dispatched after the given sequence number, or None if some of them were already dropped
+    pub fn replay_since(&self, sequence: u64) -> Option<Vec<xyncer_share::Payload>> {
+        if sequence > self.sequence {
+            return None;
+        }
+
+        let oldest = self
+            .replay
+            .front()
+            .and_then(|payload| payload.sequence)
+            .unwrap_or(self.sequence + 1);
+
+        if sequence + 1 < oldest {
+            return None;
+        }
+
+        Some(
+            self.replay
+                .iter()
+                .filter(|payload| payload.sequence > Some(sequence))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+// Authenticated sessions whose connection dropped, kept for a while so their clients can resume them
+pub struct DetachedSessions {
+    sessions: Mutex<HashMap<String, (Session, std::time::Instant)>>,
+    limit: usize, // Most sessions kept at once, so clients dropping their connections can't pile them up
+}
+
+impl DetachedSessions {
+    pub fn new(limit: usize) -> Self {
+        DetachedSessions {
+            sessions: Mutex::new(HashMap::new()),
+            limit,
+        }
+    }
+
+    // Keeps a session until the grace period is over. If there are too many already, the one closest to
+    // expiring makes room for it, and its id is returned.
+    pub fn detach(&self, session: Session, grace_period: std::time::Duration) -> Option<String> {
+        let expires = std::time::Instant::now() + grace_period;
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let dropped = match sessions.len() >= self.limit {
+            true => sessions
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(id, _)| id.clone()),
+            false => None,
+        };
+
+        if let Some(id) = &dropped {
+            sessions.remove(id);
+        }
+
+        sessions.insert(session.id.clone(), (session, expires));
+
+        dropped
+    }
+
+    // Takes a session back out, if it hasn't expired and the resume token matches
+    pub fn resume(&self, id: &str, resume_token: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get(id) {
+            Some((session, expires))
+                // Comparing in constant time doesn't give away how much of a guessed token was right
+                if bool::from(session.resume_token.as_bytes().ct_eq(resume_token.as_bytes()))
+                    && *expires > std::time::Instant::now() =>
+            {
+                sessions.remove(id).map(|(session, _)| session)
+            }
+            _ => None,
+        }
+    }
+
+    // Drops the sessions whose grace period is over, returning their ids
+    pub fn expire(&self) -> Vec<String> {
+        let now = std::time::Instant::now();
+        let mut expired = Vec::new();
+
+        self.sessions.lock().unwrap().retain(|id, (_, expires)| {
+            if *expires > now {
+                return true;
+            }
+
+            expired.push(id.clone());
+
+            false
+        });
+
+        expired
+    }
+}
+
+// Heartbeats, identifying and resuming
+pub fn route(router: &mut router::Router) {
+    router
+        .on(xyncer_share::OP::Heartbeat, |context, _| {
+            context.connection.last_heartbeat = tokio::time::Instant::now();
+            context.connection.requested_heartbeat_from_client = false;
+
+            context.replies.answer(xyncer_share::Payload {
+                op_code: xyncer_share::OP::HeartbeatAck,
+                event_name: xyncer_share::Event::None,
+                data: xyncer_share::payloads::PayloadData::HeartbeatAck,
+                sequence: None,
+                nonce: None,
+            });
+
+            Ok(())
+        })
+        .on(xyncer_share::OP::Identify, identify)
+        .on(xyncer_share::OP::Resume, resume);
+}
+
+fn identify(
+    context: &mut router::Context<'_>,
+    payload: xyncer_share::Payload,
+) -> Result<(), ErrorCode> {
+    let xyncer_share::payloads::PayloadData::Identify(identify_data) = payload.data else {
+        return Err(router::malformed(context, payload.op_code));
+    };
+
+    let session_data = &mut *context.session;
+
+    if session_data.authenticated {
+        log::warn!(
+            "Client {} sent Identify while already authenticated, ignoring",
+            session_data.address
+        );
+
+        context.replies.reject(ErrorCode::AlreadyAuthenticated);
+
+        return Ok(());
+    }
+
+    // Checked before the passphrase, so incompatible clients don't use up attempts
+    if !xyncer_share::is_compatible(identify_data.protocol_version) {
+        log::warn!(
+            "Client {} speaks incompatible protocol version {}, closing connection",
+            session_data.address,
+            identify_data.protocol_version
+        );
+
+        return Err(ErrorCode::IncompatibleVersion);
+    }
+
+    if identify_data.passphrase != session_data.password {
+        session_data.password_attempts += 1;
+
+        if session_data.password_attempts >= context.options.max_password_attempts {
+            // Close the connection because the client has run out of attempts
+            log::warn!(
+                "Client {} failed to authenticate {} times, closing connection",
+                session_data.address,
+                session_data.password_attempts
+            );
+
+            return Err(ErrorCode::AuthenticationFailed);
+        }
+
+        log::info!(
+            "Client {} sent an invalid passphrase ({}/{} attempts)",
+            session_data.address,
+            session_data.password_attempts,
+            context.options.max_password_attempts
+        );
+
+        context.replies.answer(xyncer_share::Payload {
+            op_code: xyncer_share::OP::ReIdentify,
+            event_name: xyncer_share::Event::None,
+            data: xyncer_share::payloads::PayloadData::ReIdentify,
+            sequence: None,
+            nonce: None,
+        });
+
+        return Ok(());
+    }
+
+    session_data.authenticated = true;
+    session_data.codec = xyncer_share::codecs::negotiate(&identify_data.codecs);
+    session_data.audio_codec = xyncer_share::audio::negotiate(&identify_data.audio_codecs);
+    session_data.capabilities = xyncer_share::negotiate_capabilities(&identify_data.capabilities)
+        .into_iter()
+        .filter(|capability| context.options.capabilities().contains(capability))
+        .collect();
+
+    log::info!(
+        "Client {} authenticated, using the {} codec",
+        session_data.address,
+        session_data.codec.name()
+    );
+
+    context.replies.answer(xyncer_share::Payload {
+        op_code: xyncer_share::OP::Dispatch,
+        event_name: xyncer_share::Event::Ready,
+        data: xyncer_share::payloads::PayloadData::Ready(xyncer_share::payloads::ReadyData {
+            codec: session_data.codec.name().to_string(),
+            audio_codec: session_data.audio_codec.name().to_string(),
+            capabilities: session_data.capabilities.clone(),
+            session_id: session_data.id.clone(),
+            resume_token: session_data.resume_token.clone(),
+            clipboard_max_size: Some(context.options.clipboard_max_size),
+        }),
+        sequence: None,
+        nonce: None,
+    });
+
+    Ok(())
+}
+
+fn resume(
+    context: &mut router::Context<'_>,
+    payload: xyncer_share::Payload,
+) -> Result<(), ErrorCode> {
+    let xyncer_share::payloads::PayloadData::Resume(data) = payload.data else {
+        return Err(router::malformed(context, payload.op_code));
+    };
+
+    let session_data = &mut *context.session;
+
+    if session_data.authenticated {
+        log::warn!(
+            "Client {} sent Resume while already authenticated, ignoring",
+            session_data.address
+        );
+
+        context.replies.reject(ErrorCode::AlreadyAuthenticated);
+
+        return Ok(());
+    }
+
+    // Resuming takes the session out of the detached sessions, so it can only be resumed once
+    let Some(mut resumed_session) = context
+        .options
+        .detached_sessions
+        .resume(&data.session_id, &data.resume_token)
+    else {
+        log::warn!(
+            "Client {} tried to resume unknown or expired session {}, closing connection",
+            session_data.address,
+            data.session_id
+        );
+
+        return Err(ErrorCode::ResumeFailed);
+    };
+
+    let Some(missed_events) = resumed_session.replay_since(data.sequence) else {
+        log::warn!(
+            "Client {} asked for events session {} no longer has, closing connection",
+            session_data.address,
+            data.session_id
+        );
+
+        return Err(ErrorCode::ResumeFailed);
+    };
+
+    // Frames sent before the connection dropped may never have arrived
+    for encoder in resumed_session.subscriptions.values() {
+        encoder.lock().unwrap().request_keyframe();
+    }
+
+    resumed_session.address = session_data.address.clone();
+    *session_data = resumed_session;
+
+    log::info!(
+        "Client {} resumed session {}, replaying {} events",
+        session_data.address,
+        session_data.id,
+        missed_events.len()
+    );
+
+    for payload in missed_events {
+        context.replies.send(payload);
+    }
+
+    context.replies.answer(xyncer_share::Payload {
+        op_code: xyncer_share::OP::Dispatch,
+        event_name: xyncer_share::Event::Resumed,
+        data: xyncer_share::payloads::PayloadData::Resumed,
+        sequence: None,
+        nonce: None,
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::testing::{request, Harness};
+    use xyncer_share::payloads::PayloadData;
+    use xyncer_share::OP;
+
+    fn identify(passphrase: &str) -> xyncer_share::Payload {
+        request(
+            OP::Identify,
+            PayloadData::Identify(xyncer_share::payloads::IdentifyData {
+                passphrase: passphrase.to_string(),
+                codecs: xyncer_share::codecs::names(),
+                protocol_version: xyncer_share::PROTOCOL_VERSION,
+                capabilities: xyncer_share::capabilities(),
+                audio_codecs: xyncer_share::audio::names(),
+            }),
+            Some(1),
+        )
+    }
+
+    #[test]
+    fn identifying_negotiates_what_the_server_offers() {
+        let mut harness = Harness::default();
+
+        harness.options.audio_source = None;
+
+        let (result, replies) = harness.dispatch(identify("password1"));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].nonce, Some(1));
+        assert!(matches!(
+            &replies[0].data,
+            PayloadData::Ready(data) if !data.capabilities.contains(&xyncer_share::payloads::Capability::Audio)
+        ));
+        assert!(harness.session.authenticated);
+
+        // Audio wasn't negotiated, so it can't be asked for
+        let (result, _) = harness.dispatch(request(OP::StartAudio, PayloadData::StartAudio, None));
+
+        assert_eq!(result, Err(ErrorCode::UnknownOP));
+    }
+
+    #[test]
+    fn wrong_passphrases_run_out() {
+        let mut harness = Harness::default();
+
+        for _ in 1..harness.options.max_password_attempts {
+            let (result, replies) = harness.dispatch(identify("password2"));
+
+            assert_eq!(result, Ok(()));
+            assert_eq!(replies[0].op_code, OP::ReIdentify);
+        }
+
+        let (result, _) = harness.dispatch(identify("password1"));
+
+        assert_eq!(result, Ok(()));
+        assert!(harness.session.authenticated);
+
+        let mut harness = Harness::default();
+
+        for _ in 1..harness.options.max_password_attempts {
+            assert_eq!(harness.dispatch(identify("password2")).0, Ok(()));
+        }
+
+        let (result, _) = harness.dispatch(identify("password2"));
+
+        assert_eq!(result, Err(ErrorCode::AuthenticationFailed));
+        assert!(!harness.session.authenticated);
+    }
+
+    #[test]
+    fn detached_sessions_resume_where_they_left_off() {
+        let mut harness = Harness::authenticated();
+
+        harness.subscribe(1);
+
+        let session = std::mem::replace(
+            &mut harness.session,
+            Session::new("127.0.0.1:5678".to_string(), "password1".to_string()),
+        );
+        let resume = xyncer_share::payloads::ResumeData {
+            session_id: session.id.clone(),
+            resume_token: session.resume_token.clone(),
+            sequence: 0,
+        };
+
+        harness
+            .options
+            .detached_sessions
+            .detach(session, std::time::Duration::from_secs(60));
+
+        let (result, replies) = harness.dispatch(request(
+            OP::Resume,
+            PayloadData::Resume(resume.clone()),
+            Some(9),
+        ));
+
+        assert_eq!(result, Ok(()));
+        assert!(harness.session.authenticated);
+        assert!(harness.session.subscriptions.contains_key(&1));
+        assert_eq!(harness.session.address, "127.0.0.1:5678");
+
+        // The window list was dispatched before the connection dropped
+        assert_eq!(replies.len(), 2);
+        assert!(matches!(replies[0].data, PayloadData::WindowList(_)));
+        assert!(matches!(replies[1].data, PayloadData::Resumed));
+        assert_eq!(replies[1].nonce, Some(9));
+
+        // Only once
+        let mut harness = Harness::default();
+
+        let (result, _) = harness.dispatch(request(OP::Resume, PayloadData::Resume(resume), None));
+
+        assert_eq!(result, Err(ErrorCode::ResumeFailed));
+    }
+
+    #[test]
+    fn wrong_resume_tokens_are_refused() {
+        let detached_sessions = DetachedSessions::new(4);
+        let session = Session::new("127.0.0.1:1234".to_string(), "password1".to_string());
+        let (id, resume_token) = (session.id.clone(), session.resume_token.clone());
+
+        detached_sessions.detach(session, std::time::Duration::from_secs(60));
+
+        // Wrong in the last character, too short, and empty
+        let guess = format!("{}-", &resume_token[..resume_token.len() - 1]);
+
+        assert!(detached_sessions.resume(&id, &guess).is_none());
+        assert!(detached_sessions.resume(&id, &resume_token[1..]).is_none());
+        assert!(detached_sessions.resume(&id, "").is_none());
+
+        // Guessing doesn't lose the session for its client
+        assert!(detached_sessions.resume(&id, &resume_token).is_some());
+    }
+
+    #[test]
+    fn detached_sessions_closest_to_expiring_make_room() {
+        let detached_sessions = DetachedSessions::new(2);
+        let sessions: Vec<_> = (0..3)
+            .map(|_| Session::new("127.0.0.1:1234".to_string(), "password1".to_string()))
+            .collect();
+        let ids: Vec<_> = sessions.iter().map(|session| session.id.clone()).collect();
+        let tokens: Vec<_> = sessions
+            .iter()
+            .map(|session| session.resume_token.clone())
+            .collect();
+
+        let mut grace_periods = [30, 10, 60].map(std::time::Duration::from_secs).into_iter();
+
+        let dropped: Vec<_> = sessions
+            .into_iter()
+            .map(|session| detached_sessions.detach(session, grace_periods.next().unwrap()))
+            .collect();
+
+        assert_eq!(dropped, [None, None, Some(ids[1].clone())]);
+        assert!(detached_sessions.resume(&ids[1], &tokens[1]).is_none());
+        assert!(detached_sessions.resume(&ids[0], &tokens[0]).is_some());
+        assert!(detached_sessions.resume(&ids[2], &tokens[2]).is_some());
+    }
 }
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Where the certificate and key are kept unless configured otherwise
+pub fn default_paths() -> (PathBuf, PathBuf) {
+    let directory = dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("xyncer")
+        .join("server");
+
+    (directory.join("cert.pem"), directory.join("key.pem"))
+}
+
+// Generates a self-signed certificate and key, for servers that weren't given one
+fn generate(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Clients pin the certificate rather than checking names, so these are only for other tools
+    let certified_key =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])?;
+
+    for path in [cert_path, key_path] {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+    }
+
+    std::fs::write(cert_path, certified_key.cert.pem())?;
+
+    // Only we should be able to read the key
+    let mut key_file = std::fs::OpenOptions::new();
+
+    key_file.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut key_file, 0o600);
+
+    std::io::Write::write_all(
+        &mut key_file.open(key_path)?,
+        certified_key.key_pair.serialize_pem().as_bytes(),
+    )?;
+
+    Ok(())
+}
+
+// Loads the certificate and key (generating them on first run), returning the TLS config and certificate fingerprint
+pub fn load(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Arc<rustls::ServerConfig>, String), Box<dyn std::error::Error + Send + Sync>> {
+    if !cert_path.exists() && !key_path.exists() {
+        log::info!(
+            "No certificate found, generating a self-signed one at {}",
+            cert_path.display()
+        );
+
+        generate(cert_path, key_path)?;
+    }
+
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        cert_path,
+    )?))
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let key =
+        rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(key_path)?))?
+            .ok_or_else(|| format!("No private key found in {}", key_path.display()))?;
+
+    let fingerprint = xyncer_share::certificate_fingerprint(
+        certs
+            .first()
+            .ok_or_else(|| format!("No certificate found in {}", cert_path.display()))?,
+    );
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+
+    Ok((Arc::new(config), fingerprint))
+}
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::router;
+use xyncer_share::payloads::{WindowData, WindowGeometry, WindowIcon};
+
+// Enumerates the top-level windows on the server host
+pub trait WindowProvider: Send + Sync {
+    fn windows(&self) -> Vec<WindowData>;
+}
+
+// Lists windows using XCap
+#[cfg(windows)]
+pub struct XCapWindowProvider;
+
+#[cfg(windows)]
+impl WindowProvider for XCapWindowProvider {
+    fn windows(&self) -> Vec<WindowData> {
+        let windows = match xcap::Window::all() {
+            Ok(windows) => windows,
+            Err(e) => {
+                log::error!("Error listing windows: {}", e);
+
+                return Vec::new();
+            }
+        };
+
+        windows
+            .iter()
+            // Skip windows that can't be shown to the client
+            .filter(|window| {
+                !window.is_minimized()
+                    && !window.title().is_empty()
+                    && window.width() > 0
+                    && window.height() > 0
+            })
+            .map(|window| WindowData {
+                id: window.id(),
+                title: window.title().to_string(),
+                process_name: window.app_name().to_string(),
+                geometry: WindowGeometry {
+                    x: window.x(),
+                    y: window.y(),
+                    width: window.width(),
+                    height: window.height(),
+                },
+                icon: window_icon(window.id()),
+                pid: window.process_id(),
+            })
+            .collect()
+    }
+}
+
+// How long a window gets to hand over its icon, so a hung one doesn't hold up the list
+#[cfg(windows)]
+const ICON_TIMEOUT_MS: u32 = 50;
+
+// Reads the icon a window shows in its title bar, falling back to the one of its class
+#[cfg(windows)]
+fn window_icon(id: u32) -> Option<WindowIcon> {
+    use windows_sys::Win32::UI::WindowsAndMessaging::*;
+
+    // XCap identifies windows by their handle
+    let hwnd = id as windows_sys::Win32::Foundation::HWND;
+    let mut icon = 0;
+
+    unsafe {
+        for size in [ICON_BIG, ICON_SMALL2] {
+            if icon == 0 {
+                SendMessageTimeoutW(
+                    hwnd,
+                    WM_GETICON,
+                    size as usize,
+                    0,
+                    SMTO_ABORTIFHUNG,
+                    ICON_TIMEOUT_MS,
+                    &mut icon,
+                );
+            }
+        }
+
+        if icon == 0 {
+            icon = GetClassLongPtrW(hwnd, GCLP_HICON);
+        }
+    }
+
+    // The icon still belongs to the window, so it isn't ours to destroy
+    crate::catalog::icon_pixels(icon as HICON)
+}
+
+// Serves fake windows, for hosts without window capture support
+pub struct SyntheticWindowProvider {
+    windows: Mutex<Vec<WindowData>>,
+}
+
+impl SyntheticWindowProvider {
+    pub fn new(windows: Vec<WindowData>) -> Self {
+        SyntheticWindowProvider {
+            windows: Mutex::new(windows),
+        }
+    }
+
+    // Opens another fake window, as if the given process had, returning it
+    pub fn open(&self, title: String, process_name: String, pid: u32) -> WindowData {
+        let mut windows = self.windows.lock().unwrap();
+
+        let id = windows.iter().map(|window| window.id).max().unwrap_or(0) + 1;
+
+        // Cascade new windows, so they don't all sit on top of each other
+        let offset = 40 * (id as i32 % 8);
+
+        let window = WindowData {
+            id,
+            title,
+            process_name,
+            geometry: WindowGeometry {
+                x: 200 + offset,
+                y: 150 + offset,
+                width: 480,
+                height: 360,
+            },
+            icon: None,
+            pid,
+        };
+
+        windows.push(window.clone());
+
+        window
+    }
+}
+
+impl Default for SyntheticWindowProvider {
+    fn default() -> Self {
+        // Solid colour icon, so the client has something to draw
+        let icon = WindowIcon {
+            width: 16,
+            height: 16,
+            rgba: [0x3d, 0x7e, 0xff, 0xff].repeat(16 * 16),
+        };
+
+        SyntheticWindowProvider::new(vec![
+            WindowData {
+                id: 1,
+                title: "Synthetic Window".to_string(),
+                process_name: "synthetic.exe".to_string(),
+                geometry: WindowGeometry {
+                    x: 100,
+                    y: 100,
+                    width: 640,
+                    height: 480,
+                },
+                icon: Some(icon.clone()),
+                pid: 0,
+            },
+            WindowData {
+                id: 2,
+                title: "Synthetic Window (Small)".to_string(),
+                process_name: "synthetic.exe".to_string(),
+                geometry: WindowGeometry {
+                    x: 800,
+                    y: 200,
+                    width: 320,
+                    height: 240,
+                },
+                icon: Some(icon),
+                pid: 0,
+            },
+        ])
+    }
+}
+
+impl WindowProvider for SyntheticWindowProvider {
+    fn windows(&self) -> Vec<WindowData> {
+        self.windows.lock().unwrap().clone()
+    }
+}
+
+// Picks the window provider for this host
+pub fn provider(
+    synthetic: bool,
+    synthetic_windows: std::sync::Arc<SyntheticWindowProvider>,
+) -> std::sync::Arc<dyn WindowProvider> {
+    #[cfg(windows)]
+    if !synthetic {
+        return std::sync::Arc::new(XCapWindowProvider);
+    }
+
+    #[cfg(not(windows))]
+    if !synthetic {
+        log::warn!("Window capture is only supported on Windows, using synthetic windows");
+    }
+
+    synthetic_windows
+}
+
+// Lists the current windows, and remembers them as known to the client
+pub fn list(provider: &dyn WindowProvider, known: &mut HashMap<u32, WindowData>) -> Vec<WindowData> {
+    let windows = provider.windows();
+
+    known.clear();
+    known.extend(windows.iter().map(|window| (window.id, window.clone())));
+
+    windows
+}
+
+// Compares the current windows against the known ones, returning a payload for every change
+pub fn poll(
+    provider: &dyn WindowProvider,
+    known: &mut HashMap<u32, WindowData>,
+) -> Vec<xyncer_share::Payload> {
+    let mut payloads = Vec::new();
+    let mut current = HashMap::new();
+
+    for window in provider.windows() {
+        match known.get(&window.id) {
+            Some(old) => {
+                if old.geometry != window.geometry {
+                    payloads.push(xyncer_share::Payload {
+                        op_code: xyncer_share::OP::Dispatch,
+                        event_name: xyncer_share::Event::WindowMoved,
+                        data: xyncer_share::payloads::PayloadData::WindowMoved(
+                            xyncer_share::payloads::WindowMovedData {
+                                id: window.id,
+                                geometry: window.geometry,
+                            },
+                        ),
+                        sequence: None,
+                        nonce: None,
+                    });
+                }
+
+                if old.title != window.title {
+                    payloads.push(xyncer_share::Payload {
+                        op_code: xyncer_share::OP::Dispatch,
+                        event_name: xyncer_share::Event::WindowTitleChanged,
+                        data: xyncer_share::payloads::PayloadData::WindowTitleChanged(
+                            xyncer_share::payloads::WindowTitleChangedData {
+                                id: window.id,
+                                title: window.title.clone(),
+                            },
+                        ),
+                        sequence: None,
+                        nonce: None,
+                    });
+                }
+            }
+            None => {
+                payloads.push(xyncer_share::Payload {
+                    op_code: xyncer_share::OP::Dispatch,
+                    event_name: xyncer_share::Event::WindowCreated,
+                    data: xyncer_share::payloads::PayloadData::WindowCreated(window.clone()),
+                    sequence: None,
+                    nonce: None,
+                });
+            }
+        }
+
+        current.insert(window.id, window);
+    }
+
+    for id in known.keys() {
+        if !current.contains_key(id) {
+            payloads.push(xyncer_share::Payload {
+                op_code: xyncer_share::OP::Dispatch,
+                event_name: xyncer_share::Event::WindowDestroyed,
+                data: xyncer_share::payloads::PayloadData::WindowDestroyed(
+                    xyncer_share::payloads::WindowDestroyedData { id: *id },
+                ),
+                sequence: None,
+                nonce: None,
+            });
+        }
+    }
+
+    *known = current;
+
+    payloads
+}
+
+// Listing windows
+pub fn route(router: &mut router::Router) {
+    router.on(xyncer_share::OP::ListWindows, |context, _| {
+        let windows = list(
+            context.options.window_provider.as_ref(),
+            &mut context.session.windows,
+        );
+
+        // Only the answer carries the nonce, as the copy kept for replaying goes to another connection
+        let event = context.session.sequence(xyncer_share::Payload {
+            op_code: xyncer_share::OP::Dispatch,
+            event_name: xyncer_share::Event::WindowList,
+            data: xyncer_share::payloads::PayloadData::WindowList(
+                xyncer_share::payloads::WindowListData { windows },
+            ),
+            sequence: None,
+            nonce: None,
+        });
+
+        context.replies.answer(event);
+
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::testing::{request, Harness};
+
+    fn window(id: u32, title: &str) -> WindowData {
+        WindowData {
+            id,
+            title: title.to_string(),
+            process_name: "test.exe".to_string(),
+            geometry: WindowGeometry {
+                x: 0,
+                y: 0,
+                width: 100,
+                height: 100,
+            },
+            icon: None,
+            pid: 0,
+        }
+    }
+
+    // Lists the windows, then polls once after letting the test change them
+    fn poll_after(
+        windows: Vec<WindowData>,
+        change: impl FnOnce(&mut Vec<WindowData>),
+    ) -> (Vec<xyncer_share::Payload>, HashMap<u32, WindowData>) {
+        let provider = SyntheticWindowProvider::new(windows);
+        let mut known = HashMap::new();
+
+        list(&provider, &mut known);
+        change(&mut provider.windows.lock().unwrap());
+
+        let payloads = poll(&provider, &mut known);
+
+        (payloads, known)
+    }
+
+    #[test]
+    fn unchanged_windows_send_nothing() {
+        let (payloads, known) = poll_after(vec![window(1, "One"), window(2, "Two")], |_| {});
+
+        assert!(payloads.is_empty());
+        assert_eq!(known.len(), 2);
+    }
+
+    #[test]
+    fn created_windows_are_sent_whole() {
+        let (payloads, known) = poll_after(vec![window(1, "One")], |windows| {
+            windows.push(window(2, "Two"))
+        });
+
+        assert_eq!(payloads.len(), 1);
+        assert!(matches!(
+            &payloads[0].data,
+            xyncer_share::payloads::PayloadData::WindowCreated(data) if *data == window(2, "Two")
+        ));
+        assert!(known.contains_key(&2));
+    }
+
+    #[test]
+    fn destroyed_windows_are_forgotten() {
+        let (payloads, known) = poll_after(vec![window(1, "One"), window(2, "Two")], |windows| {
+            windows.retain(|window| window.id != 1)
+        });
+
+        assert_eq!(payloads.len(), 1);
+        assert!(matches!(
+            &payloads[0].data,
+            xyncer_share::payloads::PayloadData::WindowDestroyed(data) if data.id == 1
+        ));
+        assert!(!known.contains_key(&1));
+    }
+
+    #[test]
+    fn moved_windows_send_their_geometry() {
+        let (payloads, known) = poll_after(vec![window(1, "One")], |windows| {
+            windows[0].geometry.x = 50;
+            windows[0].geometry.width = 200;
+        });
+
+        assert_eq!(payloads.len(), 1);
+        assert!(matches!(
+            &payloads[0].data,
+            xyncer_share::payloads::PayloadData::WindowMoved(data)
+                if data.id == 1 && data.geometry.x == 50 && data.geometry.width == 200
+        ));
+        assert_eq!(known[&1].geometry.x, 50);
+    }
+
+    #[test]
+    fn renamed_windows_send_their_title() {
+        let (payloads, known) = poll_after(vec![window(1, "One")], |windows| {
+            windows[0].title = "Uno".to_string()
+        });
+
+        assert_eq!(payloads.len(), 1);
+        assert!(matches!(
+            &payloads[0].data,
+            xyncer_share::payloads::PayloadData::WindowTitleChanged(data)
+                if data.id == 1 && data.title == "Uno"
+        ));
+        assert_eq!(known[&1].title, "Uno");
+    }
+
+    #[test]
+    fn moving_and_renaming_sends_both() {
+        let (payloads, _) = poll_after(vec![window(1, "One")], |windows| {
+            windows[0].geometry.y = 10;
+            windows[0].title = "Uno".to_string();
+        });
+
+        assert_eq!(payloads.len(), 2);
+        assert!(matches!(
+            payloads[0].data,
+            xyncer_share::payloads::PayloadData::WindowMoved(_)
+        ));
+        assert!(matches!(
+            payloads[1].data,
+            xyncer_share::payloads::PayloadData::WindowTitleChanged(_)
+        ));
+    }
+
+    #[test]
+    fn listing_windows_answers_with_an_event() {
+        let mut harness = Harness::authenticated();
+
+        let (result, replies) = harness.dispatch(request(
+            xyncer_share::OP::ListWindows,
+            xyncer_share::payloads::PayloadData::ListWindows,
+            Some(3),
+        ));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies[0].nonce, Some(3));
+        assert_eq!(replies[0].sequence, Some(1));
+        assert!(
+            matches!(&replies[0].data, xyncer_share::payloads::PayloadData::WindowList(data) if data.windows.len() == 2)
+        );
+        assert_eq!(harness.session.windows.len(), 2);
+
+        // Replayed to a resumed session without the nonce, as it isn't the one that asked
+        assert_eq!(harness.session.replay[0].nonce, None);
+    }
+}
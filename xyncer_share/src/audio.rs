@@ -0,0 +1,595 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::payloads::AudioData;
+
+// Audio is always sent as 48kHz stereo, in packets of 20ms
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+pub const PACKET_DURATION: Duration = Duration::from_millis(20);
+
+// Samples per channel in a packet
+pub const PACKET_FRAMES: usize = SAMPLE_RATE as usize / 50;
+
+// Interleaved samples in a packet
+pub const PACKET_SAMPLES: usize = PACKET_FRAMES * CHANNELS;
+
+pub type AudioError = Box<dyn std::error::Error + Send + Sync>;
+
+// Compresses packets of audio. Like frame codecs, these are negotiated by name, so adding one only
+// means implementing this trait and listing it in `all`.
+pub trait AudioCodec: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Encoders and decoders carry state from one packet to the next, so every stream gets its own
+    fn encoder(&self) -> Result<Box<dyn AudioEncoder>, AudioError>;
+
+    fn decoder(&self) -> Result<Box<dyn AudioDecoder>, AudioError>;
+}
+
+pub trait AudioEncoder: Send {
+    // Encodes a packet of PACKET_SAMPLES interleaved samples
+    fn encode(&mut self, samples: &[i16]) -> Result<Vec<u8>, AudioError>;
+}
+
+pub trait AudioDecoder: Send {
+    // Decodes a packet into interleaved samples, or makes up something to play in place of a lost one
+    fn decode(&mut self, data: Option<&[u8]>) -> Result<Vec<i16>, AudioError>;
+}
+
+// Sends samples as they are, little endian
+pub struct PcmCodec;
+
+struct Pcm;
+
+impl AudioCodec for PcmCodec {
+    fn name(&self) -> &'static str {
+        "pcm"
+    }
+
+    fn encoder(&self) -> Result<Box<dyn AudioEncoder>, AudioError> {
+        Ok(Box::new(Pcm))
+    }
+
+    fn decoder(&self) -> Result<Box<dyn AudioDecoder>, AudioError> {
+        Ok(Box::new(Pcm))
+    }
+}
+
+impl AudioEncoder for Pcm {
+    fn encode(&mut self, samples: &[i16]) -> Result<Vec<u8>, AudioError> {
+        Ok(samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect())
+    }
+}
+
+impl AudioDecoder for Pcm {
+    fn decode(&mut self, data: Option<&[u8]>) -> Result<Vec<i16>, AudioError> {
+        // Silence is the best we can do for a lost packet
+        let Some(data) = data else {
+            return Ok(vec![0; PACKET_SAMPLES]);
+        };
+
+        if data.len() != PACKET_SAMPLES * 2 {
+            return Err(format!(
+                "PCM packet has {} bytes instead of {}",
+                data.len(),
+                PACKET_SAMPLES * 2
+            )
+            .into());
+        }
+
+        Ok(data
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect())
+    }
+}
+
+// Bits per second, plenty for stereo music
+#[cfg(feature = "opus")]
+const OPUS_BITRATE: i32 = 128_000;
+
+// Largest packet the encoder may produce, as libopus recommends
+#[cfg(feature = "opus")]
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+
+// Lossy and a fraction of the size of PCM, and makes up for lost packets better than silence does
+#[cfg(feature = "opus")]
+pub struct OpusCodec;
+
+#[cfg(feature = "opus")]
+struct OpusEncoder(audiopus::coder::Encoder);
+
+#[cfg(feature = "opus")]
+struct OpusDecoder(audiopus::coder::Decoder);
+
+#[cfg(feature = "opus")]
+impl AudioCodec for OpusCodec {
+    fn name(&self) -> &'static str {
+        "opus"
+    }
+
+    fn encoder(&self) -> Result<Box<dyn AudioEncoder>, AudioError> {
+        let mut encoder = audiopus::coder::Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+            audiopus::Application::Audio,
+        )?;
+
+        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(OPUS_BITRATE))?;
+
+        Ok(Box::new(OpusEncoder(encoder)))
+    }
+
+    fn decoder(&self) -> Result<Box<dyn AudioDecoder>, AudioError> {
+        Ok(Box::new(OpusDecoder(audiopus::coder::Decoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+        )?)))
+    }
+}
+
+#[cfg(feature = "opus")]
+impl AudioEncoder for OpusEncoder {
+    fn encode(&mut self, samples: &[i16]) -> Result<Vec<u8>, AudioError> {
+        let mut data = vec![0; OPUS_MAX_PACKET_SIZE];
+        let size = self.0.encode(samples, &mut data)?;
+
+        data.truncate(size);
+
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "opus")]
+impl AudioDecoder for OpusDecoder {
+    fn decode(&mut self, data: Option<&[u8]>) -> Result<Vec<i16>, AudioError> {
+        let mut samples = vec![0; PACKET_SAMPLES];
+
+        // Without a packet, the decoder carries on from the ones before it
+        let packet = data.map(audiopus::packet::Packet::try_from).transpose()?;
+        let frames = self.0.decode(packet, (&mut samples).try_into()?, false)?;
+
+        samples.truncate(frames * CHANNELS);
+
+        Ok(samples)
+    }
+}
+
+// Every supported audio codec, in order of preference
+pub fn all() -> Vec<Arc<dyn AudioCodec>> {
+    vec![
+        // Needs libopus, so it is only there when built with it
+        #[cfg(feature = "opus")]
+        Arc::new(OpusCodec),
+        Arc::new(PcmCodec),
+    ]
+}
+
+// Every supported audio codec's name, in order of preference
+pub fn names() -> Vec<String> {
+    all().iter().map(|codec| codec.name().to_string()).collect()
+}
+
+// Finds a supported audio codec by name
+pub fn get(name: &str) -> Option<Arc<dyn AudioCodec>> {
+    all().into_iter().find(|codec| codec.name() == name)
+}
+
+// Picks the first audio codec the other side supports, falling back to PCM
+pub fn negotiate(offered: &[String]) -> Arc<dyn AudioCodec> {
+    offered
+        .iter()
+        .find_map(|name| get(name))
+        .unwrap_or_else(|| Arc::new(PcmCodec))
+}
+
+// Packets to collect before playing, enough to ride out the usual differences in how long they take to arrive
+pub const JITTER_DELAY: usize = 3;
+
+// Most packets to hold. Past this, playback has fallen behind (like when the output stalled) and skips ahead.
+pub const JITTER_MAX: usize = 25;
+
+// What to play next
+#[derive(Debug)]
+pub enum Playout {
+    Packet(AudioData),
+    Lost { timestamp: u64 }, // The packet never arrived in time, so something has to be made up in its place
+}
+
+// How playback went, for logging and tests
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JitterStats {
+    pub received: u64,
+    pub played: u64,
+    pub concealed: u64, // Lost or late, and made up for
+    pub dropped: u64,   // Arrived after their turn, twice, or while too far behind
+    pub underruns: u64, // Times we ran out of packets and had to wait for more
+}
+
+// Puts audio packets back in order and evens out when they arrive, so playback doesn't stutter when the network does
+pub struct JitterBuffer {
+    packets: BTreeMap<u64, AudioData>,
+    next: Option<(u64, u64)>, // Number and timestamp of the packet to play next, None while collecting packets
+    played: Option<u64>, // Number of the last packet played, anything up to it arriving now is too late
+    delay: usize,
+    pub stats: JitterStats,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        JitterBuffer::new(JITTER_DELAY)
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(delay: usize) -> Self {
+        JitterBuffer {
+            packets: BTreeMap::new(),
+            next: None,
+            played: None,
+            delay: delay.max(1),
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, packet: AudioData) {
+        self.stats.received += 1;
+
+        let late = self.played.is_some_and(|played| packet.packet <= played);
+
+        if late || self.packets.contains_key(&packet.packet) {
+            self.stats.dropped += 1;
+
+            return;
+        }
+
+        self.packets.insert(packet.packet, packet);
+
+        if self.packets.len() <= JITTER_MAX {
+            return;
+        }
+
+        // Staying this far behind would put the audio out of step with everything else
+        while self.packets.len() > self.delay {
+            if let Some((number, _)) = self.packets.pop_first() {
+                self.played = Some(number);
+                self.stats.dropped += 1;
+            }
+        }
+
+        self.next = self
+            .packets
+            .first_key_value()
+            .map(|(number, packet)| (*number, packet.timestamp));
+    }
+
+    // The next packet to play, or None if there is nothing to play yet
+    pub fn pop(&mut self) -> Option<Playout> {
+        if self.next.is_none() {
+            if self.packets.len() < self.delay {
+                return None;
+            }
+
+            self.next = self
+                .packets
+                .first_key_value()
+                .map(|(number, packet)| (*number, packet.timestamp));
+        }
+
+        let (number, timestamp) = self.next?;
+
+        // Ran dry, so collect packets again rather than making up one after another
+        if self.packets.is_empty() {
+            self.next = None;
+            self.stats.underruns += 1;
+
+            return None;
+        }
+
+        let packet_duration = PACKET_DURATION.as_millis() as u64;
+
+        self.played = Some(number);
+
+        match self.packets.remove(&number) {
+            Some(packet) => {
+                self.stats.played += 1;
+                self.next = Some((number + 1, packet.timestamp + packet_duration));
+
+                Some(Playout::Packet(packet))
+            }
+            None => {
+                self.stats.concealed += 1;
+                self.next = Some((number + 1, timestamp + packet_duration));
+
+                Some(Playout::Lost { timestamp })
+            }
+        }
+    }
+}
+
+// How long frames can be held back for the audio, so audio that stalls doesn't freeze the picture
+pub const MAX_FRAME_DELAY: Duration = Duration::from_millis(500);
+
+// How long after the last packet was due to be heard the audio counts as stopped
+const AUDIO_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Keeps frames in step with the audio playing alongside them. Both are timestamped by the same clock on the
+// server, so a frame is shown once the audio captured at the same time is heard.
+pub struct AvSync<T> {
+    clock: Option<(u64, Instant)>, // Timestamp of the audio heard at a local instant
+    pending: VecDeque<(u64, Instant, T)>,
+}
+
+impl<T> Default for AvSync<T> {
+    fn default() -> Self {
+        AvSync {
+            clock: None,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> AvSync<T> {
+    // Notes that audio with the given timestamp was handed to an output that plays it after the given latency
+    pub fn audio_played(&mut self, timestamp: u64, latency: Duration) {
+        self.clock = Some((timestamp, Instant::now() + latency));
+    }
+
+    pub fn audio_stopped(&mut self) {
+        self.clock = None;
+    }
+
+    // Timestamp of the audio being heard now, None if none is playing
+    pub fn position(&self) -> Option<u64> {
+        let (timestamp, heard_at) = self.clock?;
+        let now = Instant::now();
+
+        if now > heard_at + PACKET_DURATION + AUDIO_TIMEOUT {
+            return None;
+        }
+
+        Some(if now >= heard_at {
+            timestamp + (now - heard_at).as_millis() as u64
+        } else {
+            timestamp.saturating_sub((heard_at - now).as_millis() as u64)
+        })
+    }
+
+    // Holds something timestamped (like a frame) until the audio catches up with it
+    pub fn push(&mut self, timestamp: u64, item: T) {
+        self.pending.push_back((timestamp, Instant::now(), item));
+    }
+
+    // Takes what the audio has caught up with, oldest first. Without audio, that is everything.
+    pub fn due(&mut self) -> Vec<T> {
+        let position = self.position();
+        let mut due = Vec::new();
+
+        while let Some((timestamp, held_since, _)) = self.pending.front() {
+            let caught_up = position.is_none_or(|position| *timestamp <= position);
+
+            if !caught_up && held_since.elapsed() < MAX_FRAME_DELAY {
+                break;
+            }
+
+            if let Some((_, _, item)) = self.pending.pop_front() {
+                due.push(item);
+            }
+        }
+
+        due
+    }
+
+    pub fn clear(&mut self) {
+        self.clock = None;
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timestamp of the first packet of a test stream
+    const START: u64 = 1_000;
+
+    fn packet(number: u64) -> AudioData {
+        AudioData {
+            packet: number,
+            timestamp: START + number * PACKET_DURATION.as_millis() as u64,
+            data: vec![number as u8],
+        }
+    }
+
+    // Pushes the packets in the given order
+    fn jitter(numbers: &[u64]) -> JitterBuffer {
+        let mut jitter = JitterBuffer::default();
+
+        for number in numbers {
+            jitter.push(packet(*number));
+        }
+
+        jitter
+    }
+
+    // What plays next, as the number of the packet or the timestamp of one made up
+    fn next(jitter: &mut JitterBuffer) -> Option<Result<u64, u64>> {
+        jitter.pop().map(|playout| match playout {
+            Playout::Packet(data) => Ok(data.packet),
+            Playout::Lost { timestamp } => Err(timestamp),
+        })
+    }
+
+    #[test]
+    fn waits_for_the_delay_before_playing() {
+        let mut jitter = jitter(&[0, 1]);
+
+        assert!(next(&mut jitter).is_none());
+
+        jitter.push(packet(2));
+
+        assert_eq!(next(&mut jitter), Some(Ok(0)));
+    }
+
+    #[test]
+    fn reordered_packets_play_in_order() {
+        let mut jitter = jitter(&[2, 0, 3, 1]);
+
+        for number in 0..4 {
+            assert_eq!(next(&mut jitter), Some(Ok(number)));
+        }
+
+        assert_eq!(jitter.stats.played, 4);
+        assert_eq!(jitter.stats.dropped, 0);
+    }
+
+    #[test]
+    fn late_and_duplicate_packets_are_dropped() {
+        let mut jitter = jitter(&[0, 1, 2]);
+
+        assert_eq!(next(&mut jitter), Some(Ok(0)));
+
+        jitter.push(packet(0));
+        jitter.push(packet(2));
+
+        assert_eq!(jitter.stats.received, 5);
+        assert_eq!(jitter.stats.dropped, 2);
+        assert_eq!(next(&mut jitter), Some(Ok(1)));
+        assert_eq!(next(&mut jitter), Some(Ok(2)));
+    }
+
+    #[test]
+    fn lost_packets_are_concealed_at_their_timestamp() {
+        let mut jitter = jitter(&[0, 2, 3]);
+
+        assert_eq!(next(&mut jitter), Some(Ok(0)));
+        assert_eq!(next(&mut jitter), Some(Err(packet(1).timestamp)));
+        assert_eq!(next(&mut jitter), Some(Ok(2)));
+
+        // Too late now that something was played in its place
+        jitter.push(packet(1));
+
+        assert_eq!(next(&mut jitter), Some(Ok(3)));
+        assert_eq!(jitter.stats.concealed, 1);
+        assert_eq!(jitter.stats.dropped, 1);
+    }
+
+    #[test]
+    fn running_dry_collects_packets_again() {
+        let mut jitter = jitter(&[0, 1, 2]);
+
+        for number in 0..3 {
+            assert_eq!(next(&mut jitter), Some(Ok(number)));
+        }
+
+        assert!(next(&mut jitter).is_none());
+        assert_eq!(jitter.stats.underruns, 1);
+
+        jitter.push(packet(3));
+        jitter.push(packet(4));
+
+        assert!(next(&mut jitter).is_none());
+        assert_eq!(jitter.stats.underruns, 1);
+
+        jitter.push(packet(5));
+
+        assert_eq!(next(&mut jitter), Some(Ok(3)));
+    }
+
+    #[test]
+    fn falling_too_far_behind_skips_ahead() {
+        let numbers: Vec<u64> = (0..=JITTER_MAX as u64).collect();
+        let mut jitter = jitter(&numbers);
+
+        let kept = JITTER_MAX as u64 + 1 - JITTER_DELAY as u64;
+
+        assert_eq!(jitter.stats.dropped, kept);
+        assert_eq!(next(&mut jitter), Some(Ok(kept)));
+
+        // What was skipped is too late to play
+        jitter.push(packet(0));
+
+        assert_eq!(jitter.stats.dropped, kept + 1);
+    }
+
+    #[test]
+    fn frames_wait_for_the_audio() {
+        let mut sync = AvSync::default();
+
+        sync.audio_played(START, Duration::ZERO);
+        sync.push(START, "now");
+        sync.push(START + 300, "later");
+
+        assert_eq!(sync.due(), vec!["now"]);
+
+        sync.audio_played(START + 300, Duration::ZERO);
+
+        assert_eq!(sync.due(), vec!["later"]);
+    }
+
+    #[test]
+    fn output_latency_holds_frames_back() {
+        let mut sync = AvSync::default();
+
+        sync.audio_played(START, Duration::from_millis(100));
+
+        assert!(sync.position().is_some_and(|position| position < START));
+
+        sync.push(START, "frame");
+
+        assert!(sync.due().is_empty());
+    }
+
+    #[test]
+    fn frames_play_at_once_without_audio() {
+        let mut sync = AvSync::default();
+
+        sync.push(START + 300, "first");
+        sync.push(START, "second");
+
+        assert_eq!(sync.due(), vec!["first", "second"]);
+
+        sync.audio_played(START, Duration::ZERO);
+        sync.push(START + 300, "third");
+        sync.audio_stopped();
+
+        assert!(sync.position().is_none());
+        assert_eq!(sync.due(), vec!["third"]);
+    }
+
+    #[test]
+    fn frames_are_not_held_past_the_limit() {
+        let mut sync = AvSync::default();
+
+        sync.push(START + 60_000, "frame");
+        sync.audio_played(START, Duration::ZERO);
+
+        assert!(sync.due().is_empty());
+
+        std::thread::sleep(MAX_FRAME_DELAY);
+
+        // Still playing, just far behind
+        sync.audio_played(START, Duration::ZERO);
+
+        assert_eq!(sync.due(), vec!["frame"]);
+    }
+
+    #[test]
+    fn pcm_round_trips() {
+        let samples: Vec<i16> = (0..PACKET_SAMPLES)
+            .map(|sample| sample as i16 * 7 - 3000)
+            .collect();
+
+        let data = PcmCodec.encoder().unwrap().encode(&samples).unwrap();
+        let mut decoder = PcmCodec.decoder().unwrap();
+
+        assert_eq!(decoder.decode(Some(&data)).unwrap(), samples);
+        assert_eq!(decoder.decode(None).unwrap(), vec![0; PACKET_SAMPLES]);
+        assert!(decoder.decode(Some(&data[1..])).is_err());
+    }
+}
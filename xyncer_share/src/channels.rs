@@ -0,0 +1,553 @@
+use std::collections::VecDeque;
+
+// Payloads are split into fragments of at most this many bytes, so a channel never waits on a busier, less urgent
+// one for longer than it takes to send one fragment
+pub const FRAGMENT_SIZE: usize = 16 * 1024;
+
+// Largest payload a peer can send, bigger ones are treated as the peer misbehaving
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+// Payloads that can wait on a channel before sending more has to wait for them to go out
+pub const QUEUE_LIMIT: usize = 64;
+
+// The streams payloads are sent over, sharing one WebSocket. Each has its own priority, so heartbeats and input
+// go out ahead of frames and files, and its own flow control window, so one can't use up the others' memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Control,   // Heartbeats, authentication, events and requests
+    Input,     // Keyboard and mouse input
+    Video,     // Frames of windows
+    Audio,     // Packets of audio
+    Clipboard, // Clipboard contents
+    File,      // File transfers
+}
+
+// Every channel, by id
+pub const CHANNELS: [Channel; 6] = [
+    Channel::Control,
+    Channel::Input,
+    Channel::Video,
+    Channel::Audio,
+    Channel::Clipboard,
+    Channel::File,
+];
+
+impl Channel {
+    // Sent on the wire, so existing ids must not change
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        CHANNELS.get(usize::from(id)).copied()
+    }
+
+    // Lower goes first. Audio is ahead of video because a late packet is heard, while a late frame is only seen late.
+    pub fn priority(self) -> u8 {
+        match self {
+            Channel::Control => 0,
+            Channel::Input => 1,
+            Channel::Audio => 2,
+            Channel::Video => 3,
+            Channel::Clipboard => 4,
+            Channel::File => 5,
+        }
+    }
+
+    // Bytes the peer can have sent on this channel before we have read them, after which it waits for us. A
+    // payload is always sent whole once started, so one that is bigger than the window still gets through.
+    pub fn window(self) -> usize {
+        match self {
+            Channel::Control => 1024 * 1024,
+            Channel::Input => 256 * 1024,
+            Channel::Video => 4 * 1024 * 1024,
+            Channel::Audio => 256 * 1024,
+            Channel::Clipboard => 1024 * 1024,
+            // More than a transfer has in flight, so chunks never wait on the channel as well as the transfer
+            Channel::File => 2 * crate::transfer::WINDOW_SIZE as usize,
+        }
+    }
+}
+
+// What each message on the WebSocket is, after its channel id
+const FRAGMENT: u8 = 0; // Part of a payload, with more to come
+const LAST_FRAGMENT: u8 = 1; // The rest of a payload
+const WINDOW_UPDATE: u8 = 2; // The peer read this many more bytes of the channel's payloads, as a little endian u32
+
+// One WebSocket message, starting with a channel id and a kind
+#[derive(Debug)]
+pub enum Message<'a> {
+    Fragment {
+        channel: Channel,
+        last: bool,
+        data: &'a [u8],
+    },
+    WindowUpdate {
+        channel: Channel,
+        bytes: u32,
+    },
+}
+
+impl<'a> Message<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::Fragment {
+                channel,
+                last,
+                data,
+            } => {
+                let mut message = Vec::with_capacity(2 + data.len());
+
+                message.push(channel.id());
+                message.push(if *last { LAST_FRAGMENT } else { FRAGMENT });
+                message.extend_from_slice(data);
+
+                message
+            }
+            Message::WindowUpdate { channel, bytes } => {
+                let mut message = vec![channel.id(), WINDOW_UPDATE];
+
+                message.extend_from_slice(&bytes.to_le_bytes());
+
+                message
+            }
+        }
+    }
+
+    pub fn decode(message: &'a [u8]) -> Result<Self, ChannelError> {
+        let [id, kind, data @ ..] = message else {
+            return Err(ChannelError::Malformed);
+        };
+
+        let channel = Channel::from_id(*id).ok_or(ChannelError::UnknownChannel(*id))?;
+
+        match *kind {
+            FRAGMENT | LAST_FRAGMENT => Ok(Message::Fragment {
+                channel,
+                last: *kind == LAST_FRAGMENT,
+                data,
+            }),
+            WINDOW_UPDATE => Ok(Message::WindowUpdate {
+                channel,
+                bytes: u32::from_le_bytes(data.try_into().map_err(|_| ChannelError::Malformed)?),
+            }),
+            _ => Err(ChannelError::Malformed),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChannelError {
+    Malformed,
+    UnknownChannel(u8),
+    WindowExceeded(Channel),
+    TooLarge(Channel),
+}
+
+impl std::fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelError::Malformed => write!(f, "Received a malformed channel message"),
+            ChannelError::UnknownChannel(id) => {
+                write!(f, "Received a message on unknown channel {}", id)
+            }
+            ChannelError::WindowExceeded(channel) => {
+                write!(
+                    f,
+                    "Received more on the {:?} channel than its window allows",
+                    channel
+                )
+            }
+            ChannelError::TooLarge(channel) => write!(
+                f,
+                "Received a payload over {} bytes on the {:?} channel",
+                MAX_PAYLOAD_SIZE, channel
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+// Payloads waiting to be sent, split into fragments and interleaved by priority
+pub struct Outbox {
+    queues: [VecDeque<Vec<u8>>; CHANNELS.len()],
+    sending: [Option<(Vec<u8>, usize)>; CHANNELS.len()], // The payload being sent, and how much of it has been
+    credit: [i64; CHANNELS.len()], // Bytes the peer still has room for, which goes below 0 while finishing a big payload
+    updates: VecDeque<(Channel, u32)>, // Window updates to send, ahead of everything else
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            queues: Default::default(),
+            sending: Default::default(),
+            credit: CHANNELS.map(|channel| channel.window() as i64),
+            updates: VecDeque::new(),
+        }
+    }
+}
+
+impl Outbox {
+    pub fn push(&mut self, channel: Channel, payload: Vec<u8>) {
+        self.queues[usize::from(channel.id())].push_back(payload);
+    }
+
+    // Payloads waiting to be started on a channel
+    pub fn queued(&self, channel: Channel) -> usize {
+        self.queues[usize::from(channel.id())].len()
+    }
+
+    // Whether anything on a channel has yet to be sent
+    pub fn is_pending(&self, channel: Channel) -> bool {
+        let index = usize::from(channel.id());
+
+        !self.queues[index].is_empty() || self.sending[index].is_some()
+    }
+
+    // Whether more sent on a channel would have to wait, either behind what is there or for the peer to make room
+    pub fn is_congested(&self, channel: Channel) -> bool {
+        self.is_pending(channel) || self.credit[usize::from(channel.id())] <= 0
+    }
+
+    // The peer read some of what we sent, making room for more
+    pub fn update_window(&mut self, channel: Channel, bytes: u32) {
+        self.credit[usize::from(channel.id())] += i64::from(bytes);
+    }
+
+    // Tells the peer we read some of what it sent
+    pub fn acknowledge(&mut self, channel: Channel, bytes: u32) {
+        self.updates.push_back((channel, bytes));
+    }
+
+    // The next message to send, or None if everything waiting has to wait for the peer
+    pub fn take_message(&mut self) -> Option<Vec<u8>> {
+        if let Some((channel, bytes)) = self.updates.pop_front() {
+            return Some(Message::WindowUpdate { channel, bytes }.encode());
+        }
+
+        let mut channels = CHANNELS;
+
+        channels.sort_by_key(|channel| channel.priority());
+
+        for channel in channels {
+            let index = usize::from(channel.id());
+
+            // Payloads are only started while the peer has room, but are then sent to the end
+            if self.sending[index].is_none() && self.credit[index] > 0 {
+                self.sending[index] = self.queues[index].pop_front().map(|payload| (payload, 0));
+            }
+
+            let Some((payload, sent)) = self.sending[index].as_mut() else {
+                continue;
+            };
+
+            let end = payload.len().min(*sent + FRAGMENT_SIZE);
+            let last = end == payload.len();
+
+            let message = Message::Fragment {
+                channel,
+                last,
+                data: &payload[*sent..end],
+            }
+            .encode();
+
+            self.credit[index] -= (end - *sent) as i64;
+            *sent = end;
+
+            if last {
+                self.sending[index] = None;
+            }
+
+            return Some(message);
+        }
+
+        None
+    }
+}
+
+// Payloads received, put back together from their fragments and waiting to be read, most urgent first
+#[derive(Default)]
+pub struct Inbox {
+    partial: [Vec<u8>; CHANNELS.len()],
+    payloads: [VecDeque<Vec<u8>>; CHANNELS.len()],
+    outstanding: [usize; CHANNELS.len()], // Bytes received and not yet read, which count against the window
+    unacknowledged: [usize; CHANNELS.len()], // Bytes read that the peer hasn't been told about yet
+}
+
+impl Inbox {
+    // Takes in a message, returning the window update if that's what it was
+    pub fn receive(&mut self, message: &[u8]) -> Result<Option<(Channel, u32)>, ChannelError> {
+        match Message::decode(message)? {
+            Message::Fragment {
+                channel,
+                last,
+                data,
+            } => {
+                let index = usize::from(channel.id());
+
+                // Peers can only start a payload while we have room for it
+                if self.partial[index].is_empty() && self.outstanding[index] >= channel.window() {
+                    return Err(ChannelError::WindowExceeded(channel));
+                }
+
+                if self.partial[index].len() + data.len() > MAX_PAYLOAD_SIZE {
+                    return Err(ChannelError::TooLarge(channel));
+                }
+
+                self.partial[index].extend_from_slice(data);
+                self.outstanding[index] += data.len();
+
+                if last {
+                    let payload = std::mem::take(&mut self.partial[index]);
+
+                    self.payloads[index].push_back(payload);
+                }
+
+                Ok(None)
+            }
+            Message::WindowUpdate { channel, bytes } => Ok(Some((channel, bytes))),
+        }
+    }
+
+    // The most urgent payload waiting to be read
+    pub fn pop(&mut self) -> Option<(Channel, Vec<u8>)> {
+        let mut channels = CHANNELS;
+
+        channels.sort_by_key(|channel| channel.priority());
+
+        channels.into_iter().find_map(|channel| {
+            let index = usize::from(channel.id());
+            let payload = self.payloads[index].pop_front()?;
+
+            self.outstanding[index] -= payload.len();
+            self.unacknowledged[index] += payload.len();
+
+            Some((channel, payload))
+        })
+    }
+
+    // Bytes of a channel to tell the peer we read, once there are enough to be worth a message
+    pub fn acknowledge(&mut self, channel: Channel) -> Option<u32> {
+        let index = usize::from(channel.id());
+
+        if self.unacknowledged[index] < channel.window() / 4 {
+            return None;
+        }
+
+        let bytes = std::mem::take(&mut self.unacknowledged[index]);
+
+        Some(bytes as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A payload of the given size that differs from byte to byte, so fragments put back out of order would show
+    fn payload(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Sends everything the outbox can, returning how many messages that took
+    fn deliver(outbox: &mut Outbox, inbox: &mut Inbox) -> usize {
+        let mut messages = 0;
+
+        while let Some(message) = outbox.take_message() {
+            inbox.receive(&message).unwrap();
+            messages += 1;
+        }
+
+        messages
+    }
+
+    // Sends the next message, returning its channel
+    fn deliver_one(outbox: &mut Outbox, inbox: &mut Inbox) -> Channel {
+        let message = outbox.take_message().unwrap();
+
+        inbox.receive(&message).unwrap();
+
+        match Message::decode(&message).unwrap() {
+            Message::Fragment { channel, .. } | Message::WindowUpdate { channel, .. } => channel,
+        }
+    }
+
+    #[test]
+    fn fragmented_payloads_are_put_back_together() {
+        let mut outbox = Outbox::default();
+        let mut inbox = Inbox::default();
+        let sent = payload(2 * FRAGMENT_SIZE + 100);
+
+        outbox.push(Channel::File, sent.clone());
+
+        let fragments: Vec<_> = std::iter::from_fn(|| outbox.take_message()).collect();
+
+        assert_eq!(fragments.len(), 3);
+
+        for (i, fragment) in fragments.iter().enumerate() {
+            let Message::Fragment { channel, last, .. } = Message::decode(fragment).unwrap() else {
+                panic!("expected a fragment");
+            };
+
+            assert_eq!(channel, Channel::File);
+            assert_eq!(last, i == 2);
+
+            inbox.receive(fragment).unwrap();
+
+            // Nothing can be read until the last fragment is in
+            if !last {
+                assert!(inbox.pop().is_none());
+            }
+        }
+
+        assert_eq!(inbox.pop(), Some((Channel::File, sent)));
+        assert!(inbox.pop().is_none());
+    }
+
+    #[test]
+    fn urgent_channels_go_first() {
+        let mut outbox = Outbox::default();
+        let mut inbox = Inbox::default();
+
+        outbox.push(Channel::File, payload(3 * FRAGMENT_SIZE));
+        outbox.push(Channel::Video, vec![3]);
+
+        // Video is more urgent than the file, even though the file was pushed first
+        assert_eq!(deliver_one(&mut outbox, &mut inbox), Channel::Video);
+        assert_eq!(deliver_one(&mut outbox, &mut inbox), Channel::File);
+
+        // Input pushed midway through the file goes out between its fragments
+        outbox.push(Channel::Input, vec![1]);
+        outbox.push(Channel::Control, vec![0]);
+
+        assert_eq!(deliver_one(&mut outbox, &mut inbox), Channel::Control);
+        assert_eq!(deliver_one(&mut outbox, &mut inbox), Channel::Input);
+        assert_eq!(deliver(&mut outbox, &mut inbox), 2);
+
+        // And whatever is received is read most urgent first
+        let read: Vec<_> = std::iter::from_fn(|| inbox.pop())
+            .map(|(channel, _)| channel)
+            .collect();
+
+        assert_eq!(
+            read,
+            [
+                Channel::Control,
+                Channel::Input,
+                Channel::Video,
+                Channel::File
+            ]
+        );
+    }
+
+    #[test]
+    fn sending_waits_for_the_peer_to_make_room() {
+        // Each side of the connection
+        let (mut client_outbox, mut client_inbox) = (Outbox::default(), Inbox::default());
+        let (mut server_outbox, mut server_inbox) = (Outbox::default(), Inbox::default());
+
+        let fits = Channel::Input.window() / FRAGMENT_SIZE;
+
+        for _ in 0..fits + 1 {
+            client_outbox.push(Channel::Input, payload(FRAGMENT_SIZE));
+        }
+
+        // The window is used up, with one payload still waiting
+        assert_eq!(deliver(&mut client_outbox, &mut server_inbox), fits);
+        assert!(client_outbox.is_congested(Channel::Input));
+        assert_eq!(client_outbox.queued(Channel::Input), 1);
+
+        // Reading makes room, which the server tells the client about
+        while server_inbox.pop().is_some() {}
+
+        let bytes = server_inbox.acknowledge(Channel::Input).unwrap();
+
+        assert_eq!(bytes as usize, Channel::Input.window());
+
+        server_outbox.acknowledge(Channel::Input, bytes);
+
+        let update = server_outbox.take_message().unwrap();
+
+        assert_eq!(
+            client_inbox.receive(&update).unwrap(),
+            Some((Channel::Input, bytes))
+        );
+
+        client_outbox.update_window(Channel::Input, bytes);
+
+        assert_eq!(deliver(&mut client_outbox, &mut server_inbox), 1);
+        assert!(!client_outbox.is_congested(Channel::Input));
+    }
+
+    #[test]
+    fn payloads_bigger_than_the_window_are_sent_whole() {
+        let mut outbox = Outbox::default();
+        let mut inbox = Inbox::default();
+        let sent = payload(Channel::Input.window() + FRAGMENT_SIZE);
+
+        outbox.push(Channel::Input, sent.clone());
+        outbox.push(Channel::Input, vec![1]);
+
+        deliver(&mut outbox, &mut inbox);
+
+        // The next one waits until the peer has room again
+        assert_eq!(inbox.pop(), Some((Channel::Input, sent)));
+        assert!(inbox.pop().is_none());
+        assert_eq!(outbox.queued(Channel::Input), 1);
+    }
+
+    #[test]
+    fn peers_past_their_window_are_rejected() {
+        let mut inbox = Inbox::default();
+        let fragment = Message::Fragment {
+            channel: Channel::Input,
+            last: true,
+            data: &payload(FRAGMENT_SIZE),
+        }
+        .encode();
+
+        for _ in 0..Channel::Input.window() / FRAGMENT_SIZE {
+            inbox.receive(&fragment).unwrap();
+        }
+
+        assert!(matches!(
+            inbox.receive(&fragment),
+            Err(ChannelError::WindowExceeded(Channel::Input))
+        ));
+
+        // Other channels have windows of their own
+        let fragment = Message::Fragment {
+            channel: Channel::Control,
+            last: true,
+            data: &[0],
+        }
+        .encode();
+
+        assert!(inbox.receive(&fragment).is_ok());
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        let mut inbox = Inbox::default();
+
+        for message in [
+            &[][..],
+            &[Channel::Control.id()],
+            &[Channel::Control.id(), 9, 1, 2],
+            &[Channel::Control.id(), WINDOW_UPDATE, 1, 2],
+            &[Channel::Control.id(), WINDOW_UPDATE, 1, 2, 3, 4, 5],
+        ] {
+            assert!(
+                matches!(inbox.receive(message), Err(ChannelError::Malformed)),
+                "{:?}",
+                message
+            );
+        }
+
+        assert!(matches!(
+            inbox.receive(&[CHANNELS.len() as u8, FRAGMENT]),
+            Err(ChannelError::UnknownChannel(6))
+        ));
+    }
+}
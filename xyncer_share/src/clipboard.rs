@@ -0,0 +1,346 @@
+use std::hash::{Hash, Hasher};
+
+use crate::payloads::{ClipboardData, ClipboardItem};
+
+// Formats synced between clipboards
+pub const TEXT: &str = "text/plain;charset=utf-8";
+pub const HTML: &str = "text/html";
+pub const PNG: &str = "image/png";
+
+// Largest clipboard contents to send or accept unless configured otherwise, in bytes
+pub const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+pub type ClipboardError = Box<dyn std::error::Error + Send + Sync>;
+
+// A clipboard synced with the peer's
+pub trait Clipboard: Send + Sync {
+    // Empty if the clipboard holds nothing we can sync
+    fn read(&self) -> Result<ClipboardData, ClipboardError>;
+
+    // Puts the best format the clipboard supports on it
+    fn write(&self, data: &ClipboardData) -> Result<(), ClipboardError>;
+}
+
+impl ClipboardData {
+    pub fn size(&self) -> usize {
+        self.items.iter().map(|item| item.data.len()).sum()
+    }
+
+    pub fn get(&self, mime_type: &str) -> Option<&[u8]> {
+        self.items
+            .iter()
+            .find(|item| item.mime_type == mime_type)
+            .map(|item| item.data.as_slice())
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.get(TEXT)
+            .and_then(|text| std::str::from_utf8(text).ok())
+    }
+
+    // Drops the formats that don't fit in the size limit, keeping the best that do
+    pub fn limit(self, max_size: usize) -> ClipboardData {
+        let mut size = 0;
+
+        let items = self
+            .items
+            .into_iter()
+            .filter(|item| {
+                if size + item.data.len() > max_size {
+                    log::debug!(
+                        "Leaving {} out of the clipboard, {} bytes is over the limit",
+                        item.mime_type,
+                        item.data.len()
+                    );
+
+                    return false;
+                }
+
+                size += item.data.len();
+
+                true
+            })
+            .collect();
+
+        ClipboardData { items }
+    }
+}
+
+impl ClipboardItem {
+    pub fn new(mime_type: &str, data: Vec<u8>) -> Self {
+        ClipboardItem {
+            mime_type: mime_type.to_string(),
+            data,
+        }
+    }
+}
+
+fn fingerprint(data: &ClipboardData) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+    data.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+// Watches a clipboard for changes made on this side, so the peer's changes don't bounce back to it
+#[derive(Default)]
+pub struct ClipboardWatcher {
+    last_seen: Option<u64>,
+}
+
+impl ClipboardWatcher {
+    // The clipboard's contents if they changed since we last looked. The first look only
+    // remembers them, so connecting doesn't overwrite the peer's clipboard.
+    pub fn poll(&mut self, clipboard: &dyn Clipboard) -> Option<ClipboardData> {
+        let data = match clipboard.read() {
+            Ok(data) => data,
+            Err(e) => {
+                // Usually another app holding the clipboard, we'll look again next time
+                log::debug!("Could not read the clipboard: {}", e);
+
+                return None;
+            }
+        };
+
+        let current = fingerprint(&data);
+        let previous = self.last_seen.replace(current);
+
+        if previous.is_none() || previous == Some(current) || data.items.is_empty() {
+            return None;
+        }
+
+        Some(data)
+    }
+
+    // Puts the peer's clipboard on ours, without it counting as a change of ours
+    pub fn apply(
+        &mut self,
+        clipboard: &dyn Clipboard,
+        data: &ClipboardData,
+    ) -> Result<(), ClipboardError> {
+        clipboard.write(data)?;
+
+        // Clipboards convert what they are given, so go by what it reads back as
+        match clipboard.read() {
+            Ok(data) => self.last_seen = Some(fingerprint(&data)),
+            Err(e) => log::debug!("Could not read the clipboard back: {}", e),
+        }
+
+        Ok(())
+    }
+}
+
+// The clipboard of the OS
+#[cfg(feature = "system-clipboard")]
+pub struct SystemClipboard {
+    clipboard: std::sync::Mutex<arboard::Clipboard>,
+
+    // Encoding images is slow, and they are read every time we look for changes
+    png_cache: std::sync::Mutex<Option<(u64, Vec<u8>)>>,
+}
+
+#[cfg(feature = "system-clipboard")]
+impl SystemClipboard {
+    pub fn new() -> Result<Self, ClipboardError> {
+        Ok(SystemClipboard {
+            clipboard: std::sync::Mutex::new(arboard::Clipboard::new()?),
+            png_cache: std::sync::Mutex::new(None),
+        })
+    }
+
+    fn encode_png(&self, image: arboard::ImageData) -> Result<Vec<u8>, ClipboardError> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+        (image.width, image.height, &image.bytes).hash(&mut hasher);
+
+        let hash = hasher.finish();
+        let mut png_cache = self.png_cache.lock().unwrap();
+
+        if let Some((_, png)) = png_cache.as_ref().filter(|(cached, _)| *cached == hash) {
+            return Ok(png.clone());
+        }
+
+        let mut png = Vec::new();
+
+        image::ImageEncoder::write_image(
+            image::codecs::png::PngEncoder::new(&mut png),
+            &image.bytes,
+            image.width as u32,
+            image.height as u32,
+            image::ExtendedColorType::Rgba8,
+        )?;
+
+        *png_cache = Some((hash, png.clone()));
+
+        Ok(png)
+    }
+}
+
+// Missing formats aren't an error, the clipboard just holds something else
+#[cfg(feature = "system-clipboard")]
+fn available<T>(result: Result<T, arboard::Error>) -> Result<Option<T>, ClipboardError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(feature = "system-clipboard")]
+impl Clipboard for SystemClipboard {
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        let mut clipboard = self.clipboard.lock().unwrap();
+        let mut items = Vec::new();
+
+        if let Some(html) = available(clipboard.get().html())? {
+            items.push(ClipboardItem::new(HTML, html.into_bytes()));
+        }
+
+        if let Some(text) = available(clipboard.get_text())? {
+            items.push(ClipboardItem::new(TEXT, text.into_bytes()));
+        }
+
+        // Copied text often comes with a picture of itself, which isn't worth sending
+        if items.is_empty() {
+            if let Some(image) = available(clipboard.get_image())? {
+                drop(clipboard);
+
+                items.push(ClipboardItem::new(PNG, self.encode_png(image)?));
+            }
+        }
+
+        Ok(ClipboardData { items })
+    }
+
+    fn write(&self, data: &ClipboardData) -> Result<(), ClipboardError> {
+        let mut clipboard = self.clipboard.lock().unwrap();
+
+        let html = data
+            .get(HTML)
+            .and_then(|html| std::str::from_utf8(html).ok());
+
+        match (html, data.text(), data.get(PNG)) {
+            (Some(html), text, _) => clipboard.set_html(html, text)?,
+            (None, Some(text), _) => clipboard.set_text(text)?,
+            (None, None, Some(png)) => {
+                let image =
+                    image::load_from_memory_with_format(png, image::ImageFormat::Png)?.into_rgba8();
+
+                clipboard.set_image(arboard::ImageData {
+                    width: image.width() as usize,
+                    height: image.height() as usize,
+                    bytes: image.into_raw().into(),
+                })?
+            }
+            // Nothing this clipboard can hold
+            _ => log::debug!("Not writing clipboard data with no supported format"),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // Holds text as plain text only, like clipboards that drop the formats they don't know
+    #[derive(Default)]
+    struct TextClipboard {
+        text: Mutex<Option<Vec<u8>>>,
+    }
+
+    impl Clipboard for TextClipboard {
+        fn read(&self) -> Result<ClipboardData, ClipboardError> {
+            let items = self
+                .text
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|text| ClipboardItem::new(TEXT, text.clone()))
+                .collect();
+
+            Ok(ClipboardData { items })
+        }
+
+        fn write(&self, data: &ClipboardData) -> Result<(), ClipboardError> {
+            *self.text.lock().unwrap() = data.get(TEXT).map(<[u8]>::to_vec);
+
+            Ok(())
+        }
+    }
+
+    fn text(text: &str) -> ClipboardData {
+        ClipboardData {
+            items: vec![ClipboardItem::new(TEXT, text.as_bytes().to_vec())],
+        }
+    }
+
+    #[test]
+    fn changes_are_seen_once() {
+        let clipboard = TextClipboard::default();
+        let mut watcher = ClipboardWatcher::default();
+
+        clipboard.write(&text("before")).unwrap();
+
+        // What was there before connecting isn't a change
+        assert!(watcher.poll(&clipboard).is_none());
+
+        clipboard.write(&text("copied")).unwrap();
+
+        assert_eq!(watcher.poll(&clipboard).unwrap().text(), Some("copied"));
+        assert!(watcher.poll(&clipboard).is_none());
+    }
+
+    #[test]
+    fn the_peers_changes_do_not_bounce_back() {
+        let clipboard = TextClipboard::default();
+        let mut watcher = ClipboardWatcher::default();
+
+        assert!(watcher.poll(&clipboard).is_none());
+
+        // Comes back without the HTML, and still isn't taken for a change of ours
+        let mut data = text("from the peer");
+
+        data.items.insert(
+            0,
+            ClipboardItem::new(HTML, b"<b>from the peer</b>".to_vec()),
+        );
+
+        watcher.apply(&clipboard, &data).unwrap();
+
+        assert_eq!(clipboard.read().unwrap().text(), Some("from the peer"));
+        assert!(watcher.poll(&clipboard).is_none());
+
+        clipboard.write(&text("copied after")).unwrap();
+
+        assert_eq!(
+            watcher.poll(&clipboard).unwrap().text(),
+            Some("copied after")
+        );
+    }
+
+    #[test]
+    fn formats_over_the_limit_are_left_out() {
+        let data = ClipboardData {
+            items: vec![
+                ClipboardItem::new(HTML, vec![b'h'; 16]),
+                ClipboardItem::new(TEXT, vec![b't'; 4]),
+                ClipboardItem::new(PNG, vec![b'p'; 8]),
+            ],
+        };
+
+        // The HTML doesn't fit, but the text and picture after it do
+        let limited = data.clone().limit(12);
+
+        assert_eq!(limited.get(HTML), None);
+        assert_eq!(limited.get(TEXT), Some(&[b't'; 4][..]));
+        assert_eq!(limited.get(PNG), Some(&[b'p'; 8][..]));
+        assert_eq!(limited.size(), 12);
+
+        assert!(data.limit(2).items.is_empty());
+    }
+}
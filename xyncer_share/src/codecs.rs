@@ -0,0 +1,241 @@
+use std::sync::Arc;
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+// Frames can't be larger than this on either side, which is as large as most GPUs can draw, and
+// keeps a peer from making us allocate more than 256 MiB for one
+pub const MAX_DIMENSION: u32 = 8192;
+
+// The number of bytes in an RGBA8 rectangle, or `None` if it is larger than frames can be
+pub fn rgba_len(width: u32, height: u32) -> Option<usize> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return None;
+    }
+
+    (width as usize)
+        .checked_mul(height as usize)?
+        .checked_mul(4)
+}
+
+// Compresses the pixels of dirty rectangles. Codecs are negotiated by name, so adding one only
+// means implementing this trait and listing it in `all`.
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, width: u32, height: u32, rgba: &[u8]) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>, CodecError>;
+}
+
+// Sends pixels as they are
+pub struct RawCodec;
+
+impl Codec for RawCodec {
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    fn encode(&self, _width: u32, _height: u32, rgba: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(rgba.to_vec())
+    }
+
+    fn decode(&self, _width: u32, _height: u32, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(data.to_vec())
+    }
+}
+
+// Zstandard compression level, low enough to keep up with the frame rate
+const ZSTD_LEVEL: i32 = 3;
+
+// Lossless, better compression than LZ4 for a little more CPU
+pub struct ZstdCodec;
+
+impl Codec for ZstdCodec {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn encode(&self, _width: u32, _height: u32, rgba: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(zstd::bulk::compress(rgba, ZSTD_LEVEL)?)
+    }
+
+    fn decode(&self, width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let length = rgba_len(width, height).ok_or("Rectangle is too large")?;
+
+        Ok(zstd::bulk::decompress(data, length)?)
+    }
+}
+
+// Lossless and very fast
+pub struct Lz4Codec;
+
+impl Codec for Lz4Codec {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn encode(&self, _width: u32, _height: u32, rgba: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(lz4_flex::compress(rgba))
+    }
+
+    fn decode(&self, width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let length = rgba_len(width, height).ok_or("Rectangle is too large")?;
+
+        Ok(lz4_flex::decompress(data, length)?)
+    }
+}
+
+// JPEG quality, from 1 to 100
+const JPEG_QUALITY: u8 = 80;
+
+// Lossy, for slow connections. Drops the alpha channel.
+pub struct JpegCodec;
+
+impl Codec for JpegCodec {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn encode(&self, width: u32, height: u32, rgba: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let rgb: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+
+        let mut data = Vec::new();
+
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).encode(
+            &rgb,
+            width,
+            height,
+            image::ExtendedColorType::Rgb8,
+        )?;
+
+        Ok(data)
+    }
+
+    fn decode(&self, width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if rgba_len(width, height).is_none() {
+            return Err("Rectangle is too large".into());
+        }
+
+        // The size is checked from the header before decoding, so a peer can't make us decode a huge image
+        let decoder = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(data))?;
+
+        if image::ImageDecoder::dimensions(&decoder) != (width, height) {
+            return Err("JPEG size does not match the rectangle".into());
+        }
+
+        Ok(image::DynamicImage::from_decoder(decoder)?
+            .into_rgba8()
+            .into_raw())
+    }
+}
+
+// Every supported codec, in order of preference
+pub fn all() -> Vec<Arc<dyn Codec>> {
+    vec![
+        Arc::new(ZstdCodec),
+        Arc::new(Lz4Codec),
+        Arc::new(JpegCodec),
+        Arc::new(RawCodec),
+    ]
+}
+
+// Every supported codec's name, in order of preference
+pub fn names() -> Vec<String> {
+    all().iter().map(|codec| codec.name().to_string()).collect()
+}
+
+// Finds a supported codec by name
+pub fn get(name: &str) -> Option<Arc<dyn Codec>> {
+    all().into_iter().find(|codec| codec.name() == name)
+}
+
+// Picks the first codec the other side supports, falling back to raw
+pub fn negotiate(offered: &[String]) -> Arc<dyn Codec> {
+    offered
+        .iter()
+        .find_map(|name| get(name))
+        .unwrap_or_else(|| Arc::new(RawCodec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_rectangles_are_rejected() {
+        for codec in [&ZstdCodec as &dyn Codec, &Lz4Codec] {
+            let data = codec.encode(2, 2, &[0; 16]).unwrap();
+
+            assert!(
+                codec.decode(u32::MAX, u32::MAX, &data).is_err(),
+                "{}",
+                codec.name()
+            );
+            assert!(
+                codec.decode(MAX_DIMENSION + 1, 1, &data).is_err(),
+                "{}",
+                codec.name()
+            );
+            assert_eq!(
+                codec.decode(2, 2, &data).unwrap(),
+                vec![0; 16],
+                "{}",
+                codec.name()
+            );
+        }
+    }
+
+    // A gradient, which JPEG keeps close to the original
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| [(x * 4) as u8, (y * 4) as u8, 128, 255])
+            .collect()
+    }
+
+    #[test]
+    fn jpeg_frames_come_back_close_to_what_was_sent() {
+        let rgba = gradient(64, 48);
+        let data = JpegCodec.encode(64, 48, &rgba).unwrap();
+
+        let decoded = JpegCodec.decode(64, 48, &data).unwrap();
+
+        assert_eq!(decoded.len(), rgba.len());
+
+        for (decoded, sent) in decoded.iter().zip(&rgba) {
+            assert!(
+                decoded.abs_diff(*sent) <= 8,
+                "{} is too far from {}",
+                decoded,
+                sent
+            );
+        }
+
+        // The size has to be the one the rectangle says
+        assert!(JpegCodec.decode(48, 64, &data).is_err());
+    }
+
+    #[test]
+    fn oversized_jpeg_frames_are_rejected_before_decoding() {
+        let data = JpegCodec.encode(2, 2, &gradient(2, 2)).unwrap();
+
+        assert!(JpegCodec.decode(MAX_DIMENSION + 1, 1, &data).is_err());
+
+        // Claims to be as large as it can be in its header, far more than a 2x2 image has the data for
+        let mut huge = data;
+        let frame = huge
+            .windows(2)
+            .position(|marker| marker == [0xFF, 0xC0])
+            .unwrap();
+
+        huge[frame + 5..frame + 9].copy_from_slice(&[0xFF; 4]);
+
+        assert!(JpegCodec.decode(2, 2, &huge).is_err());
+        assert!(JpegCodec
+            .decode(u16::MAX.into(), u16::MAX.into(), &huge)
+            .is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use std::sync::Arc;
+
+use crate::codecs::{self, Codec, CodecError};
+use crate::payloads::{DirtyRect, FrameData};
+
+// Frames are compared in square tiles of this many pixels
+pub const TILE_SIZE: u32 = 64;
+
+// Send a full frame at least this often, so clients recover from anything the deltas missed
+pub const KEYFRAME_INTERVAL: u32 = 150;
+
+// An RGBA8 frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+// A rectangle of a frame, as x, y, width and height
+type Area = (u32, u32, u32, u32);
+
+impl Frame {
+    // Whether the frame is small enough to send, and has a pixel for every spot
+    fn is_valid(&self) -> bool {
+        codecs::rgba_len(self.width, self.height) == Some(self.rgba.len())
+    }
+
+    // Where a pixel of a valid frame starts
+    fn offset(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * 4
+    }
+
+    // Copies a rectangle of pixels out of a valid frame
+    fn pixels(&self, (x, y, width, height): Area) -> Vec<u8> {
+        let row_length = width as usize * 4;
+        let mut rgba = Vec::with_capacity(row_length * height as usize);
+
+        for row in y..y + height {
+            let start = self.offset(x, row);
+
+            rgba.extend_from_slice(&self.rgba[start..start + row_length]);
+        }
+
+        rgba
+    }
+
+    // Whether a tile differs from the same tile in another valid frame of the same size
+    fn tile_differs(&self, other: &Frame, (x, y, width, height): Area) -> bool {
+        (y..y + height).any(|row| {
+            let start = self.offset(x, row);
+            let end = start + width as usize * 4;
+
+            self.rgba[start..end] != other.rgba[start..end]
+        })
+    }
+}
+
+// Turns captured frames into frame updates, sending only the tiles that changed since the last one
+#[derive(Clone)]
+pub struct FrameEncoder {
+    codec: Arc<dyn Codec>,
+    previous: Option<Frame>,
+    frames_since_keyframe: u32,
+    keyframe_requested: bool,
+}
+
+impl FrameEncoder {
+    pub fn new(codec: Arc<dyn Codec>) -> Self {
+        FrameEncoder {
+            codec,
+            previous: None,
+            frames_since_keyframe: 0,
+            keyframe_requested: false,
+        }
+    }
+
+    // Makes the next update a keyframe
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    // Encodes a frame, returning `None` if nothing changed since the last one
+    pub fn encode(
+        &mut self,
+        window_id: u32,
+        timestamp: u64,
+        frame: Frame,
+    ) -> Result<Option<FrameData>, CodecError> {
+        // The areas below are only in bounds for frames with the pixels their size says
+        if !frame.is_valid() {
+            return Err(Box::new(FrameError::InvalidRect));
+        }
+
+        let keyframe = match &self.previous {
+            Some(previous) => {
+                self.keyframe_requested
+                    || self.frames_since_keyframe + 1 >= KEYFRAME_INTERVAL
+                    || previous.width != frame.width
+                    || previous.height != frame.height
+            }
+            None => true,
+        };
+
+        let areas = if keyframe {
+            vec![(0, 0, frame.width, frame.height)]
+        } else {
+            let areas = self.dirty_areas(&frame);
+
+            if areas.is_empty() {
+                return Ok(None);
+            }
+
+            areas
+        };
+
+        let rects = areas
+            .into_iter()
+            .map(|area| {
+                let (x, y, width, height) = area;
+
+                Ok(DirtyRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data: self.codec.encode(width, height, &frame.pixels(area))?,
+                })
+            })
+            .collect::<Result<Vec<_>, CodecError>>()?;
+
+        // Only count the frame as sent once it has been encoded
+        if keyframe {
+            self.frames_since_keyframe = 0;
+            self.keyframe_requested = false;
+        } else {
+            self.frames_since_keyframe += 1;
+        }
+
+        let data = FrameData {
+            window_id,
+            timestamp,
+            width: frame.width,
+            height: frame.height,
+            keyframe,
+            rects,
+        };
+
+        self.previous = Some(frame);
+
+        Ok(Some(data))
+    }
+
+    // Finds the tiles that changed, merging neighbouring tiles in the same row into one area
+    fn dirty_areas(&self, frame: &Frame) -> Vec<Area> {
+        let Some(previous) = &self.previous else {
+            return Vec::new();
+        };
+
+        let mut areas = Vec::new();
+
+        for tile_y in (0..frame.height).step_by(TILE_SIZE as usize) {
+            let height = TILE_SIZE.min(frame.height - tile_y);
+
+            // Start of the run of dirty tiles we are in, if any
+            let mut run_start = None;
+
+            for tile_x in (0..frame.width).step_by(TILE_SIZE as usize) {
+                let width = TILE_SIZE.min(frame.width - tile_x);
+
+                if frame.tile_differs(previous, (tile_x, tile_y, width, height)) {
+                    run_start.get_or_insert(tile_x);
+                } else if let Some(start) = run_start.take() {
+                    areas.push((start, tile_y, tile_x - start, height));
+                }
+            }
+
+            if let Some(start) = run_start {
+                areas.push((start, tile_y, frame.width - start, height));
+            }
+        }
+
+        areas
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    // A delta arrived without a keyframe to apply it to
+    MissingKeyframe,
+    // A rectangle doesn't fit inside the frame, has the wrong number of pixels, or the frame is too large
+    InvalidRect,
+    // The codec could not decompress a rectangle
+    Codec(CodecError),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::MissingKeyframe => write!(f, "Received a frame delta before a keyframe"),
+            FrameError::InvalidRect => write!(f, "Received an invalid dirty rectangle"),
+            FrameError::Codec(e) => write!(f, "Could not decode dirty rectangle: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+// Rebuilds frames from the updates made by a `FrameEncoder`
+#[derive(Clone)]
+pub struct FrameDecoder {
+    codec: Arc<dyn Codec>,
+    frame: Option<Frame>,
+}
+
+impl FrameDecoder {
+    pub fn new(codec: Arc<dyn Codec>) -> Self {
+        FrameDecoder { codec, frame: None }
+    }
+
+    // Applies an update, returning the reconstructed frame
+    pub fn decode(&mut self, data: &FrameData) -> Result<&Frame, FrameError> {
+        if data.keyframe {
+            let length = codecs::rgba_len(data.width, data.height).ok_or(FrameError::InvalidRect)?;
+
+            self.frame = Some(Frame {
+                width: data.width,
+                height: data.height,
+                rgba: vec![0; length],
+            });
+        }
+
+        // Taken out while the update is applied, so a bad update leaves nothing to apply the next delta to
+        let mut frame = match self.frame.take() {
+            Some(frame) if frame.width == data.width && frame.height == data.height => frame,
+            _ => return Err(FrameError::MissingKeyframe),
+        };
+
+        for rect in &data.rects {
+            let fits = rect.x.checked_add(rect.width).is_some_and(|right| right <= frame.width)
+                && rect.y.checked_add(rect.height).is_some_and(|bottom| bottom <= frame.height);
+
+            if !fits {
+                return Err(FrameError::InvalidRect);
+            }
+
+            let pixels = self
+                .codec
+                .decode(rect.width, rect.height, &rect.data)
+                .map_err(FrameError::Codec)?;
+
+            // Fitting inside the frame keeps these sizes small
+            let row_length = rect.width as usize * 4;
+
+            if pixels.len() != row_length * rect.height as usize {
+                return Err(FrameError::InvalidRect);
+            }
+
+            // Empty rectangles have no rows to copy
+            if row_length == 0 {
+                continue;
+            }
+
+            for (row, row_pixels) in pixels.chunks_exact(row_length).enumerate() {
+                let start = frame.offset(rect.x, rect.y + row as u32);
+
+                frame.rgba[start..start + row_length].copy_from_slice(row_pixels);
+            }
+        }
+
+        Ok(self.frame.insert(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codecs::{Lz4Codec, RawCodec, ZstdCodec};
+
+    // The lossless codecs, which have to rebuild every pixel
+    fn codecs() -> Vec<Arc<dyn Codec>> {
+        vec![Arc::new(RawCodec), Arc::new(ZstdCodec), Arc::new(Lz4Codec)]
+    }
+
+    // The server's synthetic test pattern: a gradient with a square that moves every frame
+    fn synthetic(width: u32, height: u32, frame_count: u32) -> Frame {
+        let square_x = (frame_count * 8) % width.saturating_sub(32).max(1);
+        let square_y = height.saturating_sub(32) / 2;
+
+        let mut rgba = Vec::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                if (square_x..square_x + 32).contains(&x) && (square_y..square_y + 32).contains(&y)
+                {
+                    rgba.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+                } else {
+                    rgba.extend_from_slice(&[
+                        (x * 255 / width) as u8,
+                        (y * 255 / height) as u8,
+                        0x40,
+                        0xff,
+                    ]);
+                }
+            }
+        }
+
+        Frame {
+            width,
+            height,
+            rgba,
+        }
+    }
+
+    #[test]
+    fn keyframes_are_pixel_exact() {
+        for codec in codecs() {
+            let mut encoder = FrameEncoder::new(codec.clone());
+            let mut decoder = FrameDecoder::new(codec.clone());
+            let frame = synthetic(200, 130, 0);
+
+            let data = encoder.encode(1, 0, frame.clone()).unwrap().unwrap();
+
+            assert!(data.keyframe, "{}", codec.name());
+            assert_eq!(decoder.decode(&data).unwrap(), &frame, "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn deltas_are_pixel_exact() {
+        for codec in codecs() {
+            let mut encoder = FrameEncoder::new(codec.clone());
+            let mut decoder = FrameDecoder::new(codec.clone());
+
+            for frame_count in 0..20 {
+                let frame = synthetic(200, 130, frame_count);
+                let data = encoder.encode(1, 0, frame.clone()).unwrap().unwrap();
+
+                // Only the tiles the square passed through are sent after the first frame
+                assert_eq!(data.keyframe, frame_count == 0, "{}", codec.name());
+
+                if !data.keyframe {
+                    let sent: u32 = data.rects.iter().map(|rect| rect.width * rect.height).sum();
+
+                    assert!(sent < 200 * 130, "{}", codec.name());
+                }
+
+                assert_eq!(decoder.decode(&data).unwrap(), &frame, "{}", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn unchanged_frames_are_skipped() {
+        for codec in codecs() {
+            let mut encoder = FrameEncoder::new(codec.clone());
+
+            encoder.encode(1, 0, synthetic(100, 100, 3)).unwrap();
+
+            assert!(
+                encoder
+                    .encode(1, 0, synthetic(100, 100, 3))
+                    .unwrap()
+                    .is_none(),
+                "{}",
+                codec.name()
+            );
+        }
+    }
+
+    #[test]
+    fn resizes_send_keyframes() {
+        for codec in codecs() {
+            let mut encoder = FrameEncoder::new(codec.clone());
+            let mut decoder = FrameDecoder::new(codec.clone());
+
+            for (frame_count, (width, height)) in [(200, 130), (200, 130), (90, 170), (90, 170)]
+                .into_iter()
+                .enumerate()
+            {
+                let frame = synthetic(width, height, frame_count as u32);
+                let data = encoder.encode(1, 0, frame.clone()).unwrap().unwrap();
+
+                assert_eq!(data.keyframe, frame_count % 2 == 0, "{}", codec.name());
+                assert_eq!(decoder.decode(&data).unwrap(), &frame, "{}", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn requested_keyframes_resync_decoders() {
+        for codec in codecs() {
+            let mut encoder = FrameEncoder::new(codec.clone());
+
+            encoder.encode(1, 0, synthetic(200, 130, 0)).unwrap();
+
+            // A decoder that missed the keyframe can't use deltas
+            let mut decoder = FrameDecoder::new(codec.clone());
+            let delta = encoder
+                .encode(1, 0, synthetic(200, 130, 1))
+                .unwrap()
+                .unwrap();
+
+            assert!(
+                matches!(decoder.decode(&delta), Err(FrameError::MissingKeyframe)),
+                "{}",
+                codec.name()
+            );
+
+            encoder.request_keyframe();
+
+            let frame = synthetic(200, 130, 2);
+            let data = encoder.encode(1, 0, frame.clone()).unwrap().unwrap();
+
+            assert!(data.keyframe, "{}", codec.name());
+            assert_eq!(decoder.decode(&data).unwrap(), &frame, "{}", codec.name());
+
+            // And the deltas after it apply again
+            let frame = synthetic(200, 130, 3);
+            let data = encoder.encode(1, 0, frame.clone()).unwrap().unwrap();
+
+            assert!(!data.keyframe, "{}", codec.name());
+            assert_eq!(decoder.decode(&data).unwrap(), &frame, "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut decoder = FrameDecoder::new(Arc::new(RawCodec));
+
+        let data = FrameData {
+            window_id: 1,
+            timestamp: 0,
+            width: u32::MAX,
+            height: u32::MAX,
+            keyframe: true,
+            rects: Vec::new(),
+        };
+
+        assert!(matches!(
+            decoder.decode(&data),
+            Err(FrameError::InvalidRect)
+        ));
+
+        let mut encoder = FrameEncoder::new(Arc::new(RawCodec));
+
+        let frame = Frame {
+            width: 100,
+            height: 100,
+            rgba: vec![0; 16],
+        };
+
+        assert!(encoder.encode(1, 0, frame).is_err());
+    }
+}
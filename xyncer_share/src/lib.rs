@@ -1,10 +1,408 @@
-use hyper;
-use hyper_util;
-use log;
-use rmp_serde;
 use serde::{Deserialize, Serialize};
 
+pub mod audio;
+pub mod channels;
+pub mod clipboard;
+pub mod codecs;
+pub mod frames;
 pub mod payloads;
+pub mod transfer;
+
+// Version of the protocol this build speaks, bumped on breaking changes
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// Oldest protocol version this build can talk to. Version 2 sends payloads over channels, which version 1 can't read.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+// Whether this build can talk to a peer speaking the given protocol version
+pub fn is_compatible(protocol_version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+// Capabilities this build supports
+pub fn capabilities() -> Vec<payloads::Capability> {
+    vec![
+        payloads::Capability::Windows,
+        payloads::Capability::Frames,
+        payloads::Capability::Input,
+        payloads::Capability::Launch,
+        payloads::Capability::Clipboard,
+        payloads::Capability::Files,
+        payloads::Capability::Audio,
+    ]
+}
+
+// The capabilities supported by both this build and the peer
+pub fn negotiate_capabilities(offered: &[payloads::Capability]) -> Vec<payloads::Capability> {
+    capabilities()
+        .into_iter()
+        .filter(|capability| offered.contains(capability))
+        .collect()
+}
+
+// SHA-256 fingerprint of a DER encoded certificate, shown to users so they can check who they are talking to
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(der);
+
+    digest
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+type Stream = hyper_util::rt::TokioIo<hyper::upgrade::Upgraded>;
+type Writer = fastwebsockets::WebSocketWrite<tokio::io::WriteHalf<Stream>>;
+type FrameResult = Result<fastwebsockets::Frame<'static>, fastwebsockets::WebSocketError>;
+
+// What the background tasks of a connection share with it
+#[derive(Default)]
+struct Shared {
+    outbox: std::sync::Mutex<channels::Outbox>,
+    inbox: std::sync::Mutex<Inbound>,
+    queued: tokio::sync::Notify,   // Something was put in the outbox
+    sent: tokio::sync::Notify,     // Something left the outbox, or the writer stopped
+    received: tokio::sync::Notify, // Something was put in the inbox
+    failed: std::sync::atomic::AtomicBool, // Writing failed, so nothing more can be sent
+}
+
+#[derive(Default)]
+struct Inbound {
+    inbox: channels::Inbox,
+    frames: std::collections::VecDeque<FrameResult>, // Frames that aren't on a channel, like closes and errors
+    finished: bool,                                  // Nothing more will be read
+}
+
+// A WebSocket connection, on either end. Payloads are sent over channels (see channels.rs), and frames are read and
+// written in the background, so a read can be given up on (like when another branch of a select! finishes first)
+// without losing half a frame.
+pub struct Connection {
+    writer: std::sync::Arc<tokio::sync::Mutex<Writer>>,
+    shared: std::sync::Arc<Shared>,
+    reader: tokio::task::JoinHandle<()>,
+    sender: tokio::task::JoinHandle<()>,
+    requests: Requests,
+}
+
+// The requests sent over one connection that are still waiting for their answer. Clones share them, so requests
+// can be sent from wherever payloads are queued for the connection, and answered by whatever reads from it.
+#[derive(Clone, Default)]
+pub struct Requests(std::sync::Arc<std::sync::Mutex<Pending>>);
+
+#[derive(Default)]
+struct Pending {
+    nonce: u64, // The last one handed out
+    answers: std::collections::HashMap<u64, tokio::sync::oneshot::Sender<Payload>>,
+    closed: bool, // The connection is gone, so nothing more will be answered
+}
+
+impl Requests {
+    // A nonce for a request nobody waits on, whose failure is still worth hearing about
+    pub fn nonce(&self) -> u64 {
+        let mut pending = self.0.lock().unwrap();
+
+        pending.nonce += 1;
+        pending.nonce
+    }
+
+    // Marks a payload as a request, returning it to send along with its answer to wait for
+    pub fn start(&self, payload: Payload) -> (Payload, Answer) {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let nonce = self.nonce();
+        let mut pending = self.0.lock().unwrap();
+
+        // Dropping the sender fails the request straight away
+        if !pending.closed {
+            pending.answers.insert(nonce, sender);
+        }
+
+        let answer = Answer {
+            nonce,
+            receiver,
+            requests: self.clone(),
+        };
+
+        (payload.answering(Some(nonce)), answer)
+    }
+
+    // Hands a payload to the request it answers, returning whether one was waiting for it
+    pub fn answer(&self, payload: &Payload) -> bool {
+        let Some(nonce) = payload.nonce else {
+            return false;
+        };
+
+        let Some(sender) = self.0.lock().unwrap().answers.remove(&nonce) else {
+            return false;
+        };
+
+        // The request may have given up just now
+        sender.send(payload.clone()).is_ok()
+    }
+
+    // Fails every request still waiting, and any started from now on
+    pub fn close(&self) {
+        let mut pending = self.0.lock().unwrap();
+
+        pending.closed = true;
+        pending.answers.clear();
+    }
+}
+
+// The answer to a request, once it arrives
+pub struct Answer {
+    nonce: u64,
+    receiver: tokio::sync::oneshot::Receiver<Payload>,
+    requests: Requests,
+}
+
+impl Answer {
+    pub async fn wait(mut self, timeout: std::time::Duration) -> Result<Payload, RequestError> {
+        let payload = match tokio::time::timeout(timeout, &mut self.receiver).await {
+            Ok(Ok(payload)) => payload,
+            Ok(Err(_)) => {
+                return Err(RequestError::Closed(
+                    fastwebsockets::WebSocketError::ConnectionClosed,
+                ))
+            }
+            Err(_) => return Err(RequestError::Timeout),
+        };
+
+        match payload.data {
+            payloads::PayloadData::Error(data) => Err(RequestError::Failed(data)),
+            _ => Ok(payload),
+        }
+    }
+}
+
+impl Drop for Answer {
+    // Whatever answers later has nobody to go to
+    fn drop(&mut self) {
+        self.requests.0.lock().unwrap().answers.remove(&self.nonce);
+    }
+}
+
+impl Connection {
+    pub fn new(websocket: fastwebsockets::WebSocket<Stream>) -> Self {
+        let (read, write) = websocket.split(tokio::io::split);
+        let writer = std::sync::Arc::new(tokio::sync::Mutex::new(write));
+        let shared = std::sync::Arc::new(Shared::default());
+
+        let reader = tokio::spawn(read_frames(
+            fastwebsockets::FragmentCollectorRead::new(read),
+            writer.clone(),
+            shared.clone(),
+        ));
+
+        let sender = tokio::spawn(send_messages(writer.clone(), shared.clone()));
+
+        Connection {
+            writer,
+            shared,
+            reader,
+            sender,
+            requests: Requests::default(),
+        }
+    }
+
+    // The next payload (most urgent first) as a binary frame, or a frame that isn't on a channel
+    pub async fn read_frame(&mut self) -> FrameResult {
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return frame;
+            }
+
+            self.shared.received.notified().await;
+        }
+    }
+
+    // Takes what read_frame returns, None if nothing has been read yet
+    fn take_frame(&self) -> Option<FrameResult> {
+        let mut inbound = self.shared.inbox.lock().unwrap();
+
+        if let Some((channel, payload)) = inbound.inbox.pop() {
+            // Reading makes room for the peer to send more
+            if let Some(bytes) = inbound.inbox.acknowledge(channel) {
+                self.shared
+                    .outbox
+                    .lock()
+                    .unwrap()
+                    .acknowledge(channel, bytes);
+                self.shared.queued.notify_one();
+            }
+
+            return Some(Ok(fastwebsockets::Frame::binary(
+                fastwebsockets::Payload::Owned(payload),
+            )));
+        }
+
+        if let Some(frame) = inbound.frames.pop_front() {
+            return Some(frame);
+        }
+
+        inbound
+            .finished
+            .then_some(Err(fastwebsockets::WebSocketError::ConnectionClosed))
+    }
+
+    // The requests sent over this connection, for whatever reads its payloads to answer
+    pub fn requests(&self) -> Requests {
+        self.requests.clone()
+    }
+
+    // Whether payloads sent on a channel would have to wait, so streams (like frames) can skip what would be late anyway
+    pub fn is_congested(&self, channel: channels::Channel) -> bool {
+        self.shared.outbox.lock().unwrap().is_congested(channel)
+    }
+
+    // Waits until the outbox passes the check, failing if nothing more can be sent
+    async fn wait_for_outbox(
+        &self,
+        mut ready: impl FnMut(&channels::Outbox) -> bool,
+    ) -> Result<(), fastwebsockets::WebSocketError> {
+        loop {
+            let sent = self.shared.sent.notified();
+
+            tokio::pin!(sent);
+
+            // Registered before checking, so a message sent in between isn't missed
+            sent.as_mut().enable();
+
+            if self
+                .shared
+                .failed
+                .load(std::sync::atomic::Ordering::Relaxed)
+            {
+                return Err(fastwebsockets::WebSocketError::ConnectionClosed);
+            }
+
+            if ready(&self.shared.outbox.lock().unwrap()) {
+                return Ok(());
+            }
+
+            sent.await;
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.sender.abort();
+        self.requests.close();
+    }
+}
+
+// Turns a channel error into one the connection can fail with
+fn channel_error(e: channels::ChannelError) -> fastwebsockets::WebSocketError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+}
+
+// Reads frames until the connection closes or fails, answering the ones that need it (like pings) and putting
+// payloads back together from their fragments
+async fn read_frames(
+    mut reader: fastwebsockets::FragmentCollectorRead<tokio::io::ReadHalf<Stream>>,
+    writer: std::sync::Arc<tokio::sync::Mutex<Writer>>,
+    shared: std::sync::Arc<Shared>,
+) {
+    loop {
+        let result = reader
+            .read_frame(&mut |frame| {
+                let writer = writer.clone();
+
+                async move { writer.lock().await.write_frame(frame).await }
+            })
+            .await;
+
+        let mut inbound = shared.inbox.lock().unwrap();
+
+        let frame = match result {
+            Ok(frame) if frame.opcode == fastwebsockets::OpCode::Binary => {
+                match inbound.inbox.receive(&frame.payload) {
+                    Ok(None) => None,
+                    Ok(Some((channel, bytes))) => {
+                        shared.outbox.lock().unwrap().update_window(channel, bytes);
+                        shared.queued.notify_one();
+
+                        None
+                    }
+                    Err(e) => {
+                        log::warn!("{}", e);
+
+                        Some(Err(channel_error(e)))
+                    }
+                }
+            }
+            // Answered already
+            Ok(frame)
+                if matches!(
+                    frame.opcode,
+                    fastwebsockets::OpCode::Ping | fastwebsockets::OpCode::Pong
+                ) =>
+            {
+                None
+            }
+            result => Some(result),
+        };
+
+        let last = match &frame {
+            Some(Ok(frame)) => frame.opcode == fastwebsockets::OpCode::Close,
+            Some(Err(_)) => true,
+            None => false,
+        };
+
+        inbound.frames.extend(frame);
+        inbound.finished = last;
+
+        // Drop the lock on the inbox
+        drop(inbound);
+
+        shared.received.notify_one();
+
+        // There is nothing left to read
+        if last {
+            break;
+        }
+    }
+}
+
+// Writes what is in the outbox, most urgent first, until the connection fails
+async fn send_messages(
+    writer: std::sync::Arc<tokio::sync::Mutex<Writer>>,
+    shared: std::sync::Arc<Shared>,
+) {
+    loop {
+        // Held from taking a message to writing it, so a close can't overtake it
+        let mut writer = writer.lock().await;
+
+        let Some(message) = shared.outbox.lock().unwrap().take_message() else {
+            drop(writer);
+
+            shared.queued.notified().await;
+
+            continue;
+        };
+
+        let result = writer
+            .write_frame(fastwebsockets::Frame::binary(
+                fastwebsockets::Payload::Owned(message),
+            ))
+            .await;
+
+        if let Err(e) = result {
+            log::debug!("Could not write to the connection: {}", e);
+
+            shared
+                .failed
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        shared.sent.notify_waiters();
+
+        if shared.failed.load(std::sync::atomic::Ordering::Relaxed) {
+            break;
+        }
+    }
+}
 
 pub trait Websocket {
     fn send_payload(
@@ -15,46 +413,216 @@ pub trait Websocket {
     fn close(
         &mut self,
     ) -> impl std::future::Future<Output = Result<(), fastwebsockets::WebSocketError>> + Send;
+
+    // Sends an InvalidSession payload with the given error code, then closes the connection
+    fn invalidate_session(
+        &mut self,
+        code: payloads::ErrorCode,
+    ) -> impl std::future::Future<Output = Result<(), fastwebsockets::WebSocketError>> + Send;
+
+    // Answers a request with an Error payload, if it asked for an answer
+    fn reject(
+        &mut self,
+        nonce: Option<u64>,
+        code: payloads::ErrorCode,
+    ) -> impl std::future::Future<Output = Result<(), fastwebsockets::WebSocketError>> + Send;
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Timeout,
+    Closed(fastwebsockets::WebSocketError),
+    Failed(payloads::InvalidSessionData), // The peer answered with an Error payload
 }
 
-impl Websocket
-    for fastwebsockets::FragmentCollector<hyper_util::rt::TokioIo<hyper::upgrade::Upgraded>>
-{
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "The request was not answered in time"),
+            RequestError::Closed(e) => {
+                write!(
+                    f,
+                    "The connection closed before the request was answered: {}",
+                    e
+                )
+            }
+            RequestError::Failed(data) => write!(f, "{}", data),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl Websocket for Connection {
     async fn send_payload(
         &mut self,
         payload: Payload,
     ) -> Result<(), fastwebsockets::WebSocketError> {
-        log::info!("Sent payload: {:?}", payload);
+        if payload.op_code.is_streamed() {
+            log::debug!("Sent payload: {:?}", payload);
+        } else {
+            log::info!("Sent payload: {:?}", payload);
+        }
 
-        self.write_frame(fastwebsockets::Frame::binary(
-            fastwebsockets::Payload::Owned(rmp_serde::to_vec(&payload).unwrap()),
-        ))
-        .await
+        let channel = payload.op_code.channel();
+
+        // Streams of payloads slow down to the pace of the connection, rather than piling up
+        self.wait_for_outbox(|outbox| outbox.queued(channel) < channels::QUEUE_LIMIT)
+            .await?;
+
+        self.shared
+            .outbox
+            .lock()
+            .unwrap()
+            .push(channel, rmp_serde::to_vec(&payload).unwrap());
+        self.shared.queued.notify_one();
+
+        Ok(())
     }
 
     async fn close(&mut self) -> Result<(), fastwebsockets::WebSocketError> {
-        self.write_frame(fastwebsockets::Frame::close_raw(vec![].into()))
+        // Whatever was sent before closing (like an InvalidSession) goes out first
+        self.wait_for_outbox(|outbox| !outbox.is_pending(channels::Channel::Control))
+            .await?;
+
+        self.writer
+            .lock()
             .await
+            .write_frame(fastwebsockets::Frame::close_raw(vec![].into()))
+            .await
+    }
+
+    async fn invalidate_session(
+        &mut self,
+        code: payloads::ErrorCode,
+    ) -> Result<(), fastwebsockets::WebSocketError> {
+        self.send_payload(Payload {
+            op_code: OP::InvalidSession,
+            event_name: Event::None,
+            data: payloads::PayloadData::InvalidSession(code.populate()),
+            sequence: None,
+            nonce: None,
+        })
+        .await?;
+
+        self.close().await
+    }
+
+    async fn reject(
+        &mut self,
+        nonce: Option<u64>,
+        code: payloads::ErrorCode,
+    ) -> Result<(), fastwebsockets::WebSocketError> {
+        // Nobody is waiting to hear about it
+        if nonce.is_none() {
+            return Ok(());
+        }
+
+        self.send_payload(Payload {
+            op_code: OP::Error,
+            event_name: Event::None,
+            data: payloads::PayloadData::Error(code.populate()),
+            sequence: None,
+            nonce,
+        })
+        .await
     }
 }
 
 // WebSocket OP codes, in order of most common. Comments show client action and description.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OP {
-    Dispatch,       // Receive | An event was dispatched
-    Heartbeat,      // Send / Receive | Keeps the connection alive
-    Identify,       // Send | Starts a new session
-    ReIdentify,     // Receive | Re-send an Identify payload with a new passphrase
-    InvalidSession, // Receive | The session is invalid
-    Hello,          // Receive | Sent immediately after connection
-    HeartbeatAck,   // Receive | Acknowledges a heartbeat
+    Dispatch,        // Receive | An event was dispatched
+    Heartbeat,       // Send / Receive | Keeps the connection alive
+    Identify,        // Send | Starts a new session
+    ReIdentify,      // Receive | Re-send an Identify payload with a new passphrase
+    InvalidSession,  // Receive | The session is invalid
+    Hello,           // Receive | Sent immediately after connection
+    HeartbeatAck,    // Receive | Acknowledges a heartbeat
+    ListWindows,     // Send | Requests the server's top-level windows
+    Frame,           // Receive | A captured frame of a subscribed window
+    Subscribe,       // Send | Starts streaming frames of a window
+    Unsubscribe,     // Send | Stops streaming frames of a window
+    Input,           // Send | Keyboard or mouse input for a window
+    RequestKeyframe, // Send | Asks for the next frame of a window to be a full frame
+    Resume,          // Send | Resumes a session after the connection dropped
+    LaunchApp,       // Send | Starts an application on the server host
+    ListApps,        // Send | Requests the server's catalog of launchable applications
+    Clipboard,       // Send / Receive | The clipboard changed on the other side
+    FileOffer,       // Send / Receive | Offers a file to the other side
+    FileAccept, // Send / Receive | Accepts an offered file, from where a previous attempt got to
+    FileChunk,  // Send / Receive | A piece of a file being transferred
+    FileAck,    // Send / Receive | Acknowledges file chunks, so more can be sent
+    FileEnd,    // Send / Receive | A file transfer finished, failed or was cancelled
+    RequestFile, // Send | Asks the server to offer one of its shared files
+    StartAudio, // Send | Starts streaming the server's audio
+    StopAudio,  // Send | Stops streaming the server's audio
+    Audio,      // Receive | A packet of the server's audio
+    Error,      // Receive | A request failed, without invalidating the session
+}
+
+impl OP {
+    // Whether payloads with this OP code are sent often enough to flood the log
+    pub fn is_streamed(&self) -> bool {
+        matches!(
+            self,
+            OP::Frame | OP::Input | OP::FileChunk | OP::FileAck | OP::Audio
+        )
+    }
+
+    // The channel payloads with this OP code are sent over
+    pub fn channel(&self) -> channels::Channel {
+        match self {
+            OP::Input => channels::Channel::Input,
+            OP::Frame => channels::Channel::Video,
+            OP::Audio => channels::Channel::Audio,
+            OP::Clipboard => channels::Channel::Clipboard,
+            OP::FileOffer
+            | OP::FileAccept
+            | OP::FileChunk
+            | OP::FileAck
+            | OP::FileEnd
+            | OP::RequestFile => channels::Channel::File,
+            _ => channels::Channel::Control,
+        }
+    }
+
+    // The capability that has to be negotiated before this OP code can be used
+    pub fn capability(&self) -> Option<payloads::Capability> {
+        match self {
+            OP::ListWindows => Some(payloads::Capability::Windows),
+            OP::Frame | OP::Subscribe | OP::Unsubscribe | OP::RequestKeyframe => {
+                Some(payloads::Capability::Frames)
+            }
+            OP::Input => Some(payloads::Capability::Input),
+            OP::LaunchApp | OP::ListApps => Some(payloads::Capability::Launch),
+            OP::Clipboard => Some(payloads::Capability::Clipboard),
+            OP::FileOffer
+            | OP::FileAccept
+            | OP::FileChunk
+            | OP::FileAck
+            | OP::FileEnd
+            | OP::RequestFile => Some(payloads::Capability::Files),
+            OP::StartAudio | OP::StopAudio | OP::Audio => Some(payloads::Capability::Audio),
+            _ => None,
+        }
+    }
 }
 
 // WebSocket events
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     None,
     Ready,
+    WindowList,
+    WindowCreated,
+    WindowDestroyed,
+    WindowMoved,
+    WindowTitleChanged,
+    Resumed,
+    AppLaunched,
+    LaunchFailed,
+    AppCatalog,
 }
 
 // WebSocket payload
@@ -63,4 +631,141 @@ pub struct Payload {
     pub op_code: OP,
     pub event_name: Event,
     pub data: payloads::PayloadData,
+    #[serde(default)]
+    pub sequence: Option<u64>, // Set on dispatched events, so a resumed session knows what it missed
+    #[serde(default)]
+    pub nonce: Option<u64>, // Set on requests that want an answer, and copied onto the answer
+}
+
+impl Payload {
+    // Marks this as the answer to the request with the given nonce, if it had one
+    pub fn answering(mut self, nonce: Option<u64>) -> Self {
+        self.nonce = nonce;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_windows() -> Payload {
+        Payload {
+            op_code: OP::ListWindows,
+            event_name: Event::None,
+            data: payloads::PayloadData::ListWindows,
+            sequence: None,
+            nonce: None,
+        }
+    }
+
+    // The server's answer to a request, or some other payload if no nonce is given
+    fn reply(nonce: Option<u64>, data: payloads::PayloadData) -> Payload {
+        Payload {
+            op_code: OP::Dispatch,
+            event_name: Event::WindowList,
+            data,
+            sequence: None,
+            nonce,
+        }
+    }
+
+    fn window_list() -> payloads::PayloadData {
+        payloads::PayloadData::WindowList(payloads::WindowListData {
+            windows: Vec::new(),
+        })
+    }
+
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    #[test]
+    fn cloned_requests_share_their_nonces() {
+        let requests = Requests::default();
+        let queued = requests.clone();
+
+        assert_eq!(requests.nonce(), 1);
+        assert_eq!(queued.start(list_windows()).0.nonce, Some(2));
+        assert_eq!(requests.nonce(), 3);
+
+        // Another connection starts from the beginning
+        assert_eq!(Requests::default().nonce(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_reach_their_request_among_other_payloads() {
+        let requests = Requests::default();
+
+        let (first, first_answer) = requests.start(list_windows());
+        let (second, second_answer) = requests.start(list_windows());
+
+        // Events, and answers to requests nobody waits on, are left to whoever reads them
+        assert!(!requests.answer(&reply(None, window_list())));
+        assert!(!requests.answer(&reply(Some(99), window_list())));
+
+        // Answered out of order, with the payloads they carry
+        assert!(requests.answer(&reply(second.nonce, payloads::PayloadData::ListApps)));
+        assert!(requests.answer(&reply(first.nonce, window_list())));
+
+        assert!(matches!(
+            second_answer.wait(TIMEOUT).await.unwrap().data,
+            payloads::PayloadData::ListApps
+        ));
+        assert!(matches!(
+            first_answer.wait(TIMEOUT).await.unwrap().data,
+            payloads::PayloadData::WindowList(_)
+        ));
+
+        // Each is only answered once
+        assert!(!requests.answer(&reply(first.nonce, window_list())));
+    }
+
+    #[tokio::test]
+    async fn errors_fail_the_request() {
+        let requests = Requests::default();
+        let (request, answer) = requests.start(list_windows());
+
+        requests.answer(&reply(
+            request.nonce,
+            payloads::PayloadData::Error(payloads::ErrorCode::UnknownOP.populate()),
+        ));
+
+        assert!(matches!(
+            answer.wait(TIMEOUT).await,
+            Err(RequestError::Failed(payloads::InvalidSessionData {
+                code: payloads::ErrorCode::UnknownOP,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_requests_time_out() {
+        let requests = Requests::default();
+        let (request, answer) = requests.start(list_windows());
+
+        assert!(matches!(
+            answer.wait(TIMEOUT).await,
+            Err(RequestError::Timeout)
+        ));
+
+        // A late answer goes to whoever reads it instead
+        assert!(!requests.answer(&reply(request.nonce, window_list())));
+    }
+
+    #[tokio::test]
+    async fn requests_fail_once_the_connection_closes() {
+        let requests = Requests::default();
+        let (_, waiting) = requests.start(list_windows());
+
+        requests.close();
+
+        let (_, late) = requests.start(list_windows());
+
+        for answer in [waiting, late] {
+            assert!(matches!(
+                answer.wait(TIMEOUT).await,
+                Err(RequestError::Closed(_))
+            ));
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // Dispatch data
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -6,19 +7,71 @@ pub struct DispatchData {
     pub data: String,
 }
 
+// Optional protocol features, negotiated during the handshake
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Windows,   // Window listing and window events
+    Frames,    // Frame streaming
+    Input,     // Keyboard and mouse input
+    Launch,    // Launching applications on the server host
+    Clipboard, // Clipboard sync
+    Files,     // File transfers
+    Audio,     // Audio streaming
+    #[serde(other)]
+    Unknown, // Added by a newer version, never negotiated
+}
+
 // Identify data
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct IdentifyData {
     pub passphrase: String,
+    #[serde(default)]
+    pub codecs: Vec<String>, // Supported frame codecs, in order of preference
+    #[serde(default)]
+    pub protocol_version: u16,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub audio_codecs: Vec<String>, // Supported audio codecs, in order of preference
+}
+
+// Ready data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReadyData {
+    pub codec: String,                 // Frame codec picked by the server
+    pub capabilities: Vec<Capability>, // Supported by both sides
+    #[serde(default)]
+    pub session_id: String,
+    #[serde(default)]
+    pub resume_token: String, // Proves ownership of the session when resuming it
+    #[serde(default)]
+    pub audio_codec: String, // Audio codec picked by the server
+    #[serde(default)]
+    pub clipboard_max_size: Option<usize>, // Largest clipboard the server sends or accepts, in bytes
+}
+
+// Resume data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResumeData {
+    pub session_id: String,
+    pub resume_token: String,
+    pub sequence: u64, // Sequence number of the last event received, later ones are replayed
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ErrorCode {
     UnknownError,
     UnknownOP,
     DecodeError,
     AuthenticationFailed,
     SessionTimeout,
+    IncompatibleVersion,
+    ResumeFailed,
+    // Sent in Error payloads, which leave the session as it was
+    AlreadyAuthenticated,
+    UnknownWindow,
+    TooLarge,
+    Unavailable,
 }
 
 impl ErrorCode {
@@ -32,44 +85,397 @@ impl ErrorCode {
             ErrorCode::UnknownOP => InvalidSessionData {
                 code: *self,
                 description: "Unknown OP code".to_string(),
-                explanation: "The server
-                received an unknown OP code. Try reconnecting?"
-                    .to_string(),
+                explanation: "An unknown OP code was received. Try reconnecting?".to_string(),
             },
             ErrorCode::DecodeError => InvalidSessionData {
                 code: *self,
                 description: "Decode error".to_string(),
-                explanation: "The server
-                received an invalid payload. Try reconnecting?"
-                    .to_string(),
+                explanation: "An invalid payload was received. Try reconnecting?".to_string(),
             },
             ErrorCode::AuthenticationFailed => InvalidSessionData {
                 code: *self,
                 description: "Authentication failed".to_string(),
-                explanation: "The server
-                received an invalid passphrase too many times."
-                    .to_string(),
+                explanation: "An invalid passphrase was sent too many times.".to_string(),
             },
             ErrorCode::SessionTimeout => InvalidSessionData {
                 code: *self,
                 description: "Session timeout".to_string(),
                 explanation: "You didn't send a heartbeat in time.".to_string(),
             },
+            ErrorCode::IncompatibleVersion => InvalidSessionData {
+                code: *self,
+                description: "Incompatible version".to_string(),
+                explanation:
+                    "The client and server speak incompatible protocol versions. Try updating both?"
+                        .to_string(),
+            },
+            ErrorCode::ResumeFailed => InvalidSessionData {
+                code: *self,
+                description: "Resume failed".to_string(),
+                explanation: "The session has expired, so you have to authenticate again."
+                    .to_string(),
+            },
+            ErrorCode::AlreadyAuthenticated => InvalidSessionData {
+                code: *self,
+                description: "Already authenticated".to_string(),
+                explanation:
+                    "The session is already authenticated, so it can't identify or resume again."
+                        .to_string(),
+            },
+            ErrorCode::UnknownWindow => InvalidSessionData {
+                code: *self,
+                description: "Unknown window".to_string(),
+                explanation: "The window doesn't exist, or isn't subscribed to.".to_string(),
+            },
+            ErrorCode::TooLarge => InvalidSessionData {
+                code: *self,
+                description: "Too large".to_string(),
+                explanation: "The payload is over the server's size limit.".to_string(),
+            },
+            ErrorCode::Unavailable => InvalidSessionData {
+                code: *self,
+                description: "Unavailable".to_string(),
+                explanation: "The server can't do that right now. Check its logs?".to_string(),
+            },
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct InvalidSessionData {
-    code: ErrorCode,
-    description: String,
-    explanation: String,
+    pub code: ErrorCode,
+    pub description: String,
+    pub explanation: String,
+}
+
+impl std::fmt::Display for InvalidSessionData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.description, self.explanation)
+    }
 }
 
 // Hello data
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HelloData {
     pub heartbeat_interval: u8,
+    #[serde(default)]
+    pub protocol_version: u16,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+// Position and size of a window, in screen coordinates
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// RGBA8 icon, of a window or an app
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WindowIcon {
+    pub width: u32,
+    pub height: u32,
+    #[serde(with = "serde_bytes")]
+    pub rgba: Vec<u8>,
+}
+
+// Window data (also sent when a window is created)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WindowData {
+    pub id: u32,
+    pub title: String,
+    pub process_name: String,
+    pub geometry: WindowGeometry,
+    pub icon: Option<WindowIcon>,
+    #[serde(default)]
+    pub pid: u32, // Process that owns the window, 0 if unknown
+}
+
+// Window list data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WindowListData {
+    pub windows: Vec<WindowData>,
+}
+
+// Window destroyed data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WindowDestroyedData {
+    pub id: u32,
+}
+
+// Window moved (or resized) data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WindowMovedData {
+    pub id: u32,
+    pub geometry: WindowGeometry,
+}
+
+// Window title changed data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WindowTitleChangedData {
+    pub id: u32,
+    pub title: String,
+}
+
+// Subscribe / unsubscribe / keyframe request data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubscribeData {
+    pub window_id: u32,
+}
+
+// A changed rectangle of a frame, compressed with the session's codec
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+// Payloads are logged, so don't dump every pixel
+impl std::fmt::Debug for DirtyRect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirtyRect")
+            .field("x", &self.x)
+            .field("y", &self.y)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("data", &format_args!("[{} bytes]", self.data.len()))
+            .finish()
+    }
+}
+
+// Frame data. Keyframes cover the whole window, other frames only the rectangles that changed since the previous one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FrameData {
+    pub window_id: u32,
+    pub timestamp: u64, // Milliseconds since the UNIX epoch, on the server
+    pub width: u32,
+    pub height: u32,
+    pub keyframe: bool,
+    pub rects: Vec<DirtyRect>,
+}
+
+// A packet of the server's audio, encoded with the session's audio codec
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AudioData {
+    pub packet: u64, // Counts up from 0, so lost packets can be told apart from late ones
+    pub timestamp: u64, // Milliseconds since the UNIX epoch on the server when the first sample was captured, like frames
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+// Payloads are logged, and there are 50 of these a second
+impl std::fmt::Debug for AudioData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AudioData")
+            .field("packet", &self.packet)
+            .field("timestamp", &self.timestamp)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+// Modifier keys held during an input event
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub meta: bool, // Windows / Command key
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+// Input events. Keys are PS/2 set 1 scancodes, with extended keys prefixed by 0xE0 (e.g. 0xE048 for the up arrow).
+// Mouse positions are in pixels, relative to the top-left corner of the window.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    KeyDown {
+        scancode: u32,
+        modifiers: Modifiers,
+    },
+    KeyUp {
+        scancode: u32,
+        modifiers: Modifiers,
+    },
+    Text {
+        text: String,
+    },
+    MouseMove {
+        x: i32,
+        y: i32,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+        x: i32,
+        y: i32,
+    },
+    // Measured in wheel notches, positive values scroll up / right
+    MouseWheel {
+        delta_x: f32,
+        delta_y: f32,
+    },
+}
+
+// Input data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InputData {
+    pub window_id: u32,
+    pub event: InputEvent,
+}
+
+// What to launch
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LaunchTarget {
+    Executable(String), // Path to an executable on the server host
+    App(String),        // Id of an app registered on the server
+}
+
+impl std::fmt::Display for LaunchTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LaunchTarget::Executable(executable) => write!(f, "{}", executable),
+            LaunchTarget::App(id) => write!(f, "app {}", id),
+        }
+    }
+}
+
+// Launch app data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LaunchAppData {
+    pub target: LaunchTarget,
+    #[serde(default)]
+    pub arguments: Vec<String>, // Passed after a registered app's own arguments
+    #[serde(default)]
+    pub working_directory: Option<String>,
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>, // Set on top of the server's environment
+}
+
+// App launched data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppLaunchedData {
+    pub target: LaunchTarget,
+    pub pid: u32,
+    pub windows: Vec<WindowData>, // Windows the process opened shortly after starting, empty if it opened none in time
+}
+
+// Launch failed data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LaunchFailedData {
+    pub target: LaunchTarget,
+    pub error: String,
+}
+
+// An app in the server's catalog
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AppData {
+    pub id: String, // Launched with LaunchTarget::App
+    pub name: String,
+    pub executable: String,
+    pub category: Option<String>,
+    pub icon: Option<WindowIcon>,
+}
+
+// App catalog data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppCatalogData {
+    pub apps: Vec<AppData>,
+}
+
+// One format of the clipboard's contents
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct ClipboardItem {
+    pub mime_type: String, // One of the types in xyncer_share::clipboard
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+// Payloads are logged, and clipboards can hold anything
+impl std::fmt::Debug for ClipboardItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClipboardItem")
+            .field("mime_type", &self.mime_type)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+// Clipboard data, the same contents in every format the sender has, best first
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ClipboardData {
+    pub items: Vec<ClipboardItem>,
+}
+
+// File offer data, sent by the side with the file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileOfferData {
+    pub transfer_id: String, // Picked by whoever started the transfer, and used by both sides for it
+    pub name: String,        // File name, without any directories
+    pub size: u64,
+    pub checksum: String, // Hex encoded SHA-256 of the whole file
+}
+
+// File accept data, the offset is where to start sending from when part of the file arrived before
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileAcceptData {
+    pub transfer_id: String,
+    pub offset: u64,
+}
+
+// A piece of a file being transferred
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileChunkData {
+    pub transfer_id: String,
+    pub offset: u64,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+// Payloads are logged, and chunks are large
+impl std::fmt::Debug for FileChunkData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileChunkData")
+            .field("transfer_id", &self.transfer_id)
+            .field("offset", &self.offset)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+// File ack data, how much of the file has arrived
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileAckData {
+    pub transfer_id: String,
+    pub received: u64,
+}
+
+// File end data, sent by the receiver once the file checks out, or by either side to give up on it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileEndData {
+    pub transfer_id: String,
+    pub error: Option<String>, // None if the file arrived intact
+}
+
+// Request file data, asking the server to offer one of its shared files
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestFileData {
+    pub transfer_id: String,
+    pub path: String, // Relative to the server's shared directory
 }
 
 // WebSocket payload data
@@ -80,6 +486,36 @@ pub enum PayloadData {
     Identify(IdentifyData),
     ReIdentify,
     InvalidSession(InvalidSessionData),
+    Error(InvalidSessionData), // Described the same way as an invalid session, but the session carries on
     Hello(HelloData),
     HeartbeatAck,
+    Ready(ReadyData),
+    ListWindows,
+    WindowList(WindowListData),
+    WindowCreated(WindowData),
+    WindowDestroyed(WindowDestroyedData),
+    WindowMoved(WindowMovedData),
+    WindowTitleChanged(WindowTitleChangedData),
+    Frame(FrameData),
+    Subscribe(SubscribeData),
+    Unsubscribe(SubscribeData),
+    Input(InputData),
+    RequestKeyframe(SubscribeData),
+    Resume(ResumeData),
+    Resumed,
+    LaunchApp(LaunchAppData),
+    AppLaunched(AppLaunchedData),
+    LaunchFailed(LaunchFailedData),
+    ListApps,
+    AppCatalog(AppCatalogData),
+    Clipboard(ClipboardData),
+    FileOffer(FileOfferData),
+    FileAccept(FileAcceptData),
+    FileChunk(FileChunkData),
+    FileAck(FileAckData),
+    FileEnd(FileEndData),
+    RequestFile(RequestFileData),
+    StartAudio,
+    StopAudio,
+    Audio(AudioData),
 }
@@ -0,0 +1,921 @@
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::payloads::{
+    FileAcceptData, FileAckData, FileChunkData, FileEndData, FileOfferData, PayloadData,
+    RequestFileData,
+};
+use crate::{Event, Payload, OP};
+
+// Size of the pieces files are sent in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// Most bytes of a file sent but not yet acknowledged. Keeps a transfer from filling the connection's queue,
+// which heartbeats and frames would otherwise wait behind.
+pub const WINDOW_SIZE: u64 = 16 * CHUNK_SIZE as u64;
+
+pub type TransferError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Requested, // Waiting for the other side to offer the file
+    Offered,   // Waiting for the other side to accept the file
+    Transferring,
+    Complete(Option<PathBuf>), // Where a received file was saved
+    Failed(String),
+}
+
+// How a transfer is going, for showing to the user
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub transfer_id: String,
+    pub name: String,
+    pub direction: Direction,
+    pub size: u64,
+    pub transferred: u64,
+    pub state: State,
+}
+
+// A file being sent
+pub struct Outgoing {
+    offer: FileOfferData,
+    file: std::fs::File,
+    sent: u64,
+    acknowledged: u64,
+    accepted: bool,
+}
+
+impl Outgoing {
+    // Opens a file to offer. The whole file is read for its checksum, so keep this off the async workers.
+    pub fn open(transfer_id: String, path: &Path) -> Result<Self, TransferError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("{} has no usable file name", path.display()))?
+            .to_string();
+
+        let mut file = std::fs::File::open(path)?;
+
+        if !file.metadata()?.is_file() {
+            return Err(format!("{} is not a file", path.display()).into());
+        }
+
+        let mut hasher = sha2::Sha256::new();
+        let size = hash(&mut file, &mut hasher)?;
+
+        Ok(Outgoing {
+            offer: FileOfferData {
+                transfer_id,
+                name,
+                size,
+                checksum: hex(hasher),
+            },
+            file,
+            sent: 0,
+            acknowledged: 0,
+            accepted: false,
+        })
+    }
+
+    pub fn offer(&self) -> &FileOfferData {
+        &self.offer
+    }
+
+    fn accept(&mut self, offset: u64) -> Result<(), TransferError> {
+        if offset > self.offer.size {
+            return Err(format!(
+                "Asked for offset {} of a {} byte file",
+                offset, self.offer.size
+            )
+            .into());
+        }
+
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+
+        self.sent = offset;
+        self.acknowledged = offset;
+        self.accepted = true;
+
+        Ok(())
+    }
+
+    // The next chunk to send, if the other side is keeping up
+    fn next_chunk(&mut self) -> Result<Option<FileChunkData>, TransferError> {
+        if !self.accepted
+            || self.sent >= self.offer.size
+            || self.sent - self.acknowledged >= WINDOW_SIZE
+        {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; (self.offer.size - self.sent).min(CHUNK_SIZE as u64) as usize];
+
+        // Fails if the file shrank since it was offered
+        self.file.read_exact(&mut data)?;
+
+        let chunk = FileChunkData {
+            transfer_id: self.offer.transfer_id.clone(),
+            offset: self.sent,
+            data,
+        };
+
+        self.sent += chunk.data.len() as u64;
+
+        Ok(Some(chunk))
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            transfer_id: self.offer.transfer_id.clone(),
+            name: self.offer.name.clone(),
+            direction: Direction::Sending,
+            size: self.offer.size,
+            transferred: self.acknowledged,
+            state: if self.accepted {
+                State::Transferring
+            } else {
+                State::Offered
+            },
+        }
+    }
+}
+
+// A file being received, written next to where it will end up until it is complete
+pub struct Incoming {
+    offer: FileOfferData,
+    directory: PathBuf,
+    part: PathBuf,
+    file: std::fs::File,
+    hasher: sha2::Sha256,
+    received: u64,
+}
+
+impl Incoming {
+    // Starts receiving an offered file into a directory, carrying on from what arrived of it before
+    fn create(offer: FileOfferData, directory: &Path) -> Result<Self, TransferError> {
+        if !is_file_name(&offer.name) {
+            return Err(format!("\"{}\" is not a valid file name", offer.name).into());
+        }
+
+        if offer.checksum.len() != 64 || !offer.checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("\"{}\" is not a valid checksum", offer.checksum).into());
+        }
+
+        std::fs::create_dir_all(directory)?;
+
+        // Named after the checksum as well, so only what arrived of the very same file is picked up
+        let part = directory.join(format!(".{}.{}.part", offer.name, &offer.checksum[..16]));
+
+        let mut hasher = sha2::Sha256::new();
+
+        let mut received = match std::fs::File::open(&part) {
+            Ok(mut file) => hash(&mut file, &mut hasher)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Not the file we were offered after all, so start over
+        if received > offer.size {
+            std::fs::remove_file(&part)?;
+
+            hasher = sha2::Sha256::new();
+            received = 0;
+        }
+
+        if received > 0 {
+            log::info!("Resuming {} from byte {}", offer.name, received);
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)?;
+
+        Ok(Incoming {
+            offer,
+            directory: directory.to_path_buf(),
+            part,
+            file,
+            hasher,
+            received,
+        })
+    }
+
+    fn write(&mut self, chunk: &FileChunkData) -> Result<(), TransferError> {
+        if chunk.offset != self.received {
+            return Err(format!(
+                "Got a chunk at byte {}, expected one at byte {}",
+                chunk.offset, self.received
+            )
+            .into());
+        }
+
+        if self.received + chunk.data.len() as u64 > self.offer.size {
+            return Err("Got more of the file than was offered".into());
+        }
+
+        self.file.write_all(&chunk.data)?;
+        self.hasher.update(&chunk.data);
+
+        self.received += chunk.data.len() as u64;
+
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.offer.size
+    }
+
+    // Checks the file arrived intact and moves it into place, returning where it went
+    fn finish(self) -> Result<PathBuf, TransferError> {
+        let Incoming {
+            offer,
+            directory,
+            part,
+            file,
+            hasher,
+            ..
+        } = self;
+
+        // Files can't be moved while they are open on Windows
+        file.sync_all()?;
+        drop(file);
+
+        if hex(hasher) != offer.checksum {
+            std::fs::remove_file(&part)?;
+
+            return Err("The file was corrupted on the way, its checksum does not match".into());
+        }
+
+        let path = unique_path(&directory, &offer.name);
+
+        std::fs::rename(&part, &path)?;
+
+        Ok(path)
+    }
+
+    // Gives up on the file, deleting what arrived of it
+    fn discard(self) {
+        drop(self.file);
+
+        if let Err(e) = std::fs::remove_file(&self.part) {
+            log::warn!("Could not delete {}: {}", self.part.display(), e);
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            transfer_id: self.offer.transfer_id.clone(),
+            name: self.offer.name.clone(),
+            direction: Direction::Receiving,
+            size: self.offer.size,
+            transferred: self.received,
+            state: State::Transferring,
+        }
+    }
+}
+
+// The file transfers of a session, in both directions
+#[derive(Default)]
+pub struct Transfers {
+    outgoing: HashMap<String, Outgoing>,
+    incoming: HashMap<String, Incoming>,
+    requests: HashMap<String, String>, // Paths of the files we asked for, until they are received
+    finished: Vec<Progress>,
+}
+
+impl Transfers {
+    // Offers a file to the other side, replacing any transfer with the same id
+    pub fn offer(&mut self, outgoing: Outgoing) -> Payload {
+        let payload = payload(
+            OP::FileOffer,
+            PayloadData::FileOffer(outgoing.offer.clone()),
+        );
+
+        self.outgoing
+            .insert(outgoing.offer.transfer_id.clone(), outgoing);
+
+        payload
+    }
+
+    // Asks the server to offer one of its shared files
+    pub fn request(&mut self, transfer_id: String, path: String) -> Payload {
+        self.requests.insert(transfer_id.clone(), path.clone());
+
+        payload(
+            OP::RequestFile,
+            PayloadData::RequestFile(RequestFileData { transfer_id, path }),
+        )
+    }
+
+    // Gives up on a transfer, returning the payload telling the other side
+    pub fn cancel(&mut self, transfer_id: &str) -> Payload {
+        self.fail(transfer_id, "Cancelled".to_string())
+    }
+
+    // Gives up on a transfer that can't go on, returning the payload telling the other side
+    pub fn fail(&mut self, transfer_id: &str, error: String) -> Payload {
+        log::warn!("File transfer {} failed: {}", transfer_id, error);
+
+        self.end(transfer_id, State::Failed(error.clone()));
+
+        end_payload(transfer_id, Some(error))
+    }
+
+    // Remembers a transfer that failed before it could be tracked, like when a file can't be opened
+    pub fn failed_to_start(
+        &mut self,
+        transfer_id: String,
+        name: String,
+        direction: Direction,
+        error: String,
+    ) {
+        log::warn!("Could not transfer {}: {}", name, error);
+
+        self.finished.push(Progress {
+            transfer_id,
+            name,
+            direction,
+            size: 0,
+            transferred: 0,
+            state: State::Failed(error),
+        });
+    }
+
+    // Stops tracking a transfer, remembering how it ended
+    fn end(&mut self, transfer_id: &str, state: State) {
+        let path = self.requests.remove(transfer_id);
+
+        let progress = if let Some(outgoing) = self.outgoing.remove(transfer_id) {
+            outgoing.progress()
+        } else if let Some(incoming) = self.incoming.remove(transfer_id) {
+            let progress = incoming.progress();
+
+            // A complete file has already been moved into place
+            if !matches!(state, State::Complete(_)) {
+                incoming.discard();
+            }
+
+            progress
+        } else if let Some(path) = path {
+            Progress {
+                transfer_id: transfer_id.to_string(),
+                name: path,
+                direction: Direction::Receiving,
+                size: 0,
+                transferred: 0,
+                state: State::Requested,
+            }
+        } else {
+            return;
+        };
+
+        self.finished.push(Progress { state, ..progress });
+    }
+
+    // Handles a file payload from the other side, saving received files in the directory.
+    // Returns the payloads to send back.
+    pub fn handle(&mut self, directory: &Path, data: &PayloadData) -> Vec<Payload> {
+        match data {
+            PayloadData::FileOffer(offer) => {
+                let transfer_id = offer.transfer_id.clone();
+                let name = offer.name.clone();
+
+                // Offering again starts over from wherever the transfer got to, like after reconnecting
+                drop(self.incoming.remove(&transfer_id));
+
+                let incoming = match Incoming::create(offer.clone(), directory) {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        self.requests.remove(&transfer_id);
+                        self.failed_to_start(
+                            transfer_id.clone(),
+                            name,
+                            Direction::Receiving,
+                            e.to_string(),
+                        );
+
+                        return vec![end_payload(&transfer_id, Some(e.to_string()))];
+                    }
+                };
+
+                let mut payloads = vec![payload(
+                    OP::FileAccept,
+                    PayloadData::FileAccept(FileAcceptData {
+                        transfer_id: transfer_id.clone(),
+                        offset: incoming.received,
+                    }),
+                )];
+
+                self.incoming.insert(transfer_id.clone(), incoming);
+
+                // All of it may have arrived before
+                payloads.extend(self.finish_if_complete(&transfer_id));
+
+                payloads
+            }
+            PayloadData::FileAccept(data) => {
+                let Some(outgoing) = self.outgoing.get_mut(&data.transfer_id) else {
+                    return Vec::new();
+                };
+
+                if let Err(e) = outgoing.accept(data.offset) {
+                    return vec![self.fail(&data.transfer_id, e.to_string())];
+                }
+
+                self.pump(&data.transfer_id)
+            }
+            PayloadData::FileChunk(chunk) => {
+                // Chunks sent before the transfer was cancelled can still arrive
+                let Some(incoming) = self.incoming.get_mut(&chunk.transfer_id) else {
+                    return Vec::new();
+                };
+
+                if let Err(e) = incoming.write(chunk) {
+                    return vec![self.fail(&chunk.transfer_id, e.to_string())];
+                }
+
+                let mut payloads = vec![payload(
+                    OP::FileAck,
+                    PayloadData::FileAck(FileAckData {
+                        transfer_id: chunk.transfer_id.clone(),
+                        received: incoming.received,
+                    }),
+                )];
+
+                payloads.extend(self.finish_if_complete(&chunk.transfer_id));
+
+                payloads
+            }
+            PayloadData::FileAck(data) => {
+                let Some(outgoing) = self.outgoing.get_mut(&data.transfer_id) else {
+                    return Vec::new();
+                };
+
+                outgoing.acknowledged = data.received.clamp(outgoing.acknowledged, outgoing.sent);
+
+                self.pump(&data.transfer_id)
+            }
+            PayloadData::FileEnd(data) => {
+                let state = match &data.error {
+                    Some(error) => State::Failed(error.clone()),
+                    // Only the receiver says the file arrived, and it has to have all of it to say so
+                    None if self.outgoing.contains_key(&data.transfer_id) => State::Complete(None),
+                    None => State::Failed("Ended before the whole file arrived".to_string()),
+                };
+
+                self.end(&data.transfer_id, state);
+
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Sends as much of a file as the other side has room for
+    fn pump(&mut self, transfer_id: &str) -> Vec<Payload> {
+        let mut payloads = Vec::new();
+
+        while let Some(outgoing) = self.outgoing.get_mut(transfer_id) {
+            match outgoing.next_chunk() {
+                Ok(Some(chunk)) => {
+                    payloads.push(payload(OP::FileChunk, PayloadData::FileChunk(chunk)))
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    payloads.push(self.fail(transfer_id, e.to_string()));
+
+                    break;
+                }
+            }
+        }
+
+        payloads
+    }
+
+    // Moves a fully received file into place, telling the sender how it went
+    fn finish_if_complete(&mut self, transfer_id: &str) -> Option<Payload> {
+        if !self.incoming.get(transfer_id)?.is_complete() {
+            return None;
+        }
+
+        let incoming = self.incoming.remove(transfer_id)?;
+        let progress = incoming.progress();
+
+        self.requests.remove(transfer_id);
+
+        let (state, error) = match incoming.finish() {
+            Ok(path) => {
+                log::info!("Received {}", path.display());
+
+                (State::Complete(Some(path)), None)
+            }
+            Err(e) => {
+                log::warn!("Could not receive {}: {}", progress.name, e);
+
+                (State::Failed(e.to_string()), Some(e.to_string()))
+            }
+        };
+
+        self.finished.push(Progress { state, ..progress });
+
+        Some(end_payload(transfer_id, error))
+    }
+
+    // After reconnecting, offers and asks for the unfinished files again so they carry on where they got to
+    pub fn resync(&mut self) -> Vec<Payload> {
+        let mut payloads = Vec::new();
+
+        for outgoing in self.outgoing.values_mut() {
+            outgoing.accepted = false;
+
+            payloads.push(payload(
+                OP::FileOffer,
+                PayloadData::FileOffer(outgoing.offer.clone()),
+            ));
+        }
+
+        for (transfer_id, path) in &self.requests {
+            payloads.push(payload(
+                OP::RequestFile,
+                PayloadData::RequestFile(RequestFileData {
+                    transfer_id: transfer_id.clone(),
+                    path: path.clone(),
+                }),
+            ));
+        }
+
+        payloads
+    }
+
+    // Whether any transfer is still going
+    pub fn is_active(&self) -> bool {
+        !self.outgoing.is_empty() || !self.incoming.is_empty() || !self.requests.is_empty()
+    }
+
+    // Every transfer, finished ones first, and the rest by name so they don't move around between looks
+    pub fn progress(&self) -> Vec<Progress> {
+        let mut progress = Vec::new();
+
+        progress.extend(self.outgoing.values().map(Outgoing::progress));
+        progress.extend(self.incoming.values().map(Incoming::progress));
+        progress.extend(
+            self.requests
+                .iter()
+                .filter(|(transfer_id, _)| !self.incoming.contains_key(*transfer_id))
+                .map(|(transfer_id, path)| Progress {
+                    transfer_id: transfer_id.clone(),
+                    name: path.clone(),
+                    direction: Direction::Receiving,
+                    size: 0,
+                    transferred: 0,
+                    state: State::Requested,
+                }),
+        );
+
+        progress.sort_by(|a, b| (&a.name, &a.transfer_id).cmp(&(&b.name, &b.transfer_id)));
+        progress.splice(0..0, self.finished.iter().cloned());
+
+        progress
+    }
+
+    pub fn clear_finished(&mut self) {
+        self.finished.clear();
+    }
+}
+
+fn payload(op_code: OP, data: PayloadData) -> Payload {
+    Payload {
+        op_code,
+        event_name: Event::None,
+        data,
+        sequence: None,
+        nonce: None,
+    }
+}
+
+fn end_payload(transfer_id: &str, error: Option<String>) -> Payload {
+    payload(
+        OP::FileEnd,
+        PayloadData::FileEnd(FileEndData {
+            transfer_id: transfer_id.to_string(),
+            error,
+        }),
+    )
+}
+
+// Hashes everything left to read, returning how many bytes that was
+fn hash(reader: &mut impl Read, hasher: &mut sha2::Sha256) -> std::io::Result<u64> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+
+        if read == 0 {
+            return Ok(size);
+        }
+
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+}
+
+fn hex(hasher: sha2::Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Whether a name from the other side is a plain file name, which can't point outside the directory
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    !name.contains(['/', '\\'])
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(component)), None) if component == name
+        )
+}
+
+// Where to save a received file, next to any file of the same name rather than over it
+fn unique_path(directory: &Path, name: &str) -> PathBuf {
+    let path = directory.join(name);
+
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    (1..)
+        .map(|number| directory.join(format!("{} ({}){}", stem, number, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory for one test
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("xyncer-transfer-{}-{}", std::process::id(), name));
+
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    // Contents of a test file, which differ from byte to byte so misplaced chunks would show
+    fn contents(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    // One side of a transfer
+    struct Side {
+        transfers: Transfers,
+        directory: PathBuf,
+    }
+
+    impl Side {
+        fn new(directory: PathBuf) -> Self {
+            Side {
+                transfers: Transfers::default(),
+                directory,
+            }
+        }
+
+        fn handle(&mut self, payloads: Vec<Payload>) -> Vec<Payload> {
+            payloads
+                .iter()
+                .flat_map(|payload| self.transfers.handle(&self.directory, &payload.data))
+                .collect()
+        }
+
+        // The chunks among some payloads
+        fn chunks(payloads: &[Payload]) -> Vec<&FileChunkData> {
+            payloads
+                .iter()
+                .filter_map(|payload| match &payload.data {
+                    PayloadData::FileChunk(chunk) => Some(chunk),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    // Passes payloads back and forth until neither side has anything more to say
+    fn exchange(sender: &mut Side, receiver: &mut Side, mut payloads: Vec<Payload>) {
+        while !payloads.is_empty() {
+            payloads = sender.handle(receiver.handle(payloads));
+        }
+    }
+
+    // Offers a new file with the given contents from the sender
+    fn offer(sender: &mut Side, name: &str, data: &[u8]) -> Payload {
+        let path = sender.directory.join(name);
+
+        std::fs::write(&path, data).unwrap();
+
+        let outgoing = Outgoing::open("transfer".to_string(), &path).unwrap();
+
+        sender.transfers.offer(outgoing)
+    }
+
+    fn state(side: &Side) -> State {
+        side.transfers.progress().pop().unwrap().state
+    }
+
+    #[test]
+    fn files_arrive_whole() {
+        let mut sender = Side::new(directory("whole-sender"));
+        let mut receiver = Side::new(directory("whole-receiver"));
+        let data = contents(3 * CHUNK_SIZE + 7);
+
+        let offer = offer(&mut sender, "file.bin", &data);
+
+        exchange(&mut sender, &mut receiver, vec![offer]);
+
+        let path = receiver.directory.join("file.bin");
+
+        assert_eq!(state(&receiver), State::Complete(Some(path.clone())));
+        assert_eq!(state(&sender), State::Complete(None));
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        assert!(!receiver.transfers.is_active() && !sender.transfers.is_active());
+    }
+
+    #[test]
+    fn half_sent_transfers_resume_from_what_arrived() {
+        let mut sender = Side::new(directory("resume-sender"));
+        let mut receiver = Side::new(directory("resume-receiver"));
+        let data = contents(5 * CHUNK_SIZE + 7);
+
+        let offer = offer(&mut sender, "file.bin", &data);
+        let chunks = sender.handle(receiver.handle(vec![offer]));
+
+        assert_eq!(Side::chunks(&chunks).len(), 6);
+
+        // Only two chunks make it before the connection goes, and the receiver starts over with what is on disk
+        receiver.handle(chunks.into_iter().take(2).collect());
+        receiver.transfers = Transfers::default();
+
+        let accepts = receiver.handle(sender.transfers.resync());
+
+        let [Payload {
+            data: PayloadData::FileAccept(accept),
+            ..
+        }] = accepts.as_slice()
+        else {
+            panic!("expected an accept, got {:?}", accepts);
+        };
+
+        assert_eq!(accept.offset, 2 * CHUNK_SIZE as u64);
+
+        // The sender carries on from there rather than from the start
+        let chunks = sender.handle(accepts);
+
+        assert_eq!(Side::chunks(&chunks)[0].offset, 2 * CHUNK_SIZE as u64);
+
+        exchange(&mut sender, &mut receiver, chunks);
+
+        assert_eq!(
+            std::fs::read(receiver.directory.join("file.bin")).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn corrupted_files_fail() {
+        let mut sender = Side::new(directory("corrupted-sender"));
+        let mut receiver = Side::new(directory("corrupted-receiver"));
+
+        let offer = offer(&mut sender, "file.bin", &contents(2 * CHUNK_SIZE));
+        let mut chunks = sender.handle(receiver.handle(vec![offer]));
+
+        if let PayloadData::FileChunk(chunk) = &mut chunks[1].data {
+            chunk.data[0] ^= 1;
+        }
+
+        exchange(&mut sender, &mut receiver, chunks);
+
+        assert!(matches!(state(&receiver), State::Failed(_)));
+        assert!(matches!(state(&sender), State::Failed(_)));
+
+        // Nothing of it is kept, not even to resume from
+        assert_eq!(std::fs::read_dir(&receiver.directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn chunks_out_of_place_fail() {
+        let mut sender = Side::new(directory("misplaced-sender"));
+        let mut receiver = Side::new(directory("misplaced-receiver"));
+
+        let offer = offer(&mut sender, "file.bin", &contents(3 * CHUNK_SIZE));
+        let mut chunks = sender.handle(receiver.handle(vec![offer]));
+
+        chunks.swap(0, 1);
+
+        exchange(&mut sender, &mut receiver, chunks);
+
+        assert!(matches!(state(&receiver), State::Failed(_)));
+        assert!(matches!(state(&sender), State::Failed(_)));
+        assert_eq!(std::fs::read_dir(&receiver.directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn offers_with_bad_checksums_are_refused() {
+        let mut receiver = Side::new(directory("checksum-receiver"));
+
+        let replies = receiver.handle(vec![payload(
+            OP::FileOffer,
+            PayloadData::FileOffer(FileOfferData {
+                transfer_id: "transfer".to_string(),
+                name: "file.bin".to_string(),
+                size: 1,
+                checksum: "not a checksum".to_string(),
+            }),
+        )]);
+
+        assert!(matches!(
+            replies.as_slice(),
+            [Payload {
+                data: PayloadData::FileEnd(FileEndData { error: Some(_), .. }),
+                ..
+            }]
+        ));
+        assert!(matches!(state(&receiver), State::Failed(_)));
+    }
+
+    #[test]
+    fn names_that_escape_the_directory_are_refused() {
+        let parent = directory("escape");
+        let mut receiver = Side::new(parent.join("downloads"));
+
+        for name in [
+            "../escaped",
+            "..",
+            ".",
+            "",
+            "nested/escaped",
+            "nested\\escaped",
+            "/tmp/escaped",
+            "C:\\escaped",
+        ] {
+            let replies = receiver.handle(vec![payload(
+                OP::FileOffer,
+                PayloadData::FileOffer(FileOfferData {
+                    transfer_id: name.to_string(),
+                    name: name.to_string(),
+                    size: 1,
+                    checksum: "0".repeat(64),
+                }),
+            )]);
+
+            assert!(
+                matches!(
+                    replies.as_slice(),
+                    [Payload {
+                        data: PayloadData::FileEnd(FileEndData { error: Some(_), .. }),
+                        ..
+                    }]
+                ),
+                "{:?} was accepted",
+                name
+            );
+        }
+
+        // Not even the download directory was made
+        assert_eq!(std::fs::read_dir(&parent).unwrap().count(), 0);
+        assert!(is_file_name("file.tar.gz") && is_file_name(".hidden"));
+    }
+
+    #[test]
+    fn received_files_do_not_replace_existing_ones() {
+        let directory = directory("unique");
+
+        std::fs::write(directory.join("file.txt"), "").unwrap();
+        std::fs::write(directory.join("file (1).txt"), "").unwrap();
+
+        assert_eq!(
+            unique_path(&directory, "file.txt"),
+            directory.join("file (2).txt")
+        );
+        assert_eq!(unique_path(&directory, "other"), directory.join("other"));
+    }
+}